@@ -0,0 +1,9 @@
+CREATE TABLE audit_log (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	method TEXT NOT NULL,
+	route TEXT NOT NULL,
+	client_ip TEXT,
+	scope TEXT NOT NULL,
+	record_ids TEXT NOT NULL DEFAULT '',
+	created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN last_seen_at TIMESTAMP;
+
+UPDATE users SET last_seen_at = (SELECT MAX(created_at) FROM handshakes WHERE handshakes.user_id = users.id);
+
+CREATE INDEX users_last_seen_at ON users (last_seen_at);
@@ -0,0 +1,4 @@
+-- SQLite can't add a column with a non-constant default, so new users have this set explicitly when they're inserted
+ALTER TABLE users ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+
+UPDATE users SET updated_at = created_at;
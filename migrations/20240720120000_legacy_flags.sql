@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN legacy BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE handshakes ADD COLUMN legacy BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE users SET legacy = TRUE WHERE resonite_id IS NULL;
+UPDATE handshakes SET legacy = TRUE WHERE user_id IN (SELECT id FROM users WHERE legacy);
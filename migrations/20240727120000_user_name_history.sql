@@ -0,0 +1,10 @@
+CREATE TABLE user_name_history (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	user_id INTEGER NOT NULL,
+	old_name TEXT NOT NULL,
+	new_name TEXT NOT NULL,
+	changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY(user_id) REFERENCES users(id)
+);
+CREATE INDEX user_name_history_user_id ON user_name_history (user_id);
+CREATE INDEX user_name_history_old_name ON user_name_history (old_name);
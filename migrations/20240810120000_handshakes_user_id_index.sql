@@ -0,0 +1 @@
+CREATE INDEX handshakes_user_id ON handshakes (user_id);
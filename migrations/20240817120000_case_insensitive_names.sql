@@ -0,0 +1,4 @@
+CREATE INDEX users_resonite_name_nocase ON users (resonite_name COLLATE NOCASE);
+
+DROP INDEX user_name_history_old_name;
+CREATE INDEX user_name_history_old_name ON user_name_history (old_name COLLATE NOCASE);
@@ -0,0 +1,29 @@
+CREATE TABLE data_revision (
+	id INTEGER PRIMARY KEY CHECK (id = 1),
+	revision INTEGER NOT NULL
+);
+INSERT INTO data_revision (id, revision) VALUES (1, 0);
+
+CREATE TRIGGER users_revision_insert AFTER INSERT ON users BEGIN
+	UPDATE data_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER users_revision_update AFTER UPDATE ON users BEGIN
+	UPDATE data_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER users_revision_delete AFTER DELETE ON users BEGIN
+	UPDATE data_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER handshakes_revision_insert AFTER INSERT ON handshakes BEGIN
+	UPDATE data_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER handshakes_revision_update AFTER UPDATE ON handshakes BEGIN
+	UPDATE data_revision SET revision = revision + 1;
+END;
+
+CREATE TRIGGER handshakes_revision_delete AFTER DELETE ON handshakes BEGIN
+	UPDATE data_revision SET revision = revision + 1;
+END;
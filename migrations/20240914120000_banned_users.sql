@@ -0,0 +1,11 @@
+CREATE TABLE banned_users (
+	id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+	resonite_id TEXT UNIQUE,
+	resonite_name TEXT COLLATE NOCASE,
+	reason TEXT,
+	banned_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	CHECK (resonite_id IS NOT NULL OR resonite_name IS NOT NULL)
+);
+
+-- Bans without a Resonite ID match on the username instead, for legacy users that were never seen with one
+CREATE UNIQUE INDEX banned_users_resonite_name ON banned_users (resonite_name) WHERE resonite_id IS NULL;
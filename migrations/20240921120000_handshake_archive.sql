@@ -0,0 +1,4 @@
+CREATE TABLE handshake_archive (
+	day DATE PRIMARY KEY NOT NULL,
+	count INTEGER NOT NULL
+);
@@ -0,0 +1,6 @@
+CREATE TABLE name_refreshes (
+	user_id INTEGER PRIMARY KEY NOT NULL,
+	refreshed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX name_refreshes_refreshed_at ON name_refreshes (refreshed_at);
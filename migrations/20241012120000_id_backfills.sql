@@ -0,0 +1,6 @@
+CREATE TABLE id_backfills (
+	user_id INTEGER PRIMARY KEY NOT NULL,
+	outcome TEXT NOT NULL,
+	attempted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
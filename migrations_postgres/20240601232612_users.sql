@@ -0,0 +1,7 @@
+-- Names are compared byte by byte (like SQLite's default collation), so that they sort the same in either database
+CREATE TABLE users (
+	id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+	resonite_id TEXT UNIQUE,
+	resonite_name TEXT COLLATE "C" UNIQUE NOT NULL,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
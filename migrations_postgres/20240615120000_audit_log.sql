@@ -0,0 +1,9 @@
+CREATE TABLE audit_log (
+	id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+	method TEXT NOT NULL,
+	route TEXT NOT NULL,
+	client_ip TEXT,
+	scope TEXT NOT NULL,
+	record_ids TEXT NOT NULL DEFAULT '',
+	created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1 @@
+ALTER TABLE handshakes ADD COLUMN source TEXT COLLATE "C";
@@ -0,0 +1,9 @@
+CREATE TABLE user_name_history (
+	id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+	user_id BIGINT NOT NULL REFERENCES users(id),
+	old_name TEXT COLLATE "C" NOT NULL,
+	new_name TEXT COLLATE "C" NOT NULL,
+	changed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX user_name_history_user_id ON user_name_history (user_id);
+CREATE INDEX user_name_history_old_name ON user_name_history (old_name);
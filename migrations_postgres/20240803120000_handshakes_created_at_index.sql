@@ -0,0 +1 @@
+CREATE INDEX handshakes_created_at_id ON handshakes (created_at, id);
@@ -0,0 +1,5 @@
+-- Names are compared without regard to case by lowering both sides, which these indexes cover
+CREATE INDEX users_resonite_name_lower ON users (lower(resonite_name));
+
+DROP INDEX user_name_history_old_name;
+CREATE INDEX user_name_history_old_name ON user_name_history (lower(old_name));
@@ -0,0 +1 @@
+-- There's no equivalent of SQLite's trigram index of usernames without an extension, so searches scan the names instead
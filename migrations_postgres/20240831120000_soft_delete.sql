@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE handshakes ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX users_deleted_at ON users (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX handshakes_deleted_at ON handshakes (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,25 @@
+-- A counter in a table rather than a sequence, since the revision mustn't change until the write that changed it commits
+CREATE TABLE data_revision (
+	id INTEGER PRIMARY KEY CHECK (id = 1),
+	revision BIGINT NOT NULL
+);
+INSERT INTO data_revision (id, revision) VALUES (1, 0);
+
+-- The revision only needs to change once per transaction, so later changes in the same one skip the update
+CREATE FUNCTION advance_data_revision() RETURNS TRIGGER LANGUAGE plpgsql AS $$
+BEGIN
+	IF current_setting('shaker.data_revision_advanced', true) IS DISTINCT FROM 'on' THEN
+		UPDATE data_revision SET revision = revision + 1;
+		PERFORM set_config('shaker.data_revision_advanced', 'on', true);
+	END IF;
+	RETURN NULL;
+END;
+$$;
+
+-- Deferring the triggers to the end of the transaction means the counter's row is always the last thing locked, so
+-- concurrent writes can't deadlock over it
+CREATE CONSTRAINT TRIGGER users_revision AFTER INSERT OR UPDATE OR DELETE ON users
+	DEFERRABLE INITIALLY DEFERRED FOR EACH ROW EXECUTE FUNCTION advance_data_revision();
+
+CREATE CONSTRAINT TRIGGER handshakes_revision AFTER INSERT OR UPDATE OR DELETE ON handshakes
+	DEFERRABLE INITIALLY DEFERRED FOR EACH ROW EXECUTE FUNCTION advance_data_revision();
@@ -0,0 +1,11 @@
+CREATE TABLE banned_users (
+	id BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+	resonite_id TEXT UNIQUE,
+	resonite_name TEXT COLLATE "C",
+	reason TEXT,
+	banned_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	CHECK (resonite_id IS NOT NULL OR resonite_name IS NOT NULL)
+);
+
+-- Bans without a Resonite ID match on the username (in any case) instead, for legacy users that were never seen with one
+CREATE UNIQUE INDEX banned_users_resonite_name ON banned_users (lower(resonite_name)) WHERE resonite_id IS NULL;
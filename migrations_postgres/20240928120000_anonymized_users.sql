@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN anonymized BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+CREATE TABLE name_refreshes (
+	user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+	refreshed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX name_refreshes_refreshed_at ON name_refreshes (refreshed_at);
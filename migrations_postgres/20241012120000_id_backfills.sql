@@ -0,0 +1,5 @@
+CREATE TABLE id_backfills (
+	user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+	outcome TEXT NOT NULL,
+	attempted_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+ALTER TABLE handshakes ADD COLUMN event TEXT COLLATE "C";
+CREATE INDEX handshakes_event ON handshakes (event);
@@ -1,51 +1,747 @@
+use std::{
+	future::{Future, IntoFuture},
+	net::{IpAddr, SocketAddr},
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use ::time::{format_description::well_known::Iso8601, Date, OffsetDateTime};
 use anyhow::Result;
 use axum::{
 	async_trait,
-	extract::{Form, FromRef, FromRequestParts, Query, State},
-	http::{request::Parts, StatusCode},
+	body::{Body, Bytes},
+	extract::{Form, FromRef, FromRequest, FromRequestParts, MatchedPath, Path, Query, Request, State},
+	http::{header, request::Parts, HeaderMap, Method, StatusCode},
+	middleware::{self, Next},
 	response::{IntoResponse, Response},
-	routing::{get, post},
-	Router,
+	routing::{delete, get, post, put},
+	Json, Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use ipnet::IpNet;
+use secrecy::Secret;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use time_tz::{OffsetDateTimeExt, TimeZone, Tz};
+use tokio::{
+	net::TcpListener,
+	signal,
+	sync::{broadcast, watch, RwLock, Semaphore},
+	time,
+};
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+	auth::{self, Scope, ScopedToken, TokenDigest, TokenRegistry},
+	backup::{self, Backup, BackupError, BackupErrorKind},
+	db::{
+		self, Anniversary, AuditEntry, Ban, BannedError, ConflictError, CreatedHandshake, DayBreakdown, DayCount,
+		DbError, EventSummary, Handshake, HandshakeContext, HandshakeRate, HandshakeWithUser, HeatmapCell,
+		IntegrityReport, LimitViolation, NameChange, NameCollision, NewBan, OutboxEntry, SourceCount, Stats,
+		UndoOutcome, User, UserOrder, UserStats, UserWithCount, WorldStats,
+	},
+	discord::Discord,
+	resonite::{NameRefresher, Resonite},
+	systemd::{self, Notification},
+	tls,
+	validate::{self, FieldLimits, ValidationError},
+	webhook::Webhooks,
+	Config,
 };
-use secrecy::{ExposeSecret, Secret};
-use serde::Deserialize;
-use tokio::{net::TcpListener, signal};
-use tracing::{info, warn};
 
-use crate::{db, Config};
+mod cache;
+mod client_ip;
+mod dashboard;
+mod display;
+mod docs;
+mod export;
+mod heartbeat;
+mod limits;
+mod live;
+mod metrics;
+mod trace;
+#[cfg(unix)]
+mod unix;
+
+/// Where the API server listens for connections
+#[derive(Debug)]
+pub enum Listener {
+	/// Bind a TCP listener to an address
+	Address(SocketAddr),
+
+	/// Accept connections on a TCP listener that's already bound, such as one passed by systemd
+	Inherited(std::net::TcpListener),
+
+	/// Create a Unix domain socket at a path, with the given permissions
+	Unix {
+		/// Path to create the socket at
+		path: PathBuf,
+
+		/// Permissions to give the socket
+		mode: u32,
+	},
+}
+
+impl Listener {
+	/// Determines where to listen from the configuration, taking the socket passed by systemd if it started Shaker via
+	/// socket activation rather than binding one
+	pub fn from_config(cfg: &Config) -> Result<Self> {
+		let activated = systemd::take_listener()?;
+		match (activated, &cfg.api_unix) {
+			(Some(_), Some(_)) => {
+				anyhow::bail!("Unable to listen on a Unix domain socket when systemd passes a socket to listen on")
+			}
+			(Some(listener), None) => Ok(Self::Inherited(listener)),
+			(None, Some(path)) => Ok(Self::Unix {
+				path: path.clone(),
+				mode: cfg.api_unix_mode,
+			}),
+			(None, None) => Ok(Self::Address(cfg.api)),
+		}
+	}
+}
+
+/// Settings of the API server itself, as opposed to the state shared by its handlers (see [`AppState`])
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+	/// Address to serve the admin endpoints on instead of alongside the rest of the API
+	pub admin_api: Option<SocketAddr>,
+
+	/// Address to serve metrics on instead of alongside the API
+	pub metrics: Option<SocketAddr>,
+
+	/// Certificate file to serve HTTPS with (along with the key), instead of plain HTTP
+	pub tls_cert: Option<PathBuf>,
+
+	/// Private key file to serve HTTPS with (along with the certificate), instead of plain HTTP
+	pub tls_key: Option<PathBuf>,
+
+	/// File that the accepted tokens are reloaded from on SIGHUP
+	pub token_file: Option<PathBuf>,
+
+	/// Maximum time to wait for in-flight requests to finish once a shutdown has been requested
+	pub drain_timeout: Duration,
+
+	/// Interval to log heartbeats at
+	pub heartbeat_interval: Option<Duration>,
+
+	/// Directory to write scheduled backups to
+	pub backup_dir: Option<PathBuf>,
+
+	/// Interval to write scheduled backups at
+	pub backup_interval: Option<Duration>,
+
+	/// Number of scheduled backups to keep
+	pub backup_keep: usize,
+
+	/// Time after being deleted that records are purged
+	pub purge_deleted_after: Option<::time::Duration>,
+
+	/// Time after taking place that handshakes are deleted
+	pub retention: Option<::time::Duration>,
+
+	/// Whether handshakes are archived before being deleted after the retention period
+	pub retention_archive: bool,
+}
+
+impl ServeOptions {
+	/// Takes the settings of the API server from the configuration
+	pub fn from_config(cfg: &Config) -> Result<Self> {
+		let days = |days: u64| anyhow::Ok(::time::Duration::days(days.try_into()?));
+		Ok(Self {
+			admin_api: cfg.admin_api,
+			metrics: cfg.metrics,
+			tls_cert: cfg.tls_cert.clone(),
+			tls_key: cfg.tls_key.clone(),
+			token_file: cfg.token_file.clone(),
+			drain_timeout: Duration::from_secs(cfg.drain_timeout),
+			heartbeat_interval: cfg.heartbeat_interval.map(Duration::from_secs),
+			backup_dir: cfg.backup_dir.clone(),
+			backup_interval: cfg.backup_interval.map(Duration::from_secs),
+			backup_keep: cfg.backup_keep.into(),
+			purge_deleted_after: cfg.purge_deleted_after.map(days).transpose()?,
+			retention: cfg.retention_days.map(days).transpose()?,
+			retention_archive: cfg.retention_archive,
+		})
+	}
+}
 
-/// Runs the API server
-pub async fn run(cfg: Config, db: db::Database) -> Result<()> {
+/// Runs the API server on a listener, with handlers sharing the given state
+pub async fn run(options: ServeOptions, listener: Listener, mut state: AppState) -> Result<()> {
 	info!("Running API server");
 
-	if cfg.token.is_none() {
+	if !state.auth_required {
 		warn!("No token provided in configuration - requests will not be required to provide a token to authenticate");
 	}
+	let db = state.db.clone();
+
+	// Broadcast the shutdown signal so the server, the drain timer, and long-lived responses can all watch for it
+	let (shutdown_tx, shutdown_rx) = watch::channel(false);
+	tokio::spawn(async move {
+		shutdown_signal().await;
+		systemd::notify(&[Notification::Stopping]);
+		let _ = shutdown_tx.send(true);
+	});
+	state.shutdown = shutdown_rx.clone();
+
+	// Activity is counted in memory for heartbeats, so they're cheap to log however often they're configured
+	if let Some(interval) = options.heartbeat_interval {
+		heartbeat::spawn(Arc::clone(&state.activity), db.clone(), interval, shutdown_rx.clone());
+	}
+
+	#[cfg(unix)]
+	tokio::spawn(reload_tokens_on_hangup(
+		Arc::clone(&state.tokens),
+		options.token_file.clone(),
+	));
+
+	// Serve the admin endpoints on their own listener if one was configured, otherwise alongside the rest of the API
+	let in_flight = state.in_flight.clone();
+	let backups = state.backups.clone();
+	let (mut app, admin) = if let Some(addr) = options.admin_api {
+		let listener = TcpListener::bind(addr).await?;
+		info!("Serving admin endpoints on http://{addr}");
+		(
+			with_layers(routes(&state), state.clone()),
+			Some((listener, with_layers(admin_routes(), state))),
+		)
+	} else {
+		(router(state), None)
+	};
+
+	// Serve metrics on their own listener if one was configured, otherwise alongside the API
+	let metrics = metrics::router(metrics::install(db.clone())?);
+	if let Some(addr) = options.metrics {
+		let listener = TcpListener::bind(addr).await?;
+		info!("Serving metrics on http://{addr}/metrics");
+		tokio::spawn(
+			axum::serve(listener, metrics)
+				.with_graceful_shutdown(shutdown_requested(shutdown_rx.clone()))
+				.into_future(),
+		);
+	} else {
+		app = app.merge(metrics);
+	}
+
+	// Schedule background maintenance now that the metrics recorder is installed, so the backup times are exported
+	spawn_maintenance(&options, db, backups);
+	systemd::spawn_watchdog(shutdown_rx.clone());
+
+	let server = serve(&options, listener, app, shutdown_requested(shutdown_rx.clone()));
+	let admin_server = serve_admin(admin, shutdown_requested(shutdown_rx.clone()));
+	let server = async { tokio::try_join!(server, admin_server).map(|_| ()) };
+	tokio::pin!(server);
+
+	// Serve until a shutdown is requested, then give in-flight requests a limited amount of time to finish
+	tokio::select! {
+		result = &mut server => return result,
+		() = shutdown_requested(shutdown_rx) => {}
+	}
+
+	let pending = in_flight.count();
+	info!(
+		"Shutting down; waiting up to {}s for {pending} in-flight request(s) to finish",
+		options.drain_timeout.as_secs()
+	);
+
+	if let Ok(result) = time::timeout(options.drain_timeout, server).await {
+		result?;
+		info!("Drained {pending} in-flight request(s)");
+	} else {
+		warn!(
+			"Drain timeout hit with {} of {pending} in-flight request(s) unfinished",
+			in_flight.count()
+		);
+	}
+
+	Ok(())
+}
+
+/// Creates the Resonite API client that submitted handshakes are verified with, if verification is enabled
+fn user_verifier(cfg: &Config) -> Result<Option<Resonite>> {
+	if !cfg.verify_users {
+		return Ok(None);
+	}
+
+	Ok(Some(Resonite::new(
+		cfg.resonite_api_url.clone(),
+		Duration::from_secs(cfg.verify_users_cache_ttl),
+		cfg.verify_users_canonical_names,
+	)?))
+}
+
+/// Spawns the task that refreshes usernames from the Resonite API, if a refresh interval is configured
+fn spawn_name_refresher(cfg: &Config, db: &db::Database) -> Result<Option<NameRefresher>> {
+	let Some(interval) = cfg.refresh_names_interval else {
+		return Ok(None);
+	};
+
+	// Refreshing always fetches names fresh, so lookups aren't cached
+	Ok(Some(NameRefresher::spawn(
+		db.clone(),
+		Resonite::new(cfg.resonite_api_url.clone(), Duration::ZERO, false)?,
+		Duration::from_secs(interval),
+		cfg.refresh_names_rate,
+		::time::Duration::days(cfg.refresh_names_after.try_into()?),
+	)))
+}
+
+/// Spawns the tasks for any scheduled backups, purging of deleted records, and deletion of handshakes past the retention
+/// period that are configured
+fn spawn_maintenance(options: &ServeOptions, db: db::Database, backups: backup::Schedule) {
+	if let (Some(dir), Some(interval)) = (&options.backup_dir, options.backup_interval) {
+		backup::spawn_scheduled(db.clone(), dir.clone(), interval, options.backup_keep, backups);
+	}
+	if let Some(retention) = options.purge_deleted_after {
+		spawn_purging(db.clone(), retention);
+	}
+	if let Some(retention) = options.retention {
+		spawn_expiring(db, retention, options.retention_archive);
+	}
+}
+
+/// Spawns a task that permanently purges records deleted longer ago than a retention period, checking once an hour
+fn spawn_purging(db: db::Database, retention: ::time::Duration) {
+	info!("Purging deleted records after {} day(s)", retention.whole_days());
+	tokio::spawn(async move {
+		let mut ticker = time::interval(Duration::from_hours(1));
+		ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			match db.purge_deleted(OffsetDateTime::now_utc() - retention).await {
+				Ok(db::PurgedCounts {
+					users: 0,
+					handshakes: 0,
+				}) => {}
+				Ok(counts) => info!(
+					"Purged {} deleted user(s) and {} deleted handshake(s)",
+					counts.users, counts.handshakes
+				),
+				Err(err) => warn!("Unable to purge deleted records: {err:#}"),
+			}
+		}
+	});
+}
+
+/// Number of handshakes deleted at a time for being past the retention period, so that no write lock is held for long
+const EXPIRY_BATCH_SIZE: i64 = 500;
+
+/// Maximum number of batches of handshakes deleted for being past the retention period each hour, so that catching up on
+/// a large backlog is spread out
+const EXPIRY_MAX_BATCHES: u32 = 100;
+
+/// Spawns a task that permanently deletes handshakes older than a retention period in batches, checking once an hour
+fn spawn_expiring(db: db::Database, retention: ::time::Duration, archive: bool) {
+	info!("Deleting handshakes after {} day(s)", retention.whole_days());
+	tokio::spawn(async move {
+		let mut ticker = time::interval(Duration::from_hours(1));
+		ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+		loop {
+			ticker.tick().await;
+			let before = OffsetDateTime::now_utc() - retention;
+			let mut expired = 0;
+			for _ in 0..EXPIRY_MAX_BATCHES {
+				match db.expire_handshakes(before, archive, EXPIRY_BATCH_SIZE).await {
+					Ok(count) => {
+						expired += count;
+						if count < EXPIRY_BATCH_SIZE.unsigned_abs() {
+							break;
+						}
+					}
+					Err(err) => {
+						warn!("Unable to delete handshakes past the retention period: {err:#}");
+						break;
+					}
+				}
+
+				// Give other writers a chance at the database between batches
+				time::sleep(Duration::from_millis(100)).await;
+			}
 
-	let app = Router::new()
+			if expired > 0 {
+				info!(
+					"Permanently deleted {expired} handshake(s) from before {} for being past the retention period{}",
+					before.date(),
+					if archive {
+						" (after archiving their daily counts)"
+					} else {
+						""
+					}
+				);
+			}
+		}
+	});
+}
+
+/// Builds the router for the whole API, including the admin endpoints, ready to serve with the state. Metrics aren't
+/// included, since they're served by the global recorder that only [`run`] installs.
+pub fn router(state: AppState) -> Router {
+	let routes = routes(&state).merge(admin_routes());
+	with_layers(routes, state)
+}
+
+/// Applies the state and the middleware that every request passes through to a router
+fn with_layers(router: Router<AppState>, state: AppState) -> Router {
+	let concurrency_limit = Arc::clone(&state.concurrency_limit);
+	let in_flight = state.in_flight.clone();
+	let activity = Arc::clone(&state.activity);
+	let trusted_proxies = Arc::clone(&state.trusted_proxies);
+	let request_timeout = state.request_timeout;
+	router
+		.with_state(state)
+		.layer(middleware::from_fn_with_state(request_timeout, limits::timeout))
+		.layer(middleware::from_fn_with_state(
+			concurrency_limit,
+			limits::limit_concurrency,
+		))
+		.layer(middleware::from_fn_with_state(in_flight, track_in_flight))
+		.layer(middleware::from_fn_with_state(activity, heartbeat::count_request))
+		.layer(middleware::from_fn(metrics::track))
+		.layer(middleware::from_fn(trace::trace_request))
+		.layer(middleware::from_fn_with_state(trusted_proxies, client_ip::resolve))
+}
+
+/// Builds the router for the API's endpoints, other than those that require the admin scope
+fn routes(state: &AppState) -> Router<AppState> {
+	Router::new()
+		.route("/users", get(list_users))
 		.route("/users/count", get(count_users))
 		.route("/users/names", get(list_user_names))
-		.route("/handshakes", post(create_handshake))
+		.route("/users/search", get(search_users))
+		.route("/users/inactive", get(list_inactive_users))
+		.route("/users/unmatched", get(list_unmatched_users))
+		.route("/users/unmatched/count", get(count_unmatched_users))
+		.route("/users/anniversaries", get(list_anniversaries))
+		.route("/users/:id/names", get(list_user_name_history))
+		.route("/users/:id/stats", get(get_user_stats))
+		.route("/users/resonite/:resonite_id/stats", get(get_user_stats_by_resonite_id))
+		.route("/users/by-resonite-id/:id/exists", get(has_shaken_hands))
+		.route("/users/:id/handshakes/undo", post(undo_handshake))
+		.route(
+			"/users/resonite/:resonite_id/handshakes/undo",
+			post(undo_handshake_by_resonite_id),
+		)
+		.route("/handshakes", get(list_handshakes).post(create_handshake))
+		.route("/handshakes/latest", get(get_latest_handshake))
 		.route("/handshakes/count", get(count_handshakes))
 		.route("/handshakes/count/user", get(count_handshakes_for_user))
-		.with_state(AppState { token: cfg.token, db });
+		.route("/handshakes/daily", get(count_handshakes_per_day))
+		.route("/handshakes/heatmap", get(get_activity_heatmap))
+		.route("/handshakes/rate", get(get_handshake_rate))
+		.route("/handshakes/stream", get(live::stream_handshakes))
+		.route("/worlds/:name/stats", get(get_world_stats))
+		.route("/sources", get(list_sources))
+		.route("/events", get(list_events))
+		.route("/stats", get(get_stats))
+		.route("/ws", get(live::websocket))
+		.route("/dashboard", get(dashboard::dashboard))
+		.route("/display/:stat", get(display::display_stat))
+		.merge(docs::router(
+			docs::spec(state.auth_required, state.query_token),
+			state.swagger_ui,
+		))
+}
 
-	let listener = TcpListener::bind(cfg.api).await?;
-	axum::serve(listener, app)
-		.with_graceful_shutdown(shutdown_signal())
-		.await?;
+/// Builds the router for the endpoints that require the admin scope, which may be served on their own listener
+fn admin_routes() -> Router<AppState> {
+	Router::new()
+		.route("/users/:id", delete(delete_user).patch(update_user))
+		.route("/users/:id/note", put(set_user_note))
+		.route("/users/:id/anonymize", post(anonymize_user))
+		.route("/handshakes/:id", delete(delete_handshake))
+		.route("/admin/token", post(rotate_token))
+		.route("/admin/audit", get(list_audit_entries))
+		.route("/admin/duplicates", get(list_duplicate_names))
+		.route("/admin/limit-violations", get(list_limit_violations))
+		.route("/admin/backup", post(create_backup))
+		.route("/admin/integrity", get(check_integrity))
+		.route("/admin/refresh-names", post(refresh_names))
+		.route("/export", get(export::export))
+		.route("/export/handshakes.csv", get(export::export_handshakes_csv))
+		.route("/export/users.csv", get(export::export_users_csv))
+		.route("/admin/deleted/users", get(list_deleted_users))
+		.route("/admin/deleted/handshakes", get(list_deleted_handshakes))
+		.route("/admin/deleted/users/:id/restore", post(restore_user))
+		.route("/admin/deleted/handshakes/:id/restore", post(restore_handshake))
+		.route("/admin/webhooks/outbox", get(list_webhook_outbox))
+		.route("/admin/bans", get(list_bans).post(ban_user))
+		.route("/admin/bans/:resonite_id", delete(unban_user))
+		.route("/admin/bans/by-name/:name", delete(unban_user_name))
+		.route("/admin/webhooks/outbox/:id/retry", post(retry_webhook_delivery))
+}
+
+/// Serves the admin endpoints on their own listener (if one was configured) until the shutdown future completes and open
+/// connections have closed
+async fn serve_admin(
+	admin: Option<(TcpListener, Router)>,
+	shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+	if let Some((listener, app)) = admin {
+		axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+			.with_graceful_shutdown(shutdown)
+			.await?;
+	}
+	Ok(())
+}
+
+/// Serves the app on the listener until the shutdown future completes and open connections have closed
+async fn serve(
+	options: &ServeOptions,
+	listener: Listener,
+	app: Router,
+	shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+	let (addr, activated) = match listener {
+		Listener::Address(addr) => (addr, None),
+		Listener::Inherited(listener) => (listener.local_addr()?, Some(listener)),
+
+		// Serve over a Unix domain socket if one was provided
+		Listener::Unix { path, mode } => {
+			#[cfg(unix)]
+			return unix::serve(&path, mode, app, shutdown).await;
+
+			#[cfg(not(unix))]
+			anyhow::bail!(
+				"Unable to listen on {}: Unix domain sockets aren't supported on this platform",
+				path.display()
+			);
+		}
+	};
+
+	// Serve over HTTPS if a certificate was provided, otherwise plain HTTP
+	let app = app.into_make_service_with_connect_info::<SocketAddr>();
+	if let (Some(cert), Some(key)) = (&options.tls_cert, &options.tls_key) {
+		let tls = RustlsConfig::from_config(tls::load(cert, key).await?);
+
+		#[cfg(unix)]
+		tokio::spawn(tls::reload_on_hangup(tls.clone(), cert.clone(), key.clone()));
+
+		let handle = Handle::new();
+		tokio::spawn({
+			let handle = handle.clone();
+			async move {
+				shutdown.await;
+				handle.graceful_shutdown(None);
+			}
+		});
+		tokio::spawn({
+			let handle = handle.clone();
+			async move {
+				if handle.listening().await.is_some() {
+					systemd::notify(&[Notification::Ready]);
+				}
+			}
+		});
+
+		let server = if let Some(listener) = activated {
+			info!(
+				"Listening on https://{} (socket passed by systemd)",
+				listener.local_addr()?
+			);
+			axum_server::from_tcp_rustls(listener, tls)
+		} else {
+			info!("Listening on https://{addr}");
+			axum_server::bind_rustls(addr, tls)
+		};
+		server.handle(handle).serve(app).await?;
+	} else {
+		let listener = if let Some(listener) = activated {
+			info!(
+				"Listening on http://{} (socket passed by systemd)",
+				listener.local_addr()?
+			);
+			TcpListener::from_std(listener)?
+		} else {
+			let listener = TcpListener::bind(addr).await?;
+			info!("Listening on http://{addr}");
+			listener
+		};
+		systemd::notify(&[Notification::Ready]);
+		axum::serve(listener, app).with_graceful_shutdown(shutdown).await?;
+	}
 
 	Ok(())
 }
 
 /// State for the API
 #[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct AppState {
-	/// Token required to authenticate
-	token: Option<Secret<String>>,
+	/// Tokens accepted for authentication, which may be replaced at runtime
+	tokens: Arc<RwLock<TokenRegistry>>,
+
+	/// Whether the token may be provided via the query string
+	query_token: bool,
+
+	/// Whether any tokens were accepted at startup, which the API docs describe
+	auth_required: bool,
+
+	/// Whether to serve Swagger UI for the API docs
+	swagger_ui: bool,
+
+	/// Maximum time to spend handling a request
+	request_timeout: Duration,
+
+	/// Permits for requests being handled, limiting how many may be handled at once
+	concurrency_limit: Arc<Semaphore>,
+
+	/// Address ranges of reverse proxies whose forwarding headers are trusted
+	trusted_proxies: Arc<[IpNet]>,
+
+	/// Counter of requests currently being handled
+	in_flight: InFlight,
 
 	/// Database to store/retrieve records
 	db: db::Database,
+
+	/// Responses of frequently polled endpoints, cached until the data changes
+	cache: cache::ReadCache,
+
+	/// Channel that newly created handshakes are published to for live subscribers
+	handshakes: broadcast::Sender<HandshakeWithUser>,
+
+	/// Permits for open WebSocket connections, limiting how many may be open at once
+	websockets: Arc<Semaphore>,
+
+	/// Duration without hearing from a WebSocket client before its connection is closed
+	websocket_idle_timeout: Duration,
+
+	/// Outgoing webhook notifier, if any webhooks are configured
+	webhooks: Option<Webhooks>,
+
+	/// Limits on the fields of submitted handshakes
+	field_limits: FieldLimits,
+
+	/// Source recorded for handshakes submitted without one
+	default_source: Option<String>,
+
+	/// Event that handshakes submitted without one are tagged with
+	default_event: Option<String>,
+
+	/// Whether submitted handshakes with unknown fields are rejected
+	strict_requests: bool,
+
+	/// Duration after a handshake that it may still be undone
+	undo_window: Duration,
+
+	/// Timezone that days start at midnight in for daily stats
+	timezone: &'static Tz,
+
+	/// Total handshake counts that are reported as milestones
+	milestones: Milestones,
+
+	/// Resonite API client to verify the users of submitted handshakes with, if verification is enabled
+	resonite: Option<Resonite>,
+
+	/// Task that refreshes usernames from the Resonite API, if it's enabled
+	name_refresher: Option<NameRefresher>,
+
+	/// Discord announcer, if a Discord webhook is configured
+	discord: Option<Discord>,
+
+	/// Directory to write database backups to, if backups are enabled
+	backup_dir: Option<PathBuf>,
+
+	/// Times of the last and next scheduled backups, which stay unset if backups aren't scheduled
+	backups: backup::Schedule,
+
+	/// Counters of activity for heartbeats
+	activity: Arc<heartbeat::Activity>,
+
+	/// Receiver that is notified once a shutdown has been requested
+	shutdown: watch::Receiver<bool>,
+}
+
+impl AppState {
+	/// Creates state for the API that accepts the given tokens and stores data in the database, with every other
+	/// setting at its default and no Resonite, Discord, or webhook integrations
+	#[must_use]
+	pub fn new(tokens: TokenRegistry, db: db::Database) -> Self {
+		Self {
+			auth_required: !tokens.is_empty(),
+			tokens: Arc::new(RwLock::new(tokens)),
+			query_token: true,
+			swagger_ui: false,
+			request_timeout: Duration::from_secs(10),
+			concurrency_limit: Arc::new(Semaphore::new(256)),
+			trusted_proxies: Arc::new([]),
+			in_flight: InFlight::default(),
+			db,
+			cache: cache::ReadCache::new(true),
+			handshakes: broadcast::channel(live::CHANNEL_CAPACITY).0,
+			websockets: Arc::new(Semaphore::new(64)),
+			websocket_idle_timeout: Duration::from_secs(90),
+			webhooks: None,
+			field_limits: FieldLimits::default(),
+			default_source: None,
+			default_event: None,
+			strict_requests: false,
+			undo_window: Duration::from_mins(5),
+			timezone: time_tz::timezones::db::UTC,
+			milestones: Milestones::new(Vec::new(), Vec::new()),
+			resonite: None,
+			name_refresher: None,
+			discord: None,
+			backup_dir: None,
+			backups: backup::Schedule::default(),
+			activity: Arc::default(),
+			shutdown: watch::channel(false).1,
+		}
+	}
+
+	/// Creates state for the API as configured, accepting the given tokens and storing data in the database. Spawns the
+	/// background tasks that handlers interact with.
+	pub fn from_config(cfg: &Config, tokens: TokenRegistry, db: db::Database) -> Result<Self> {
+		// Queue webhook deliveries alongside each handshake that gets created
+		let db = db.with_webhook_urls(&cfg.webhook_url);
+
+		let (request_timeout, concurrency_limit) = limits::from_config(cfg);
+		Ok(Self {
+			auth_required: !tokens.is_empty(),
+			tokens: Arc::new(RwLock::new(tokens)),
+			query_token: !cfg.header_auth_only,
+			db: db.clone(),
+			cache: cache::ReadCache::new(!cfg.disable_cache),
+			handshakes: broadcast::channel(live::CHANNEL_CAPACITY).0,
+			websockets: Arc::new(Semaphore::new(cfg.ws_max_connections)),
+			websocket_idle_timeout: Duration::from_secs(cfg.ws_idle_timeout),
+			webhooks: Webhooks::spawn(
+				db.clone(),
+				&cfg.webhook_url,
+				cfg.webhook_secret.as_ref(),
+				cfg.webhook_max_attempts,
+			)?,
+			swagger_ui: cfg.swagger_ui,
+			request_timeout,
+			concurrency_limit,
+			trusted_proxies: cfg.trusted_proxies.clone().into(),
+			in_flight: InFlight::default(),
+			field_limits: cfg.field_limits.limits(),
+			default_source: cfg.default_source.clone(),
+			default_event: cfg.default_event.clone(),
+			strict_requests: cfg.strict_requests,
+			undo_window: Duration::from_secs(cfg.undo_window),
+			timezone: cfg.timezone,
+			milestones: Milestones::new(cfg.milestone_every.clone(), cfg.milestone_at.clone()),
+			resonite: user_verifier(cfg)?,
+			name_refresher: spawn_name_refresher(cfg, &db)?,
+			discord: Discord::spawn(
+				db.clone(),
+				cfg.discord_webhook_url.as_ref(),
+				cfg.discord_milestone_interval,
+				cfg.discord_first_time,
+				cfg.timezone,
+			)?,
+			backup_dir: cfg.backup_dir.clone(),
+			backups: backup::Schedule::default(),
+			activity: Arc::default(),
+			shutdown: watch::channel(false).1,
+		})
+	}
 }
 
 impl FromRef<AppState> for db::Database {
@@ -54,120 +750,2741 @@ impl FromRef<AppState> for db::Database {
 	}
 }
 
+impl FromRef<AppState> for cache::ReadCache {
+	fn from_ref(state: &AppState) -> cache::ReadCache {
+		state.cache.clone()
+	}
+}
+
 /// Authenticated session for a request
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Session {
-	/// Token being used to authenticate
-	token: Option<Secret<String>>,
+	/// Scope granted by the token used to authenticate
+	scope: Scope,
+
+	/// HTTP method of the request
+	method: Method,
+
+	/// Route matched by the request
+	route: String,
+
+	/// IP address of the client making the request, if known
+	client_ip: Option<IpAddr>,
+}
+
+impl Session {
+	/// Creates a session with the given scope for a request
+	fn new(scope: Scope, parts: &Parts) -> Self {
+		Self {
+			scope,
+			method: parts.method.clone(),
+			route: parts
+				.extensions
+				.get::<MatchedPath>()
+				.map_or_else(|| parts.uri.path().to_owned(), |path| path.as_str().to_owned()),
+			client_ip: parts
+				.extensions
+				.get::<client_ip::ClientIp>()
+				.map(|&client_ip::ClientIp(ip)| ip),
+		}
+	}
+
+	/// Records the request in the audit log along with the records it affected, each given as a type and ID.
+	/// Failing to write the entry only logs a warning so that it never fails the request itself.
+	async fn audit(&self, db: &db::Database, records: &[(&str, i64)]) {
+		// A read-only instance has nowhere to write the entry
+		if db.is_read_only() {
+			return;
+		}
+
+		let entry = db::NewAuditEntry {
+			method: self.method.as_str(),
+			route: &self.route,
+			client_ip: self.client_ip.map(|ip| ip.to_string()),
+			scope: self.scope.as_str(),
+			record_ids: records
+				.iter()
+				.map(|(kind, id)| format!("{kind}:{id}"))
+				.collect::<Vec<_>>()
+				.join(","),
+		};
+
+		if let Err(err) = db.create_audit_entry(&entry).await {
+			warn!(
+				"Unable to write audit log entry for {} {}: {err}",
+				entry.method, entry.route
+			);
+		}
+	}
+
+	/// Prepares a user to be shown to the session, leaving out their note unless the session has the admin scope
+	fn show_user(&self, user: User) -> User {
+		if self.scope >= Scope::Admin {
+			user
+		} else {
+			user.without_note()
+		}
+	}
+
+	/// Ensures the session has been granted at least the given scope
+	fn require(&self, scope: Scope) -> Result<(), Error> {
+		if self.scope >= scope {
+			Ok(())
+		} else {
+			Err(Error::Forbidden(format!("token lacks the {scope} scope")))
+		}
+	}
+
+	/// Parses the token from an `Authorization: Bearer <token>` header, if one is present, and digests it
+	fn header_token(parts: &Parts) -> Result<Option<TokenDigest>, Error> {
+		let Some(value) = parts.headers.get(header::AUTHORIZATION) else {
+			return Ok(None);
+		};
+
+		let token = value
+			.to_str()
+			.ok()
+			.and_then(|value| value.strip_prefix("Bearer "))
+			.map(str::trim)
+			.filter(|token| !token.is_empty())
+			.ok_or_else(|| Error::BadRequest("malformed authorization header".to_owned()))?;
+
+		Ok(Some(TokenDigest::new(token)))
+	}
+
+	/// Parses the token from the `token` query parameter, if one is present, and digests it
+	fn query_token(parts: &Parts) -> Result<Option<TokenDigest>, Error> {
+		let Query(query): Query<TokenQuery> =
+			Query::try_from_uri(&parts.uri).map_err(|_| Error::BadRequest("malformed query string".to_owned()))?;
+		Ok(query.token.as_ref().map(TokenDigest::from))
+	}
 }
 
 #[async_trait]
 impl FromRequestParts<AppState> for Session {
-	type Rejection = (StatusCode, String);
+	type Rejection = Error;
 
 	async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
-		// If we aren't expecting a token, then go ahead and return an empty session
-		let Some(expected_token) = &state.token else {
-			return Ok(Session { token: None });
+		// If we aren't expecting a token, then go ahead and return a session with full access
+		let tokens = state.tokens.read().await;
+		if tokens.is_empty() {
+			return Ok(Session::new(Scope::Admin, parts));
+		}
+
+		// Parse the token from the Authorization header, falling back to the query string if allowed
+		let header = Self::header_token(parts)?;
+		let query = if state.query_token {
+			Self::query_token(parts)?
+		} else {
+			None
 		};
 
-		// Parse the session from the query string
-		let Query(session): Query<Session> =
-			Query::try_from_uri(&parts.uri).map_err(|_| (StatusCode::BAD_REQUEST, "missing token".to_owned()))?;
+		let token = match (header, query) {
+			(Some(header), Some(query)) if header != query => {
+				return Err(Error::BadRequest("mismatched header and query tokens".to_owned()));
+			}
+			(Some(token), _) | (None, Some(token)) => token,
+			(None, None) => return Err(Error::BadRequest("missing token".to_owned())),
+		};
 
-		// Ensure the given token matches
-		match &session.token {
-			Some(secret) if secret.expose_secret() == expected_token.expose_secret() => Ok(session),
-			Some(_) => Err((StatusCode::UNAUTHORIZED, "invalid token".to_owned())),
-			None => Err((StatusCode::BAD_REQUEST, "missing token".to_owned())),
+		// Ensure the given token is a known one and determine its scope
+		match tokens.resolve(&token) {
+			Some(scope) => Ok(Session::new(scope, parts)),
+			None => Err(Error::Unauthorized("invalid token".to_owned())),
 		}
 	}
 }
 
+/// Ensures the database can be written to, since requests that would write are forbidden on a read-only instance
+fn require_writable(db: &db::Database) -> Result<(), Error> {
+	if db.is_read_only() {
+		Err(Error::Forbidden("read-only instance".to_owned()))
+	} else {
+		Ok(())
+	}
+}
+
+/// Query parameters for providing a token
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+	/// Token being used to authenticate
+	token: Option<Secret<String>>,
+}
+
+/// Returns a page of users as JSON, optionally along with the number of handshakes each has performed
+///
+/// Requires the `read` scope.
+#[utoipa::path(
+	get,
+	path = "/users",
+	tag = "users",
+	params(UserListing, Pagination),
+	responses((status = 200, description = "Users, with handshake counts if requested", body = UserList))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_users(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(listing): Query<UserListing>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<UserList>, Error> {
+	session.require(Scope::Read)?;
+	let users = db
+		.get_users_with_counts(page.limit(), page.offset(), listing.order)
+		.await?;
+
+	Ok(Json(match listing.include {
+		Some(UserInclude::Counts) => UserList::WithCounts(
+			users
+				.into_iter()
+				.map(|counted| UserWithCount {
+					user: session.show_user(counted.user),
+					..counted
+				})
+				.collect(),
+		),
+		None => UserList::Users(
+			users
+				.into_iter()
+				.map(|counted| session.show_user(counted.user))
+				.collect(),
+		),
+	}))
+}
+
+/// Query parameters for listing users
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserListing {
+	/// Additional details to include with each user
+	#[param(inline)]
+	include: Option<UserInclude>,
+
+	/// Order to list the users in
+	#[serde(default)]
+	#[param(inline)]
+	order: UserOrder,
+}
+
+/// Additional details that can be included when listing users
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum UserInclude {
+	/// Number of handshakes each user has performed
+	Counts,
+}
+
+/// Listing of users, with or without their handshake counts
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+enum UserList {
+	/// Users without their handshake counts
+	Users(Vec<User>),
+
+	/// Users along with their handshake counts
+	WithCounts(Vec<UserWithCount>),
+}
+
 /// Returns the number of unique users that have shaken hands
-#[tracing::instrument(level = "debug", skip(_session, db))]
-async fn count_users(_session: Session, State(db): State<db::Database>) -> Result<String, Error> {
-	let count = db.count_users().await?;
-	Ok(count.to_string())
+///
+/// Requires the `read` scope.
+#[utoipa::path(
+	get,
+	path = "/users/count",
+	tag = "users",
+	params(db::UserFilter),
+	responses((status = 200, description = "Number of users", body = String, content_type = "text/plain"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db, cache))]
+async fn count_users(
+	session: Session,
+	State(db): State<db::Database>,
+	State(cache): State<cache::ReadCache>,
+	Query(filter): Query<db::UserFilter>,
+) -> Result<String, Error> {
+	session.require(Scope::Read)?;
+	let key = cache::Key::UserCount { legacy: filter.legacy };
+	let count = cache
+		.get_or_load(&db, key, || async {
+			Ok(db.count_users_matching(&filter).await?.to_string())
+		})
+		.await?;
+	Ok(count)
 }
 
 /// Returns a newline-delimited list of the usernames of all unique users that have shaken hands
-#[tracing::instrument(level = "debug", skip(_session, db))]
-async fn list_user_names(_session: Session, State(db): State<db::Database>) -> Result<String, Error> {
-	let names = db.get_all_user_resonite_names().await?;
-	Ok(names.join("\n"))
+///
+/// Requires the `read` scope.
+#[utoipa::path(
+	get,
+	path = "/users/names",
+	tag = "users",
+	params(db::UserFilter),
+	responses((status = 200, description = "Newline-delimited usernames", body = String, content_type = "text/plain"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db, cache))]
+async fn list_user_names(
+	session: Session,
+	State(db): State<db::Database>,
+	State(cache): State<cache::ReadCache>,
+	Query(filter): Query<db::UserFilter>,
+) -> Result<String, Error> {
+	session.require(Scope::Read)?;
+	let key = cache::Key::UserNames { legacy: filter.legacy };
+	let names = cache
+		.get_or_load(&db, key, || async {
+			Ok(db.get_user_resonite_names(&filter).await?.join("\n"))
+		})
+		.await?;
+	Ok(names)
 }
 
-/// Stores record of a new handshake
-#[tracing::instrument(level = "debug", skip(_session, db))]
-async fn create_handshake(
-	_session: Session,
+/// Returns users whose usernames match a search query as JSON
+///
+/// Requires the `read` scope. Usernames are matched without regard to case.
+#[utoipa::path(
+	get,
+	path = "/users/search",
+	tag = "users",
+	params(UserSearch),
+	responses((status = 200, description = "Matching users", body = [User]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn search_users(
+	session: Session,
 	State(db): State<db::Database>,
-	Form(shake): Form<db::HandshakeContext>,
-) -> Result<Form<db::Handshake>, Error> {
-	let created = db.create_handshake(shake).await?;
-	Ok(Form(created))
+	Query(search): Query<UserSearch>,
+) -> Result<Json<Vec<User>>, Error> {
+	session.require(Scope::Read)?;
+	let query = validate::normalize_name(&search.q);
+	let limit = search
+		.limit
+		.unwrap_or(Pagination::DEFAULT_LIMIT)
+		.clamp(1, Pagination::MAX_LIMIT);
+
+	let users = match search.mode {
+		SearchMode::Prefix => db.search_users_prefix(&query, limit).await?,
+		SearchMode::Contains => db.search_users_fts(&query, limit).await?,
+	};
+	Ok(Json(users.into_iter().map(|user| session.show_user(user)).collect()))
 }
 
-/// Returns the total number of handshakes that have occurred
-#[tracing::instrument(level = "debug", skip(_session, db))]
-async fn count_handshakes(_session: Session, State(db): State<db::Database>) -> Result<String, Error> {
-	let count = db.count_handshakes().await?;
-	Ok(count.to_string())
+/// Query parameters for searching users
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserSearch {
+	/// Text to search usernames for
+	q: String,
+
+	/// How to match the text against usernames
+	#[serde(default)]
+	#[param(inline)]
+	mode: SearchMode,
+
+	/// Maximum number of users to return
+	limit: Option<i64>,
 }
 
-/// Returns the number of handshakes that a specific user has performed
-#[tracing::instrument(level = "debug", skip(_session, db))]
-async fn count_handshakes_for_user(
-	_session: Session,
+/// How to match search text against usernames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum SearchMode {
+	/// Usernames that start with the text, in alphabetical order
+	#[default]
+	Prefix,
+
+	/// Usernames that contain the text anywhere, best matches first
+	Contains,
+}
+
+/// Returns a page of users that haven't shaken hands since a date/time as JSON, longest-inactive first
+///
+/// Requires the `read` scope. Users that have never shaken hands aren't included.
+#[utoipa::path(
+	get,
+	path = "/users/inactive",
+	tag = "users",
+	params(InactiveSince, db::UserFilter, Pagination),
+	responses((status = 200, description = "Inactive users, longest-inactive first", body = [User]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_inactive_users(
+	session: Session,
 	State(db): State<db::Database>,
-	Query(info): Query<db::UserResoniteInfo>,
+	Query(InactiveSince { since }): Query<InactiveSince>,
+	Query(filter): Query<db::UserFilter>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<User>>, Error> {
+	session.require(Scope::Read)?;
+	let users = db
+		.get_inactive_users(since, &filter, page.limit(), page.offset())
+		.await?;
+	Ok(Json(users.into_iter().map(|user| session.show_user(user)).collect()))
+}
+
+/// Returns a page of users without a Resonite ID as JSON, along with the number of handshakes each has performed,
+/// ordered by name
+///
+/// Requires the `read` scope. These are legacy users that haven't been matched with a Resonite ID yet, optionally only
+/// those whose usernames start with `q` (ignoring case). Anonymized users aren't included.
+#[utoipa::path(
+	get,
+	path = "/users/unmatched",
+	tag = "users",
+	params(db::NameFilter, Pagination),
+	responses((status = 200, description = "Unmatched users, ordered by name", body = [UserWithCount]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_unmatched_users(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(filter): Query<db::NameFilter>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<UserWithCount>>, Error> {
+	session.require(Scope::Read)?;
+	let users = db
+		.get_users_missing_resonite_id(&filter, page.limit(), page.offset())
+		.await?;
+	Ok(Json(
+		users
+			.into_iter()
+			.map(|counted| UserWithCount {
+				user: session.show_user(counted.user),
+				..counted
+			})
+			.collect(),
+	))
+}
+
+/// Returns the number of users without a Resonite ID
+///
+/// Requires the `read` scope. Like `/users/unmatched`, this can be narrowed down to usernames starting with `q`.
+#[utoipa::path(
+	get,
+	path = "/users/unmatched/count",
+	tag = "users",
+	params(db::NameFilter),
+	responses((status = 200, description = "Number of unmatched users", body = String, content_type = "text/plain"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn count_unmatched_users(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(filter): Query<db::NameFilter>,
 ) -> Result<String, Error> {
-	let user = db.get_user_by_resonite_info(&info).await?.ok_or(Error::NotFound)?;
-	Ok(db.count_user_handshakes(user.id).await?.to_string())
+	session.require(Scope::Read)?;
+	Ok(db.count_users_missing_resonite_id(&filter).await?.to_string())
 }
 
-/// Error type returned from handlers
-#[derive(Debug)]
-pub enum Error {
-	Internal(anyhow::Error),
-	NotFound,
+/// Returns the users that first shook hands on this day a whole number of years ago as JSON, longest-standing first
+///
+/// Requires the `read` scope. Days are in the configured timezone, and default to today. Users whose first handshake
+/// was on February 29th are included on February 28th in years without one.
+#[utoipa::path(
+	get,
+	path = "/users/anniversaries",
+	tag = "users",
+	params(AnniversaryQuery),
+	responses((status = 200, description = "Users with an anniversary, longest-standing first", body = [Anniversary]))
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn list_anniversaries(
+	session: Session,
+	State(state): State<AppState>,
+	Query(query): Query<AnniversaryQuery>,
+) -> Result<Json<Vec<Anniversary>>, Error> {
+	session.require(Scope::Read)?;
+	let date = query
+		.date
+		.unwrap_or_else(|| OffsetDateTime::now_utc().to_timezone(state.timezone).date());
+	Ok(Json(
+		state.db.get_anniversaries(date, query.years, state.timezone).await?,
+	))
 }
 
-impl IntoResponse for Error {
-	fn into_response(self) -> Response {
-		match self {
-			Self::Internal(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
-			Self::NotFound => (StatusCode::NOT_FOUND, "no record found").into_response(),
-		}
+/// Query parameters for listing anniversaries
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AnniversaryQuery {
+	/// Minimum number of years since users' first handshakes
+	#[serde(default = "AnniversaryQuery::default_years")]
+	#[param(minimum = 1, default = 1)]
+	years: i32,
+
+	/// Date (`YYYY-MM-DD`) to find anniversaries on instead of today
+	#[serde(default, deserialize_with = "deserialize_date")]
+	#[param(value_type = Option<String>, format = Date)]
+	date: Option<Date>,
+}
+
+impl AnniversaryQuery {
+	/// Default minimum number of years since users' first handshakes
+	const fn default_years() -> i32 {
+		1
 	}
 }
 
-impl<E: Into<anyhow::Error>> From<E> for Error {
-	fn from(err: E) -> Self {
-		Self::Internal(err.into())
+/// Deserializes an optional date in `YYYY-MM-DD` format
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Date>, D::Error> {
+	Option::<String>::deserialize(deserializer)?
+		.map(|date| Date::parse(&date, &Iso8601::DATE).map_err(de::Error::custom))
+		.transpose()
+}
+
+/// Returns the history of a user's username changes as JSON, newest first
+///
+/// Requires the `read` scope.
+#[utoipa::path(
+	get,
+	path = "/users/{id}/names",
+	tag = "users",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	responses(
+		(status = 200, description = "Username changes, newest first", body = [NameChange]),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_user_name_history(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+) -> Result<Json<Vec<NameChange>>, Error> {
+	session.require(Scope::Read)?;
+	if db.get_user(id).await?.is_none() {
+		return Err(Error::NotFound("no such user".to_owned()));
 	}
+	Ok(Json(db.get_user_name_history(id).await?))
 }
 
-/// Returns a future that waits for Ctrl + C or a terminate signal
-async fn shutdown_signal() {
-	let ctrl_c = async {
-		signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
-	};
+/// Returns statistics about a user's handshakes as JSON
+///
+/// Requires the `read` scope. Streaks are counted in days that start at midnight in the server's configured timezone.
+#[utoipa::path(
+	get,
+	path = "/users/{id}/stats",
+	tag = "users",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	responses(
+		(status = 200, description = "Statistics about the user's handshakes", body = UserStats),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn get_user_stats(
+	session: Session,
+	State(state): State<AppState>,
+	Path(id): Path<i64>,
+) -> Result<Json<UserStats>, Error> {
+	session.require(Scope::Read)?;
+	if state.db.get_user(id).await?.is_none() {
+		return Err(Error::NotFound("no such user".to_owned()));
+	}
+	Ok(Json(state.db.get_user_stats(id, state.timezone).await?))
+}
 
-	#[cfg(unix)]
-	let terminate = async {
-		signal::unix::signal(signal::unix::SignalKind::terminate())
-			.expect("failed to install signal handler")
-			.recv()
-			.await;
-	};
+/// Returns statistics about a user's handshakes as JSON, looking the user up by Resonite ID
+///
+/// Requires the `read` scope. Streaks are counted in days that start at midnight in the server's configured timezone.
+#[utoipa::path(
+	get,
+	path = "/users/resonite/{resonite_id}/stats",
+	tag = "users",
+	params(("resonite_id" = String, Path, description = "Resonite ID of the user")),
+	responses(
+		(status = 200, description = "Statistics about the user's handshakes", body = UserStats),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn get_user_stats_by_resonite_id(
+	session: Session,
+	State(state): State<AppState>,
+	Path(resonite_id): Path<String>,
+) -> Result<Json<UserStats>, Error> {
+	session.require(Scope::Read)?;
+	let user = state
+		.db
+		.get_user_by_resonite_id(&resonite_id)
+		.await?
+		.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+	Ok(Json(state.db.get_user_stats(user.id, state.timezone).await?))
+}
 
-	#[cfg(not(unix))]
-	let terminate = std::future::pending::<()>();
+/// Returns whether a user has ever shaken hands, as a bare `true` or `false`
+///
+/// Requires the `read` scope. The user is looked up by Resonite ID, falling back to the given username for users that
+/// were recorded before IDs were (ignoring case). Unknown users are `false` rather than not found. The value is JSON
+/// instead of plain text if the `Accept` header asks for JSON.
+#[utoipa::path(
+	get,
+	path = "/users/by-resonite-id/{id}/exists",
+	tag = "users",
+	params(("id" = String, Path, description = "Resonite ID of the user"), ShakenLookup),
+	responses((
+		status = 200,
+		description = "Whether the user has shaken hands",
+		content(("text/plain" = String), ("application/json" = bool))
+	))
+)]
+#[tracing::instrument(level = "debug", skip(session, db, headers))]
+async fn has_shaken_hands(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<String>,
+	Query(lookup): Query<ShakenLookup>,
+	headers: HeaderMap,
+) -> Result<Response, Error> {
+	session.require(Scope::Read)?;
+	let shaken = db.has_shaken_hands(&id, lookup.name.as_deref()).await?;
 
-	tokio::select! {
-		() = ctrl_c => {},
-		() = terminate => {},
+	let wants_json = headers
+		.get(header::ACCEPT)
+		.and_then(|accept| accept.to_str().ok())
+		.is_some_and(|accept| accept.contains("application/json"));
+	Ok(if wants_json {
+		Json(shaken).into_response()
+	} else {
+		shaken.to_string().into_response()
+	})
+}
+
+/// Query parameters for checking whether a user has shaken hands
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ShakenLookup {
+	/// Resonite username to check instead for users without a recorded Resonite ID
+	name: Option<String>,
+}
+
+/// Deletes a user's most recent handshake if it was made by accident, returning the deleted handshake as JSON
+///
+/// Requires the `write` scope. Only the most recent handshake may be undone, and only within the configured window
+/// after it took place (five minutes by default), so undoing again never reaches any further back. The handshake can be
+/// restored like any other deleted one. If the user is being removed and it was their only handshake, they're deleted
+/// along with it for good, as though they had never shaken hands.
+#[utoipa::path(
+	post,
+	path = "/users/{id}/handshakes/undo",
+	tag = "handshakes",
+	params(("id" = i64, Path, description = "Database ID of the user"), UndoQuery),
+	responses(
+		(status = 200, description = "Deleted handshake", body = UndoneHandshake),
+		(status = 404, description = "No such user, or the user has no handshakes", body = ErrorBody),
+		(
+			status = 409,
+			description = "The most recent handshake is too old to undo or was already deleted (with the code `not_undoable`)",
+			body = ErrorBody
+		),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn undo_handshake(
+	session: Session,
+	State(state): State<AppState>,
+	Path(id): Path<i64>,
+	Query(query): Query<UndoQuery>,
+) -> Result<Json<UndoneHandshake>, Error> {
+	session.require(Scope::Write)?;
+	require_writable(&state.db)?;
+	if state.db.get_user(id).await?.is_none() {
+		return Err(Error::NotFound("no such user".to_owned()));
+	}
+	undo(&session, &state, id, query).await
+}
+
+/// Deletes a user's most recent handshake if it was made by accident, looking the user up by Resonite ID
+///
+/// Requires the `write` scope. This works the same as undoing by the user's database ID.
+#[utoipa::path(
+	post,
+	path = "/users/resonite/{resonite_id}/handshakes/undo",
+	tag = "handshakes",
+	params(("resonite_id" = String, Path, description = "Resonite ID of the user"), UndoQuery),
+	responses(
+		(status = 200, description = "Deleted handshake", body = UndoneHandshake),
+		(status = 404, description = "No such user, or the user has no handshakes", body = ErrorBody),
+		(
+			status = 409,
+			description = "The most recent handshake is too old to undo or was already deleted (with the code `not_undoable`)",
+			body = ErrorBody
+		),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn undo_handshake_by_resonite_id(
+	session: Session,
+	State(state): State<AppState>,
+	Path(resonite_id): Path<String>,
+	Query(query): Query<UndoQuery>,
+) -> Result<Json<UndoneHandshake>, Error> {
+	session.require(Scope::Write)?;
+	require_writable(&state.db)?;
+	let user = state
+		.db
+		.get_user_by_resonite_id(&resonite_id)
+		.await?
+		.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+	undo(&session, &state, user.id, query).await
+}
+
+/// Undoes a user's most recent handshake, turning the outcome into a response
+async fn undo(
+	session: &Session,
+	state: &AppState,
+	user_id: i64,
+	query: UndoQuery,
+) -> Result<Json<UndoneHandshake>, Error> {
+	let outcome = state
+		.db
+		.undo_latest_handshake(user_id, state.undo_window, query.remove_user)
+		.await?;
+	match outcome {
+		UndoOutcome::Undone {
+			handshake,
+			user_removed,
+		} => {
+			info!("Undid handshake {} of user {user_id}", handshake.id);
+			session
+				.audit(&state.db, &[("handshake", handshake.id), ("user", user_id)])
+				.await;
+			Ok(Json(UndoneHandshake {
+				handshake,
+				user_removed,
+			}))
+		}
+		UndoOutcome::NoHandshakes => Err(Error::NotFound("the user has no handshakes".to_owned())),
+		UndoOutcome::AlreadyUndone(shake) => Err(Error::NotUndoable(format!(
+			"the most recent handshake ({}) has already been deleted",
+			shake.id
+		))),
+		UndoOutcome::TooOld(shake) => Err(Error::NotUndoable(format!(
+			"the most recent handshake ({}) is more than {} seconds old, which is too old to undo",
+			shake.id,
+			state.undo_window.as_secs()
+		))),
+	}
+}
+
+/// Query parameters for undoing a handshake
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UndoQuery {
+	/// Whether to also delete the user (for good) if the handshake was their only one
+	#[serde(default)]
+	remove_user: bool,
+}
+
+/// Handshake that was undone
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UndoneHandshake {
+	/// Handshake that was deleted
+	#[serde(flatten)]
+	handshake: Handshake,
+
+	/// Whether the user was deleted along with it
+	user_removed: bool,
+}
+
+/// Removes a user's Resonite ID, name, and name history while keeping their handshakes, returning the anonymized user as
+/// JSON
+///
+/// Requires the `admin` scope. The name is replaced with a placeholder (`deleted-user-` followed by the user's ID), and
+/// the user is never matched by ID or name again, so the same person shaking hands later becomes a new user.
+#[utoipa::path(
+	post,
+	path = "/users/{id}/anonymize",
+	tag = "users",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	responses(
+		(status = 200, description = "Anonymized user", body = User),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn anonymize_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+) -> Result<Json<User>, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	let user = db
+		.anonymize_user(id)
+		.await?
+		.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+
+	session.audit(&db, &[("user", id)]).await;
+	Ok(Json(user))
+}
+
+/// Deletes a user along with all of their handshakes
+///
+/// Requires the `admin` scope. Deleted records are kept (but excluded from everything else) until they're purged, and
+/// may be restored until then.
+#[utoipa::path(
+	delete,
+	path = "/users/{id}",
+	tag = "users",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	responses(
+		(status = 204, description = "User deleted"),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn delete_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	if !db.delete_user(id).await? {
+		return Err(Error::NotFound("no such user".to_owned()));
+	}
+
+	session.audit(&db, &[("user", id)]).await;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Updates the details of a user that admins manage, returning the updated user as JSON
+///
+/// Requires the `admin` scope. Only the fields that are given are changed, and an empty note clears it.
+#[utoipa::path(
+	patch,
+	path = "/users/{id}",
+	tag = "users",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	request_body(content = UserPatch, content_type = "application/x-www-form-urlencoded"),
+	responses(
+		(status = 200, description = "Updated user", body = User),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db, patch))]
+async fn update_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+	Form(patch): Form<UserPatch>,
+) -> Result<Json<User>, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	let user = match patch.note {
+		Some(note) => db.set_user_note(id, Some(&note)).await?,
+		None => db.get_user(id).await?,
+	}
+	.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+
+	session.audit(&db, &[("user", id)]).await;
+	Ok(Json(user))
+}
+
+/// Changes to make to a user
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct UserPatch {
+	/// Note to leave on the user for other admins (empty to clear it), up to 2000 characters
+	note: Option<String>,
+}
+
+/// Sets the note on a user from the plain-text request body, returning the updated user as JSON
+///
+/// Requires the `admin` scope. Notes are only shown to admins, and an empty body clears the note.
+#[utoipa::path(
+	put,
+	path = "/users/{id}/note",
+	tag = "users",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	request_body(content = String, content_type = "text/plain"),
+	responses(
+		(status = 200, description = "Updated user", body = User),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db, note))]
+async fn set_user_note(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+	note: String,
+) -> Result<Json<User>, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	let user = db
+		.set_user_note(id, Some(&note))
+		.await?
+		.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+
+	session.audit(&db, &[("user", id)]).await;
+	Ok(Json(user))
+}
+
+/// Deletes a handshake
+///
+/// Requires the `admin` scope. Deleted records are kept (but excluded from everything else) until they're purged, and
+/// may be restored until then.
+#[utoipa::path(
+	delete,
+	path = "/handshakes/{id}",
+	tag = "handshakes",
+	params(("id" = i64, Path, description = "ID of the handshake")),
+	responses(
+		(status = 204, description = "Handshake deleted"),
+		(status = 404, description = "No such handshake", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn delete_handshake(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	if !db.delete_handshake(id).await? {
+		return Err(Error::NotFound("no such handshake".to_owned()));
+	}
+
+	session.audit(&db, &[("handshake", id)]).await;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Checks that the user of a submitted handshake exists via the Resonite API if verification is enabled, optionally
+/// replacing the submitted username with the one from the API. If the API can't be reached, the handshake is accepted
+/// as submitted.
+async fn verify_user(state: &AppState, shake: HandshakeContext) -> Result<HandshakeContext, Error> {
+	let Some(resonite) = &state.resonite else {
+		return Ok(shake);
+	};
+
+	match resonite.get_user(&shake.id).await {
+		Ok(Some(user)) if resonite.canonical_names() => Ok(HandshakeContext {
+			name: user.username,
+			..shake
+		}),
+		Ok(Some(_)) => Ok(shake),
+		Ok(None) => Err(Error::Invalid(ValidationError::new(
+			"id",
+			"no Resonite user has this ID",
+		))),
+		Err(err) => match err.downcast::<ValidationError>() {
+			Ok(err) => Err(Error::Invalid(err)),
+			Err(err) => {
+				warn!(
+					"Unable to verify Resonite user {}; accepting the handshake anyway: {err:#}",
+					shake.id
+				);
+				Ok(shake)
+			}
+		},
+	}
+}
+
+/// Query parameters for listing inactive users
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct InactiveSince {
+	/// RFC 3339 date/time that users must not have shaken hands since
+	#[serde(with = "::time::serde::rfc3339")]
+	#[param(value_type = String, format = DateTime)]
+	since: OffsetDateTime,
+}
+
+/// Stores record of a new handshake and publishes it to live subscribers
+///
+/// Requires the `write` scope.
+#[utoipa::path(
+	post,
+	path = "/handshakes",
+	tag = "handshakes",
+	request_body(content = HandshakeContext, content_type = "application/x-www-form-urlencoded"),
+	responses(
+		(
+			status = 200,
+			description = "Created handshake",
+			body = HandshakeCreated,
+			content_type = "application/x-www-form-urlencoded"
+		),
+		(status = 403, description = "The user is banned (with the code `banned`)", body = ErrorBody),
+		(status = 409, description = "Another user already has the username", body = ErrorBody),
+		(
+			status = 422,
+			description = "A field is invalid, or isn't a field of handshakes while strict requests are enabled (with the \
+			               code `unknown_field`)",
+			body = ErrorBody
+		),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn create_handshake(
+	session: Session,
+	State(state): State<AppState>,
+	HandshakeForm(shake): HandshakeForm,
+) -> Result<Form<HandshakeCreated>, Error> {
+	session.require(Scope::Write)?;
+	require_writable(&state.db)?;
+	let shake = HandshakeContext {
+		source: shake.source.or_else(|| state.default_source.clone()),
+		event: shake.event.or_else(|| state.default_event.clone()),
+		..shake
+	};
+	let shake = verify_user(&state, shake).await?.apply_limits(state.field_limits)?;
+	let created = state.db.create_handshake(shake).await?;
+	let id = created.handshake.id;
+	state.activity.handshake_created(created.first_time);
+	session
+		.audit(&state.db, &[("handshake", id), ("user", created.handshake.user_id)])
+		.await;
+
+	// Publish the handshake, which only fails if there are no subscribers
+	if state.handshakes.receiver_count() > 0 {
+		match state.db.get_handshake_with_user(id).await {
+			Ok(Some(shake)) => {
+				let _ = state.handshakes.send(shake);
+			}
+			Ok(None) => warn!("Handshake {id} disappeared before it could be published"),
+			Err(err) => warn!("Unable to retrieve handshake {id} for publishing: {err}"),
+		}
+	}
+
+	// Deliveries were queued in the outbox along with the handshake, so just let the delivery task know about them
+	if let Some(webhooks) = &state.webhooks {
+		webhooks.wake();
+	}
+
+	// Make any Discord announcements in the background so that failures can't affect the response
+	if let Some(discord) = state.discord {
+		let db = state.db.clone();
+		let created = created.clone();
+		tokio::spawn(async move {
+			if let Err(err) = discord.handshake_created(&db, &created).await {
+				warn!("Unable to prepare Discord announcements for handshake {id}: {err:#}");
+			}
+		});
+	}
+
+	let milestone = state.milestones.reached(created.total_count);
+	Ok(Form(HandshakeCreated::new(created, milestone)))
+}
+
+/// Form of a submitted handshake. If strict requests are enabled, it's rejected if it has any fields that a handshake
+/// doesn't have.
+#[derive(Debug)]
+struct HandshakeForm(HandshakeContext);
+
+#[async_trait]
+impl FromRequest<AppState> for HandshakeForm {
+	type Rejection = Response;
+
+	async fn from_request(request: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+		if !state.strict_requests {
+			let Form(shake) = Form::from_request(request, state)
+				.await
+				.map_err(IntoResponse::into_response)?;
+			return Ok(Self(shake));
+		}
+
+		// Hang on to the body so it can be checked for unknown fields before it's parsed as a handshake
+		let (parts, body) = request.into_parts();
+		let body = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+			.await
+			.map_err(IntoResponse::into_response)?;
+		if let Some(field) = unknown_field(&body, HandshakeContext::FIELDS) {
+			return Err(Error::UnknownField(field, HandshakeContext::FIELDS).into_response());
+		}
+
+		let Form(shake) = Form::from_request(Request::from_parts(parts, Body::from(body)), state)
+			.await
+			.map_err(IntoResponse::into_response)?;
+		Ok(Self(shake))
+	}
+}
+
+/// Finds the first field in a URL-encoded form that isn't one of the known fields. Forms that can't be parsed are left
+/// for the form extractor to reject.
+fn unknown_field(body: &[u8], known: &[&str]) -> Option<String> {
+	serde_urlencoded::from_bytes::<Vec<(String, String)>>(body)
+		.ok()?
+		.into_iter()
+		.map(|(field, _)| field)
+		.find(|field| !known.contains(&field.as_str()))
+}
+
+/// Response to creating a handshake
+#[derive(Debug, Serialize, ToSchema)]
+struct HandshakeCreated {
+	/// Handshake that was created, along with the counts at the moment it was stored
+	#[serde(flatten)]
+	created: CreatedHandshake,
+
+	/// Details of the user that shook hands
+	#[serde(flatten)]
+	user: HandshakeUser,
+
+	/// Total handshake count, if it's a milestone
+	#[serde(skip_serializing_if = "Option::is_none")]
+	milestone: Option<i64>,
+}
+
+impl HandshakeCreated {
+	/// Builds the response for a newly created handshake
+	fn new(created: CreatedHandshake, milestone: Option<i64>) -> Self {
+		let user = HandshakeUser {
+			resonite_id: created.user.resonite_id.clone(),
+			resonite_name: created.user.resonite_name.clone(),
+			user_created_at: created.user.created_at,
+			user_legacy: created.user.legacy,
+		};
+		Self {
+			created,
+			user,
+			milestone,
+		}
+	}
+}
+
+/// Details of the user that created a handshake, named so that they don't clash with the handshake's own fields in
+/// the flat response
+#[derive(Debug, Serialize, ToSchema)]
+struct HandshakeUser {
+	/// Resonite ID of the user, if known
+	resonite_id: Option<String>,
+
+	/// Resonite username of the user, as it is after the handshake
+	resonite_name: String,
+
+	/// Date/time the user was first recorded
+	#[serde(with = "::time::serde::iso8601")]
+	user_created_at: OffsetDateTime,
+
+	/// Whether the user was imported from a legacy list of usernames
+	user_legacy: bool,
+}
+
+/// Total handshake counts that are considered milestones
+#[derive(Debug, Clone, Default)]
+pub struct Milestones {
+	/// Intervals that every multiple of is a milestone
+	every: Vec<i64>,
+
+	/// Specific counts that are milestones
+	at: Vec<i64>,
+}
+
+impl Milestones {
+	/// Creates a set of milestones from intervals and specific counts
+	#[must_use]
+	pub fn new(every: Vec<i64>, at: Vec<i64>) -> Self {
+		Self { every, at }
+	}
+
+	/// Gets the count back if it is a milestone
+	fn reached(&self, count: i64) -> Option<i64> {
+		let is_milestone = self.every.iter().any(|interval| count % interval == 0) || self.at.contains(&count);
+		is_milestone.then_some(count)
+	}
+}
+
+/// Returns a page of handshakes as JSON, newest first
+///
+/// Requires the `read` scope. Pass the returned `next_cursor` as the `cursor` of the next request to continue where the
+/// page left off, which is unaffected by any handshakes that are created in the meantime.
+#[utoipa::path(
+	get,
+	path = "/handshakes",
+	tag = "handshakes",
+	params(CursorPagination, db::HandshakeFilter),
+	responses(
+		(status = 200, description = "Handshakes, newest first", body = HandshakePage),
+		(status = 400, description = "Malformed cursor", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_handshakes(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(page): Query<CursorPagination>,
+	Query(filter): Query<db::HandshakeFilter>,
+) -> Result<Json<HandshakePage>, Error> {
+	session.require(Scope::Read)?;
+	let before = page.cursor.as_deref().map(Cursor::decode).transpose()?;
+
+	// Retrieve one extra handshake to find out whether there's another page
+	let limit = page.limit();
+	let mut handshakes = db
+		.get_handshakes_before(before.map(|cursor| (cursor.created_at, cursor.id)), &filter, limit + 1)
+		.await?;
+	let next_cursor = if handshakes.len() > usize::try_from(limit).unwrap_or(usize::MAX) {
+		handshakes.pop();
+		handshakes.last().map(|shake| {
+			Cursor {
+				created_at: shake.created_at,
+				id: shake.id,
+			}
+			.encode()
+		})
+	} else {
+		None
+	};
+
+	Ok(Json(HandshakePage {
+		handshakes,
+		next_cursor,
+	}))
+}
+
+/// Page of handshakes
+#[derive(Debug, Serialize, ToSchema)]
+struct HandshakePage {
+	/// Handshakes, newest first
+	handshakes: Vec<HandshakeWithUser>,
+
+	/// Cursor to retrieve the next page with, or null if there are no more handshakes
+	next_cursor: Option<String>,
+}
+
+/// Returns the most recent handshake along with details of the user that performed it as JSON
+///
+/// Requires the `read` scope. Only handshakes by a specific user are considered if one is given by database ID or
+/// Resonite ID (or both, which must then be the same user).
+#[utoipa::path(
+	get,
+	path = "/handshakes/latest",
+	tag = "handshakes",
+	params(LatestQuery),
+	responses(
+		(status = 200, description = "Most recent handshake", body = HandshakeWithUser),
+		(status = 404, description = "No such user, or no handshakes", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn get_latest_handshake(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(query): Query<LatestQuery>,
+) -> Result<Json<HandshakeWithUser>, Error> {
+	session.require(Scope::Read)?;
+
+	let mut user_id = query.user_id;
+	if let Some(resonite_id) = &query.resonite_id {
+		let user = db
+			.get_user_by_resonite_id(resonite_id)
+			.await?
+			.filter(|user| user_id.is_none_or(|id| id == user.id))
+			.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+		user_id = Some(user.id);
+	}
+
+	db.get_latest_handshake(user_id)
+		.await?
+		.map(Json)
+		.ok_or_else(|| Error::NotFound("no handshakes".to_owned()))
+}
+
+/// Query parameters for retrieving the most recent handshake
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LatestQuery {
+	/// Only consider handshakes by the user with this database ID
+	user_id: Option<i64>,
+
+	/// Only consider handshakes by the user with this Resonite ID
+	resonite_id: Option<String>,
+}
+
+/// Returns the total number of handshakes that have occurred
+///
+/// Requires the `read` scope.
+#[utoipa::path(
+	get,
+	path = "/handshakes/count",
+	tag = "handshakes",
+	params(db::HandshakeFilter),
+	responses((status = 200, description = "Number of handshakes", body = String, content_type = "text/plain"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db, cache))]
+async fn count_handshakes(
+	session: Session,
+	State(db): State<db::Database>,
+	State(cache): State<cache::ReadCache>,
+	Query(filter): Query<db::HandshakeFilter>,
+) -> Result<String, Error> {
+	session.require(Scope::Read)?;
+	let key = cache::Key::HandshakeCount {
+		source: filter.source.clone(),
+		legacy: filter.legacy,
+		event: filter.event.clone(),
+	};
+	let count = cache
+		.get_or_load(&db, key, || async {
+			Ok(db.count_handshakes_matching(&filter).await?.to_string())
+		})
+		.await?;
+	Ok(count)
+}
+
+/// Returns the number of handshakes that a specific user has performed
+///
+/// Requires the `read` scope. The user is looked up by Resonite ID, falling back to username (and then to past
+/// usernames, if requested).
+#[utoipa::path(
+	get,
+	path = "/handshakes/count/user",
+	tag = "handshakes",
+	params(db::UserResoniteInfo, NameLookup, db::HandshakeFilter),
+	responses(
+		(status = 200, description = "Number of handshakes by the user", body = String, content_type = "text/plain"),
+		(status = 404, description = "No such user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn count_handshakes_for_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(info): Query<db::UserResoniteInfo>,
+	Query(lookup): Query<NameLookup>,
+	Query(filter): Query<db::HandshakeFilter>,
+) -> Result<String, Error> {
+	session.require(Scope::Read)?;
+	let user = if lookup.include_history {
+		db.get_user_by_resonite_info_with_history(&info).await?
+	} else {
+		db.get_user_by_resonite_info(&info).await?
+	}
+	.ok_or_else(|| Error::NotFound("no such user".to_owned()))?;
+	let count = db.count_user_handshakes_matching(user.id, &filter).await?;
+	Ok(count.to_string())
+}
+
+/// Query parameters for how users are looked up by username
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct NameLookup {
+	/// Also match usernames that users previously had, if no user currently has the name
+	#[serde(default)]
+	include_history: bool,
+}
+
+/// Returns statistics about the handshakes that took place in a world as JSON
+///
+/// Requires the `read` scope. The world name must match exactly (once URL-decoded), and worlds without any handshakes
+/// aren't found.
+#[utoipa::path(
+	get,
+	path = "/worlds/{name}/stats",
+	tag = "handshakes",
+	params(("name" = String, Path, description = "Name of the world")),
+	responses(
+		(status = 200, description = "Statistics about the world's handshakes", body = WorldStats),
+		(status = 404, description = "No handshakes in the world", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn get_world_stats(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(name): Path<String>,
+) -> Result<Json<WorldStats>, Error> {
+	session.require(Scope::Read)?;
+	db.get_world_stats(&name)
+		.await?
+		.map(Json)
+		.ok_or_else(|| Error::NotFound("no such world".to_owned()))
+}
+
+/// Returns the number of handshakes submitted from each source as JSON, most first
+///
+/// Requires the `read` scope. Handshakes submitted without a source are counted under a null source.
+#[utoipa::path(
+	get,
+	path = "/sources",
+	tag = "handshakes",
+	responses((status = 200, description = "Handshake counts by source", body = [SourceCount]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_sources(session: Session, State(db): State<db::Database>) -> Result<Json<Vec<SourceCount>>, Error> {
+	session.require(Scope::Read)?;
+	Ok(Json(db.count_handshakes_by_source().await?))
+}
+
+/// Returns the number of handshakes tagged with each event as JSON, along with when the first and last of them took
+/// place, most recently active first
+///
+/// Requires the `read` scope. Handshakes without an event are counted together in an "untagged" bucket with a null
+/// event, which comes last.
+#[utoipa::path(
+	get,
+	path = "/events",
+	tag = "handshakes",
+	responses((status = 200, description = "Handshake counts by event", body = [EventSummary]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_events(session: Session, State(db): State<db::Database>) -> Result<Json<Vec<EventSummary>>, Error> {
+	session.require(Scope::Read)?;
+	Ok(Json(db.count_handshakes_by_event().await?))
+}
+
+/// Returns the number of handshakes on each day as JSON, oldest first, along with the timezone that days are counted in
+///
+/// Requires the `read` scope. Days start at midnight in the server's configured timezone, and days without any
+/// handshakes are left out. The time range applies to when handshakes were created. With `breakdown=new_returning`,
+/// each day also has the number of users that shook hands for the first time ever (`new_users`) and the number of
+/// handshakes from users that had shaken hands before (`returning`).
+#[utoipa::path(
+	get,
+	path = "/handshakes/daily",
+	tag = "handshakes",
+	params(db::TimeRange, db::EventFilter, DailyQuery),
+	responses((status = 200, description = "Handshake counts by day", body = DailyCounts))
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn count_handshakes_per_day(
+	session: Session,
+	State(state): State<AppState>,
+	Query(range): Query<db::TimeRange>,
+	Query(filter): Query<db::EventFilter>,
+	Query(query): Query<DailyQuery>,
+) -> Result<Json<DailyCounts>, Error> {
+	session.require(Scope::Read)?;
+	let days = match query.breakdown {
+		Some(DailyBreakdown::NewReturning) => DailyDays::NewReturning(
+			state
+				.db
+				.count_new_and_returning_per_day(&range, &filter, !query.exclude_legacy, state.timezone)
+				.await?,
+		),
+		None => DailyDays::Counts(
+			state
+				.db
+				.count_handshakes_per_day(&range, &filter, state.timezone)
+				.await?,
+		),
+	};
+	Ok(Json(DailyCounts {
+		timezone: state.timezone.name().to_owned(),
+		days,
+	}))
+}
+
+/// Query parameters for counting handshakes by day
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DailyQuery {
+	/// How to break each day's count down
+	#[param(inline)]
+	breakdown: Option<DailyBreakdown>,
+
+	/// Whether to leave legacy handshakes out of the breakdown, since their dates are made up. A user's first
+	/// handshake is then their first one that isn't legacy.
+	#[serde(default)]
+	exclude_legacy: bool,
+}
+
+/// Ways that each day's handshake count can be broken down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum DailyBreakdown {
+	/// Users shaking hands for the first time ever versus handshakes from users that had before
+	NewReturning,
+}
+
+/// Handshake counts by day
+#[derive(Debug, Serialize, ToSchema)]
+struct DailyCounts {
+	/// IANA name of the timezone that days start at midnight in
+	timezone: String,
+
+	/// Number of handshakes on each day that had any, oldest first
+	days: DailyDays,
+}
+
+/// Handshake counts for each day, with or without a breakdown
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+enum DailyDays {
+	/// Days with only their handshake counts
+	Counts(Vec<DayCount>),
+
+	/// Days with their handshake counts split between new and returning users
+	NewReturning(Vec<DayBreakdown>),
+}
+
+/// Returns the number of handshakes in each hour of each day of the week as JSON, for finding the busiest times
+///
+/// Requires the `read` scope. Hours are in the server's configured timezone, and every hour of the week is included
+/// (with a count of zero if there were no handshakes), ordered from midnight on Sunday onwards. The time range applies
+/// to when handshakes were created.
+#[utoipa::path(
+	get,
+	path = "/handshakes/heatmap",
+	tag = "handshakes",
+	params(db::TimeRange, HeatmapQuery),
+	responses((status = 200, description = "Handshake counts by weekday and hour", body = Heatmap))
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn get_activity_heatmap(
+	session: Session,
+	State(state): State<AppState>,
+	Query(range): Query<db::TimeRange>,
+	Query(query): Query<HeatmapQuery>,
+) -> Result<Json<Heatmap>, Error> {
+	session.require(Scope::Read)?;
+	Ok(Json(Heatmap {
+		timezone: state.timezone.name().to_owned(),
+		cells: state
+			.db
+			.get_activity_heatmap(&range, query.world.as_deref(), state.timezone)
+			.await?,
+	}))
+}
+
+/// Query parameters for the activity heatmap
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HeatmapQuery {
+	/// Only include handshakes that took place in this world
+	world: Option<String>,
+}
+
+/// Handshake counts by weekday and hour
+#[derive(Debug, Serialize, ToSchema)]
+struct Heatmap {
+	/// IANA name of the timezone that hours are in
+	timezone: String,
+
+	/// Number of handshakes in each hour of the week, from midnight on Sunday onwards
+	cells: Vec<HeatmapCell>,
+}
+
+/// Returns the number of handshakes in the last 5, 15, and 60 minutes as JSON, along with the number in each minute of
+/// the last hour, for seeing how quickly handshakes are happening during an event
+///
+/// Requires the `read` scope. The response includes the server's current time that handshakes were counted up to, so
+/// that clients can line the minutes up with their own clocks.
+#[utoipa::path(
+	get,
+	path = "/handshakes/rate",
+	tag = "handshakes",
+	params(RateQuery),
+	responses((status = 200, description = "Recent handshake counts", body = HandshakeRate))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn get_handshake_rate(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(query): Query<RateQuery>,
+) -> Result<Json<HandshakeRate>, Error> {
+	session.require(Scope::Read)?;
+	Ok(Json(
+		db.get_handshake_rate(
+			OffsetDateTime::now_utc(),
+			query.world.as_deref(),
+			query.event.as_deref(),
+		)
+		.await?,
+	))
+}
+
+/// Query parameters for the handshake rate
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RateQuery {
+	/// Only include handshakes that took place in this world
+	world: Option<String>,
+
+	/// Only include handshakes tagged with this event
+	event: Option<String>,
+}
+
+/// Returns overall statistics as JSON: record counts, the number of handshakes today, the time of the newest
+/// handshake, the users with the most handshakes, and the times of the last and next scheduled backups
+///
+/// Requires the `read` scope. Today is the current day in the server's configured timezone, which is included so that
+/// clients can label days the same way.
+#[utoipa::path(
+	get,
+	path = "/stats",
+	tag = "handshakes",
+	responses((status = 200, description = "Overall statistics", body = StatsResponse))
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn get_stats(session: Session, State(state): State<AppState>) -> Result<Json<StatsResponse>, Error> {
+	session.require(Scope::Read)?;
+	let today = OffsetDateTime::now_utc().to_timezone(state.timezone).date();
+	let backups = state.backups.times().await;
+	Ok(Json(StatsResponse {
+		stats: state.db.stats(STATS_TOP_USERS).await?,
+		today: state.db.count_handshakes_on(today, state.timezone).await?,
+		timezone: state.timezone.name().to_owned(),
+		last_backup_at: backups.last,
+		next_backup_at: backups.next,
+	}))
+}
+
+/// Number of users with the most handshakes included in the stats
+const STATS_TOP_USERS: i64 = 10;
+
+/// Overall statistics, along with how many handshakes there have been today
+#[derive(Debug, Serialize, ToSchema)]
+struct StatsResponse {
+	/// Counts, the newest handshake time, and the top users
+	#[serde(flatten)]
+	stats: Stats,
+
+	/// Number of handshakes since midnight in the configured timezone
+	today: i64,
+
+	/// IANA name of the timezone that days start at midnight in
+	timezone: String,
+
+	/// Date/time of the last successful scheduled backup, if backups are scheduled and one has been written yet
+	#[serde(with = "::time::serde::iso8601::option")]
+	last_backup_at: Option<OffsetDateTime>,
+
+	/// Date/time that the next scheduled backup is due, if backups are scheduled
+	#[serde(with = "::time::serde::iso8601::option")]
+	next_backup_at: Option<OffsetDateTime>,
+}
+
+/// Replaces the token for a scope, immediately invalidating any previous tokens for that scope
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	post,
+	path = "/admin/token",
+	tag = "admin",
+	request_body(content = RotateTokenForm, content_type = "application/x-www-form-urlencoded"),
+	responses((status = 204, description = "Token rotated"))
+)]
+#[tracing::instrument(level = "debug", skip(session, state, form))]
+async fn rotate_token(
+	session: Session,
+	State(state): State<AppState>,
+	Form(form): Form<RotateTokenForm>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	let token: ScopedToken = form.token.parse().map_err(|err| Error::BadRequest(format!("{err}")))?;
+
+	state.tokens.write().await.rotate(&token);
+	info!("Rotated the {} token via the API", token.scope);
+	session.audit(&state.db, &[]).await;
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Form for rotating a token
+#[derive(Deserialize, ToSchema)]
+struct RotateTokenForm {
+	/// New token, optionally prefixed with its scope in the same form as the configuration option
+	token: String,
+}
+
+/// Returns a page of audit log entries as JSON, newest first
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	get,
+	path = "/admin/audit",
+	tag = "admin",
+	params(Pagination),
+	responses((status = 200, description = "Audit log entries, newest first", body = [AuditEntry]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_audit_entries(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<AuditEntry>>, Error> {
+	session.require(Scope::Admin)?;
+	let entries = db.get_audit_entries(page.limit(), page.offset()).await?;
+	Ok(Json(entries))
+}
+
+/// Writes a consistent copy of the database into the backup directory and returns its path and size as JSON
+///
+/// Requires the `admin` scope. Backups are named after the current time unless a name is given, and existing files
+/// are never overwritten.
+#[utoipa::path(
+	post,
+	path = "/admin/backup",
+	tag = "admin",
+	params(BackupParams),
+	responses(
+		(status = 201, description = "Backup that was written", body = Backup),
+		(status = 404, description = "Backups aren't enabled", body = ErrorBody),
+		(status = 409, description = "A file with the backup's name already exists", body = ErrorBody),
+		(status = 507, description = "Not enough disk space for the backup", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn create_backup(
+	session: Session,
+	State(state): State<AppState>,
+	Query(params): Query<BackupParams>,
+) -> Result<(StatusCode, Json<Backup>), Error> {
+	session.require(Scope::Admin)?;
+	let Some(dir) = &state.backup_dir else {
+		return Err(Error::NotFound(
+			"backups aren't enabled (no backup directory is configured)".to_owned(),
+		));
+	};
+
+	let backup = backup::create(&state.db, dir, params.name.as_deref()).await?;
+	session.audit(&state.db, &[]).await;
+	Ok((StatusCode::CREATED, Json(backup)))
+}
+
+/// Query parameters for creating a backup
+#[derive(Debug, Deserialize, IntoParams)]
+struct BackupParams {
+	/// File name to give the backup within the backup directory, instead of one based on the current time
+	name: Option<String>,
+}
+
+/// Starts a pass that updates stored usernames to match the Resonite API right away, rather than waiting for the next
+/// scheduled one
+///
+/// Requires the `admin` scope. The pass runs in the background at the configured rate, so this returns immediately.
+#[utoipa::path(
+	post,
+	path = "/admin/refresh-names",
+	tag = "admin",
+	responses(
+		(status = 202, description = "Pass started"),
+		(status = 404, description = "Refreshing usernames isn't enabled", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn refresh_names(session: Session, State(state): State<AppState>) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	let Some(refresher) = &state.name_refresher else {
+		return Err(Error::NotFound(
+			"refreshing usernames isn't enabled (no refresh interval is configured)".to_owned(),
+		));
+	};
+
+	refresher.wake();
+	session.audit(&state.db, &[]).await;
+	Ok(StatusCode::ACCEPTED)
+}
+
+/// Returns groups of users whose usernames are identical once normalized and compared without regard to case as JSON
+///
+/// Requires the `admin` scope. Lookups by username can only ever match one user of each group, so they need to be
+/// merged or renamed by hand.
+#[utoipa::path(
+	get,
+	path = "/admin/duplicates",
+	tag = "admin",
+	responses((status = 200, description = "Groups of users with duplicate usernames", body = [NameCollision]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_duplicate_names(
+	session: Session,
+	State(db): State<db::Database>,
+) -> Result<Json<Vec<NameCollision>>, Error> {
+	session.require(Scope::Admin)?;
+	Ok(Json(db.find_duplicate_names().await?))
+}
+
+/// Returns stored usernames, Resonite IDs, world names, and sources that break the configured limits on the fields of
+/// handshakes as JSON
+///
+/// Requires the `admin` scope. Values stored before the limits were in place (or were lowered) aren't changed by them,
+/// so they need to be cleaned up by hand. Overlong values are listed even if they'd be truncated when submitted.
+#[utoipa::path(
+	get,
+	path = "/admin/limit-violations",
+	tag = "admin",
+	responses((status = 200, description = "Stored values that break the limits", body = [LimitViolation]))
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn list_limit_violations(
+	session: Session,
+	State(state): State<AppState>,
+) -> Result<Json<Vec<LimitViolation>>, Error> {
+	session.require(Scope::Admin)?;
+	Ok(Json(state.db.find_limit_violations(state.field_limits).await?))
+}
+
+/// Checks the database for corruption and rows that reference missing rows, returning the results as JSON
+///
+/// Requires the `admin` scope. The check reads the entire database, so it may take a while on large ones.
+#[utoipa::path(
+	get,
+	path = "/admin/integrity",
+	tag = "admin",
+	responses((status = 200, description = "Results of the check", body = IntegrityReport))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn check_integrity(session: Session, State(db): State<db::Database>) -> Result<Json<IntegrityReport>, Error> {
+	session.require(Scope::Admin)?;
+	Ok(Json(db.integrity_check().await?))
+}
+
+/// Returns a page of deleted users as JSON, most recently deleted first
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	get,
+	path = "/admin/deleted/users",
+	tag = "admin",
+	params(Pagination),
+	responses((status = 200, description = "Deleted users, most recently deleted first", body = [User]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_deleted_users(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<User>>, Error> {
+	session.require(Scope::Admin)?;
+	Ok(Json(db.get_deleted_users(page.limit(), page.offset()).await?))
+}
+
+/// Returns a page of deleted handshakes as JSON, most recently deleted first
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	get,
+	path = "/admin/deleted/handshakes",
+	tag = "admin",
+	params(Pagination),
+	responses((status = 200, description = "Deleted handshakes, most recently deleted first", body = [Handshake]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_deleted_handshakes(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Handshake>>, Error> {
+	session.require(Scope::Admin)?;
+	Ok(Json(db.get_deleted_handshakes(page.limit(), page.offset()).await?))
+}
+
+/// Restores a deleted user along with the handshakes that were deleted with them
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	post,
+	path = "/admin/deleted/users/{id}/restore",
+	tag = "admin",
+	params(("id" = i64, Path, description = "Database ID of the user")),
+	responses(
+		(status = 204, description = "User restored"),
+		(status = 404, description = "No such deleted user", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn restore_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	if !db.restore_user(id).await? {
+		return Err(Error::NotFound(format!("no deleted user with ID {id}")));
+	}
+
+	session.audit(&db, &[("user", id)]).await;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Restores a deleted handshake
+///
+/// Requires the `admin` scope. Handshakes of deleted users can only be restored by restoring the user.
+#[utoipa::path(
+	post,
+	path = "/admin/deleted/handshakes/{id}/restore",
+	tag = "admin",
+	params(("id" = i64, Path, description = "ID of the handshake")),
+	responses(
+		(status = 204, description = "Handshake restored"),
+		(status = 404, description = "No such deleted handshake, or its user is deleted", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn restore_handshake(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(id): Path<i64>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	if !db.restore_handshake(id).await? {
+		return Err(Error::NotFound(format!(
+			"no deleted handshake with ID {id} whose user exists"
+		)));
+	}
+
+	session.audit(&db, &[("handshake", id)]).await;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns a page of webhook deliveries that are pending or have failed as JSON, newest first
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	get,
+	path = "/admin/webhooks/outbox",
+	tag = "admin",
+	params(Pagination),
+	responses((status = 200, description = "Pending and failed webhook deliveries, newest first", body = [OutboxEntry]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_webhook_outbox(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<OutboxEntry>>, Error> {
+	session.require(Scope::Admin)?;
+	let entries = db.get_undelivered_outbox_entries(page.limit(), page.offset()).await?;
+	Ok(Json(entries))
+}
+
+/// Returns a page of bans as JSON, most recent first
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	get,
+	path = "/admin/bans",
+	tag = "admin",
+	params(Pagination),
+	responses((status = 200, description = "Bans, most recent first", body = [Ban]))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn list_bans(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(page): Query<Pagination>,
+) -> Result<Json<Vec<Ban>>, Error> {
+	session.require(Scope::Admin)?;
+	Ok(Json(db.get_bans(page.limit(), page.offset()).await?))
+}
+
+/// Bans a user from shaking hands and returns the ban as JSON
+///
+/// Requires the `admin` scope. Bans match on the Resonite ID, or on the username (in any case) if no ID is given, for
+/// legacy users. Handshakes submitted by banned users are rejected with a 403 and the code `banned`, but their existing
+/// handshakes are kept.
+#[utoipa::path(
+	post,
+	path = "/admin/bans",
+	tag = "admin",
+	request_body(content = NewBan, content_type = "application/x-www-form-urlencoded"),
+	responses(
+		(status = 201, description = "Ban that was created", body = Ban),
+		(status = 409, description = "The user is already banned", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn ban_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Form(ban): Form<NewBan>,
+) -> Result<(StatusCode, Json<Ban>), Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	let ban = db.ban_user(&ban).await?;
+	info!(
+		"Banned {} via the API",
+		ban.resonite_id
+			.as_ref()
+			.or(ban.resonite_name.as_ref())
+			.map_or("", String::as_str)
+	);
+	session.audit(&db, &[("ban", ban.id)]).await;
+	Ok((StatusCode::CREATED, Json(ban)))
+}
+
+/// Lifts the ban on a Resonite ID
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	delete,
+	path = "/admin/bans/{resonite_id}",
+	tag = "admin",
+	params(("resonite_id" = String, Path, description = "Banned Resonite user ID")),
+	responses(
+		(status = 204, description = "Ban lifted"),
+		(status = 404, description = "No such ban", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn unban_user(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(resonite_id): Path<String>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	if !db.unban_user(&resonite_id).await? {
+		return Err(Error::NotFound(format!("no ban for {resonite_id}")));
+	}
+
+	info!("Unbanned {resonite_id} via the API");
+	session.audit(&db, &[]).await;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lifts the ban on a username that was banned without a Resonite ID
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	delete,
+	path = "/admin/bans/by-name/{name}",
+	tag = "admin",
+	params(("name" = String, Path, description = "Banned Resonite username (in any case)")),
+	responses(
+		(status = 204, description = "Ban lifted"),
+		(status = 404, description = "No such ban", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+async fn unban_user_name(
+	session: Session,
+	State(db): State<db::Database>,
+	Path(name): Path<String>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&db)?;
+	if !db.unban_user_name(&name).await? {
+		return Err(Error::NotFound(format!("no ban for the username {name}")));
+	}
+
+	info!("Unbanned the username {name} via the API");
+	session.audit(&db, &[]).await;
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Resets an undelivered webhook delivery so that it's attempted again right away
+///
+/// Requires the `admin` scope.
+#[utoipa::path(
+	post,
+	path = "/admin/webhooks/outbox/{id}/retry",
+	tag = "admin",
+	params(("id" = i64, Path, description = "ID of the delivery")),
+	responses(
+		(status = 204, description = "Delivery queued for another attempt"),
+		(status = 404, description = "No such undelivered delivery", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+async fn retry_webhook_delivery(
+	session: Session,
+	State(state): State<AppState>,
+	Path(id): Path<i64>,
+) -> Result<StatusCode, Error> {
+	session.require(Scope::Admin)?;
+	require_writable(&state.db)?;
+	if !state.db.retry_outbox_entry(id).await? {
+		return Err(Error::NotFound(format!("no undelivered webhook delivery with ID {id}")));
+	}
+
+	session.audit(&state.db, &[("webhook_outbox", id)]).await;
+	if let Some(webhooks) = &state.webhooks {
+		webhooks.wake();
+	}
+
+	Ok(StatusCode::NO_CONTENT)
+}
+
+/// Query parameters for paginated listings
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Pagination {
+	/// Maximum number of records to return
+	limit: Option<i64>,
+
+	/// Number of records to skip
+	offset: Option<i64>,
+}
+
+impl Pagination {
+	/// Number of records returned when no limit is given
+	const DEFAULT_LIMIT: i64 = 100;
+
+	/// Maximum number of records that may be requested at once
+	const MAX_LIMIT: i64 = 1000;
+
+	/// Gets the effective limit, clamped to a sane range
+	fn limit(self) -> i64 {
+		self.limit.unwrap_or(Self::DEFAULT_LIMIT).clamp(1, Self::MAX_LIMIT)
+	}
+
+	/// Gets the effective offset
+	fn offset(self) -> i64 {
+		self.offset.unwrap_or(0).max(0)
+	}
+}
+
+/// Query parameters for cursor-paginated listings
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CursorPagination {
+	/// Maximum number of records to return
+	limit: Option<i64>,
+
+	/// Cursor returned with the previous page, to continue where it left off
+	cursor: Option<String>,
+}
+
+impl CursorPagination {
+	/// Gets the effective limit, clamped to a sane range
+	fn limit(&self) -> i64 {
+		self.limit
+			.unwrap_or(Pagination::DEFAULT_LIMIT)
+			.clamp(1, Pagination::MAX_LIMIT)
+	}
+}
+
+/// Position in a listing of handshakes, identifying the last handshake that was returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cursor {
+	/// Date/time of the last handshake
+	created_at: OffsetDateTime,
+
+	/// ID of the last handshake
+	id: i64,
+}
+
+impl Cursor {
+	/// Encodes the cursor as an opaque string
+	fn encode(self) -> String {
+		hex::encode(format!("{}:{}", self.created_at.unix_timestamp(), self.id))
+	}
+
+	/// Decodes a cursor from a string produced by [`Self::encode`]
+	fn decode(cursor: &str) -> Result<Self, Error> {
+		let invalid = || Error::BadRequest("malformed cursor".to_owned());
+		let decoded = String::from_utf8(hex::decode(cursor).map_err(|_| invalid())?).map_err(|_| invalid())?;
+		let (timestamp, id) = decoded.split_once(':').ok_or_else(invalid)?;
+
+		Ok(Self {
+			created_at: OffsetDateTime::from_unix_timestamp(timestamp.parse().map_err(|_| invalid())?)
+				.map_err(|_| invalid())?,
+			id: id.parse().map_err(|_| invalid())?,
+		})
+	}
+}
+
+/// Error type returned from handlers
+#[derive(Debug)]
+pub enum Error {
+	/// Something unexpected went wrong (500)
+	Internal(anyhow::Error),
+
+	/// The requested resource doesn't exist (404)
+	NotFound(String),
+
+	/// The request was malformed (400)
+	BadRequest(String),
+
+	/// A field of the request failed validation (422)
+	Invalid(ValidationError),
+
+	/// A field of the request conflicts with existing data (409)
+	Conflict(ConflictError),
+
+	/// The most recent handshake can't be undone (409)
+	NotUndoable(String),
+
+	/// The request didn't include a valid token (401)
+	Unauthorized(String),
+
+	/// The token doesn't grant access to the route (403)
+	Forbidden(String),
+
+	/// The user is banned from shaking hands (403)
+	Banned(BannedError),
+
+	/// The server can't handle the request right now (503)
+	Unavailable(String),
+
+	/// Handling the request took too long (504)
+	Timeout(String),
+
+	/// The request included a field that isn't recognized, listing the ones that are (422)
+	UnknownField(String, &'static [&'static str]),
+
+	/// Backing up the database failed
+	Backup(BackupError),
+
+	/// The database refused the request, along with the error it gave
+	Database(DbError, anyhow::Error),
+}
+
+impl Error {
+	/// Gets the HTTP status code for the error
+	fn status(&self) -> StatusCode {
+		match self {
+			Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::NotFound(_) => StatusCode::NOT_FOUND,
+			Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+			Self::Invalid(_) | Self::UnknownField(..) => StatusCode::UNPROCESSABLE_ENTITY,
+			Self::Conflict(_) | Self::NotUndoable(_) => StatusCode::CONFLICT,
+			Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+			Self::Forbidden(_) | Self::Banned(_) => StatusCode::FORBIDDEN,
+			Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+			Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+			Self::Backup(err) => match err.kind {
+				BackupErrorKind::AlreadyExists => StatusCode::CONFLICT,
+				BackupErrorKind::OutsideDirectory => StatusCode::BAD_REQUEST,
+				BackupErrorKind::NoSpace => StatusCode::INSUFFICIENT_STORAGE,
+				BackupErrorKind::PermissionDenied | BackupErrorKind::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+			},
+			Self::Database(err, _) => match err {
+				DbError::UniqueViolation => StatusCode::CONFLICT,
+				DbError::ForeignKeyViolation => StatusCode::UNPROCESSABLE_ENTITY,
+				DbError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+			},
+		}
+	}
+
+	/// Gets a stable machine-readable code for the error
+	fn code(&self) -> &'static str {
+		match self {
+			Self::Internal(_) => "internal",
+			Self::NotFound(_) => "not_found",
+			Self::BadRequest(_) => "bad_request",
+			Self::Invalid(_) => "invalid",
+			Self::Conflict(_) => "conflict",
+			Self::NotUndoable(_) => "not_undoable",
+			Self::Unauthorized(_) => "unauthorized",
+			Self::Forbidden(_) => "forbidden",
+			Self::Banned(_) => "banned",
+			Self::Unavailable(_) => "unavailable",
+			Self::Timeout(_) => "timeout",
+			Self::UnknownField(..) => "unknown_field",
+			Self::Backup(err) => match err.kind {
+				BackupErrorKind::AlreadyExists => "backup_exists",
+				BackupErrorKind::OutsideDirectory => "backup_outside_directory",
+				BackupErrorKind::NoSpace => "backup_no_space",
+				BackupErrorKind::PermissionDenied => "backup_permission_denied",
+				BackupErrorKind::Failed => "backup_failed",
+			},
+			Self::Database(err, _) => match err {
+				DbError::UniqueViolation => "duplicate",
+				DbError::ForeignKeyViolation => "missing_reference",
+				DbError::Busy => "database_busy",
+			},
+		}
+	}
+}
+
+impl IntoResponse for Error {
+	fn into_response(self) -> Response {
+		let status = self.status();
+		let code = self.code();
+		let (message, field) = match self {
+			Self::Internal(err) => {
+				error!("Internal error handling request: {err:#}");
+				(err.to_string(), None)
+			}
+			Self::Invalid(err) => (err.message, Some(err.field)),
+			Self::Conflict(err) => (err.message, Some(err.field)),
+			Self::Banned(err) => (err.to_string(), Some(err.field)),
+			Self::Backup(err) => {
+				error!("Unable to back up database: {err}");
+				(err.message, None)
+			}
+			Self::UnknownField(field, known) => (
+				format!(
+					"unknown field `{field}`, expected one of {}",
+					known
+						.iter()
+						.map(|field| format!("`{field}`"))
+						.collect::<Vec<_>>()
+						.join(", ")
+				),
+				None,
+			),
+			Self::Database(err, source) => {
+				warn!("Database refused to handle request: {source:#}");
+				(err.to_string(), None)
+			}
+			Self::NotFound(msg)
+			| Self::BadRequest(msg)
+			| Self::NotUndoable(msg)
+			| Self::Unauthorized(msg)
+			| Self::Forbidden(msg)
+			| Self::Unavailable(msg)
+			| Self::Timeout(msg) => (msg, None),
+		};
+
+		let body = ErrorBody {
+			error: message,
+			code,
+			field,
+			request_id: trace::current_request_id(),
+		};
+		(status, Json(body)).into_response()
+	}
+}
+
+/// JSON body of an error response
+#[derive(Debug, Serialize, ToSchema)]
+struct ErrorBody {
+	/// Human-readable description of the error
+	error: String,
+
+	/// Stable machine-readable code for the error
+	#[schema(value_type = String, example = "unauthorized")]
+	code: &'static str,
+
+	/// Name of the submitted field that was invalid or conflicting, if the error is about one
+	#[serde(skip_serializing_if = "Option::is_none")]
+	#[schema(value_type = Option<String>, example = "id")]
+	field: Option<&'static str>,
+
+	/// ID of the request that failed, for correlating with the logs
+	request_id: Option<String>,
+}
+
+impl<E: Into<anyhow::Error>> From<E> for Error {
+	fn from(err: E) -> Self {
+		// Validation failures, conflicts, bans, and backup failures can surface from deep within other operations, so pick them
+		// back out
+		let err = match err.into().downcast::<ValidationError>() {
+			Ok(err) => return Self::Invalid(err),
+			Err(err) => err,
+		};
+		let err = match err.downcast::<ConflictError>() {
+			Ok(err) => return Self::Conflict(err),
+			Err(err) => err,
+		};
+		let err = match err.downcast::<BannedError>() {
+			Ok(err) => return Self::Banned(err),
+			Err(err) => err,
+		};
+		let err = match err.downcast::<BackupError>() {
+			Ok(err) => return Self::Backup(err),
+			Err(err) => err,
+		};
+
+		// Anything else the database refused (that wasn't already translated into something more specific) is still
+		// the client's or another connection's doing rather than an internal error
+		match err.downcast_ref::<sqlx::Error>().and_then(DbError::classify) {
+			Some(kind) => Self::Database(kind, err),
+			None => Self::Internal(err),
+		}
+	}
+}
+
+/// Returns a future that completes once a shutdown has been requested via the channel
+async fn shutdown_requested(mut requested: watch::Receiver<bool>) {
+	// If the sender is gone without requesting a shutdown, then one never will be
+	if requested.wait_for(|requested| *requested).await.is_err() {
+		std::future::pending::<()>().await;
+	}
+}
+
+/// Counter of requests currently being handled
+#[derive(Debug, Clone, Default)]
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+	/// Gets the number of requests currently being handled
+	fn count(&self) -> usize {
+		self.0.load(Ordering::SeqCst)
+	}
+}
+
+/// Guard that decrements the in-flight counter when dropped, so requests are uncounted even if their handler panics
+struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		self.0 .0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Middleware that counts requests while they're being handled
+async fn track_in_flight(State(in_flight): State<InFlight>, request: Request, next: Next) -> Response {
+	in_flight.0.fetch_add(1, Ordering::SeqCst);
+	let _guard = InFlightGuard(in_flight);
+	next.run(request).await
+}
+
+/// Returns a future that waits for Ctrl + C or a terminate signal
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		signal::unix::signal(signal::unix::SignalKind::terminate())
+			.expect("failed to install signal handler")
+			.recv()
+			.await;
+	};
+
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		() = ctrl_c => {},
+		() = terminate => {},
+	}
+}
+
+/// Reloads the tokens whenever a hangup signal is received, from the token file if one is in use or otherwise from the
+/// `.env` file or environment
+#[cfg(unix)]
+async fn reload_tokens_on_hangup(tokens: Arc<RwLock<TokenRegistry>>, token_file: Option<PathBuf>) {
+	let mut hangup = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+		Ok(hangup) => hangup,
+		Err(err) => {
+			error!("Unable to install SIGHUP handler; tokens won't be reloadable: {err}");
+			return;
+		}
+	};
+
+	while hangup.recv().await.is_some() {
+		let loaded = match &token_file {
+			Some(path) => auth::tokens_from_file(path).await.map(Some),
+			None => auth::tokens_from_env(),
+		};
+
+		match loaded {
+			Ok(Some(loaded)) => {
+				let registry = TokenRegistry::new(&loaded);
+				info!("Reloaded {} token(s)", registry.len());
+				*tokens.write().await = registry;
+			}
+			Ok(None) => warn!("Received SIGHUP, but no tokens are defined in the environment; keeping current tokens"),
+			Err(err) => error!("Unable to reload tokens: {err:#}"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cursors_round_trip() {
+		let cursor = Cursor {
+			created_at: OffsetDateTime::from_unix_timestamp(1_718_452_800).unwrap(),
+			id: 42,
+		};
+		assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+	}
+
+	#[test]
+	fn malformed_cursors_are_rejected() {
+		for cursor in [
+			"",
+			"zz",
+			&hex::encode("1718452800"),
+			&hex::encode("a:1"),
+			&hex::encode("1:b"),
+			"ff",
+		] {
+			assert!(
+				matches!(Cursor::decode(cursor), Err(Error::BadRequest(_))),
+				"{cursor:?}"
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn handshake_responses_stay_flat() {
+		let db = db::Database::open_in_memory().await.unwrap();
+		let form = |created| serde_urlencoded::to_string(HandshakeCreated::new(created, None)).unwrap();
+
+		let first = db
+			.create_handshake(serde_urlencoded::from_str("id=U-a&name=A").unwrap())
+			.await
+			.unwrap();
+		let body = form(first);
+		for field in [
+			"first_time=true",
+			"user_count=1",
+			"resonite_id=U-a",
+			"resonite_name=A",
+			"user_legacy=false",
+		] {
+			assert!(body.contains(field), "{field} missing from {body}");
+		}
+
+		let again = db
+			.create_handshake(serde_urlencoded::from_str("id=U-a&name=B").unwrap())
+			.await
+			.unwrap();
+		let body = form(again);
+		for field in [
+			"first_time=false",
+			"user_count=2",
+			"resonite_name=B",
+			"user_created_at=",
+		] {
+			assert!(body.contains(field), "{field} missing from {body}");
+		}
+	}
+
+	#[test]
+	fn unknown_fields_are_found() {
+		let known = HandshakeContext::FIELDS;
+		assert_eq!(unknown_field(b"id=U-a&name=A&world=W&source=S", known), None);
+		assert_eq!(unknown_field(b"id=U-a&name=A&wolrd=W", known).as_deref(), Some("wolrd"));
+		assert_eq!(unknown_field(b"", known), None);
+
+		let err = Error::UnknownField("wolrd".to_owned(), known);
+		assert_eq!(
+			(err.status(), err.code()),
+			(StatusCode::UNPROCESSABLE_ENTITY, "unknown_field")
+		);
+	}
+
+	#[tokio::test]
+	async fn database_refusals_get_stable_error_codes() {
+		let mut conn = <sqlx::SqliteConnection as sqlx::Connection>::connect("sqlite::memory:")
+			.await
+			.unwrap();
+		let refusal = |err: sqlx::Error| {
+			let err = Error::from(anyhow::Error::from(err).context("Unable to write"));
+			(err.status(), err.code())
+		};
+
+		sqlx::query("CREATE TABLE parents (id INTEGER PRIMARY KEY)")
+			.execute(&mut conn)
+			.await
+			.unwrap();
+		sqlx::query("CREATE TABLE children (parent_id INTEGER REFERENCES parents(id))")
+			.execute(&mut conn)
+			.await
+			.unwrap();
+		sqlx::query("INSERT INTO parents (id) VALUES (1)")
+			.execute(&mut conn)
+			.await
+			.unwrap();
+
+		let err = sqlx::query("INSERT INTO parents (id) VALUES (1)")
+			.execute(&mut conn)
+			.await
+			.unwrap_err();
+		assert_eq!(refusal(err), (StatusCode::CONFLICT, "duplicate"));
+
+		let err = sqlx::query("INSERT INTO children (parent_id) VALUES (2)")
+			.execute(&mut conn)
+			.await
+			.unwrap_err();
+		assert_eq!(refusal(err), (StatusCode::UNPROCESSABLE_ENTITY, "missing_reference"));
+
+		assert_eq!(
+			refusal(sqlx::Error::PoolTimedOut),
+			(StatusCode::SERVICE_UNAVAILABLE, "database_busy")
+		);
+		assert_eq!(
+			refusal(sqlx::Error::RowNotFound),
+			(StatusCode::INTERNAL_SERVER_ERROR, "internal")
+		);
+	}
+
+	#[tokio::test]
+	async fn banned_users_get_a_stable_error_code() {
+		let db = db::Database::open_in_memory().await.unwrap();
+		let ban = NewBan {
+			resonite_id: Some("U-a".to_owned()),
+			..NewBan::default()
+		};
+		db.ban_user(&ban).await.unwrap();
+
+		let err: Error = db
+			.create_handshake(serde_urlencoded::from_str("id=U-a&name=A").unwrap())
+			.await
+			.unwrap_err()
+			.into();
+		assert_eq!((err.status(), err.code()), (StatusCode::FORBIDDEN, "banned"));
+	}
+
+	#[tokio::test]
+	async fn cached_responses_follow_writes_from_other_connections() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("shaker.db");
+		let open = || db::Database::open(&path, db::ConnectionSettings::default(), db::PoolSettings::default());
+
+		// The second database stands in for another process, such as an import, writing to the same file
+		let db = open().await.unwrap();
+		db.migrate().await.unwrap();
+		let other = open().await.unwrap();
+
+		let loads = AtomicUsize::new(0);
+		let count = |cache: cache::ReadCache| {
+			let (db, loads) = (&db, &loads);
+			async move {
+				let key = cache::Key::HandshakeCount {
+					source: None,
+					legacy: None,
+					event: None,
+				};
+				cache
+					.get_or_load(db, key, || async {
+						loads.fetch_add(1, Ordering::Relaxed);
+						Ok(db.count_handshakes().await?.to_string())
+					})
+					.await
+					.unwrap()
+			}
+		};
+
+		let cache = cache::ReadCache::new(true);
+		assert_eq!(count(cache.clone()).await, "0");
+		assert_eq!(count(cache.clone()).await, "0");
+		assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+		let shake = serde_urlencoded::from_str("id=U-a&name=A").unwrap();
+		other.create_handshake(shake).await.unwrap();
+		assert_eq!(count(cache.clone()).await, "1");
+		assert_eq!(loads.load(Ordering::Relaxed), 2);
+
+		let disabled = cache::ReadCache::new(false);
+		assert_eq!(count(disabled.clone()).await, "1");
+		assert_eq!(count(disabled).await, "1");
+		assert_eq!(loads.load(Ordering::Relaxed), 4);
+
+		db.close().await;
+		other.close().await;
+	}
+
+	#[tokio::test]
+	async fn read_only_instances_reject_handshakes() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("shaker.db");
+
+		let db = db::Database::open(&path, db::ConnectionSettings::default(), db::PoolSettings::default())
+			.await
+			.unwrap();
+		db.migrate().await.unwrap();
+		db.close().await;
+
+		let settings = db::ConnectionSettings {
+			read_only: true,
+			..db::ConnectionSettings::default()
+		};
+		let db = db::Database::open(&path, settings, db::PoolSettings::default())
+			.await
+			.unwrap();
+		db.ensure_migrated().await.unwrap();
+
+		let state = AppState::new(TokenRegistry::new(&[]), db.clone());
+		let session = Session {
+			scope: Scope::Admin,
+			method: Method::POST,
+			route: "/handshakes".to_owned(),
+			client_ip: None,
+		};
+		let shake = serde_urlencoded::from_str("id=U-a&name=A").unwrap();
+
+		let err = create_handshake(session, State(state), HandshakeForm(shake))
+			.await
+			.unwrap_err();
+		assert_eq!(err.status(), StatusCode::FORBIDDEN);
+		assert_eq!(db.count_handshakes().await.unwrap(), 0);
+		assert_eq!(db.count_users().await.unwrap(), 0);
+
+		db.close().await;
+	}
+
+	#[tokio::test]
+	async fn stats_include_scheduled_backup_times() {
+		let db = db::Database::open_temp().await.unwrap();
+		let dir = tempfile::tempdir().unwrap();
+		let state = AppState::new(TokenRegistry::new(&[]), db.clone());
+		let session = || Session {
+			scope: Scope::Read,
+			method: Method::GET,
+			route: "/stats".to_owned(),
+			client_ip: None,
+		};
+
+		let Json(response) = get_stats(session(), State(state.clone())).await.unwrap();
+		assert_eq!((response.last_backup_at, response.next_backup_at), (None, None));
+
+		let started = OffsetDateTime::now_utc();
+		backup::spawn_scheduled(
+			db,
+			dir.path().to_owned(),
+			Duration::from_millis(50),
+			2,
+			state.backups.clone(),
+		);
+		time::timeout(Duration::from_secs(10), async {
+			while state.backups.times().await.last.is_none() {
+				time::sleep(Duration::from_millis(10)).await;
+			}
+		})
+		.await
+		.unwrap();
+
+		let Json(response) = get_stats(session(), State(state)).await.unwrap();
+		let last = response.last_backup_at.unwrap();
+		assert!(last > started);
+		assert!(response.next_backup_at.unwrap() > started);
+		let json = serde_json::to_value(&response).unwrap();
+		assert!(json["last_backup_at"].is_string(), "{json}");
+		assert!(json["next_backup_at"].is_string(), "{json}");
+	}
+
+	#[tokio::test]
+	async fn servers_run_with_default_options() {
+		let db = db::Database::open_in_memory().await.unwrap();
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		listener.set_nonblocking(true).unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let state = AppState::new(TokenRegistry::new(&[]), db.clone());
+		let server = tokio::spawn(run(ServeOptions::default(), Listener::Inherited(listener), state));
+		db.create_handshake(serde_urlencoded::from_str("id=U-a&name=A").unwrap())
+			.await
+			.unwrap();
+
+		let count = reqwest::get(format!("http://{addr}/users/count"))
+			.await
+			.unwrap()
+			.text()
+			.await
+			.unwrap();
+		assert_eq!(count, "1");
+		server.abort();
+	}
+
+	#[tokio::test]
+	async fn handshakes_are_tagged_with_the_default_event() {
+		let db = db::Database::open_in_memory().await.unwrap();
+		let state = AppState {
+			default_event: Some("MMC".to_owned()),
+			..AppState::new(TokenRegistry::new(&[]), db.clone())
+		};
+		let session = || Session {
+			scope: Scope::Write,
+			method: Method::POST,
+			route: "/handshakes".to_owned(),
+			client_ip: None,
+		};
+
+		for (form, event) in [
+			("id=U-a&name=A", "MMC"),
+			("id=U-a&name=A&event=Meetup", "Meetup"),
+			("id=U-a&name=A&event=%20", "MMC"),
+		] {
+			let shake = serde_urlencoded::from_str(form).unwrap();
+			let Form(created) = create_handshake(session(), State(state.clone()), HandshakeForm(shake))
+				.await
+				.unwrap();
+			assert_eq!(created.created.handshake.event.as_deref(), Some(event), "{form}");
+		}
+		let events = db.count_handshakes_by_event().await.unwrap();
+		let mut counts: Vec<_> = events
+			.iter()
+			.map(|event| (event.event.as_deref(), event.count))
+			.collect();
+		counts.sort_unstable();
+		assert_eq!(counts, [(Some("MMC"), 2), (Some("Meetup"), 1)]);
 	}
 }
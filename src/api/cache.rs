@@ -0,0 +1,90 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use anyhow::Result;
+use tokio::sync::RwLock;
+
+use crate::db;
+
+/// Maximum number of responses kept for a single revision of the data, so that requests with many different filters
+/// can't grow the cache without bound
+const MAX_ENTRIES: usize = 256;
+
+/// Cache of responses from frequently polled endpoints, which are only valid for the revision of the data they were
+/// loaded at. The revision is checked on every request, so writes made by anything (including other processes, such as
+/// imports) are never hidden by the cache.
+#[derive(Debug, Clone, Default)]
+pub struct ReadCache {
+	/// Cached responses, or none if caching is disabled
+	entries: Option<Arc<RwLock<Entries>>>,
+}
+
+/// Responses cached for a revision of the data
+#[derive(Debug, Default)]
+struct Entries {
+	/// Revision of the data that the responses were loaded at
+	revision: i64,
+
+	/// Cached responses
+	responses: HashMap<Key, String>,
+}
+
+/// Identifies a cached response
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+	/// Number of users, optionally only legacy (or non-legacy) ones
+	UserCount { legacy: Option<bool> },
+
+	/// Number of handshakes, optionally only from a source, legacy (or non-legacy) ones, and/or ones tagged with an
+	/// event
+	HandshakeCount {
+		source: Option<String>,
+		legacy: Option<bool>,
+		event: Option<String>,
+	},
+
+	/// Usernames, optionally only of legacy (or non-legacy) users
+	UserNames { legacy: Option<bool> },
+}
+
+impl ReadCache {
+	/// Creates an empty cache, or one that never caches anything if it isn't enabled
+	#[must_use]
+	pub fn new(enabled: bool) -> Self {
+		Self {
+			entries: enabled.then(Default::default),
+		}
+	}
+
+	/// Gets a cached response if it's for the current revision of the data, otherwise loads it and caches it
+	pub async fn get_or_load<F, Fut>(&self, db: &db::Database, key: Key, load: F) -> Result<String>
+	where
+		F: FnOnce() -> Fut,
+		Fut: Future<Output = Result<String>>,
+	{
+		let Some(entries) = &self.entries else {
+			return load().await;
+		};
+
+		// The revision is read before loading, so a response is never older than the revision it's cached for
+		let revision = db.data_revision().await?;
+		{
+			let entries = entries.read().await;
+			if entries.revision == revision {
+				if let Some(response) = entries.responses.get(&key) {
+					return Ok(response.clone());
+				}
+			}
+		}
+
+		let response = load().await?;
+		let mut entries = entries.write().await;
+		if revision > entries.revision {
+			entries.revision = revision;
+			entries.responses.clear();
+		}
+		if revision == entries.revision && entries.responses.len() < MAX_ENTRIES {
+			entries.responses.insert(key, response.clone());
+		}
+		Ok(response)
+	}
+}
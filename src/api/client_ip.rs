@@ -0,0 +1,198 @@
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+};
+
+use axum::{
+	async_trait,
+	extract::{ConnectInfo, FromRequestParts, Request, State},
+	http::{header, request::Parts, HeaderMap, StatusCode},
+	middleware::Next,
+	response::Response,
+};
+use ipnet::IpNet;
+use tracing::debug;
+
+/// Header that proxies append the addresses they received a request from to
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// IP address of the client making a request. If the request came through a trusted reverse proxy, this is the address
+/// the proxy says it came from, and otherwise it's the address of the peer itself. It's unknown for requests made over
+/// a Unix domain socket, so extract an `Option<ClientIp>` wherever those are served.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+	type Rejection = (StatusCode, &'static str);
+
+	async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+		parts
+			.extensions
+			.get::<Self>()
+			.copied()
+			.ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Client IP address is unknown"))
+	}
+}
+
+/// Middleware that determines the IP address of the client for each request, making it available as a [`ClientIp`]
+/// to everything after it
+pub async fn resolve(State(trusted): State<Arc<[IpNet]>>, mut request: Request, next: Next) -> Response {
+	if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+		let ip = client_ip(peer.ip(), request.headers(), &trusted);
+		request.extensions_mut().insert(ClientIp(ip));
+	}
+	next.run(request).await
+}
+
+/// Determines the IP address of the client from the address of the peer and the forwarding headers it sent, which are
+/// only honored if the peer is a trusted proxy. The proxies in the chain are walked back from the nearest one until
+/// reaching an address that isn't trusted, since any earlier hops could have been made up by the client. Headers that
+/// can't be parsed are ignored in favor of the peer's address.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+	if !is_trusted(peer, trusted) {
+		return peer;
+	}
+
+	let hops = if headers.contains_key(X_FORWARDED_FOR) {
+		x_forwarded_for(headers)
+	} else if headers.contains_key(header::FORWARDED) {
+		forwarded(headers)
+	} else {
+		return peer;
+	};
+	let Some(hops) = hops.filter(|hops| !hops.is_empty()) else {
+		debug!("Ignoring malformed forwarding headers from proxy {peer}");
+		return peer;
+	};
+
+	hops.iter()
+		.rev()
+		.find(|&&ip| !is_trusted(ip, trusted))
+		.or_else(|| hops.first())
+		.copied()
+		.unwrap_or(peer)
+}
+
+/// Checks whether an address belongs to a trusted proxy, treating IPv4-mapped IPv6 addresses as the IPv4 ones
+fn is_trusted(ip: IpAddr, trusted: &[IpNet]) -> bool {
+	let ip = ip.to_canonical();
+	trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Parses the hops listed in every `X-Forwarded-For` header, in the order they were added
+fn x_forwarded_for(headers: &HeaderMap) -> Option<Vec<IpAddr>> {
+	let mut hops = Vec::new();
+	for value in headers.get_all(X_FORWARDED_FOR) {
+		for hop in value.to_str().ok()?.split(',') {
+			hops.push(parse_hop(hop)?);
+		}
+	}
+	Some(hops)
+}
+
+/// Parses the `for` parameter of each element in every `Forwarded` header, in the order they were added
+fn forwarded(headers: &HeaderMap) -> Option<Vec<IpAddr>> {
+	let mut hops = Vec::new();
+	for value in headers.get_all(header::FORWARDED) {
+		for element in value.to_str().ok()?.split(',') {
+			let hop = element.split(';').find_map(|pair| {
+				let (name, value) = pair.split_once('=')?;
+				name.trim().eq_ignore_ascii_case("for").then_some(value)
+			})?;
+			hops.push(parse_hop(hop)?);
+		}
+	}
+	Some(hops)
+}
+
+/// Parses a single hop's address, which may be quoted, include a port, or have its IPv6 address in brackets
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+	let hop = hop.trim().trim_matches('"');
+	if let Ok(addr) = hop.parse::<SocketAddr>() {
+		return Some(addr.ip());
+	}
+	hop.strip_prefix('[')
+		.and_then(|hop| hop.strip_suffix(']'))
+		.unwrap_or(hop)
+		.parse()
+		.ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::http::HeaderValue;
+
+	use super::*;
+
+	fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for &(name, value) in pairs {
+			headers.append(
+				header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+				HeaderValue::from_str(value).unwrap(),
+			);
+		}
+		headers
+	}
+
+	#[test]
+	fn forwarding_headers_are_only_honored_from_trusted_proxies() {
+		let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+		let ip = |peer: &str, pairs: &[(&str, &str)]| client_ip(peer.parse().unwrap(), &headers(pairs), &trusted);
+		let addr = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+		// Untrusted peers can't claim to be anyone else
+		assert_eq!(
+			ip("203.0.113.9", &[("x-forwarded-for", "198.51.100.1")]),
+			addr("203.0.113.9")
+		);
+
+		// The rightmost untrusted hop is the client, so anything it prepended is ignored
+		assert_eq!(
+			ip("10.0.0.1", &[("x-forwarded-for", "198.51.100.1")]),
+			addr("198.51.100.1")
+		);
+		assert_eq!(
+			ip("10.0.0.1", &[("x-forwarded-for", "1.2.3.4, 198.51.100.1, 10.0.0.2")]),
+			addr("198.51.100.1")
+		);
+		assert_eq!(
+			ip(
+				"10.0.0.1",
+				&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-for", "198.51.100.1")]
+			),
+			addr("198.51.100.1")
+		);
+		assert_eq!(
+			ip("::ffff:10.0.0.1", &[("x-forwarded-for", "198.51.100.1")]),
+			addr("198.51.100.1")
+		);
+		assert_eq!(
+			ip("10.0.0.1", &[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]),
+			addr("10.0.0.3")
+		);
+
+		assert_eq!(
+			ip(
+				"::1",
+				&[("forwarded", "for=1.2.3.4, for=\"[2001:db8::1]:4711\";proto=https")]
+			),
+			addr("2001:db8::1")
+		);
+		assert_eq!(
+			ip("::1", &[("forwarded", "proto=https;For=198.51.100.1:80")]),
+			addr("198.51.100.1")
+		);
+
+		// Malformed headers fall back to the peer
+		assert_eq!(ip("10.0.0.1", &[]), addr("10.0.0.1"));
+		assert_eq!(
+			ip("10.0.0.1", &[("x-forwarded-for", "1.2.3.4, nonsense")]),
+			addr("10.0.0.1")
+		);
+		assert_eq!(ip("10.0.0.1", &[("x-forwarded-for", "")]), addr("10.0.0.1"));
+		assert_eq!(ip("10.0.0.1", &[("forwarded", "for=unknown")]), addr("10.0.0.1"));
+		assert_eq!(ip("10.0.0.1", &[("forwarded", "proto=https")]), addr("10.0.0.1"));
+	}
+}
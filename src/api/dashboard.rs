@@ -0,0 +1,154 @@
+use std::fmt::Write;
+
+use axum::{extract::State, response::Html};
+use time::OffsetDateTime;
+
+use super::{Error, Session};
+use crate::{
+	auth::Scope,
+	db::{self, HandshakeWithUser, UserHandshakeCount},
+};
+
+/// Number of recent handshakes shown
+const RECENT_HANDSHAKES: i64 = 20;
+
+/// Number of users shown on the leaderboard
+const LEADERBOARD_SIZE: i64 = 10;
+
+/// Seconds between automatic refreshes of the page
+const REFRESH_INTERVAL: u32 = 30;
+
+/// Styles for the page
+const STYLE: &str = "
+	body { font-family: system-ui, sans-serif; margin: 2rem auto; max-width: 60rem; padding: 0 1rem; color: #222; }
+	h1 { margin-bottom: 0.25rem; }
+	.totals { display: flex; gap: 1rem; margin: 1.5rem 0; }
+	.total { flex: 1; padding: 1rem; border-radius: 0.5rem; background: #f2f2f7; }
+	.total strong { display: block; font-size: 2rem; }
+	.tables { display: flex; flex-wrap: wrap; gap: 2rem; }
+	.tables section { flex: 1; min-width: 20rem; }
+	table { width: 100%; border-collapse: collapse; }
+	th, td { text-align: left; padding: 0.35rem 0.5rem; border-bottom: 1px solid #ddd; }
+	.empty { color: #777; font-style: italic; }
+	footer { margin-top: 2rem; color: #777; font-size: 0.85rem; }
+";
+
+/// Renders a self-contained HTML page summarizing the instance
+///
+/// Requires the `read` scope.
+#[utoipa::path(
+	get,
+	path = "/dashboard",
+	tag = "users",
+	responses((status = 200, description = "Dashboard page", body = String, content_type = "text/html"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+pub async fn dashboard(session: Session, State(db): State<db::Database>) -> Result<Html<String>, Error> {
+	session.require(Scope::Read)?;
+
+	let users = db.count_users().await?;
+	let handshakes = db.count_handshakes().await?;
+	let recent = db.get_recent_handshakes(RECENT_HANDSHAKES).await?;
+	let leaders = db.get_top_users(LEADERBOARD_SIZE).await?;
+
+	Ok(Html(format!(
+		r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+	<meta charset="utf-8">
+	<meta name="viewport" content="width=device-width, initial-scale=1">
+	<meta http-equiv="refresh" content="{REFRESH_INTERVAL}">
+	<title>Shaker dashboard</title>
+	<style>{STYLE}</style>
+</head>
+<body>
+	<h1>Shaker</h1>
+	<div class="totals">
+		<div class="total"><strong>{users}</strong> users</div>
+		<div class="total"><strong>{handshakes}</strong> handshakes</div>
+	</div>
+	<div class="tables">
+		<section>
+			<h2>Recent handshakes</h2>
+			{recent}
+		</section>
+		<section>
+			<h2>Leaderboard</h2>
+			{leaders}
+		</section>
+	</div>
+	<footer>Generated {generated}. Refreshes every {REFRESH_INTERVAL} seconds.</footer>
+</body>
+</html>
+"#,
+		recent = recent_table(&recent),
+		leaders = leaderboard_table(&leaders),
+		generated = format_datetime(OffsetDateTime::now_utc()),
+	)))
+}
+
+/// Renders the table of recent handshakes
+fn recent_table(recent: &[HandshakeWithUser]) -> String {
+	if recent.is_empty() {
+		return r#"<p class="empty">No handshakes yet.</p>"#.to_owned();
+	}
+
+	let mut rows = String::new();
+	for shake in recent {
+		let _ = write!(
+			rows,
+			"<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+			escape(&shake.resonite_name),
+			shake.world_name.as_deref().map_or_else(|| "—".to_owned(), escape),
+			format_datetime(shake.created_at),
+		);
+	}
+
+	format!("<table><thead><tr><th>User</th><th>World</th><th>When</th></tr></thead><tbody>{rows}</tbody></table>")
+}
+
+/// Renders the leaderboard table
+fn leaderboard_table(leaders: &[UserHandshakeCount]) -> String {
+	if leaders.is_empty() {
+		return r#"<p class="empty">Nobody has shaken hands yet.</p>"#.to_owned();
+	}
+
+	let mut rows = String::new();
+	for (rank, leader) in leaders.iter().enumerate() {
+		let _ = write!(
+			rows,
+			"<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+			rank + 1,
+			escape(&leader.resonite_name),
+			leader.count,
+		);
+	}
+
+	format!("<table><thead><tr><th>#</th><th>User</th><th>Handshakes</th></tr></thead><tbody>{rows}</tbody></table>")
+}
+
+/// Formats a date/time for display to the minute
+fn format_datetime(datetime: OffsetDateTime) -> String {
+	format!(
+		"{} {:02}:{:02} UTC",
+		datetime.date(),
+		datetime.hour(),
+		datetime.minute()
+	)
+}
+
+/// Escapes text for safe inclusion in HTML
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for ch in text.chars() {
+		match ch {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			_ => escaped.push(ch),
+		}
+	}
+	escaped
+}
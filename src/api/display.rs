@@ -0,0 +1,72 @@
+use axum::extract::{Path, State};
+use time::OffsetDateTime;
+use time_tz::OffsetDateTimeExt;
+
+use super::{AppState, Error, Session};
+use crate::{auth::Scope, db};
+
+/// Names of the stats that can be displayed
+const STATS: &[&str] = &["users", "handshakes", "latest_name", "latest_world", "today"];
+
+/// Returns a single stat as a bare plain-text value, for displaying in-world without any parsing
+///
+/// Requires the `read` scope. Values never contain quotes or line breaks. The latest name and world are empty if there
+/// are no handshakes (or the latest has no world), and `today` counts handshakes since midnight in the server's
+/// configured timezone.
+#[utoipa::path(
+	get,
+	path = "/display/{stat}",
+	tag = "handshakes",
+	params(("stat" = String, Path, description = "One of `users`, `handshakes`, `latest_name`, `latest_world`, or `today`")),
+	responses(
+		(status = 200, description = "Value of the stat", body = String, content_type = "text/plain"),
+		(status = 404, description = "Unknown stat", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+pub async fn display_stat(
+	session: Session,
+	State(state): State<AppState>,
+	Path(stat): Path<String>,
+) -> Result<String, Error> {
+	session.require(Scope::Read)?;
+	let db = &state.db;
+
+	let value = match stat.as_str() {
+		"users" => db.count_users().await?.to_string(),
+		"handshakes" => db.count_handshakes().await?.to_string(),
+		"latest_name" => latest(db).await?.map(|shake| shake.resonite_name).unwrap_or_default(),
+		"latest_world" => latest(db).await?.and_then(|shake| shake.world_name).unwrap_or_default(),
+		"today" => db
+			.count_handshakes_on(
+				OffsetDateTime::now_utc().to_timezone(state.timezone).date(),
+				state.timezone,
+			)
+			.await?
+			.to_string(),
+		_ => {
+			return Err(Error::NotFound(format!(
+				"unknown stat \"{stat}\" (expected one of: {})",
+				STATS.join(", ")
+			)))
+		}
+	};
+
+	Ok(sanitize(&value))
+}
+
+/// Retrieves the most recent handshake, if there is one
+async fn latest(db: &db::Database) -> anyhow::Result<Option<db::HandshakeWithUser>> {
+	Ok(db.get_recent_handshakes(1).await?.into_iter().next())
+}
+
+/// Strips characters that would break a bare in-world text value, replacing line breaks with spaces
+fn sanitize(value: &str) -> String {
+	value
+		.chars()
+		.filter(|ch| *ch != '"')
+		.map(|ch| if ch.is_control() { ' ' } else { ch })
+		.collect::<String>()
+		.trim()
+		.to_owned()
+}
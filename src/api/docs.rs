@@ -0,0 +1,265 @@
+// The OpenApi derive expands to code that trips this lint
+#![allow(clippy::needless_for_each)]
+
+use axum::{
+	response::{Html, IntoResponse},
+	routing::get,
+	Json, Router,
+};
+use utoipa::{
+	openapi::{
+		security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme},
+		Content, OpenApi as Spec, Ref, Response, ResponseBuilder,
+	},
+	OpenApi,
+};
+
+use super::{
+	dashboard, display, export, live, DailyCounts, DailyDays, ErrorBody, HandshakeCreated, HandshakePage,
+	HandshakeUser, Heatmap, RotateTokenForm, StatsResponse, UndoneHandshake, UserList, UserPatch,
+};
+use crate::{
+	backup::Backup,
+	db::{
+		Anniversary, AuditEntry, Ban, CreatedHandshake, DayBreakdown, DayCount, EventSummary, ForeignKeyViolation,
+		Handshake, HandshakeContext, HandshakeRate, HandshakeWithUser, HeatmapCell, IntegrityReport, LimitViolation,
+		MinuteCount, NameChange, NameCollision, NewBan, OutboxEntry, SourceCount, Stats, User, UserHandshakeCount,
+		UserStats, UserWithCount, WorldStats,
+	},
+};
+
+/// Name of the security scheme for tokens given in the Authorization header
+const BEARER_SCHEME: &str = "bearer";
+
+/// Name of the security scheme for tokens given in the query string
+const QUERY_SCHEME: &str = "query";
+
+/// Responses that any authenticated endpoint may return, all with an error body
+const ERROR_RESPONSES: &[(&str, &str)] = &[
+	(
+		"400",
+		"Malformed request, missing token, or mismatched header and query tokens",
+	),
+	("401", "Invalid token"),
+	("403", "Token lacks the scope required by the endpoint"),
+	(
+		"422",
+		"Submitted value failed validation; the error body names the field",
+	),
+	("500", "Internal error"),
+];
+
+/// Static portion of the API specification, generated from the annotated handlers and types
+#[derive(OpenApi)]
+#[openapi(
+	info(
+		title = "Shaker",
+		description = "API for recording and querying handshakes. Each endpoint requires a token with at least the scope \
+			noted in its description, unless the server has no tokens configured."
+	),
+	paths(
+		super::list_users,
+		super::count_users,
+		super::list_user_names,
+		super::search_users,
+		super::list_inactive_users,
+		super::list_unmatched_users,
+		super::count_unmatched_users,
+		super::list_anniversaries,
+		super::list_user_name_history,
+		super::get_user_stats,
+		super::get_user_stats_by_resonite_id,
+		super::has_shaken_hands,
+		super::undo_handshake,
+		super::undo_handshake_by_resonite_id,
+		super::anonymize_user,
+		super::delete_user,
+		super::update_user,
+		super::set_user_note,
+		super::list_handshakes,
+		super::create_handshake,
+		super::delete_handshake,
+		super::get_latest_handshake,
+		super::count_handshakes,
+		super::count_handshakes_for_user,
+		live::stream_handshakes,
+		super::count_handshakes_per_day,
+		super::get_activity_heatmap,
+		super::get_handshake_rate,
+		super::get_world_stats,
+		super::list_sources,
+		super::list_events,
+		super::get_stats,
+		live::websocket,
+		dashboard::dashboard,
+		display::display_stat,
+		super::rotate_token,
+		super::list_audit_entries,
+		super::list_duplicate_names,
+		super::list_limit_violations,
+		super::create_backup,
+		super::check_integrity,
+		super::refresh_names,
+		export::export,
+		export::export_handshakes_csv,
+		export::export_users_csv,
+		super::list_deleted_users,
+		super::list_deleted_handshakes,
+		super::restore_user,
+		super::restore_handshake,
+		super::list_webhook_outbox,
+		super::retry_webhook_delivery,
+		super::list_bans,
+		super::ban_user,
+		super::unban_user,
+		super::unban_user_name,
+	),
+	components(schemas(
+		User,
+		UserWithCount,
+		UserStats,
+		Handshake,
+		HandshakeContext,
+		HandshakeWithUser,
+		CreatedHandshake,
+		NameChange,
+		NameCollision,
+		LimitViolation,
+		AuditEntry,
+		OutboxEntry,
+		Ban,
+		NewBan,
+		SourceCount,
+		EventSummary,
+		WorldStats,
+		DayCount,
+		DayBreakdown,
+		Anniversary,
+		DailyCounts,
+		DailyDays,
+		HeatmapCell,
+		HandshakeRate,
+		MinuteCount,
+		Heatmap,
+		Stats,
+		UserHandshakeCount,
+		StatsResponse,
+		UndoneHandshake,
+		Backup,
+		IntegrityReport,
+		ForeignKeyViolation,
+		UserList,
+		HandshakePage,
+		HandshakeCreated,
+		HandshakeUser,
+		RotateTokenForm,
+		UserPatch,
+		ErrorBody,
+	)),
+	tags(
+		(name = "users", description = "Users that have shaken hands"),
+		(name = "handshakes", description = "Handshakes and live feeds of new ones"),
+		(name = "admin", description = "Administrative endpoints"),
+	)
+)]
+struct ApiDoc;
+
+/// Builds the API specification, describing authentication the way the server is actually configured
+#[must_use]
+pub fn spec(auth_required: bool, query_token: bool) -> Spec {
+	let mut spec = ApiDoc::openapi();
+	spec.info.license = None;
+
+	// Describe each accepted way of providing the token; any one of them is sufficient
+	let components = spec.components.get_or_insert_with(Default::default);
+	components.add_security_scheme(
+		BEARER_SCHEME,
+		SecurityScheme::Http(
+			HttpBuilder::new()
+				.scheme(HttpAuthScheme::Bearer)
+				.description(Some("Token in the `Authorization: Bearer <token>` header"))
+				.build(),
+		),
+	);
+	let mut security = vec![SecurityRequirement::new(BEARER_SCHEME, Vec::<String>::new())];
+
+	if query_token {
+		components.add_security_scheme(
+			QUERY_SCHEME,
+			SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::with_description(
+				"token",
+				"Token in the `token` query parameter. If it's also given in the header, both must match.",
+			))),
+		);
+		security.push(SecurityRequirement::new(QUERY_SCHEME, Vec::<String>::new()));
+	}
+
+	// An empty requirement marks authentication as optional
+	if !auth_required {
+		security.push(SecurityRequirement::default());
+	}
+	spec.security = Some(security);
+
+	// Every operation shares the same error responses
+	for item in spec.paths.paths.values_mut() {
+		for operation in item.operations.values_mut() {
+			for (status, description) in ERROR_RESPONSES {
+				operation
+					.responses
+					.responses
+					.entry((*status).to_owned())
+					.or_insert_with(|| error_response(description).into());
+			}
+		}
+	}
+
+	spec
+}
+
+/// Builds a response with an error body
+fn error_response(description: &str) -> Response {
+	ResponseBuilder::new()
+		.description(description)
+		.content("application/json", Content::new(Ref::from_schema_name("ErrorBody")))
+		.build()
+}
+
+/// Builds a router serving the API specification, along with a Swagger UI page for it if enabled
+pub fn router<S: Clone + Send + Sync + 'static>(spec: Spec, swagger_ui: bool) -> Router<S> {
+	let router = Router::new().route(
+		"/openapi.json",
+		get(move || {
+			let spec = spec.clone();
+			async move { Json(spec) }
+		}),
+	);
+	if swagger_ui {
+		router.route("/docs", get(swagger_ui_page))
+	} else {
+		router
+	}
+}
+
+/// Serves a Swagger UI page for the API specification. The UI's assets are loaded from a CDN to avoid bundling them.
+async fn swagger_ui_page() -> impl IntoResponse {
+	Html(
+		r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+	<meta charset="utf-8">
+	<title>Shaker API</title>
+	<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+	<div id="swagger-ui"></div>
+	<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+	<script>
+		window.onload = () => {
+			window.ui = SwaggerUIBundle({ url: "openapi.json", dom_id: "#swagger-ui" });
+		};
+	</script>
+</body>
+</html>
+"##,
+	)
+}
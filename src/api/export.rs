@@ -0,0 +1,284 @@
+use std::{future::Future, io};
+
+use axum::{
+	body::{Body, Bytes},
+	extract::{Query, State},
+	http::header,
+	response::{IntoResponse, Response},
+};
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use super::{Error, Session};
+use crate::{
+	auth::Scope,
+	db::{self, TimeRange},
+	export::{
+		exported_user, write_csv_row, write_handshake_csv, write_ndjson_line, write_user_csv, write_user_csv_header,
+		Line, FORMAT_VERSION, HANDSHAKE_CSV_COLUMNS,
+	},
+};
+
+/// Number of chunks that may be waiting to be sent to a client before fetching more rows pauses
+const CHANNEL_CAPACITY: usize = 8;
+
+/// Size (in bytes) that lines are buffered up to before being sent to the client as a chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Content type of CSV files
+const CSV_TYPE: &str = "text/csv; charset=utf-8";
+
+/// Query parameters for exporting data
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+	/// Format to export the data in
+	#[serde(default)]
+	#[param(inline)]
+	format: ExportFormat,
+
+	/// Whether to include the notes that admins have left on users
+	#[serde(default)]
+	include_notes: bool,
+}
+
+/// Query parameters for exporting users as CSV
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserCsvQuery {
+	/// Whether to include the notes that admins have left on users, as a `note` column at the end
+	#[serde(default)]
+	include_notes: bool,
+}
+
+/// Format that data can be exported in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+	/// Newline-delimited JSON: a header line, then a line for each user, then a line for each handshake
+	#[default]
+	Ndjson,
+}
+
+/// Streams all users and handshakes as a downloadable file, reading them from the database as the client receives them
+///
+/// Requires the `admin` scope. In the NDJSON format, the first line is a `header` describing the export, followed by a
+/// `user` line for every user and then a `handshake` line for every handshake. Deleted records aren't included, and
+/// neither are the notes admins have left on users unless `include_notes` is set.
+#[utoipa::path(
+	get,
+	path = "/export",
+	tag = "admin",
+	params(ExportQuery),
+	responses((
+		status = 200,
+		description = "Newline-delimited JSON of every user and handshake",
+		content_type = "application/x-ndjson"
+	))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+pub async fn export(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(query): Query<ExportQuery>,
+) -> Result<Response, Error> {
+	session.require(Scope::Admin)?;
+
+	let exported_at = OffsetDateTime::now_utc();
+	let header = Line::Header {
+		format_version: FORMAT_VERSION,
+		schema_version: db.schema_version().await?,
+		exported_at,
+	};
+
+	Ok(match query.format {
+		ExportFormat::Ndjson => streamed(
+			"application/x-ndjson",
+			"export",
+			"ndjson",
+			exported_at,
+			|mut out| async move {
+				write_ndjson_line(out.buf(), &header)?;
+
+				let mut users = db.stream_all_users();
+				while let Some(user) = users.try_next().await? {
+					write_ndjson_line(out.buf(), &Line::User(&exported_user(user, query.include_notes)))?;
+					out.flush_if_full().await?;
+				}
+				drop(users);
+
+				let mut handshakes = db.stream_all_handshakes();
+				while let Some(shake) = handshakes.try_next().await? {
+					write_ndjson_line(out.buf(), &Line::Handshake(&shake))?;
+					out.flush_if_full().await?;
+				}
+
+				out.finish().await
+			},
+		),
+	})
+}
+
+/// Streams handshakes along with the IDs and names of the users that performed them as a downloadable CSV file
+///
+/// Requires the `admin` scope. The columns are `handshake_id`, `resonite_id`, `resonite_name`, `world_name`, and
+/// `created_at` (in RFC 3339 format), preceded by a header row.
+#[utoipa::path(
+	get,
+	path = "/export/handshakes.csv",
+	tag = "admin",
+	params(TimeRange),
+	responses((status = 200, description = "CSV of handshakes", content_type = "text/csv"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+pub async fn export_handshakes_csv(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(range): Query<TimeRange>,
+) -> Result<Response, Error> {
+	session.require(Scope::Admin)?;
+
+	Ok(streamed(
+		CSV_TYPE,
+		"handshakes",
+		"csv",
+		OffsetDateTime::now_utc(),
+		|mut out| async move {
+			write_csv_row(out.buf(), HANDSHAKE_CSV_COLUMNS);
+
+			let mut handshakes = db.stream_handshakes_with_users_in(&range);
+			while let Some(shake) = handshakes.try_next().await? {
+				write_handshake_csv(out.buf(), &shake)?;
+				out.flush_if_full().await?;
+			}
+			drop(handshakes);
+
+			out.finish().await
+		},
+	))
+}
+
+/// Streams users as a downloadable CSV file
+///
+/// Requires the `admin` scope. The columns are `user_id`, `resonite_id`, `resonite_name`, `legacy`, `created_at`, and
+/// `last_seen_at` (both in RFC 3339 format), preceded by a header row. The time range applies to when users were
+/// created. With `include_notes` set, a `note` column is added at the end with the notes admins have left on users.
+#[utoipa::path(
+	get,
+	path = "/export/users.csv",
+	tag = "admin",
+	params(TimeRange, UserCsvQuery),
+	responses((status = 200, description = "CSV of users", content_type = "text/csv"))
+)]
+#[tracing::instrument(level = "debug", skip(session, db))]
+pub async fn export_users_csv(
+	session: Session,
+	State(db): State<db::Database>,
+	Query(range): Query<TimeRange>,
+	Query(query): Query<UserCsvQuery>,
+) -> Result<Response, Error> {
+	session.require(Scope::Admin)?;
+
+	Ok(streamed(
+		CSV_TYPE,
+		"users",
+		"csv",
+		OffsetDateTime::now_utc(),
+		|mut out| async move {
+			write_user_csv_header(out.buf(), query.include_notes);
+
+			let mut users = db.stream_users_created_in(&range);
+			while let Some(user) = users.try_next().await? {
+				write_user_csv(out.buf(), &user, query.include_notes)?;
+				out.flush_if_full().await?;
+			}
+			drop(users);
+
+			out.finish().await
+		},
+	))
+}
+
+/// Builds a response for a downloadable file named after what it contains and the time it was made, with a body that's
+/// written by a separate task as the client receives it. The task waits whenever the client falls behind, so only a few
+/// chunks of the file are ever in memory.
+fn streamed<F, Fut>(content_type: &'static str, name: &str, extension: &str, time: OffsetDateTime, write: F) -> Response
+where
+	F: FnOnce(Chunks) -> Fut,
+	Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+	let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+	let task = write(Chunks {
+		buf: Vec::with_capacity(CHUNK_SIZE),
+		sender: sender.clone(),
+	});
+	tokio::spawn(async move {
+		if let Err(err) = task.await {
+			if sender.is_closed() {
+				debug!("Client went away during export: {err}");
+			} else {
+				warn!("Export failed partway through: {err:#}");
+				let _ = sender.send(Err(io::Error::other(err.to_string()))).await;
+			}
+		}
+	});
+
+	let filename = format!(
+		"shaker-{name}-{:04}{:02}{:02}-{:02}{:02}{:02}.{extension}",
+		time.year(),
+		u8::from(time.month()),
+		time.day(),
+		time.hour(),
+		time.minute(),
+		time.second()
+	);
+	(
+		[
+			(header::CONTENT_TYPE, content_type.to_owned()),
+			(
+				header::CONTENT_DISPOSITION,
+				format!("attachment; filename=\"{filename}\""),
+			),
+		],
+		Body::from_stream(ReceiverStream::new(receiver)),
+	)
+		.into_response()
+}
+
+/// Buffer for the body of a streamed file that's sent to the client a chunk at a time
+struct Chunks {
+	/// Data that hasn't been sent yet
+	buf: Vec<u8>,
+
+	/// Channel to send chunks to the client through
+	sender: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl Chunks {
+	/// Gets the buffer to write data into
+	fn buf(&mut self) -> &mut Vec<u8> {
+		&mut self.buf
+	}
+
+	/// Sends the buffered data to the client once it has reached the chunk size
+	async fn flush_if_full(&mut self) -> anyhow::Result<()> {
+		if self.buf.len() >= CHUNK_SIZE {
+			let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+			self.sender.send(Ok(chunk.into())).await?;
+		}
+		Ok(())
+	}
+
+	/// Sends any remaining buffered data to the client, ending the file
+	async fn finish(self) -> anyhow::Result<()> {
+		if !self.buf.is_empty() {
+			self.sender.send(Ok(self.buf.into())).await?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,108 @@
+use std::{
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+use axum::{
+	extract::{Request, State},
+	middleware::Next,
+	response::Response,
+};
+use tokio::{sync::watch, time};
+use tracing::info;
+
+use super::shutdown_requested;
+use crate::db;
+
+/// Counters of activity since the server started, kept in memory so that heartbeats don't need to query the database
+/// for them
+#[derive(Debug, Default)]
+pub struct Activity {
+	/// Number of requests handled
+	requests: AtomicU64,
+
+	/// Number of handshakes created
+	handshakes: AtomicU64,
+
+	/// Number of users created by their first handshake
+	new_users: AtomicU64,
+}
+
+/// Values of the activity counters at a moment
+#[derive(Debug, Clone, Copy)]
+struct Counts {
+	/// Number of requests handled
+	requests: u64,
+
+	/// Number of handshakes created
+	handshakes: u64,
+
+	/// Number of users created by their first handshake
+	new_users: u64,
+}
+
+impl Activity {
+	/// Counts a created handshake, and its user if they were created by it
+	pub fn handshake_created(&self, first_time: bool) {
+		self.handshakes.fetch_add(1, Ordering::Relaxed);
+		if first_time {
+			self.new_users.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	/// Reads the current values of the counters
+	fn counts(&self) -> Counts {
+		Counts {
+			requests: self.requests.load(Ordering::Relaxed),
+			handshakes: self.handshakes.load(Ordering::Relaxed),
+			new_users: self.new_users.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// Middleware that counts handled requests
+pub async fn count_request(State(activity): State<Arc<Activity>>, request: Request, next: Next) -> Response {
+	activity.requests.fetch_add(1, Ordering::Relaxed);
+	next.run(request).await
+}
+
+/// Spawns the task that logs a heartbeat every interval with the activity since the previous one, until a shutdown is
+/// requested
+pub fn spawn(activity: Arc<Activity>, db: db::Database, interval: Duration, shutdown: watch::Receiver<bool>) {
+	info!("Logging a heartbeat every {}s", interval.as_secs());
+	tokio::spawn(async move {
+		tokio::select! {
+			() = run(&activity, &db, interval) => {}
+			() = shutdown_requested(shutdown) => {}
+		}
+	});
+}
+
+/// Logs a heartbeat every interval, forever
+async fn run(activity: &Activity, db: &db::Database, interval: Duration) {
+	let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+	ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+	let mut previous = (Instant::now(), activity.counts());
+	loop {
+		ticker.tick().await;
+		let now = (Instant::now(), activity.counts());
+		let elapsed = now.0.duration_since(previous.0).as_secs_f64();
+		let pool = db.pool_status();
+
+		#[allow(clippy::cast_precision_loss)]
+		let request_rate = (now.1.requests - previous.1.requests) as f64 / elapsed;
+		info!(
+			"Heartbeat: {} handshake(s) and {} new user(s) in the last {elapsed:.0}s, {request_rate:.2} request(s)/s, \
+			 {} database connection(s) ({} idle)",
+			now.1.handshakes - previous.1.handshakes,
+			now.1.new_users - previous.1.new_users,
+			pool.size,
+			pool.idle,
+		);
+		previous = now;
+	}
+}
@@ -0,0 +1,122 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+	extract::{Request, State},
+	http::{header, HeaderValue},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use tokio::{sync::Semaphore, time};
+use tracing::{info, warn};
+
+use super::Error;
+use crate::Config;
+
+/// Seconds that clients turned away by the concurrency limit are told to wait before retrying
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Creates the request timeout and the semaphore for the concurrency limit as configured
+pub fn from_config(cfg: &Config) -> (Duration, Arc<Semaphore>) {
+	info!(
+		"Handling up to {} request(s) at once, each for up to {}s",
+		cfg.max_concurrent_requests, cfg.request_timeout
+	);
+	(
+		Duration::from_secs(cfg.request_timeout),
+		Arc::new(Semaphore::new(cfg.max_concurrent_requests as usize)),
+	)
+}
+
+/// Middleware that aborts handling a request once it's taken longer than the timeout, responding with a 504 instead
+pub async fn timeout(State(timeout): State<Duration>, request: Request, next: Next) -> Response {
+	if let Ok(response) = time::timeout(timeout, next.run(request)).await {
+		return response;
+	}
+
+	warn!("Request timed out after {}s", timeout.as_secs());
+	Error::Timeout(format!("request took longer than {}s to handle", timeout.as_secs())).into_response()
+}
+
+/// Middleware that turns requests away with a 503 while the maximum number of them are already being handled
+pub async fn limit_concurrency(State(limit): State<Arc<Semaphore>>, request: Request, next: Next) -> Response {
+	let Ok(_permit) = limit.try_acquire() else {
+		warn!("Turning away request; too many requests are being handled");
+		let mut response =
+			Error::Unavailable("too many requests are being handled; try again shortly".to_owned()).into_response();
+		response
+			.headers_mut()
+			.insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+		return response;
+	};
+
+	next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+	use tower::ServiceExt;
+
+	use super::*;
+
+	/// Creates a router with a deliberately slow route, limited by the given timeout and concurrency limit
+	fn app(timeout: Duration, limit: &Arc<Semaphore>) -> Router {
+		Router::new()
+			.route(
+				"/slow",
+				get(|| async {
+					time::sleep(Duration::from_millis(200)).await;
+					"done"
+				}),
+			)
+			.layer(middleware::from_fn_with_state(timeout, super::timeout))
+			.layer(middleware::from_fn_with_state(Arc::clone(limit), limit_concurrency))
+	}
+
+	fn request() -> Request {
+		Request::builder().uri("/slow").body(Body::empty()).unwrap()
+	}
+
+	#[tokio::test]
+	async fn slow_requests_time_out() {
+		let limit = Arc::new(Semaphore::new(8));
+		let response = app(Duration::from_millis(50), &limit).oneshot(request()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+		let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+		let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+		assert_eq!(body["code"], "timeout");
+
+		let response = app(Duration::from_secs(5), &limit).oneshot(request()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn excess_requests_are_shed() {
+		let limit = Arc::new(Semaphore::new(2));
+		let app = app(Duration::from_secs(5), &limit);
+
+		let responses = futures_util::future::join_all((0..3).map(|_| app.clone().oneshot(request()))).await;
+		let mut statuses: Vec<_> = responses
+			.iter()
+			.map(|response| response.as_ref().unwrap().status())
+			.collect();
+		statuses.sort_unstable();
+		assert_eq!(
+			statuses,
+			[StatusCode::OK, StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]
+		);
+
+		let shed = responses
+			.into_iter()
+			.map(Result::unwrap)
+			.find(|response| response.status() == StatusCode::SERVICE_UNAVAILABLE)
+			.unwrap();
+		assert_eq!(shed.headers()[header::RETRY_AFTER], "1");
+
+		// Permits are released once requests finish
+		assert_eq!(limit.available_permits(), 2);
+		let response = app.oneshot(request()).await.unwrap();
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+}
@@ -0,0 +1,211 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use axum::{
+	extract::{
+		ws::{Message, WebSocket, WebSocketUpgrade},
+		State,
+	},
+	response::{
+		sse::{Event, KeepAlive, Sse},
+		Response,
+	},
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+	sync::{broadcast::error::RecvError, OwnedSemaphorePermit},
+	time::{self, Instant},
+};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{debug, warn};
+
+use super::{shutdown_requested, AppState, Error, Session};
+use crate::{
+	auth::Scope,
+	db::{self, HandshakeWithUser},
+};
+
+/// Number of new handshakes that may be buffered for each live subscriber before the slowest start missing events
+pub const CHANNEL_CAPACITY: usize = 64;
+
+/// Interval between keep-alive comments on event streams
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Interval between pings sent to WebSocket clients
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Streams newly created handshakes as server-sent events, ending the stream when the server shuts down
+///
+/// Requires the `read` scope. Each new handshake is sent as a `handshake` event with JSON data.
+#[utoipa::path(
+	get,
+	path = "/handshakes/stream",
+	tag = "handshakes",
+	responses((
+		status = 200,
+		description = "Stream of handshake events",
+		body = HandshakeWithUser,
+		content_type = "text/event-stream"
+	))
+)]
+#[tracing::instrument(level = "debug", skip(session, state))]
+pub async fn stream_handshakes(
+	session: Session,
+	State(state): State<AppState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+	session.require(Scope::Read)?;
+
+	let stream = BroadcastStream::new(state.handshakes.subscribe())
+		.filter_map(|received| async move {
+			match received {
+				Ok(shake) => handshake_event(&shake),
+				Err(BroadcastStreamRecvError::Lagged(missed)) => {
+					debug!("Handshake stream subscriber lagged behind; dropped {missed} event(s)");
+					None
+				}
+			}
+		})
+		.map(Ok)
+		.take_until(shutdown_requested(state.shutdown.clone()));
+
+	Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(KEEP_ALIVE_INTERVAL)))
+}
+
+/// Builds a server-sent event for a new handshake
+fn handshake_event(shake: &HandshakeWithUser) -> Option<Event> {
+	match Event::default().event("handshake").json_data(shake) {
+		Ok(event) => Some(event),
+		Err(err) => {
+			warn!("Unable to serialize handshake {} for the live stream: {err}", shake.id);
+			None
+		}
+	}
+}
+
+/// Upgrades the connection to a WebSocket that pushes newly created handshakes and responds to client commands
+///
+/// Requires the `read` scope. The server sends JSON messages tagged by `type` (`handshake`, `counts`, or `error`), and
+/// accepts JSON commands tagged by `cmd` (`counts`).
+#[utoipa::path(
+	get,
+	path = "/ws",
+	tag = "handshakes",
+	responses(
+		(status = 101, description = "Switching to the WebSocket protocol"),
+		(status = 503, description = "Too many open WebSocket connections", body = ErrorBody),
+	)
+)]
+#[tracing::instrument(level = "debug", skip(session, state, upgrade))]
+pub async fn websocket(
+	session: Session,
+	State(state): State<AppState>,
+	upgrade: WebSocketUpgrade,
+) -> Result<Response, Error> {
+	session.require(Scope::Read)?;
+
+	let permit = Arc::clone(&state.websockets)
+		.try_acquire_owned()
+		.map_err(|_| Error::Unavailable("too many open WebSocket connections".to_owned()))?;
+
+	Ok(upgrade.on_upgrade(move |socket| handle_websocket(socket, state, permit)))
+}
+
+/// Runs a WebSocket connection until the client disconnects, goes idle, or the server shuts down. The permit is held
+/// for the duration so that it counts against the connection limit.
+async fn handle_websocket(mut socket: WebSocket, state: AppState, _permit: OwnedSemaphorePermit) {
+	let mut handshakes = state.handshakes.subscribe();
+	let mut ping = time::interval(PING_INTERVAL);
+	let mut last_seen = Instant::now();
+
+	loop {
+		let outgoing = tokio::select! {
+			received = socket.recv() => match received {
+				Some(Ok(Message::Text(text))) => {
+					last_seen = Instant::now();
+					respond_to_command(&state.db, &text).await
+				}
+				Some(Ok(Message::Close(_))) | None => break,
+				Some(Ok(_)) => {
+					last_seen = Instant::now();
+					continue;
+				}
+				Some(Err(err)) => {
+					debug!("WebSocket error: {err}");
+					break;
+				}
+			},
+
+			shake = handshakes.recv() => match shake {
+				Ok(handshake) => ServerMessage::Handshake { handshake },
+				Err(RecvError::Lagged(missed)) => {
+					debug!("WebSocket subscriber lagged behind; dropped {missed} handshake(s)");
+					continue;
+				}
+				Err(RecvError::Closed) => break,
+			},
+
+			_ = ping.tick() => {
+				if last_seen.elapsed() > state.websocket_idle_timeout {
+					debug!("Closing idle WebSocket connection");
+					break;
+				}
+				if socket.send(Message::Ping(Vec::new())).await.is_err() {
+					break;
+				}
+				continue;
+			},
+
+			() = shutdown_requested(state.shutdown.clone()) => break,
+		};
+
+		let text = match serde_json::to_string(&outgoing) {
+			Ok(text) => text,
+			Err(err) => {
+				warn!("Unable to serialize WebSocket message: {err}");
+				continue;
+			}
+		};
+		if socket.send(Message::Text(text)).await.is_err() {
+			break;
+		}
+	}
+
+	let _ = socket.send(Message::Close(None)).await;
+}
+
+/// Handles a command sent by a WebSocket client, producing the message to respond with
+async fn respond_to_command(db: &db::Database, text: &str) -> ServerMessage {
+	match serde_json::from_str(text) {
+		Ok(Command::Counts) => match tokio::try_join!(db.count_users(), db.count_handshakes()) {
+			Ok((users, handshakes)) => ServerMessage::Counts { users, handshakes },
+			Err(err) => ServerMessage::Error {
+				error: format!("unable to retrieve counts: {err}"),
+			},
+		},
+		Err(err) => ServerMessage::Error {
+			error: format!("invalid command: {err}"),
+		},
+	}
+}
+
+/// Command sent by a WebSocket client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Command {
+	/// Requests the current user and handshake counts
+	Counts,
+}
+
+/// Message sent to a WebSocket client
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+	/// A new handshake has been created
+	Handshake { handshake: HandshakeWithUser },
+
+	/// Current user and handshake counts, in response to a `counts` command
+	Counts { users: i64, handshakes: i64 },
+
+	/// A command couldn't be handled
+	Error { error: String },
+}
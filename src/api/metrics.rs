@@ -0,0 +1,81 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use axum::{
+	extract::{MatchedPath, Request, State},
+	middleware::Next,
+	response::Response,
+	routing::get,
+	Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use super::Error;
+use crate::db;
+
+/// Name of the counter of handled HTTP requests
+const REQUESTS_TOTAL: &str = "shaker_http_requests_total";
+
+/// Name of the histogram of HTTP request latencies
+const REQUEST_DURATION: &str = "shaker_http_request_duration_seconds";
+
+/// Buckets (in seconds) for the request latency histogram
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// State for the metrics endpoint
+#[derive(Clone)]
+pub struct MetricsState {
+	/// Handle for rendering the recorded metrics
+	handle: PrometheusHandle,
+
+	/// Database to refresh the record count gauges from
+	db: db::Database,
+}
+
+/// Installs the global Prometheus metrics recorder
+pub fn install(db: db::Database) -> Result<MetricsState> {
+	let handle = PrometheusBuilder::new()
+		.set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.to_owned()), DURATION_BUCKETS)?
+		.install_recorder()?;
+	Ok(MetricsState { handle, db })
+}
+
+/// Builds a router serving the metrics endpoint, which never requires a token
+pub fn router(state: MetricsState) -> Router {
+	Router::new().route("/metrics", get(render)).with_state(state)
+}
+
+/// Middleware that records the count and latency of requests by route and status
+pub async fn track(request: Request, next: Next) -> Response {
+	let start = Instant::now();
+	let method = request.method().to_string();
+	let route = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+
+	let response = next.run(request).await;
+
+	let status = response.status().as_u16().to_string();
+	metrics::counter!(REQUESTS_TOTAL, "method" => method.clone(), "route" => route.clone(), "status" => status)
+		.increment(1);
+	metrics::histogram!(REQUEST_DURATION, "method" => method, "route" => route).record(start.elapsed());
+
+	response
+}
+
+/// Refreshes the record count and connection pool gauges and renders all metrics in the Prometheus text format
+#[tracing::instrument(level = "debug", skip(state))]
+async fn render(State(state): State<MetricsState>) -> Result<String, Error> {
+	#[allow(clippy::cast_precision_loss)]
+	{
+		metrics::gauge!("shaker_users_total").set(state.db.count_users().await? as f64);
+		metrics::gauge!("shaker_handshakes_total").set(state.db.count_handshakes().await? as f64);
+
+		let pool = state.db.pool_status();
+		metrics::gauge!("shaker_db_connections").set(f64::from(pool.size));
+		metrics::gauge!("shaker_db_idle_connections").set(pool.idle as f64);
+	}
+
+	Ok(state.handle.render())
+}
@@ -0,0 +1,69 @@
+use std::time::Instant;
+
+use axum::{
+	extract::{MatchedPath, Request},
+	http::HeaderValue,
+	middleware::Next,
+	response::Response,
+};
+use tracing::{info, info_span, Instrument};
+use uuid::Uuid;
+
+use super::client_ip::ClientIp;
+
+/// Header used to pass request IDs in and out
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Maximum length of an incoming request ID that will be honored
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+	/// ID of the request currently being handled
+	static REQUEST_ID: String;
+}
+
+/// Gets the ID of the request currently being handled, if called from within one
+pub fn current_request_id() -> Option<String> {
+	REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Middleware that assigns each request an ID (honoring an incoming `X-Request-Id` header), runs it inside a span
+/// tagged with that ID (and the client's IP address), logs its status and latency on completion, and returns the ID in the response headers
+pub async fn trace_request(request: Request, next: Next) -> Response {
+	let start = Instant::now();
+	let id = request
+		.headers()
+		.get(REQUEST_ID_HEADER)
+		.and_then(|value| value.to_str().ok())
+		.filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.chars().all(|c| c.is_ascii_graphic()))
+		.map_or_else(|| Uuid::new_v4().to_string(), str::to_owned);
+
+	let method = request.method().clone();
+	let path = request
+		.extensions()
+		.get::<MatchedPath>()
+		.map_or_else(|| request.uri().path().to_owned(), |path| path.as_str().to_owned());
+	let client_ip = request
+		.extensions()
+		.get::<ClientIp>()
+		.map(|ClientIp(ip)| tracing::field::display(ip));
+	let span = info_span!("Request", id = %id, %method, %path, client_ip);
+
+	let mut response = REQUEST_ID
+		.scope(id.clone(), next.run(request).instrument(span.clone()))
+		.await;
+
+	span.in_scope(|| {
+		info!(
+			status = response.status().as_u16(),
+			elapsed_ms = start.elapsed().as_secs_f64() * 1000.0,
+			"Finished request"
+		);
+	});
+
+	if let Ok(value) = HeaderValue::from_str(&id) {
+		response.headers_mut().insert(REQUEST_ID_HEADER, value);
+	}
+
+	response
+}
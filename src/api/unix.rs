@@ -0,0 +1,85 @@
+use std::{fs::Permissions, future::Future, io::ErrorKind, os::unix::fs::PermissionsExt, path::Path};
+
+use anyhow::{Context, Result};
+use axum::Router;
+use hyper_util::{
+	rt::{TokioExecutor, TokioIo},
+	server::conn::auto::Builder,
+	service::TowerToHyperService,
+};
+use tokio::{fs, net::UnixListener, sync::watch};
+use tracing::{debug, error, info, warn};
+
+use crate::systemd::{self, Notification};
+
+/// Serves the app over a Unix domain socket at the given path until the shutdown future completes. Any stale socket
+/// left behind at the path is removed beforehand, and the socket is removed again once the server has stopped.
+pub async fn serve(path: &Path, mode: u32, app: Router, shutdown: impl Future<Output = ()>) -> Result<()> {
+	// Remove a stale socket from a previous run that didn't shut down cleanly
+	match fs::remove_file(path).await {
+		Ok(()) => warn!("Removed stale socket {}", path.display()),
+		Err(err) if err.kind() == ErrorKind::NotFound => {}
+		Err(err) => return Err(err).with_context(|| format!("Unable to remove stale socket {}", path.display())),
+	}
+
+	let listener = UnixListener::bind(path).with_context(|| format!("Unable to bind socket {}", path.display()))?;
+	fs::set_permissions(path, Permissions::from_mode(mode))
+		.await
+		.with_context(|| format!("Unable to set permissions of socket {}", path.display()))?;
+	info!("Listening on unix:{} (mode {mode:o})", path.display());
+	systemd::notify(&[Notification::Ready]);
+
+	accept(&listener, app, shutdown).await;
+	drop(listener);
+
+	if let Err(err) = fs::remove_file(path).await {
+		error!("Unable to remove socket {}: {err}", path.display());
+	}
+
+	Ok(())
+}
+
+/// Accepts connections until the shutdown future completes, then waits for open connections to finish
+async fn accept(listener: &UnixListener, app: Router, shutdown: impl Future<Output = ()>) {
+	// Each connection holds a receiver, and is told to shut down gracefully once the sender signals
+	let (close_tx, close_rx) = watch::channel(());
+
+	tokio::pin!(shutdown);
+	loop {
+		let stream = tokio::select! {
+			result = listener.accept() => match result {
+				Ok((stream, _addr)) => stream,
+				Err(err) => {
+					error!("Unable to accept connection: {err}");
+					continue;
+				}
+			},
+			() = &mut shutdown => break,
+		};
+
+		let service = TowerToHyperService::new(app.clone());
+		let mut close_rx = close_rx.clone();
+		tokio::spawn(async move {
+			let builder = Builder::new(TokioExecutor::new());
+			let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+			tokio::pin!(conn);
+
+			loop {
+				tokio::select! {
+					result = conn.as_mut() => {
+						if let Err(err) = result {
+							debug!("Error serving connection: {err}");
+						}
+						break;
+					}
+					_ = close_rx.changed() => conn.as_mut().graceful_shutdown(),
+				}
+			}
+		});
+	}
+
+	// Tell open connections to finish up, then wait for all of them to drop their receivers
+	drop(close_rx);
+	let _ = close_tx.send(());
+	close_tx.closed().await;
+}
@@ -0,0 +1,254 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::fs;
+
+/// Environment variable that tokens are read from
+pub const TOKEN_ENV_VAR: &str = "SHAKER_TOKEN";
+
+/// Level of access granted by a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Scope {
+	/// Access to endpoints that only read data
+	Read,
+
+	/// Access to endpoints that create or modify data
+	Write,
+
+	/// Access to all endpoints, including administrative ones
+	Admin,
+}
+
+impl Scope {
+	/// Gets the name of the scope as used in configuration
+	#[must_use]
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Read => "read",
+			Self::Write => "write",
+			Self::Admin => "admin",
+		}
+	}
+}
+
+impl fmt::Display for Scope {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+impl FromStr for Scope {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"read" => Ok(Self::Read),
+			"write" => Ok(Self::Write),
+			"admin" => Ok(Self::Admin),
+			_ => bail!("unknown scope \"{s}\" (expected read, write, or admin)"),
+		}
+	}
+}
+
+/// Token tagged with the scope it grants
+#[derive(Debug, Clone)]
+pub struct ScopedToken {
+	/// Scope granted by the token
+	pub scope: Scope,
+
+	/// Secret value of the token
+	pub token: Secret<String>,
+}
+
+impl FromStr for ScopedToken {
+	type Err = anyhow::Error;
+
+	/// Parses a token in the form `scope:token`. Tokens without a recognized scope prefix are granted the admin scope
+	/// so that configurations from before scopes existed keep their full access.
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (scope, token) = match s.split_once(':') {
+			Some((scope, token)) => match scope.parse() {
+				Ok(scope) => (scope, token),
+				Err(_) => (Scope::Admin, s),
+			},
+			None => (Scope::Admin, s),
+		};
+
+		if token.is_empty() {
+			bail!("token must not be empty");
+		}
+
+		Ok(Self {
+			scope,
+			token: Secret::new(token.to_owned()),
+		})
+	}
+}
+
+/// SHA-256 digest of a token. Tokens are only ever compared by their digests so that comparisons happen in constant
+/// time over fixed-length values, regardless of the length or content of the presented token.
+#[derive(Clone)]
+pub struct TokenDigest([u8; 32]);
+
+impl TokenDigest {
+	/// Computes the digest of a token
+	#[must_use]
+	pub fn new(token: &str) -> Self {
+		Self(Sha256::digest(token.as_bytes()).into())
+	}
+}
+
+impl From<&Secret<String>> for TokenDigest {
+	fn from(token: &Secret<String>) -> Self {
+		Self::new(token.expose_secret())
+	}
+}
+
+impl PartialEq for TokenDigest {
+	fn eq(&self, other: &Self) -> bool {
+		self.0.ct_eq(&other.0).into()
+	}
+}
+
+impl Eq for TokenDigest {}
+
+impl fmt::Debug for TokenDigest {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("TokenDigest([REDACTED])")
+	}
+}
+
+/// Registry of all tokens that are accepted for authentication
+#[derive(Debug, Clone, Default)]
+pub struct TokenRegistry {
+	/// Digests of accepted tokens along with the scope each grants
+	tokens: Vec<(Scope, TokenDigest)>,
+}
+
+impl TokenRegistry {
+	/// Creates a registry containing the given tokens
+	#[must_use]
+	pub fn new(tokens: &[ScopedToken]) -> Self {
+		Self {
+			tokens: tokens
+				.iter()
+				.map(|token| (token.scope, TokenDigest::from(&token.token)))
+				.collect(),
+		}
+	}
+
+	/// Checks whether the registry has no tokens, meaning authentication isn't required
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.tokens.is_empty()
+	}
+
+	/// Gets the number of tokens in the registry
+	#[must_use]
+	pub fn len(&self) -> usize {
+		self.tokens.len()
+	}
+
+	/// Replaces all tokens for the scope of the given token with it, so previous tokens for that scope stop working
+	pub fn rotate(&mut self, token: &ScopedToken) {
+		self.tokens.retain(|(scope, _)| *scope != token.scope);
+		self.tokens.push((token.scope, TokenDigest::from(&token.token)));
+	}
+
+	/// Resolves the scope granted by a token, if it is a known one. Every known token is compared against so that the
+	/// time taken doesn't depend on which token matched. If the same token is configured more than once, the broadest
+	/// scope is granted.
+	#[must_use]
+	pub fn resolve(&self, digest: &TokenDigest) -> Option<Scope> {
+		self.tokens
+			.iter()
+			.filter(|(_, known)| known == digest)
+			.map(|(scope, _)| *scope)
+			.max()
+	}
+}
+
+/// Loads tokens from the `.env` file or the environment. The `.env` file is preferred since any values it provided at
+/// startup are already baked into the environment and wouldn't reflect changes to the file. Returns `None` if neither
+/// defines any tokens.
+pub fn tokens_from_env() -> Result<Option<Vec<ScopedToken>>> {
+	// The iterator is deprecated, but it's the only way to read the file's values without the stale ones already in
+	// the environment taking precedence
+	#[allow(deprecated)]
+	let from_file = match dotenv::dotenv_iter() {
+		Ok(iter) => iter
+			.filter_map(Result::ok)
+			.find_map(|(key, value)| (key == TOKEN_ENV_VAR).then_some(value)),
+		Err(err) if err.not_found() => None,
+		Err(err) => return Err(err.into()),
+	};
+
+	let Some(value) = from_file.or_else(|| std::env::var(TOKEN_ENV_VAR).ok()) else {
+		return Ok(None);
+	};
+
+	let tokens = value.split(',').map(str::parse).collect::<Result<Vec<ScopedToken>>>()?;
+	Ok(Some(tokens))
+}
+
+/// Loads tokens from a file containing one token per line. Blank lines and surrounding whitespace (such as a trailing
+/// newline) are ignored, but the file must contain at least one token.
+pub async fn tokens_from_file(path: &Path) -> Result<Vec<ScopedToken>> {
+	let content = fs::read_to_string(path)
+		.await
+		.with_context(|| format!("Unable to read token file {}", path.display()))?;
+
+	let tokens = content
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty())
+		.map(str::parse)
+		.collect::<Result<Vec<ScopedToken>>>()
+		.with_context(|| format!("Invalid token in token file {}", path.display()))?;
+
+	if tokens.is_empty() {
+		bail!("Token file {} is empty", path.display());
+	}
+
+	Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn registry() -> TokenRegistry {
+		TokenRegistry::new(&["read:reader-secret".parse().unwrap(), "admin-secret".parse().unwrap()])
+	}
+
+	#[test]
+	fn accepts_exact_tokens() {
+		let registry = registry();
+		assert_eq!(registry.resolve(&TokenDigest::new("reader-secret")), Some(Scope::Read));
+		assert_eq!(registry.resolve(&TokenDigest::new("admin-secret")), Some(Scope::Admin));
+	}
+
+	#[test]
+	fn rejects_tokens_with_the_wrong_content() {
+		let registry = registry();
+		assert_eq!(registry.resolve(&TokenDigest::new("admin-secreT")), None);
+		assert_eq!(registry.resolve(&TokenDigest::new("bdmin-secret")), None);
+	}
+
+	#[test]
+	fn rejects_tokens_with_the_wrong_length() {
+		let registry = registry();
+		assert_eq!(registry.resolve(&TokenDigest::new("admin-secre")), None);
+		assert_eq!(registry.resolve(&TokenDigest::new("admin-secret2")), None);
+		assert_eq!(registry.resolve(&TokenDigest::new("")), None);
+	}
+
+	#[test]
+	fn scope_prefixes_are_not_part_of_tokens() {
+		assert_eq!(registry().resolve(&TokenDigest::new("read:reader-secret")), None);
+		assert!("read:".parse::<ScopedToken>().is_err());
+	}
+}
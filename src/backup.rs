@@ -0,0 +1,339 @@
+use std::{
+	fmt, io,
+	path::{Component, Path, PathBuf},
+	sync::Arc,
+	time::Duration,
+};
+
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{fs, sync::RwLock, time as tokio_time};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::db;
+
+/// Prefix of the names of timestamped backups
+const TIMESTAMPED_PREFIX: &str = "shaker-";
+
+/// Extension of the names of timestamped backups
+const TIMESTAMPED_SUFFIX: &str = ".db";
+
+/// Name of the gauge of the Unix time of the last successful scheduled backup
+const LAST_BACKUP: &str = "shaker_backup_last_success_timestamp_seconds";
+
+/// Name of the gauge of the Unix time that the next scheduled backup is due
+const NEXT_BACKUP: &str = "shaker_backup_next_timestamp_seconds";
+
+/// Primary result code for a disk being full
+const SQLITE_FULL: i32 = 13;
+
+/// Primary result codes for a file being impossible to open or write due to permissions
+const SQLITE_PERMISSION_CODES: &[i32] = &[3, 8, 14];
+
+/// Backup of the database that was written to disk
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Backup {
+	/// Path of the backup file
+	#[schema(value_type = String)]
+	pub path: PathBuf,
+
+	/// Size of the backup file in bytes
+	pub size: u64,
+}
+
+/// Reason a backup couldn't be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupErrorKind {
+	/// A file already exists at the backup's path
+	AlreadyExists,
+
+	/// The backup's path isn't within the backup directory
+	OutsideDirectory,
+
+	/// There isn't enough space on the disk for the backup
+	NoSpace,
+
+	/// The backup directory or file can't be written to
+	PermissionDenied,
+
+	/// Writing the backup failed for some other reason
+	Failed,
+}
+
+/// Error for a backup that couldn't be written
+#[derive(Debug)]
+pub struct BackupError {
+	/// Reason the backup couldn't be written
+	pub kind: BackupErrorKind,
+
+	/// Description of what went wrong
+	pub message: String,
+}
+
+impl BackupError {
+	/// Creates an error of a kind
+	#[must_use]
+	pub fn new(kind: BackupErrorKind, message: impl Into<String>) -> Self {
+		Self {
+			kind,
+			message: message.into(),
+		}
+	}
+
+	/// Classifies a filesystem error
+	fn io(err: &io::Error, path: &Path) -> Self {
+		let kind = match err.kind() {
+			io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => BackupErrorKind::PermissionDenied,
+			io::ErrorKind::StorageFull => BackupErrorKind::NoSpace,
+			_ => BackupErrorKind::Failed,
+		};
+		Self::new(kind, format!("unable to write to {}: {err}", path.display()))
+	}
+
+	/// Classifies an error from the database writing the backup
+	fn database(err: &anyhow::Error, path: &Path) -> Self {
+		let kind = match db::result_code(err) {
+			Some(SQLITE_FULL) => BackupErrorKind::NoSpace,
+			Some(code) if SQLITE_PERMISSION_CODES.contains(&code) => BackupErrorKind::PermissionDenied,
+			_ => BackupErrorKind::Failed,
+		};
+		Self::new(kind, format!("unable to write backup to {}: {err}", path.display()))
+	}
+}
+
+impl fmt::Display for BackupError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+impl std::error::Error for BackupError {}
+
+/// Times of the last successful scheduled backup and the next one that's due, shared between the task that writes the
+/// backups and the API that reports them
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+	/// Times that have been recorded so far
+	times: Arc<RwLock<ScheduleTimes>>,
+}
+
+impl Schedule {
+	/// Gets the times of the last successful scheduled backup and the next one, which are unset until there is one
+	pub async fn times(&self) -> ScheduleTimes {
+		*self.times.read().await
+	}
+}
+
+/// Times of the last successful scheduled backup and the next one that's due
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScheduleTimes {
+	/// Time of the last successful scheduled backup
+	pub last: Option<OffsetDateTime>,
+
+	/// Time that the next scheduled backup is due
+	pub next: Option<OffsetDateTime>,
+}
+
+/// Writes a backup of the database into a directory, creating the directory if needed. The backup is given the file
+/// name if one is provided (which must not contain any path separators), otherwise one based on the current time.
+pub async fn create(db: &db::Database, dir: &Path, name: Option<&str>) -> Result<Backup, BackupError> {
+	let name = name.map_or_else(|| timestamped_name(OffsetDateTime::now_utc()), ToOwned::to_owned);
+	let mut components = Path::new(&name).components();
+	if !matches!(
+		(components.next(), components.next()),
+		(Some(Component::Normal(_)), None)
+	) {
+		return Err(BackupError::new(
+			BackupErrorKind::OutsideDirectory,
+			format!("backup name \"{name}\" must be a plain file name within the backup directory"),
+		));
+	}
+
+	fs::create_dir_all(dir)
+		.await
+		.map_err(|err| BackupError::io(&err, dir))?;
+	let path = dir.join(&name);
+	if fs::try_exists(&path)
+		.await
+		.map_err(|err| BackupError::io(&err, &path))?
+	{
+		return Err(BackupError::new(
+			BackupErrorKind::AlreadyExists,
+			format!("backup {} already exists", path.display()),
+		));
+	}
+
+	db.backup_to(&path)
+		.await
+		.map_err(|err| BackupError::database(&err, &path))?;
+	let size = fs::metadata(&path)
+		.await
+		.map_err(|err| BackupError::io(&err, &path))?
+		.len();
+
+	info!("Backed up database to {} ({size} bytes)", path.display());
+	Ok(Backup { path, size })
+}
+
+/// Spawns a task that writes a timestamped backup into a directory every interval, keeping only the most recent ones.
+/// The times of the last successful backup and the next one are recorded in the schedule and exported as metrics.
+pub fn spawn_scheduled(db: db::Database, dir: PathBuf, interval: Duration, keep: usize, schedule: Schedule) {
+	info!(
+		"Backing up database to {} every {interval:?}, keeping the latest {keep}",
+		dir.display()
+	);
+	tokio::spawn(async move {
+		let mut ticker = tokio_time::interval(interval);
+		ticker.set_missed_tick_behavior(tokio_time::MissedTickBehavior::Delay);
+
+		// The first tick completes immediately, but backing up at startup is up to --backup-on-start
+		ticker.tick().await;
+		loop {
+			let next = OffsetDateTime::now_utc() + interval;
+			set_gauge(NEXT_BACKUP, next);
+			schedule.times.write().await.next = Some(next);
+			ticker.tick().await;
+
+			// Failures are left for the next tick to try again
+			match create(&db, &dir, None).await {
+				Ok(_) => {
+					let last = OffsetDateTime::now_utc();
+					set_gauge(LAST_BACKUP, last);
+					schedule.times.write().await.last = Some(last);
+				}
+				Err(err) => {
+					warn!("Scheduled backup failed: {err}");
+					continue;
+				}
+			}
+
+			if let Err(err) = prune(&dir, keep).await {
+				warn!("Unable to prune old backups in {}: {err}", dir.display());
+			}
+		}
+	});
+}
+
+/// Deletes the oldest timestamped backups in a directory beyond the given number. Backups with other names are left
+/// alone.
+async fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+	let mut names = Vec::new();
+	let mut entries = fs::read_dir(dir).await?;
+	while let Some(entry) = entries.next_entry().await? {
+		if let Some(name) = entry.file_name().to_str() {
+			if is_timestamped_name(name) {
+				names.push(name.to_owned());
+			}
+		}
+	}
+
+	// Timestamped names sort chronologically, so the oldest are first
+	names.sort_unstable();
+	let excess = names.len().saturating_sub(keep);
+	for name in &names[..excess] {
+		fs::remove_file(dir.join(name)).await?;
+		info!("Deleted old backup {name}");
+	}
+
+	Ok(())
+}
+
+/// Sets a gauge to a Unix time
+#[allow(clippy::cast_precision_loss)]
+fn set_gauge(name: &'static str, time: OffsetDateTime) {
+	metrics::gauge!(name).set(time.unix_timestamp() as f64);
+}
+
+/// Checks whether a file name is one given to timestamped backups
+fn is_timestamped_name(name: &str) -> bool {
+	name.strip_prefix(TIMESTAMPED_PREFIX)
+		.and_then(|rest| rest.strip_suffix(TIMESTAMPED_SUFFIX))
+		.is_some_and(|stamp| {
+			stamp.len() == 15
+				&& stamp
+					.chars()
+					.enumerate()
+					.all(|(i, ch)| if i == 8 { ch == '-' } else { ch.is_ascii_digit() })
+		})
+}
+
+/// Builds a backup file name for a point in time, which sorts chronologically
+fn timestamped_name(time: OffsetDateTime) -> String {
+	format!(
+		"{TIMESTAMPED_PREFIX}{:04}{:02}{:02}-{:02}{:02}{:02}{TIMESTAMPED_SUFFIX}",
+		time.year(),
+		u8::from(time.month()),
+		time.day(),
+		time.hour(),
+		time.minute(),
+		time.second()
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_sort_chronologically() {
+		let time = OffsetDateTime::from_unix_timestamp(1_718_452_800).unwrap();
+		assert_eq!(timestamped_name(time), "shaker-20240615-120000.db");
+		assert!(is_timestamped_name(&timestamped_name(time)));
+		assert!(!is_timestamped_name("shaker-manual.db"));
+		assert!(!is_timestamped_name("shaker-20240615-120000.db.bak"));
+	}
+
+	#[tokio::test]
+	async fn pruning_keeps_the_newest_timestamped_backups() {
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		let names = [
+			"shaker-20240613-120000.db",
+			"shaker-20240614-120000.db",
+			"shaker-20240615-120000.db",
+			"manual.db",
+		];
+		for name in names {
+			std::fs::write(dir.join(name), "").unwrap();
+		}
+
+		prune(dir, 2).await.unwrap();
+		let mut remaining: Vec<_> = std::fs::read_dir(dir)
+			.unwrap()
+			.map(|entry| entry.unwrap().file_name().into_string().unwrap())
+			.collect();
+		remaining.sort_unstable();
+		assert_eq!(remaining, ["manual.db", names[1], names[2]]);
+	}
+
+	#[tokio::test]
+	async fn backups_are_written_once_within_the_directory() {
+		let root = tempfile::tempdir().unwrap();
+		let dir = root.path().join("backups");
+
+		// Backups of in-memory databases are written in memory too, so this needs a real file
+		let db = db::Database::open(
+			&root.path().join("shaker.db"),
+			db::ConnectionSettings::default(),
+			db::PoolSettings::default(),
+		)
+		.await
+		.unwrap();
+		db.migrate().await.unwrap();
+
+		let backup = create(&db, &dir, Some("copy.db")).await.unwrap();
+		assert_eq!(backup.path, dir.join("copy.db"));
+		assert!(backup.size > 0);
+
+		let err = create(&db, &dir, Some("copy.db")).await.unwrap_err();
+		assert_eq!(err.kind, BackupErrorKind::AlreadyExists);
+
+		for name in ["../copy.db", "nested/copy.db", "/tmp/copy.db", ".", ""] {
+			let err = create(&db, &dir, Some(name)).await.unwrap_err();
+			assert_eq!(err.kind, BackupErrorKind::OutsideDirectory, "{name:?}");
+		}
+
+		db.close().await;
+	}
+}
@@ -0,0 +1,934 @@
+use std::{
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{Context, Result};
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use dotenv::dotenv;
+use ipnet::IpNet;
+use reqwest::Url;
+use secrecy::Secret;
+use sqlx::sqlite::{SqliteJournalMode, SqliteSynchronous};
+use time_tz::{timezones, Tz};
+use tracing::{error, info, warn};
+use tracing_forest::traits::*;
+use tracing_subscriber::EnvFilter;
+
+use crate::{auth::ScopedToken, config_file, config_file::ConfigFile, db, export, import, logging};
+
+pub use self::args::{
+	BackfillIdsArgs, ExportArgs, FieldLimitArgs, ImportArgs, LogFormat, MergeUsersArgs, OutputFormat, StatsArgs,
+	UserArgs,
+};
+
+mod args;
+mod commands;
+
+/// Command-line interface for Shaker
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+	/// Database options, which apply to every command
+	#[command(flatten)]
+	pub database: DatabaseArgs,
+
+	/// Command to run. If none is given, the API server is run.
+	#[command(subcommand)]
+	pub command: Option<Command>,
+
+	/// Configuration for the API server when it's run without the `serve` command (ignored with any other command)
+	#[command(flatten)]
+	pub serve: Config,
+
+	/// Options that ran other commands before they were subcommands
+	#[command(flatten)]
+	pub legacy: LegacyArgs,
+
+	/// Path to a TOML file to load options from, named like the long options (such as `webhook_url = ["..."]`). Options
+	/// given on the command line or in the environment take precedence over the file.
+	#[arg(long, global = true, env("SHAKER_CONFIG"))]
+	pub config: Option<PathBuf>,
+
+	/// Check the configuration and exit without running anything
+	#[arg(long, global = true)]
+	pub check: bool,
+
+	/// Path to the dotenv file (if one was used)
+	#[arg(skip)]
+	pub dotenv: Option<dotenv::Result<PathBuf>>,
+
+	/// Config file that was loaded (if one was used)
+	#[arg(skip)]
+	pub config_file: Option<ConfigFile>,
+}
+
+impl Cli {
+	/// Loads configuration from the following sources, in order of precedence:
+	/// - CLI arguments
+	/// - Environment variables
+	/// - `.env` file
+	/// - Config file (given by `--config` or `SHAKER_CONFIG`)
+	pub fn load() -> Result<Self> {
+		let dotenv = dotenv();
+
+		// The config file's options become the defaults of the options before parsing, so its path is found beforehand
+		let config_path = Self::command()
+			.ignore_errors(true)
+			.try_get_matches()
+			.ok()
+			.and_then(|matches| matches.get_one::<PathBuf>("config").cloned());
+		let (mut command, config_file) = match config_path {
+			Some(path) => {
+				let (command, file) = config_file::load(&path, Self::command())?;
+				(command, Some(file))
+			}
+			None => (Self::command(), None),
+		};
+
+		let matches = command.get_matches_mut();
+		let mut cli = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.format(&mut command).exit());
+		cli.dotenv = Some(dotenv);
+		cli.config_file = config_file;
+		Ok(cli)
+	}
+
+	/// Splits the interface into the database options and the command to run, falling back on any legacy options and
+	/// then serving the API if no command was given
+	#[must_use]
+	pub fn into_parts(self) -> (DatabaseArgs, Command) {
+		let command = self.command.unwrap_or_else(|| self.legacy.command(self.serve));
+		(self.database, command)
+	}
+
+	/// Configuration for the API server and logging, preferring that of the `serve` command
+	#[must_use]
+	pub fn serve_config(&self) -> &Config {
+		match &self.command {
+			Some(Command::Serve(cfg)) => cfg,
+			_ => &self.serve,
+		}
+	}
+
+	/// Emits trace events for information about any config file used, warning about any options in it that were ignored
+	fn emit_config_file_info(&self) {
+		if let Some(file) = &self.config_file {
+			info!("Loaded options from config file {}", file.path.display());
+			if !file.unknown_keys.is_empty() {
+				warn!(
+					"Ignored unknown option(s) in config file {}: {}",
+					file.path.display(),
+					file.unknown_keys.join(", ")
+				);
+			}
+		}
+	}
+
+	/// Emits trace events for information about any dotenv file used
+	fn emit_dotenv_info(&self) {
+		if let Some(dotenv) = &self.dotenv {
+			match dotenv {
+				Ok(file) => info!("Parsed environment variables from {}", file.display()),
+				Err(err) if err.not_found() => {}
+				Err(err) => error!("Error loading .env file: {err}"),
+			}
+		}
+	}
+}
+
+/// Command to run
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// Run the API server (the default when no command is given)
+	Serve(Box<Config>),
+
+	/// Import past handshakes from a plain-text file of usernames or a CSV file, or restore an NDJSON export
+	Import(ImportArgs),
+
+	/// Export every user and handshake to a file
+	Export(ExportArgs),
+
+	/// Normalize the usernames of existing users (trimming and collapsing whitespace, among other things), reporting
+	/// any that would collide with each other instead of changing them
+	NormalizeNames,
+
+	/// Check the database for corruption and broken references, printing any problems (or "ok" if there are none).
+	/// The exit status is nonzero if any problems were found.
+	CheckDb,
+
+	/// Print counts of users, handshakes, and worlds, along with the top users, without modifying the database
+	Stats(StatsArgs),
+
+	/// Look up a user by database ID, Resonite ID, or username, printing their details and most recent handshakes.
+	/// If a name matches several users, they're all listed instead.
+	User(UserArgs),
+
+	/// Merge duplicate users into one, moving their handshakes and name history over to the user that's kept. A plan
+	/// of the merges is printed first, and they're only made once confirmed.
+	MergeUsers(MergeUsersArgs),
+
+	/// Fill in the Resonite IDs of users that don't have one (typically legacy users) by searching Resonite's API for
+	/// their names, only using an ID when exactly one Resonite user has the same name. Names that matched several users
+	/// or none are reported instead. Each user is only looked up once, so an interrupted backfill can simply be run
+	/// again to continue.
+	BackfillIds(BackfillIdsArgs),
+}
+
+/// Options for the database
+#[derive(Debug, Args)]
+pub struct DatabaseArgs {
+	/// Path to the SQLite database, or the `postgres://` URL of a PostgreSQL database (if built with the `postgres`
+	/// feature). The journal mode, synchronous, and foreign key options only apply to SQLite.
+	#[allow(clippy::doc_markdown)]
+	#[arg(long, short, global = true, env("SHAKER_DB"), default_value = "shaker.db")]
+	pub db: PathBuf,
+
+	/// Journal mode for the database (`delete`, `truncate`, `persist`, `memory`, `wal`, or `off`)
+	#[arg(long, global = true, env("SHAKER_DB_JOURNAL_MODE"), default_value = "wal")]
+	pub db_journal_mode: SqliteJournalMode,
+
+	/// How often the database syncs to disk (`off`, `normal`, `full`, or `extra`)
+	#[arg(long, global = true, env("SHAKER_DB_SYNCHRONOUS"), default_value = "normal")]
+	pub db_synchronous: SqliteSynchronous,
+
+	/// Seconds to wait for a lock held by another database connection before failing
+	#[arg(long, global = true, env("SHAKER_DB_BUSY_TIMEOUT"), default_value_t = 5)]
+	pub db_busy_timeout: u64,
+
+	/// Whether to enforce foreign key constraints in the database
+	#[arg(long, global = true, env("SHAKER_DB_FOREIGN_KEYS"), default_value_t = true, action = ArgAction::Set)]
+	pub db_foreign_keys: bool,
+
+	/// Maximum number of connections to the database to keep open
+	#[arg(long, global = true, env("SHAKER_DB_MAX_CONNECTIONS"), default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+	pub db_max_connections: u32,
+
+	/// Minimum number of connections to the database to keep open, even when they're idle
+	#[arg(long, global = true, env("SHAKER_DB_MIN_CONNECTIONS"), default_value_t = 0)]
+	pub db_min_connections: u32,
+
+	/// Seconds to wait for a database connection to become available before failing a request
+	#[arg(long, global = true, env("SHAKER_DB_ACQUIRE_TIMEOUT"), default_value_t = 30)]
+	pub db_acquire_timeout: u64,
+
+	/// Number of times to attempt a write (such as storing a handshake) before giving up with a "database busy" error
+	/// if the database is still locked by another connection after the busy timeout
+	#[arg(long, global = true, env("SHAKER_DB_WRITE_ATTEMPTS"), default_value_t = db::DEFAULT_WRITE_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+	pub db_write_attempts: u32,
+
+	/// Open the database read-only, such as for serving stats from a copy of it. Migrations aren't run (Shaker refuses
+	/// to start if any are pending), and requests that would write are rejected.
+	#[arg(long, global = true, env("SHAKER_READ_ONLY"))]
+	pub read_only: bool,
+}
+
+impl DatabaseArgs {
+	/// Opens the database with the configured connection and pool settings
+	pub async fn open(&self) -> Result<db::Database> {
+		self.open_as(self.read_only).await
+	}
+
+	/// Opens the database with the configured connection and pool settings, but read-only or not as given
+	async fn open_as(&self, read_only: bool) -> Result<db::Database> {
+		let settings = db::ConnectionSettings {
+			journal_mode: self.db_journal_mode,
+			synchronous: self.db_synchronous,
+			busy_timeout: Duration::from_secs(self.db_busy_timeout),
+			foreign_keys: self.db_foreign_keys,
+			read_only,
+		};
+		let pool = db::PoolSettings {
+			max_connections: self.db_max_connections,
+			min_connections: self.db_min_connections,
+			acquire_timeout: Duration::from_secs(self.db_acquire_timeout),
+		};
+		Ok(db::Database::open(&self.db, settings, pool)
+			.await?
+			.with_write_attempts(self.db_write_attempts))
+	}
+
+	/// Checks that the database can be opened as configured, returning any problems found. The database must exist if
+	/// it's read-only, and otherwise its directory must exist and be writable (for it and its journal).
+	#[must_use]
+	pub fn validate(&self) -> Vec<String> {
+		let path = match db::DatabaseLocation::parse(&self.db) {
+			db::DatabaseLocation::Sqlite(path) => path,
+			db::DatabaseLocation::Postgres(_) if cfg!(feature = "postgres") => return Vec::new(),
+			db::DatabaseLocation::Postgres(_) => {
+				return vec![
+					"Database is a PostgreSQL URL, but PostgreSQL support isn't enabled; rebuild with the postgres feature"
+						.to_owned(),
+				];
+			}
+			db::DatabaseLocation::Unsupported(scheme) => {
+				return vec![format!(
+					"Database is a {scheme}: URL, but only SQLite database files and PostgreSQL are supported"
+				)];
+			}
+		};
+		if self.read_only {
+			return check_readable("Database", path).into_iter().collect();
+		}
+
+		let dir = match path.parent() {
+			Some(dir) if !dir.as_os_str().is_empty() => dir,
+			_ => Path::new("."),
+		};
+		check_writable_dir("Database directory", dir).into_iter().collect()
+	}
+
+	/// Checks that the database can be backed up if the server is configured to make backups, which `PostgreSQL`
+	/// databases can't be (they need to be backed up with `pg_dump` instead)
+	#[must_use]
+	pub fn validate_backups(&self, cfg: &Config) -> Option<String> {
+		let postgres = matches!(db::DatabaseLocation::parse(&self.db), db::DatabaseLocation::Postgres(_));
+		(postgres && cfg.backup_dir.is_some())
+			.then(|| "PostgreSQL databases can't be backed up to a backup directory; use pg_dump instead".to_owned())
+	}
+}
+
+impl Config {
+	/// Checks the configuration for problems that can be found without starting anything, such as unreadable files or
+	/// options that can't be used together, returning any that are found
+	#[must_use]
+	pub fn validate(&self, read_only: bool) -> Vec<String> {
+		let mut problems = Vec::new();
+
+		// Clap can't catch these when the read-only option comes before the serve command, since it's checked separately
+		if read_only
+			&& (!self.webhook_url.is_empty()
+				|| self.discord_webhook_url.is_some()
+				|| self.purge_deleted_after.is_some()
+				|| self.retention_days.is_some()
+				|| self.refresh_names_interval.is_some())
+		{
+			problems.push(
+				"Webhooks, purging deleted records, retention, and refreshing usernames need write access, so they \
+				 can't be used while read-only"
+					.to_owned(),
+			);
+		}
+
+		for (name, addr) in [("Admin API", self.admin_api), ("Metrics", self.metrics)] {
+			if addr == Some(self.api) && self.api_unix.is_none() {
+				problems.push(format!("{name} address {} is the same as the API's", self.api));
+			}
+		}
+
+		let files = [
+			("Token file", &self.token_file),
+			("TLS certificate", &self.tls_cert),
+			("TLS key", &self.tls_key),
+		];
+		for (name, path) in files {
+			if let Some(path) = path {
+				problems.extend(check_readable(name, path));
+			}
+		}
+
+		let dirs = [
+			(
+				"Unix domain socket directory",
+				self.api_unix.as_deref().and_then(Path::parent),
+			),
+			("Log file directory", self.log_file.as_deref().and_then(Path::parent)),
+			// The backup directory is created when the first backup is made
+			(
+				"Backup directory",
+				self.backup_dir.as_deref().filter(|dir| dir.exists()),
+			),
+		];
+		for (name, dir) in dirs {
+			if let Some(dir) = dir.filter(|dir| !dir.as_os_str().is_empty()) {
+				problems.extend(check_writable_dir(name, dir));
+			}
+		}
+
+		problems
+	}
+}
+
+/// Checks that a file can be opened for reading
+fn check_readable(name: &str, path: &Path) -> Option<String> {
+	std::fs::File::open(path)
+		.err()
+		.map(|err| format!("{name} {} isn't readable: {err}", path.display()))
+}
+
+/// Checks that a directory exists and that files can be created in it, by creating (and removing) an empty file
+fn check_writable_dir(name: &str, dir: &Path) -> Option<String> {
+	if !dir.is_dir() {
+		return Some(format!("{name} {} doesn't exist", dir.display()));
+	}
+
+	let probe = dir.join(format!(".shaker-check-{}", std::process::id()));
+	match std::fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+		Ok(_) => {
+			let _ = std::fs::remove_file(probe);
+			None
+		}
+		Err(err) => Some(format!("{name} {} isn't writable: {err}", dir.display())),
+	}
+}
+
+/// Configuration for the API server
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+	/// Directory to write database backups to (made via `POST /admin/backup`)
+	#[arg(long, env("SHAKER_BACKUP_DIR"))]
+	pub backup_dir: Option<PathBuf>,
+
+	/// Back up the database into the backup directory at startup, before running any migrations
+	#[arg(long, env("SHAKER_BACKUP_ON_START"), requires = "backup_dir")]
+	pub backup_on_start: bool,
+
+	/// Seconds between automatic backups into the backup directory. If not set, backups are only made on request.
+	#[arg(long, env("SHAKER_BACKUP_INTERVAL"), requires = "backup_dir", value_parser = clap::value_parser!(u64).range(1..))]
+	pub backup_interval: Option<u64>,
+
+	/// Number of automatic backups to keep, deleting the oldest ones beyond it (backups with other names are never
+	/// deleted)
+	#[arg(long, env("SHAKER_BACKUP_KEEP"), default_value_t = 7, value_parser = clap::value_parser!(u16).range(1..))]
+	pub backup_keep: u16,
+
+	/// Days to keep deleted users and handshakes before permanently purging them. If not set, they're kept forever.
+	#[arg(
+		long,
+		env("SHAKER_PURGE_DELETED_AFTER"),
+		conflicts_with = "read_only",
+		value_parser = clap::value_parser!(u64).range(1..),
+	)]
+	pub purge_deleted_after: Option<u64>,
+
+	/// Days to keep handshakes for, permanently deleting older ones in the background (including any that were already
+	/// deleted via the API and are waiting to be purged). If not set, handshakes are kept forever.
+	#[arg(
+		long,
+		env("SHAKER_RETENTION_DAYS"),
+		conflicts_with = "read_only",
+		value_parser = clap::value_parser!(u64).range(1..),
+	)]
+	pub retention_days: Option<u64>,
+
+	/// Add up handshakes into per-day counts in the archive before deleting them for being past the retention period,
+	/// so that historical totals survive
+	#[arg(long, env("SHAKER_RETENTION_ARCHIVE"), requires = "retention_days")]
+	pub retention_archive: bool,
+
+	/// Address for the API to listen on
+	#[arg(long, short, env("SHAKER_API"), default_value = "127.0.0.1:9001")]
+	pub api: SocketAddr,
+
+	/// Seconds between heartbeats logged with the numbers of handshakes and new users since the previous one, the
+	/// request rate, and the database connection pool's status. If not set, no heartbeats are logged.
+	#[arg(long, env("SHAKER_HEARTBEAT_INTERVAL"), value_parser = clap::value_parser!(u64).range(1..))]
+	pub heartbeat_interval: Option<u64>,
+
+	/// Address for the endpoints that require the admin scope to listen on, such as a localhost or VPN-only address. If
+	/// set, they're removed from the main listener entirely. If not set, they're served on the main listener.
+	#[arg(long, env("SHAKER_ADMIN_API"))]
+	pub admin_api: Option<SocketAddr>,
+
+	/// Address for the Prometheus metrics endpoint to listen on. If not set, metrics are served at `/metrics` on the
+	/// API listener.
+	#[arg(long, env("SHAKER_METRICS"))]
+	pub metrics: Option<SocketAddr>,
+
+	/// Path to a Unix domain socket for the API to listen on instead of a TCP address
+	#[arg(long, env("SHAKER_API_UNIX"), conflicts_with = "tls_cert")]
+	pub api_unix: Option<PathBuf>,
+
+	/// Permissions (in octal) to set on the Unix domain socket
+	#[arg(long, env("SHAKER_API_UNIX_MODE"), default_value = "660", value_parser = parse_mode)]
+	pub api_unix_mode: u32,
+
+	/// Path to a PEM-encoded TLS certificate chain to serve the API over HTTPS with (requires `--tls-key`)
+	#[arg(long, env("SHAKER_TLS_CERT"), requires = "tls_key")]
+	pub tls_cert: Option<PathBuf>,
+
+	/// Path to the PEM-encoded private key for the TLS certificate (requires `--tls-cert`)
+	#[arg(long, env("SHAKER_TLS_KEY"), requires = "tls_cert")]
+	pub tls_key: Option<PathBuf>,
+
+	/// Address ranges (in CIDR notation) of reverse proxies to trust the `X-Forwarded-For` and `Forwarded` headers
+	/// from to determine the client's IP address. Headers from any other peer are ignored. May be given multiple times
+	/// or comma-separated.
+	#[arg(long, env("SHAKER_TRUSTED_PROXIES"), value_delimiter = ',')]
+	pub trusted_proxies: Vec<IpNet>,
+
+	/// Maximum number of WebSocket connections that may be open at once
+	#[arg(long, env("SHAKER_WS_MAX_CONNECTIONS"), default_value_t = 64)]
+	pub ws_max_connections: usize,
+
+	/// Seconds without hearing from a WebSocket client before its connection is closed
+	#[arg(long, env("SHAKER_WS_IDLE_TIMEOUT"), default_value_t = 90)]
+	pub ws_idle_timeout: u64,
+
+	/// URL to POST a JSON notification to whenever a handshake is created. May be given multiple times or
+	/// comma-separated.
+	#[arg(long, env("SHAKER_WEBHOOK_URL"), value_delimiter = ',', conflicts_with = "read_only")]
+	pub webhook_url: Vec<Url>,
+
+	/// Secret to sign webhook payloads with, sent as an HMAC-SHA256 signature in the `X-Shaker-Signature` header
+	#[arg(long, env("SHAKER_WEBHOOK_SECRET"))]
+	pub webhook_secret: Option<Secret<String>>,
+
+	/// Maximum number of attempts to deliver each webhook before giving up on it
+	#[arg(long, env("SHAKER_WEBHOOK_MAX_ATTEMPTS"), default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+	pub webhook_max_attempts: u32,
+
+	/// Intervals of total handshakes at which a milestone is reported when creating a handshake. May be given multiple
+	/// times or comma-separated.
+	#[arg(long, env("SHAKER_MILESTONE_EVERY"), value_delimiter = ',', default_value = "100,1000", value_parser = clap::value_parser!(i64).range(1..))]
+	pub milestone_every: Vec<i64>,
+
+	/// Specific total handshake counts at which a milestone is reported when creating a handshake. May be given
+	/// multiple times or comma-separated.
+	#[arg(long, env("SHAKER_MILESTONE_AT"), value_delimiter = ',')]
+	pub milestone_at: Vec<i64>,
+
+	/// Limits on the fields of submitted handshakes
+	#[command(flatten)]
+	pub field_limits: FieldLimitArgs,
+
+	/// Source to record for handshakes that are submitted without one
+	#[arg(long, env("SHAKER_DEFAULT_SOURCE"))]
+	pub default_source: Option<String>,
+
+	/// Event to tag handshakes that are submitted without one with, such as the name of an event that's running
+	#[arg(long, env("SHAKER_DEFAULT_EVENT"))]
+	pub default_event: Option<String>,
+
+	/// Whether to reject submitted handshakes that have fields a handshake doesn't have (such as a misspelled `world`)
+	/// instead of ignoring them
+	#[arg(long, env("SHAKER_STRICT_REQUESTS"))]
+	pub strict_requests: bool,
+
+	/// Seconds after a handshake that it may still be undone via `POST /users/:id/handshakes/undo`
+	#[arg(long, env("SHAKER_UNDO_WINDOW"), default_value_t = 300)]
+	pub undo_window: u64,
+
+	/// IANA name of the timezone that days start at midnight in for daily stats, such as `America/Los_Angeles`
+	#[arg(long, env("SHAKER_TIMEZONE"), default_value = "UTC", value_parser = parse_timezone)]
+	pub timezone: &'static Tz,
+
+	/// Discord webhook URL to post announcements of milestones, first-time handshakers, and daily summaries to
+	#[arg(long, env("SHAKER_DISCORD_WEBHOOK_URL"), conflicts_with = "read_only")]
+	pub discord_webhook_url: Option<Url>,
+
+	/// Number of handshakes between milestone announcements on Discord
+	#[arg(long, env("SHAKER_DISCORD_MILESTONE_INTERVAL"), default_value_t = 100, value_parser = clap::value_parser!(i64).range(1..))]
+	pub discord_milestone_interval: i64,
+
+	/// Whether to announce users shaking hands for the first time on Discord
+	#[arg(long, env("SHAKER_DISCORD_FIRST_TIME"), default_value_t = true, action = ArgAction::Set)]
+	pub discord_first_time: bool,
+
+	/// Check that the Resonite IDs of submitted handshakes belong to real users via Resonite's public API, rejecting
+	/// handshakes for unknown IDs. Handshakes are still accepted if the API can't be reached.
+	#[arg(long, env("SHAKER_VERIFY_USERS"))]
+	pub verify_users: bool,
+
+	/// Replace the usernames of submitted handshakes with the ones from Resonite's API when verifying users
+	#[arg(long, env("SHAKER_VERIFY_USERS_CANONICAL_NAMES"), requires = "verify_users")]
+	pub verify_users_canonical_names: bool,
+
+	/// Seconds to reuse the result of looking up a user in Resonite's API for
+	#[arg(long, env("SHAKER_VERIFY_USERS_CACHE_TTL"), default_value_t = 3600)]
+	pub verify_users_cache_ttl: u64,
+
+	/// Seconds between passes that update stored usernames to match Resonite's API (also triggered by
+	/// `POST /admin/refresh-names`). If not set, usernames are only updated when users shake hands.
+	#[arg(
+		long,
+		env("SHAKER_REFRESH_NAMES_INTERVAL"),
+		conflicts_with = "read_only",
+		value_parser = clap::value_parser!(u64).range(1..),
+	)]
+	pub refresh_names_interval: Option<u64>,
+
+	/// Maximum number of requests per minute to make to Resonite's API when refreshing usernames
+	#[arg(long, env("SHAKER_REFRESH_NAMES_RATE"), default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+	pub refresh_names_rate: u32,
+
+	/// Days after refreshing a user's username before it's refreshed again
+	#[arg(long, env("SHAKER_REFRESH_NAMES_AFTER"), default_value_t = 7, value_parser = clap::value_parser!(u64).range(1..))]
+	pub refresh_names_after: u64,
+
+	/// Base URL of Resonite's public API
+	#[arg(long, env("SHAKER_RESONITE_API_URL"), default_value = "https://api.resonite.com/")]
+	pub resonite_api_url: Url,
+
+	/// Don't cache the responses of frequently polled endpoints (the user and handshake counts and the username list)
+	/// between changes to the data, for debugging
+	#[arg(long, env("SHAKER_DISABLE_CACHE"))]
+	pub disable_cache: bool,
+
+	/// Serve a Swagger UI page for the API specification (at `/openapi.json`) at `/docs`
+	#[arg(long, env("SHAKER_SWAGGER_UI"))]
+	pub swagger_ui: bool,
+
+	/// Seconds to wait for in-flight requests to finish when shutting down
+	#[arg(long, env("SHAKER_DRAIN_TIMEOUT"), default_value_t = 30)]
+	pub drain_timeout: u64,
+
+	/// Seconds a request may take to be handled before it's aborted with a 504 response
+	#[arg(long, env("SHAKER_REQUEST_TIMEOUT"), default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+	pub request_timeout: u64,
+
+	/// Maximum number of requests that may be handled at once. Any more are turned away with a 503 response.
+	#[arg(long, env("SHAKER_MAX_CONCURRENT_REQUESTS"), default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+	pub max_concurrent_requests: u32,
+
+	/// Format to write logs in. The server's format also applies to other commands when given before them (or set in
+	/// the environment).
+	#[arg(long, env("SHAKER_LOG_FORMAT"), value_enum, default_value_t = LogFormat::Forest)]
+	pub log_format: LogFormat,
+
+	/// Log more: debug logs from Shaker with `-v`, and trace logs with `-vv` (or from everything with `-vvv`). Ignored
+	/// if a filter is set with `SHAKER_LOG` or `RUST_LOG`.
+	#[arg(long, short, action = ArgAction::Count, conflicts_with = "quiet")]
+	pub verbose: u8,
+
+	/// Log less: only warnings with `-q`, and only errors with `-qq`. Ignored if a filter is set with `SHAKER_LOG` or
+	/// `RUST_LOG`.
+	#[arg(long, short, action = ArgAction::Count)]
+	pub quiet: u8,
+
+	/// Path to a file to write logs to as well as the console, such as when running as a service without a console.
+	/// Like the format, this also applies to other commands when given before them.
+	#[arg(long, env("SHAKER_LOG_FILE"))]
+	pub log_file: Option<PathBuf>,
+
+	/// Size in MiB beyond which the log file is rotated, renaming it with a `.1` suffix (and older ones with the next
+	/// number up)
+	#[arg(long, env("SHAKER_LOG_MAX_SIZE"), requires = "log_file", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+	pub log_max_size: u64,
+
+	/// Number of rotated log files to keep, deleting the oldest ones beyond it
+	#[arg(long, env("SHAKER_LOG_KEEP"), requires = "log_file", default_value_t = 5)]
+	pub log_keep: u16,
+
+	/// Only write logs to the log file, not the console
+	#[arg(long, env("SHAKER_LOG_FILE_ONLY"), requires = "log_file")]
+	pub log_file_only: bool,
+
+	/// Token accepted for making requests, optionally prefixed with the scope it grants (`read:`, `write:`, or
+	/// `admin:`). Tokens without a scope grant admin access. May be given multiple times or comma-separated.
+	#[arg(long, short, env("SHAKER_TOKEN"), value_delimiter = ',')]
+	pub token: Vec<ScopedToken>,
+
+	/// Path to a file to read tokens from (one per line, in the same form as `--token`), such as a systemd
+	/// credential or Docker secret
+	#[arg(long, env("SHAKER_TOKEN_FILE"), conflicts_with = "token")]
+	pub token_file: Option<PathBuf>,
+
+	/// Only accept the token via the Authorization header, disabling the `?token=` query parameter fallback
+	#[arg(long, env("SHAKER_HEADER_AUTH_ONLY"))]
+	pub header_auth_only: bool,
+}
+
+/// Options that ran other commands before they were subcommands, kept working for existing setups. They're hidden from
+/// the help, since the subcommands replace them.
+#[derive(Debug, Args)]
+#[allow(clippy::struct_field_names)]
+pub struct LegacyArgs {
+	/// Same as the `import` command
+	#[arg(long, env("SHAKER_IMPORT"), hide = true)]
+	import: Option<PathBuf>,
+
+	/// Same as the `--import-format` option of the `import` command
+	#[arg(long, env("SHAKER_IMPORT_FORMAT"), value_enum, hide = true)]
+	import_format: Option<import::Format>,
+
+	/// Same as the `--add-handshake` option of the `import` command
+	#[arg(long, env("SHAKER_IMPORT_ADD_HANDSHAKE"), hide = true)]
+	import_add_handshake: bool,
+
+	/// Same as the `export` command
+	#[arg(long, env("SHAKER_EXPORT"), hide = true, conflicts_with_all = ["import", "normalize_names", "check_db"])]
+	export: Option<PathBuf>,
+
+	/// Same as the `--format` option of the `export` command
+	#[arg(long, env("SHAKER_EXPORT_FORMAT"), value_enum, hide = true, requires = "export")]
+	export_format: Option<export::Format>,
+
+	/// Same as the `normalize-names` command
+	#[arg(long, env("SHAKER_NORMALIZE_NAMES"), hide = true)]
+	normalize_names: bool,
+
+	/// Same as the `check-db` command
+	#[arg(long, env("SHAKER_CHECK_DB"), hide = true)]
+	check_db: bool,
+}
+
+impl LegacyArgs {
+	/// Determines the command that the options select, serving the API if none of them are set
+	fn command(self, serve: Config) -> Command {
+		if self.check_db {
+			Command::CheckDb
+		} else if let Some(path) = self.export {
+			Command::Export(ExportArgs {
+				path,
+				format: self.export_format,
+				include_notes: false,
+			})
+		} else if let Some(path) = self.import {
+			Command::Import(ImportArgs {
+				path,
+				import_format: self.import_format,
+				add_handshake: self.import_add_handshake,
+				merge: false,
+				strict: false,
+				dry_run: false,
+				format: OutputFormat::Text,
+				field_limits: serve.field_limits,
+			})
+		} else if self.normalize_names {
+			Command::NormalizeNames
+		} else {
+			Command::Serve(Box::new(serve))
+		}
+	}
+}
+
+/// Parses an octal file mode
+fn parse_mode(mode: &str) -> Result<u32, String> {
+	u32::from_str_radix(mode, 8)
+		.ok()
+		.filter(|mode| *mode <= 0o7777)
+		.ok_or_else(|| format!("\"{mode}\" isn't a valid octal file mode"))
+}
+
+/// Parses an IANA timezone name
+fn parse_timezone(name: &str) -> Result<&'static Tz, String> {
+	timezones::get_by_name(name).ok_or_else(|| format!("\"{name}\" isn't a known IANA timezone name"))
+}
+
+/// Runs Shaker's command-line interface: loads the configuration from the command line, environment, and config file,
+/// sets up logging, then runs the chosen command (serving the API by default)
+pub async fn run() -> Result<()> {
+	let cli = Cli::load()?;
+
+	// Logs go to stderr so that commands' output on stdout can be piped into other programs. The subscriber is set up
+	// before anything is logged, so that every line is in the same format. The log file's guard is held until the end,
+	// so that any lines still waiting to be written to the file are flushed.
+	let log_config = cli.serve_config();
+	let (log_format, filter) = (log_config.log_format, log_filter(log_config));
+	let (writer, _log_guard) =
+		logging::writer(log_config, log_format == LogFormat::Forest).context("Unable to open the log file")?;
+	let filter_description = filter.to_string();
+	match log_format {
+		LogFormat::Forest => {
+			tracing_forest::worker_task()
+				.map_receiver(|printer| printer.writer(writer))
+				.build_on(|subscriber| subscriber.with(filter))
+				.on(Box::pin(commands::init(cli, &filter_description)))
+				.await
+		}
+		LogFormat::Json => {
+			tracing_subscriber::fmt()
+				.json()
+				.flatten_event(true)
+				.with_current_span(true)
+				.with_span_list(false)
+				.with_writer(writer)
+				.with_env_filter(filter)
+				.init();
+			commands::init(cli, &filter_description).await
+		}
+	}
+}
+
+/// Filter for which logs to write, taken from `SHAKER_LOG` or `RUST_LOG` if either is set, otherwise from the verbosity
+fn log_filter(cfg: &Config) -> EnvFilter {
+	["SHAKER_LOG", "RUST_LOG"]
+		.into_iter()
+		.find_map(|var| EnvFilter::try_from_env(var).ok())
+		.unwrap_or_else(|| {
+			default_log_filter(cfg)
+				.parse()
+				.expect("Unable to parse default EnvFilter string")
+		})
+}
+
+/// Default filter for which logs to write at the configured verbosity
+fn default_log_filter(cfg: &Config) -> &'static str {
+	match (cfg.verbose, cfg.quiet) {
+		(0, 0) => "warn,shaker=info",
+		(0, 1) => "warn",
+		(0, _) => "error",
+		(1, _) => "warn,shaker=debug",
+		(2, _) => "warn,shaker=trace",
+		_ => "trace",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::CommandFactory;
+
+	use super::*;
+
+	fn parse(args: &[&str]) -> Result<(DatabaseArgs, Command), clap::Error> {
+		Cli::try_parse_from([&["shaker"], args].concat()).map(Cli::into_parts)
+	}
+
+	#[test]
+	fn cli_is_consistent() {
+		Cli::command().debug_assert();
+	}
+
+	#[test]
+	fn serves_without_a_command() {
+		assert!(matches!(parse(&[]).unwrap().1, Command::Serve(_)));
+		let (database, command) = parse(&["--db", "other.db", "--swagger-ui"]).unwrap();
+		assert_eq!(database.db, Path::new("other.db"));
+		assert!(matches!(command, Command::Serve(cfg) if cfg.swagger_ui));
+		assert!(matches!(
+			parse(&["serve", "--swagger-ui"]).unwrap().1,
+			Command::Serve(cfg) if cfg.swagger_ui
+		));
+	}
+
+	#[test]
+	fn legacy_options_select_commands() {
+		assert!(matches!(
+			parse(&["--import", "names.txt"]).unwrap().1,
+			Command::Import(args) if args.path == Path::new("names.txt") && args.import_format.is_none() && !args.add_handshake
+		));
+		assert!(matches!(
+			parse(&["--import", "names.txt", "--import-add-handshake"]).unwrap().1,
+			Command::Import(args) if args.add_handshake
+		));
+		assert!(matches!(
+			parse(&["--export", "dump.json"]).unwrap().1,
+			Command::Export(args) if args.path == Path::new("dump.json") && args.format.is_none()
+		));
+		assert!(matches!(parse(&["--check-db"]).unwrap().1, Command::CheckDb));
+		assert!(matches!(
+			parse(&["--normalize-names"]).unwrap().1,
+			Command::NormalizeNames
+		));
+	}
+
+	#[test]
+	fn stats_print_text_unless_asked_for_json() {
+		assert!(matches!(
+			parse(&["stats", "--db", "shaker.db"]).unwrap().1,
+			Command::Stats(StatsArgs {
+				format: OutputFormat::Text
+			})
+		));
+		assert!(matches!(
+			parse(&["stats", "--format", "json"]).unwrap().1,
+			Command::Stats(StatsArgs {
+				format: OutputFormat::Json
+			})
+		));
+	}
+
+	#[test]
+	fn database_options_apply_to_every_command() {
+		let (database, command) = parse(&["import", "names.txt", "--db", "other.db"]).unwrap();
+		assert_eq!(database.db, Path::new("other.db"));
+		assert!(matches!(command, Command::Import(args) if args.path == Path::new("names.txt")));
+
+		let (database, command) = parse(&["--read-only", "export", "dump", "--format", "csv"]).unwrap();
+		assert!(database.read_only);
+		assert!(matches!(command, Command::Export(args) if args.format == Some(export::Format::Csv)));
+	}
+
+	#[test]
+	fn read_only_conflicts_with_server_options() {
+		assert!(parse(&["--read-only", "--webhook-url", "http://localhost/", "serve"]).is_err());
+		assert!(parse(&["serve", "--read-only", "--webhook-url", "http://localhost/"]).is_err());
+	}
+
+	#[test]
+	fn merges_need_both_users_or_names() {
+		let (_, Command::MergeUsers(args)) = parse(&["merge-users", "--from", "42", "--to", "7", "--dry-run"]).unwrap()
+		else {
+			panic!("expected the merge-users command");
+		};
+		assert_eq!((args.from, args.to), (Some(42), Some(7)));
+		assert!(args.dry_run && !args.yes);
+
+		assert!(matches!(
+			parse(&["merge-users", "--by-name", "-y"]).unwrap().1,
+			Command::MergeUsers(MergeUsersArgs {
+				by_name: true,
+				yes: true,
+				..
+			})
+		));
+		assert!(parse(&["merge-users"]).is_err());
+		assert!(parse(&["merge-users", "--from", "42"]).is_err());
+		assert!(parse(&["merge-users", "--by-name", "--to", "7"]).is_err());
+	}
+
+	#[test]
+	fn log_format_prefers_the_serve_command() {
+		let format = |args: &[&str]| {
+			Cli::try_parse_from([&["shaker"], args].concat())
+				.unwrap()
+				.serve_config()
+				.log_format
+		};
+		assert_eq!(format(&[]), LogFormat::Forest);
+		assert_eq!(format(&["--log-format", "json", "stats"]), LogFormat::Json);
+		assert_eq!(format(&["serve", "--log-format", "json"]), LogFormat::Json);
+	}
+
+	#[test]
+	fn verbosity_adjusts_the_default_log_filter() {
+		let filter = |args: &[&str]| {
+			let cli = Cli::try_parse_from([&["shaker"], args].concat()).unwrap();
+			default_log_filter(cli.serve_config())
+		};
+		assert_eq!(filter(&[]), "warn,shaker=info");
+		assert_eq!(filter(&["-v"]), "warn,shaker=debug");
+		assert_eq!(filter(&["serve", "-vv"]), "warn,shaker=trace");
+		assert_eq!(filter(&["-q", "stats"]), "warn");
+		assert_eq!(filter(&["--quiet", "--quiet"]), "error");
+		assert!(Cli::try_parse_from(["shaker", "-v", "-q"]).is_err());
+	}
+
+	#[test]
+	fn configs_are_validated() {
+		let cli = Cli::try_parse_from([
+			"shaker",
+			"--metrics",
+			"127.0.0.1:9001",
+			"--token-file",
+			"/nonexistent/tokens",
+		])
+		.unwrap();
+		let problems = cli.serve.validate(false);
+		assert_eq!(problems.len(), 2, "{problems:?}");
+		assert!(problems[0].starts_with("Metrics address"));
+		assert!(problems[1].starts_with("Token file /nonexistent/tokens isn't readable"));
+
+		let cli = Cli::try_parse_from(["shaker", "--purge-deleted-after", "30"]).unwrap();
+		assert!(cli.serve.validate(false).is_empty());
+		assert_eq!(cli.serve.validate(true).len(), 1);
+
+		let cli = Cli::try_parse_from(["shaker", "--db", "/nonexistent/shaker.db"]).unwrap();
+		assert_eq!(
+			cli.database.validate(),
+			["Database directory /nonexistent doesn't exist"]
+		);
+	}
+
+	#[test]
+	fn backfill_ids_is_parsed() {
+		let (_, Command::BackfillIds(args)) = parse(&["backfill-ids", "--dry-run", "--rate", "10"]).unwrap() else {
+			panic!("expected the backfill-ids command");
+		};
+		assert!(args.dry_run && !args.retry);
+		assert_eq!(args.rate, 10);
+		assert!(parse(&["backfill-ids", "--rate", "0"]).is_err());
+	}
+}
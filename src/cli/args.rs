@@ -0,0 +1,189 @@
+use std::path::PathBuf;
+
+use clap::{Args, ValueEnum};
+use reqwest::Url;
+
+use crate::{
+	export, import,
+	validate::{FieldLimits, OverlongPolicy},
+};
+
+/// Options for importing handshakes
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ImportArgs {
+	/// Path to a file to import past handshakes from
+	#[arg(env("SHAKER_IMPORT"))]
+	pub path: PathBuf,
+
+	/// Format of the file. If not set, files with a `.csv` extension are CSV, those with a `.ndjson` or `.jsonl`
+	/// extension are NDJSON exports, and anything else is plain text.
+	#[arg(long, env("SHAKER_IMPORT_FORMAT"), value_enum)]
+	pub import_format: Option<import::Format>,
+
+	/// Add a legacy handshake to users that already exist if they don't have one yet, rather than skipping them
+	#[arg(long, env("SHAKER_IMPORT_ADD_HANDSHAKE"))]
+	pub add_handshake: bool,
+
+	/// Restore an NDJSON export even if the database already has data, using users that already exist instead of
+	/// creating them again and skipping any handshakes they already have
+	#[arg(long)]
+	pub merge: bool,
+
+	/// Fail if any records were skipped for already existing, not just if any couldn't be imported
+	#[arg(long)]
+	pub strict: bool,
+
+	/// Go through the whole import without writing anything to the database, printing what would be done (including
+	/// which users would be created or skipped). Pending migrations aren't run, so there must not be any.
+	#[arg(long)]
+	pub dry_run: bool,
+
+	/// Format to print the summary of the import in (it's also logged either way)
+	#[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+	pub format: OutputFormat,
+
+	/// Limits on the fields of imported handshakes
+	#[command(flatten)]
+	pub field_limits: FieldLimitArgs,
+}
+
+/// Options for the limits on the fields of handshakes, which apply both to submitted and imported ones
+#[derive(Debug, Args)]
+pub struct FieldLimitArgs {
+	/// Maximum number of characters in Resonite user IDs (up to 64), which are always rejected rather than truncated if
+	/// they're longer
+	#[arg(long, env("SHAKER_MAX_ID_LENGTH"), default_value_t = 64, value_parser = clap::value_parser!(u16).range(1..=64))]
+	pub max_id_length: u16,
+
+	/// Maximum number of characters in usernames
+	#[arg(long, env("SHAKER_MAX_NAME_LENGTH"), default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+	pub max_name_length: u16,
+
+	/// Maximum number of characters in world names. If not set, `--max-field-length` is used.
+	#[arg(long, env("SHAKER_MAX_WORLD_LENGTH"), value_parser = clap::value_parser!(u16).range(1..))]
+	pub max_world_length: Option<u16>,
+
+	/// Maximum number of characters in sources and events, and in world names unless `--max-world-length` is set
+	#[arg(long, env("SHAKER_MAX_FIELD_LENGTH"), default_value_t = 256, value_parser = clap::value_parser!(u16).range(1..))]
+	pub max_field_length: u16,
+
+	/// What to do with usernames, world names, sources, and events that are longer than the maximum
+	#[arg(long, env("SHAKER_OVERLONG_FIELDS"), value_enum, default_value_t = OverlongPolicy::Truncate)]
+	pub overlong_fields: OverlongPolicy,
+}
+
+impl FieldLimitArgs {
+	/// Limits on the fields of handshakes as configured
+	#[must_use]
+	pub fn limits(&self) -> FieldLimits {
+		FieldLimits {
+			id: self.max_id_length.into(),
+			name: self.max_name_length.into(),
+			world: self.max_world_length.unwrap_or(self.max_field_length).into(),
+			source: self.max_field_length.into(),
+			event: self.max_field_length.into(),
+			policy: self.overlong_fields,
+		}
+	}
+}
+
+/// Options for exporting data
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+	/// Path to export to. JSON exports are a single document, while CSV exports are split into a file of users and a
+	/// file of handshakes alongside the path (with `-users` and `-handshakes` added to its name).
+	#[arg(env("SHAKER_EXPORT"))]
+	pub path: PathBuf,
+
+	/// Format to export in. If not set, it's determined by the path's extension.
+	#[arg(long, env("SHAKER_EXPORT_FORMAT"), value_enum)]
+	pub format: Option<export::Format>,
+
+	/// Include the notes that admins have left on users in the export
+	#[arg(long, env("SHAKER_EXPORT_INCLUDE_NOTES"))]
+	pub include_notes: bool,
+}
+
+/// Options for printing statistics
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+	/// Format to print the statistics in
+	#[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+	pub format: OutputFormat,
+}
+
+/// Options for looking up a user
+#[derive(Debug, Args)]
+pub struct UserArgs {
+	/// Database ID, Resonite ID (starting with `U-`), or current or past username of the user
+	pub query: String,
+
+	/// Format to print the user in
+	#[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+	pub format: OutputFormat,
+}
+
+/// Options for merging users
+#[derive(Debug, Args)]
+pub struct MergeUsersArgs {
+	/// ID of the user to merge into another, which is deleted once merged
+	#[arg(long, requires = "to", required_unless_present = "by_name")]
+	pub from: Option<i64>,
+
+	/// ID of the user to merge the other into, which is kept
+	#[arg(long, requires = "from")]
+	pub to: Option<i64>,
+
+	/// Merge each group of users whose names are the same when ignoring case into the oldest user of the group
+	#[arg(long, conflicts_with_all = ["from", "to"])]
+	pub by_name: bool,
+
+	/// Merge without asking for confirmation
+	#[arg(long, short)]
+	pub yes: bool,
+
+	/// Print the plan without merging anything
+	#[arg(long)]
+	pub dry_run: bool,
+}
+
+/// Options for backfilling Resonite IDs
+#[derive(Debug, Args)]
+pub struct BackfillIdsArgs {
+	/// Maximum number of requests per minute to make to Resonite's API
+	#[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+	pub rate: u32,
+
+	/// Look up users again whose names matched several Resonite users or none the last time
+	#[arg(long)]
+	pub retry: bool,
+
+	/// Print what would be filled in without changing anything
+	#[arg(long)]
+	pub dry_run: bool,
+
+	/// Base URL of Resonite's public API
+	#[arg(long, env("SHAKER_RESONITE_API_URL"), default_value = "https://api.resonite.com/")]
+	pub resonite_api_url: Url,
+}
+
+/// Format to write logs in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+	/// Human-readable trees of spans and their events
+	Forest,
+
+	/// One JSON object per event, for log aggregators
+	Json,
+}
+
+/// Format for commands to print their results in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+	/// Human-readable text
+	Text,
+
+	/// JSON, for scripts
+	Json,
+}
@@ -0,0 +1,598 @@
+use std::{
+	io::{self, Write},
+	path::Path,
+	time::{Duration, Instant},
+};
+
+use ::time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use anyhow::{Context, Result};
+use serde_json::json;
+use tokio::{fs, time};
+use tracing::{info, warn};
+
+use super::{BackfillIdsArgs, Cli, Command, Config, ExportArgs, ImportArgs, MergeUsersArgs, OutputFormat};
+use crate::{api, auth, backup, db, export, import, resonite};
+
+/// Maximum time to spend counting users and handshakes for the database summary logged at startup
+const SUMMARY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Initialize the app
+pub async fn init(cli: Cli, log_filter: &str) -> Result<()> {
+	info!("Starting Shaker, logging with filter {log_filter}");
+	cli.emit_dotenv_info();
+	cli.emit_config_file_info();
+	if cli.check {
+		return check_config(&cli).await;
+	}
+
+	let (database, command) = cli.into_parts();
+	let mut problems = database.validate();
+	if let Command::Serve(cfg) = &command {
+		problems.extend(database.validate_backups(cfg));
+	}
+	if !problems.is_empty() {
+		anyhow::bail!("{}", problems.join("; "));
+	}
+	if database.read_only
+		&& matches!(
+			command,
+			Command::Import(_) | Command::NormalizeNames | Command::MergeUsers(_) | Command::BackfillIds(_)
+		) {
+		anyhow::bail!("Unable to modify the database while it's read-only");
+	}
+	let db = database.open().await?;
+
+	// Integrity checks, stats, and lookups only read the database, so they're run before (and without) any migrations
+	if let Command::CheckDb | Command::Stats(_) | Command::User(_) = &command {
+		let result = match command {
+			Command::Stats(args) => print_stats(&db, args.format).await,
+			Command::User(args) => print_user(&db, &args.query, args.format).await,
+			_ => check_db(&db).await,
+		};
+		db.close().await;
+		return result;
+	}
+
+	// Run pending migrations (backing up first if requested), or just make sure there aren't any if it's read-only
+	if let Command::Serve(cfg) = &command {
+		if let (true, Some(dir)) = (cfg.backup_on_start, &cfg.backup_dir) {
+			backup::create(&db, dir, None).await?;
+		}
+	}
+	if database.read_only {
+		db.ensure_migrated().await?;
+		info!("Database is read-only; requests that would write to it will be rejected");
+	} else if let Command::Import(ImportArgs { dry_run: true, .. })
+	| Command::BackfillIds(BackfillIdsArgs { dry_run: true, .. }) = &command
+	{
+		db.ensure_migrated().await?;
+	} else {
+		db.migrate().await?;
+	}
+	log_database_summary(&database.db, &db).await?;
+	report_duplicate_names(&db).await?;
+
+	let result = match command {
+		Command::Serve(cfg) => return serve(*cfg, db).await,
+		Command::Import(args) => import(&args, &db).await,
+		Command::Export(args) => export_to_file(&args, &db).await,
+		Command::NormalizeNames => normalize_names(&db).await,
+		Command::MergeUsers(args) => merge_users(&db, &args).await,
+		Command::BackfillIds(args) => backfill_ids(&db, args).await,
+		Command::CheckDb | Command::Stats(_) | Command::User(_) => {
+			unreachable!("read-only commands are run before migrating")
+		}
+	};
+	db.close().await;
+	result
+}
+
+/// Checks the configuration without running anything or changing the database, printing a report and failing if any
+/// problems were found. The database is opened read-only (if it exists) to report any pending migrations.
+async fn check_config(cli: &Cli) -> Result<()> {
+	let database = &cli.database;
+	let config = cli.serve_config();
+	let mut problems = database.validate();
+	problems.extend(database.validate_backups(config));
+	problems.extend(config.validate(database.read_only));
+
+	let location = db::DatabaseLocation::parse(&database.db);
+	let exists = match location {
+		db::DatabaseLocation::Sqlite(path) => path.exists(),
+		_ => true,
+	};
+	if exists {
+		match database.open_as(true).await {
+			Ok(db) => {
+				let pending = db.pending_migrations().await;
+				db.close().await;
+				match pending {
+					Ok(pending) if pending.is_empty() => {
+						println!("Database {location} is up to date");
+					}
+					Ok(pending) => println!(
+						"Database {location} has {} pending migration(s): {}",
+						pending.len(),
+						pending.join(", ")
+					),
+					Err(err) => problems.push(format!("Unable to check the database's migrations: {err:#}")),
+				}
+			}
+			Err(err) => problems.push(format!("Unable to open the database: {err:#}")),
+		}
+	} else if !database.read_only {
+		println!("Database {location} doesn't exist yet, and will be created");
+	}
+
+	if problems.is_empty() {
+		println!("Configuration is valid");
+		return Ok(());
+	}
+	for problem in &problems {
+		println!("Problem: {problem}");
+	}
+	anyhow::bail!("Found {} problem(s) with the configuration", problems.len())
+}
+
+/// Runs the API server, then closes the database once it has stopped
+async fn serve(mut cfg: Config, db: db::Database) -> Result<()> {
+	let problems = cfg.validate(db.is_read_only());
+	if !problems.is_empty() {
+		anyhow::bail!("{}", problems.join("; "));
+	}
+
+	// Load tokens from a file if one was given
+	if let Some(path) = &cfg.token_file {
+		cfg.token = auth::tokens_from_file(path).await?;
+		info!("Loaded {} token(s) from {}", cfg.token.len(), path.display());
+	}
+
+	let tokens = auth::TokenRegistry::new(&cfg.token);
+	let listener = api::Listener::from_config(&cfg)?;
+	let options = api::ServeOptions::from_config(&cfg)?;
+	let state = api::AppState::from_config(&cfg, tokens, db.clone())?;
+
+	// Requests that outlived the drain timeout may still be holding connections, so don't wait on them forever
+	api::run(options, listener, state).await?;
+	if time::timeout(Duration::from_secs(5), db.close()).await.is_err() {
+		warn!("Timed out waiting for database connections to be released");
+	}
+
+	Ok(())
+}
+
+/// Imports legacy handshake data from a file, printing a summary of the outcome and failing if any records couldn't be
+/// imported (or if any users were skipped, when strict)
+#[tracing::instrument("Importing legacy handshakes", level = "info", skip_all, fields(path = %args.path.display()))]
+async fn import(args: &ImportArgs, db: &db::Database) -> Result<()> {
+	let content = fs::read_to_string(&args.path)
+		.await
+		.with_context(|| format!("Unable to read {}", args.path.display()))?;
+	let format = args
+		.import_format
+		.unwrap_or_else(|| import::Format::from_path(&args.path));
+	let options = import::Options {
+		add_handshake: args.add_handshake,
+		merge: args.merge,
+		dry_run: args.dry_run,
+		limits: args.field_limits.limits(),
+	};
+	let summary = import::from_str(db, &content, format, options).await?;
+
+	if args.dry_run {
+		info!("Dry run; nothing was written to the database");
+		if args.format == OutputFormat::Text {
+			for (label, names) in [
+				("Would create", &summary.created_names),
+				("Would add handshakes to", &summary.added_handshake_names),
+				("Would skip", &summary.skipped_names),
+			] {
+				for name in names {
+					println!("{label}: {name}");
+				}
+			}
+		}
+	}
+	info!(
+		"Created {} user(s) and {} handshake(s), skipped {} existing record(s) and {} blank line(s), and failed to import \
+		 {} record(s)",
+		summary.users_created,
+		summary.handshakes_created,
+		summary.skipped,
+		summary.blank,
+		summary.failures.len()
+	);
+	if args.format == OutputFormat::Json {
+		println!("{}", serde_json::to_string_pretty(&summary)?);
+	}
+
+	if !summary.failures.is_empty() {
+		anyhow::bail!("{} record(s) couldn't be imported", summary.failures.len());
+	}
+	if args.strict && summary.skipped > 0 {
+		anyhow::bail!("{} record(s) were skipped for already existing", summary.skipped);
+	}
+	Ok(())
+}
+
+/// Exports all data to a file (or files), printing a summary of what was written
+async fn export_to_file(args: &ExportArgs, db: &db::Database) -> Result<()> {
+	let path = &args.path;
+	let format = args
+		.format
+		.or_else(|| export::Format::from_path(path))
+		.with_context(|| {
+			format!(
+				"Unable to tell which format to export to {} in from its extension; use --export-format",
+				path.display()
+			)
+		})?;
+
+	let start = Instant::now();
+	let summary = export::to_file(db, path, format, args.include_notes).await?;
+	let files: Vec<_> = summary.files.iter().map(|file| file.display().to_string()).collect();
+	println!(
+		"Exported {} row(s) ({} bytes) to {} in {:.2?}",
+		summary.rows,
+		summary.bytes,
+		files.join(" and "),
+		start.elapsed()
+	);
+	Ok(())
+}
+
+/// Normalizes existing usernames and reports the outcome
+async fn normalize_names(db: &db::Database) -> Result<()> {
+	let report = db.normalize_user_names().await?;
+	info!("Normalized {} username(s)", report.updated);
+
+	for collision in &report.collisions {
+		warn!(
+			"Not normalizing users {:?}, since they would all be named \"{}\"; merge them manually if they're the same \
+			 person",
+			collision.user_ids, collision.name
+		);
+	}
+	if !report.emptied.is_empty() {
+		warn!(
+			"Not normalizing users {:?}, since their names would be empty",
+			report.emptied
+		);
+	}
+
+	Ok(())
+}
+
+/// Merges users as asked, printing the plan of merges and asking for confirmation before making them (unless told not
+/// to), then printing a summary of what was merged
+async fn merge_users(db: &db::Database, args: &MergeUsersArgs) -> Result<()> {
+	let plan = plan_merges(db, args).await?;
+	if plan.is_empty() {
+		println!("No users to merge");
+		return Ok(());
+	}
+
+	println!("Merge plan:");
+	for (from, to) in &plan {
+		println!(
+			"  User {} (\"{}\") into user {} (\"{}\")",
+			from.id, from.resonite_name, to.id, to.resonite_name
+		);
+	}
+	if args.dry_run {
+		println!("Dry run; nothing was merged");
+		return Ok(());
+	}
+	if !args.yes && !confirm(&format!("Merge {} user(s)?", plan.len()))? {
+		println!("Cancelled; nothing was merged");
+		return Ok(());
+	}
+
+	let mut removed = 0;
+	let mut handshakes = 0;
+	for (from, to) in &plan {
+		if let Some(merged) = db.merge_users(from.id, to.id).await? {
+			removed += 1;
+			handshakes += merged.handshakes;
+		} else {
+			warn!(
+				"Not merging user {} into user {}, since one of them no longer exists",
+				from.id, to.id
+			);
+		}
+	}
+	println!("Merged away {removed} user(s), reassigning {handshakes} handshake(s)");
+
+	Ok(())
+}
+
+/// Determines the pairs of users to merge (the first of each pair into the second), either from the given IDs or from
+/// groups of users with duplicate names
+async fn plan_merges(db: &db::Database, args: &MergeUsersArgs) -> Result<Vec<(db::User, db::User)>> {
+	if let (Some(from), Some(to)) = (args.from, args.to) {
+		if from == to {
+			anyhow::bail!("Unable to merge user {from} into itself");
+		}
+		let from = db
+			.get_user(from)
+			.await?
+			.with_context(|| format!("No user has ID {from}"))?;
+		let to = db.get_user(to).await?.with_context(|| format!("No user has ID {to}"))?;
+		return Ok(vec![(from, to)]);
+	}
+
+	let mut plan = Vec::new();
+	for duplicate in db.find_duplicate_names().await? {
+		let mut users = Vec::with_capacity(duplicate.user_ids.len());
+		for id in duplicate.user_ids {
+			users.extend(db.get_user(id).await?);
+		}
+
+		users.sort_by_key(|user| (user.created_at, user.id));
+		let mut users = users.into_iter();
+		if let Some(oldest) = users.next() {
+			plan.extend(users.map(|user| (user, oldest.clone())));
+		}
+	}
+	Ok(plan)
+}
+
+/// Backfills missing Resonite IDs as asked, then prints a report of the users whose IDs were (or weren't) found, even
+/// if the backfill stopped early
+async fn backfill_ids(db: &db::Database, args: BackfillIdsArgs) -> Result<()> {
+	let resonite = resonite::Resonite::new(args.resonite_api_url, Duration::ZERO, false)?;
+	let mut backfill = resonite::IdBackfill::new(db.clone(), resonite, args.rate, args.dry_run, args.retry);
+	let result = backfill.run().await;
+
+	let report = &backfill.report;
+	let filled = report
+		.iter()
+		.filter(|(_, outcome)| matches!(outcome, resonite::BackfillOutcome::Filled(_)))
+		.count();
+	if report.is_empty() && result.is_ok() {
+		println!("No users are left to look up");
+	}
+	for (user, outcome) in report {
+		match outcome {
+			resonite::BackfillOutcome::Filled(id) => {
+				println!("Filled:    user {} (\"{}\") is {id}", user.id, user.resonite_name);
+			}
+			resonite::BackfillOutcome::Ambiguous(count) => println!(
+				"Ambiguous: user {} (\"{}\") matches {count} Resonite users",
+				user.id, user.resonite_name
+			),
+			resonite::BackfillOutcome::NotFound => println!(
+				"Not found: user {} (\"{}\") matches no Resonite users",
+				user.id, user.resonite_name
+			),
+			resonite::BackfillOutcome::Conflict { resonite_id, user_id } => println!(
+				"Conflict:  user {} (\"{}\") is {resonite_id}, which user {user_id} already has",
+				user.id, user.resonite_name
+			),
+		}
+	}
+	if !report.is_empty() {
+		println!(
+			"{} {filled} of {} user(s) looked up",
+			if args.dry_run { "Dry run; would fill" } else { "Filled" },
+			report.len()
+		);
+	}
+
+	result.context("Backfill stopped early; run it again to continue")
+}
+
+/// Asks a yes/no question on the terminal, taking anything other than yes as no
+fn confirm(question: &str) -> Result<bool> {
+	print!("{question} [y/N] ");
+	io::stdout().flush()?;
+
+	let mut answer = String::new();
+	io::stdin().read_line(&mut answer)?;
+	Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Checks the integrity of the database, printing the results and failing if there are any problems
+async fn check_db(db: &db::Database) -> Result<()> {
+	let report = db.integrity_check().await?;
+	if report.ok {
+		println!("ok");
+		return Ok(());
+	}
+
+	let mut problems = 0;
+	for message in report.integrity.iter().filter(|message| *message != "ok") {
+		println!("{message}");
+		problems += 1;
+	}
+	for violation in &report.foreign_key_violations {
+		println!("{violation}");
+		problems += 1;
+	}
+	anyhow::bail!("Database integrity check found {problems} problem(s)");
+}
+
+/// Warns about any migrations that haven't been applied to the database, for commands that only read it and so leave
+/// them for the server to run
+async fn warn_pending_migrations(db: &db::Database) -> Result<()> {
+	let pending = db.pending_migrations().await?;
+	if !pending.is_empty() {
+		warn!(
+			"Database has {} pending migration(s), which will run the next time the server starts: {}",
+			pending.len(),
+			pending.join(", ")
+		);
+	}
+	Ok(())
+}
+
+/// Prints overall statistics about the database
+async fn print_stats(db: &db::Database, format: OutputFormat) -> Result<()> {
+	warn_pending_migrations(db).await?;
+	let stats = db.stats(5).await?;
+	match format {
+		OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+		OutputFormat::Text => {
+			let newest = stats.newest_handshake_at.map(format_time).transpose()?;
+			println!("Users:            {}", stats.users);
+			println!("Handshakes:       {}", stats.handshakes);
+			if stats.archived_handshakes > 0 {
+				println!("Archived:         {}", stats.archived_handshakes);
+			}
+			println!("Worlds:           {}", stats.worlds);
+			println!("Newest handshake: {}", newest.as_deref().unwrap_or("never"));
+			println!("Top users:");
+			for (rank, user) in stats.top_users.iter().enumerate() {
+				println!("  {}. {} ({})", rank + 1, user.resonite_name, user.count);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Prints the details and most recent handshakes of the user that a query refers to, or lists the candidates if it's a
+/// name that several users have
+async fn print_user(db: &db::Database, query: &str, format: OutputFormat) -> Result<()> {
+	warn_pending_migrations(db).await?;
+
+	let users = find_users(db, query).await?;
+	let [user] = users.as_slice() else {
+		if users.is_empty() {
+			anyhow::bail!("No user matches \"{query}\"");
+		}
+
+		match format {
+			OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&json!({ "candidates": users }))?),
+			OutputFormat::Text => {
+				println!(
+					"\"{query}\" matches {} users; look one up by its ID instead:",
+					users.len()
+				);
+				println!("  {:<8} {:<24} Name", "ID", "Resonite ID");
+				for user in &users {
+					println!(
+						"  {:<8} {:<24} {}",
+						user.id,
+						user.resonite_id.as_deref().unwrap_or("-"),
+						user.resonite_name
+					);
+				}
+			}
+		}
+		return Ok(());
+	};
+
+	let count = db.count_user_handshakes(user.id).await?;
+	let recent = db.get_user_recent_handshakes(user.id, 5).await?;
+	match format {
+		OutputFormat::Json => println!(
+			"{}",
+			serde_json::to_string_pretty(&json!({
+				"user": user,
+				"handshakes": count,
+				"recent_handshakes": recent,
+			}))?
+		),
+		OutputFormat::Text => {
+			let last_seen = user.last_seen_at.map(format_time).transpose()?;
+			println!("User {}", user.id);
+			println!("  Resonite ID: {}", user.resonite_id.as_deref().unwrap_or("-"));
+			println!("  Name:        {}", user.resonite_name);
+			println!("  Created:     {}", format_time(user.created_at)?);
+			println!("  Last seen:   {}", last_seen.as_deref().unwrap_or("never"));
+			println!("  Legacy:      {}", if user.legacy { "yes" } else { "no" });
+			println!("  Handshakes:  {count}");
+
+			if !recent.is_empty() {
+				println!();
+				println!("Recent handshakes:");
+				println!("  {:<8} {:<21} {:<24} Source", "ID", "Created", "World");
+				for shake in &recent {
+					println!(
+						"  {:<8} {:<21} {:<24} {}",
+						shake.id,
+						format_time(shake.created_at)?,
+						shake.world_name.as_deref().unwrap_or("-"),
+						shake.source.as_deref().unwrap_or("-")
+					);
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Finds the users that a query refers to. Queries that are numbers are tried as database IDs first, queries that start
+/// with `U-` are Resonite IDs, and anything else is a username, current or past (in that order).
+async fn find_users(db: &db::Database, query: &str) -> Result<Vec<db::User>> {
+	if let Ok(id) = query.parse() {
+		if let Some(user) = db.get_user(id).await? {
+			return Ok(vec![user]);
+		}
+	}
+
+	if query.starts_with("U-") {
+		return Ok(db.get_user_by_resonite_id(query).await?.into_iter().collect());
+	}
+
+	let users = db.get_users_by_resonite_name(query).await?;
+	if !users.is_empty() {
+		return Ok(users);
+	}
+	Ok(db.get_user_by_past_resonite_name(query).await?.into_iter().collect())
+}
+
+/// Formats a date/time as RFC 3339 for printing
+fn format_time(time: OffsetDateTime) -> Result<String> {
+	Ok(time.format(&Rfc3339)?)
+}
+
+/// Logs a summary of the database, so that it's easy to tell whether the right one is in use. The counts are skipped
+/// if they take too long, such as on a huge database.
+#[tracing::instrument("Database summary", level = "info", skip_all)]
+async fn log_database_summary(path: &Path, db: &db::Database) -> Result<()> {
+	let location = db::DatabaseLocation::parse(path);
+	if let db::DatabaseLocation::Sqlite(path) = location {
+		let size = fs::metadata(path).await.map(|meta| meta.len()).unwrap_or_default();
+		info!("Path: {} ({size} bytes)", path.display());
+	} else {
+		info!("URL: {location}");
+	}
+	let version = db.schema_version().await?;
+	info!(
+		"Schema version: {}",
+		version.map_or_else(|| "none (never migrated)".to_owned(), |version| version.to_string())
+	);
+	if let db::DatabaseLocation::Sqlite(_) = location {
+		info!("Journal mode: {}", db.journal_mode().await?);
+	}
+
+	let Ok(stats) = time::timeout(SUMMARY_TIMEOUT, db.stats(0)).await else {
+		info!(
+			"Skipped counting users and handshakes, since it took longer than {}s",
+			SUMMARY_TIMEOUT.as_secs()
+		);
+		return Ok(());
+	};
+	let stats = stats?;
+	info!("Users: {}", stats.users);
+	info!("Handshakes: {}", stats.handshakes);
+	let newest = stats.newest_handshake_at.map(format_time).transpose()?;
+	info!("Newest handshake: {}", newest.as_deref().unwrap_or("none"));
+
+	Ok(())
+}
+
+/// Warns about any users whose names are duplicates of each other when compared without regard to case, since lookups
+/// by name can only ever find one of them
+async fn report_duplicate_names(db: &db::Database) -> Result<()> {
+	for duplicate in db.find_duplicate_names().await? {
+		warn!(
+			"Users {:?} are all named \"{}\" when ignoring case; merge them manually if they're the same person",
+			duplicate.user_ids, duplicate.name
+		);
+	}
+	Ok(())
+}
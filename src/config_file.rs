@@ -0,0 +1,212 @@
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::{Arg, Command};
+use toml::{Table, Value};
+
+/// Result of loading a config file
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+	/// Path the file was loaded from
+	pub path: PathBuf,
+
+	/// Keys in the file that don't correspond to any option that can be set in it, and were ignored
+	pub unknown_keys: Vec<String>,
+}
+
+/// Loads a TOML config file, making the values of the options in it the defaults of those options in a command-line
+/// parser. Options are named by their long name with either dashes or underscores (such as `webhook_url`), and lists
+/// are given as arrays. Only options that can also be set by environment variables can be set in the file. Since the
+/// values are only defaults, the environment (and the command line) take precedence over the file.
+pub fn load(path: &Path, command: Command) -> anyhow::Result<(Command, ConfigFile)> {
+	let contents =
+		std::fs::read_to_string(path).with_context(|| format!("Unable to read config file {}", path.display()))?;
+	let table: Table = contents
+		.parse()
+		.with_context(|| format!("Unable to parse config file {}", path.display()))?;
+
+	let options = option_names(&command);
+	let defaults = defaults(&table, &options)?;
+	let file = ConfigFile {
+		path: path.to_owned(),
+		unknown_keys: unknown_keys(&table, &options),
+	};
+	Ok((apply_defaults(command, &defaults), file))
+}
+
+/// Maps the names of options that can be set in a config file (with both dashes and underscores) to their long names,
+/// across the command and all of its subcommands
+fn option_names(command: &Command) -> HashMap<String, String> {
+	let mut names = HashMap::new();
+	let mut commands = vec![command];
+	while let Some(command) = commands.pop() {
+		commands.extend(command.get_subcommands());
+		for arg in command.get_arguments().filter(|arg| arg.get_env().is_some()) {
+			if let Some(long) = arg.get_long() {
+				names.insert(long.to_owned(), long.to_owned());
+				names.insert(long.replace('-', "_"), long.to_owned());
+			}
+		}
+	}
+	names
+}
+
+/// Determines the default values to give options (by their long names) for the values in a config file, skipping any
+/// keys that aren't options
+fn defaults(table: &Table, options: &HashMap<String, String>) -> anyhow::Result<Vec<(String, Vec<String>)>> {
+	let mut defaults = Vec::new();
+	for (key, value) in table {
+		let Some(long) = options.get(key) else {
+			continue;
+		};
+
+		let values = match value {
+			Value::Array(values) => values
+				.iter()
+				.map(|value| scalar(key, value))
+				.collect::<anyhow::Result<Vec<_>>>()?,
+			value => vec![scalar(key, value)?],
+		};
+		defaults.push((long.clone(), values));
+	}
+	Ok(defaults)
+}
+
+/// Gives the options of a command and all of its subcommands default values, by their long names. The values are left
+/// out of the help, since they can be secrets.
+fn apply_defaults(mut command: Command, defaults: &[(String, Vec<String>)]) -> Command {
+	let ids: Vec<_> = command
+		.get_arguments()
+		.filter_map(|arg| {
+			let (_, values) = defaults
+				.iter()
+				.find(|(long, _)| arg.get_long() == Some(long.as_str()))?;
+			Some((arg.get_id().clone(), values.clone()))
+		})
+		.collect();
+	for (id, values) in ids {
+		command = command.mut_arg(id, |arg: Arg| {
+			// Hiding the default of a flag that doesn't take a value is an error
+			let hide = arg.get_action().takes_values();
+			arg.default_values(values).hide_default_value(hide)
+		});
+	}
+
+	let subcommands: Vec<_> = command
+		.get_subcommands()
+		.map(|subcommand| subcommand.get_name().to_owned())
+		.collect();
+	for name in subcommands {
+		command = command.mut_subcommand(name, |subcommand| apply_defaults(subcommand, defaults));
+	}
+	command
+}
+
+/// Lists the keys in a config file that aren't options
+fn unknown_keys(table: &Table, options: &HashMap<String, String>) -> Vec<String> {
+	table
+		.keys()
+		.filter(|key| !options.contains_key(*key))
+		.cloned()
+		.collect()
+}
+
+/// Formats a single value of an option as it would be given on the command line
+fn scalar(key: &str, value: &Value) -> anyhow::Result<String> {
+	match value {
+		Value::String(value) => Ok(value.clone()),
+		Value::Integer(value) => Ok(value.to_string()),
+		Value::Float(value) => Ok(value.to_string()),
+		Value::Boolean(value) => Ok(value.to_string()),
+		Value::Datetime(value) => Ok(value.to_string()),
+		Value::Array(_) | Value::Table(_) => {
+			anyhow::bail!("Config file option {key} must be a value or a list of values")
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::{CommandFactory, FromArgMatches};
+	use secrecy::ExposeSecret;
+
+	use super::*;
+	use crate::{Cli, Command as CliCommand};
+
+	#[test]
+	fn options_default_to_their_values() {
+		let table: Table = r#"
+			db = "handshakes.db"
+			api-unix-mode = "640"
+			swagger_ui = true
+			token = ["read:abc", "admin:def"]
+			backup_keep = 3
+			verbose = 2
+			tokens = "typo"
+		"#
+		.parse()
+		.unwrap();
+
+		let options = option_names(&Cli::command());
+		let defaults = defaults(&table, &options).unwrap();
+		let command = apply_defaults(Cli::command(), &defaults);
+		let parse =
+			|args: &[&str]| Cli::from_arg_matches(&command.clone().try_get_matches_from(args).unwrap()).unwrap();
+
+		let cli = parse(&["shaker"]);
+		assert_eq!(cli.database.db, Path::new("handshakes.db"));
+		assert_eq!(cli.serve.api_unix_mode, 0o640);
+		assert!(cli.serve.swagger_ui);
+		assert_eq!(cli.serve.backup_keep, 3);
+		let tokens: Vec<_> = cli
+			.serve
+			.token
+			.iter()
+			.map(|token| token.token.expose_secret().clone())
+			.collect();
+		assert_eq!(tokens, ["abc", "def"]);
+
+		// The values apply to the serve command too, and the command line takes precedence over them
+		let cli = parse(&["shaker", "--db", "other.db", "serve", "--backup-keep", "5"]);
+		assert_eq!(cli.database.db, Path::new("other.db"));
+		let Some(CliCommand::Serve(serve)) = cli.command else {
+			panic!("expected the serve command");
+		};
+		assert!(serve.swagger_ui);
+		assert_eq!(serve.backup_keep, 5);
+
+		// Options without a variable (like the verbosity) can't be set in the file either
+		let mut unknown = unknown_keys(&table, &options);
+		unknown.sort_unstable();
+		assert_eq!(unknown, ["tokens", "verbose"]);
+
+		let table: Table = "token = [[\"nested\"]]".parse().unwrap();
+		assert!(super::defaults(&table, &options).is_err());
+	}
+
+	#[test]
+	fn defaults_are_hidden_from_help() {
+		let table: Table = r#"
+			token = ["admin:supersecret"]
+			webhook_secret = "whsecret"
+			backup_keep = 37
+		"#
+		.parse()
+		.unwrap();
+
+		let options = option_names(&Cli::command());
+		let defaults = defaults(&table, &options).unwrap();
+		let command = apply_defaults(Cli::command(), &defaults);
+		let help = command
+			.try_get_matches_from(["shaker", "serve", "--help"])
+			.unwrap_err()
+			.to_string();
+		assert!(help.contains("--webhook-secret"));
+		for value in ["supersecret", "whsecret", "[default: 37]"] {
+			assert!(!help.contains(value), "help shows {value}");
+		}
+	}
+}
@@ -0,0 +1,1971 @@
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use sqlx::{
+	migrate,
+	postgres::{PgConnectOptions, PgDatabaseError},
+	Connection, PgPool,
+};
+#[cfg(any(test, feature = "test-util"))]
+use sqlx::{postgres::PgPoolOptions, PgConnection};
+use time::{Date, OffsetDateTime};
+use time_tz::{OffsetDateTimeExt, Tz};
+use tracing::info;
+#[cfg(any(test, feature = "test-util"))]
+use tracing::warn;
+
+use super::{
+	ban_conflict, escape_like, longest_streak, merge_notes, plan_name_normalization, start_of_day, user_conflict,
+	AuditEntry, Ban, BannedError, ConflictError, ConnectionSettings, CreatedHandshake, EventSummary, FieldValueCount,
+	FirstHandshake, Handshake, HandshakeContext, HandshakeFilter, HandshakeWithUser, ImportOutcome, LegacyRecord,
+	MergedUsers, MinuteCounts, NameChange, NameFilter, NameNormalization, NewAuditEntry, NewBan, OutboxEntry,
+	PoolSettings, PoolStatus, PurgedCounts, QuarterHourBreakdown, SourceCount, Stats, TimeRange, UndoOutcome, User,
+	UserFilter, UserHandshakeCount, UserOrder, UserResoniteInfo, UserStats, UserWithCount, WorldStats,
+};
+use crate::{validate, webhook};
+
+/// Error code for a transaction that couldn't be serialized with a concurrent one
+const SERIALIZATION_FAILURE: &str = "40001";
+
+/// Error code for a transaction that was chosen to break a deadlock
+const DEADLOCK_DETECTED: &str = "40P01";
+
+/// Error code for a lock that couldn't be acquired within the lock timeout
+const LOCK_NOT_AVAILABLE: &str = "55P03";
+
+#[cfg(any(test, feature = "test-util"))]
+/// Most connections to keep open to a test database, since many tests run against the same server at once
+const TEST_MAX_CONNECTIONS: u32 = 4;
+
+/// Columns of a handshake along with details of the user that performed it, for [`HandshakeWithUser`]
+macro_rules! handshake_with_user_columns {
+	() => {
+		"handshakes.id, handshakes.user_id, users.resonite_id, users.resonite_name, handshakes.world_name,
+		handshakes.source, handshakes.event, handshakes.created_at"
+	};
+}
+
+/// `PostgreSQL` database. Names are compared without regard to (ASCII) case by comparing their lowercase forms, which
+/// the migrations index, rather than with a collation.
+#[derive(Debug, Clone)]
+pub struct Postgres {
+	/// Connection pool to use for queries
+	pool: PgPool,
+
+	/// Schema of its own that the database was opened in, if it was opened with [`Postgres::open_test`]. It's dropped
+	/// once every clone of the database has been dropped, so it's only ever held.
+	#[cfg(any(test, feature = "test-util"))]
+	#[allow(dead_code)]
+	test_schema: Option<Arc<TestSchema>>,
+}
+
+impl Postgres {
+	/// Opens the database at a `postgres://` URL. The busy timeout is used as the lock timeout, so that writes waiting
+	/// too long on another connection fail in a way that's retried like a busy `SQLite` database.
+	pub async fn open(url: &str, settings: ConnectionSettings, pool: PoolSettings) -> Result<Self> {
+		let mut options: PgConnectOptions = url.parse().context("Invalid PostgreSQL URL")?;
+		options = options.options([("lock_timeout", settings.busy_timeout.as_millis().to_string())]);
+		if settings.read_only {
+			options = options.options([("default_transaction_read_only", "on")]);
+		}
+
+		info!(
+			"Using up to {} database connection(s) (keeping at least {} open), waiting up to {:?} to acquire one",
+			pool.max_connections, pool.min_connections, pool.acquire_timeout
+		);
+		let pool = pool.options().connect_with(options).await?;
+		Ok(Self {
+			pool,
+			#[cfg(any(test, feature = "test-util"))]
+			test_schema: None,
+		})
+	}
+
+	/// Opens the database at a `postgres://` URL in a new, empty schema of its own, so that tests running at the same
+	/// time don't see each other's records
+	#[cfg(any(test, feature = "test-util"))]
+	pub async fn open_test(url: &str) -> Result<Self> {
+		let options: PgConnectOptions = url.parse().context("Invalid PostgreSQL URL")?;
+		let name = format!("shaker_test_{}", uuid::Uuid::new_v4().simple());
+		let mut conn = PgConnection::connect_with(&options).await?;
+		sqlx::query(&format!("CREATE SCHEMA {name}")).execute(&mut conn).await?;
+		conn.close().await?;
+
+		let test_schema = Arc::new(TestSchema {
+			options: options.clone(),
+			name: name.clone(),
+		});
+		let pool = PgPoolOptions::new()
+			.max_connections(TEST_MAX_CONNECTIONS)
+			.connect_with(options.application_name(&name).options([("search_path", &name)]))
+			.await?;
+		Ok(Self {
+			pool,
+			test_schema: Some(test_schema),
+		})
+	}
+
+	/// Retrieves the current size of the connection pool and how much of it is idle
+	pub fn pool_status(&self) -> PoolStatus {
+		PoolStatus {
+			size: self.pool.size(),
+			idle: self.pool.num_idle(),
+		}
+	}
+
+	/// Closes the database, waiting for all connections to be released
+	pub async fn close(&self) {
+		self.pool.close().await;
+	}
+
+	/// Runs pending migrations against the database
+	pub async fn migrate(&self) -> Result<()> {
+		migrate!("./migrations_postgres").run(&self.pool).await?;
+		Ok(())
+	}
+
+	/// Lists the migrations that haven't been applied to the database yet, as their versions and descriptions
+	pub async fn pending_migrations(&self) -> Result<Vec<String>> {
+		// The migrations table won't exist if the database has never been migrated at all
+		let applied: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+			.fetch_all(&self.pool)
+			.await
+			.unwrap_or_default();
+		Ok(super::pending_migrations(&migrate!("./migrations_postgres"), &applied))
+	}
+
+	/// Retrieves the version of the latest migration that has been applied to the database
+	pub async fn schema_version(&self) -> Result<Option<i64>> {
+		Ok(
+			sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+				.fetch_one(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves the revision of the stored users and handshakes, which is advanced by triggers as the writes that
+	/// change them commit
+	pub async fn data_revision(&self) -> Result<i64> {
+		Ok(sqlx::query_scalar("SELECT revision FROM data_revision")
+			.fetch_one(&self.pool)
+			.await?)
+	}
+
+	/// Retrieves a single user record by its ID
+	pub async fn get_user(&self, id: i64) -> Result<Option<User>> {
+		Ok(
+			sqlx::query_as("SELECT * FROM users WHERE id = $1 AND deleted_at IS NULL")
+				.bind(id)
+				.fetch_optional(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves a single user record by its Resonite ID
+	pub async fn get_user_by_resonite_id(&self, id: &str) -> Result<Option<User>> {
+		Ok(
+			sqlx::query_as("SELECT * FROM users WHERE resonite_id = $1 AND deleted_at IS NULL")
+				.bind(id)
+				.fetch_optional(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves a single user record by its (normalized) Resonite username, ignoring differences in case
+	pub async fn get_user_by_resonite_name(&self, name: &str) -> Result<Option<User>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM users WHERE lower(resonite_name) = $1 AND deleted_at IS NULL AND NOT anonymized
+			ORDER BY resonite_name = $2 DESC, id LIMIT 1",
+		)
+		.bind(name.to_ascii_lowercase())
+		.bind(name)
+		.fetch_optional(&self.pool)
+		.await?)
+	}
+
+	/// Checks whether a user has ever shaken hands, by Resonite ID or by (normalized) Resonite username
+	pub async fn has_shaken_hands(&self, resonite_id: &str, name: Option<&str>) -> Result<bool> {
+		Ok(sqlx::query_scalar(
+			"SELECT EXISTS(
+				SELECT 1 FROM users INNER JOIN handshakes ON handshakes.user_id = users.id AND handshakes.deleted_at IS NULL
+				WHERE (users.resonite_id = $1 OR (users.resonite_id IS NULL AND lower(users.resonite_name) = $2))
+					AND users.deleted_at IS NULL AND NOT users.anonymized
+			)",
+		)
+		.bind(resonite_id)
+		.bind(name.map(str::to_ascii_lowercase))
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves all user records with a (normalized) Resonite username, ignoring differences in case
+	pub async fn get_users_by_resonite_name(&self, name: &str) -> Result<Vec<User>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM users WHERE lower(resonite_name) = $1 AND deleted_at IS NULL AND NOT anonymized
+			ORDER BY resonite_name = $2 DESC, id",
+		)
+		.bind(name.to_ascii_lowercase())
+		.bind(name)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a single user record by a (normalized) Resonite username it previously had, ignoring differences in
+	/// case
+	pub async fn get_user_by_past_resonite_name(&self, name: &str) -> Result<Option<User>> {
+		Ok(sqlx::query_as(
+			"SELECT users.* FROM users INNER JOIN user_name_history ON user_name_history.user_id = users.id
+			WHERE lower(user_name_history.old_name) = $1 AND users.deleted_at IS NULL
+			ORDER BY user_name_history.id DESC LIMIT 1",
+		)
+		.bind(name.to_ascii_lowercase())
+		.fetch_optional(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves all user records
+	pub async fn get_all_users(&self) -> Result<Vec<User>> {
+		Ok(sqlx::query_as("SELECT * FROM users WHERE deleted_at IS NULL")
+			.fetch_all(&self.pool)
+			.await?)
+	}
+
+	/// Streams all user records in ID order
+	pub fn stream_all_users(&self) -> BoxStream<'_, Result<User>> {
+		sqlx::query_as("SELECT * FROM users WHERE deleted_at IS NULL ORDER BY id")
+			.fetch(&self.pool)
+			.map_err(Into::into)
+			.boxed()
+	}
+
+	/// Streams the user records created within a time range in ID order
+	pub fn stream_users_created_in<'a>(&'a self, range: &'a TimeRange) -> BoxStream<'a, Result<User>> {
+		sqlx::query_as(
+			"SELECT * FROM users
+			WHERE ($1::TIMESTAMPTZ IS NULL OR created_at >= $1) AND ($2::TIMESTAMPTZ IS NULL OR created_at < $2)
+				AND deleted_at IS NULL
+			ORDER BY id",
+		)
+		.bind(range.since)
+		.bind(range.until)
+		.fetch(&self.pool)
+		.map_err(Into::into)
+		.boxed()
+	}
+
+	/// Retrieves users that match a filter and haven't shaken hands since a date/time, longest-inactive first
+	pub async fn get_inactive_users(
+		&self,
+		since: OffsetDateTime,
+		filter: &UserFilter,
+		limit: i64,
+		offset: i64,
+	) -> Result<Vec<User>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM users
+			WHERE last_seen_at < $1 AND ($2::BOOLEAN IS NULL OR legacy = $2) AND deleted_at IS NULL
+			ORDER BY last_seen_at, id LIMIT $3 OFFSET $4",
+		)
+		.bind(since)
+		.bind(filter.legacy)
+		.bind(limit)
+		.bind(offset)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves users whose Resonite usernames match a `LIKE` pattern (that uses `\` as its escape), ignoring
+	/// differences in case, ordered by name
+	pub async fn search_users_like(&self, pattern: &str, limit: i64) -> Result<Vec<User>> {
+		Ok(sqlx::query_as(
+			r"SELECT * FROM users WHERE resonite_name ILIKE $1 ESCAPE '\' AND deleted_at IS NULL
+			ORDER BY resonite_name LIMIT $2",
+		)
+		.bind(pattern)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves users whose Resonite usernames contain a string, ignoring differences in case. There's no full-text
+	/// index of usernames, so this always scans them, ordered by name.
+	pub async fn search_users_fts(&self, query: &str, limit: i64) -> Result<Vec<User>> {
+		self.search_users_like(&format!("%{}%", escape_like(query)), limit)
+			.await
+	}
+
+	/// Retrieves the Resonite usernames of all user records that match a filter
+	pub async fn get_user_resonite_names(&self, filter: &UserFilter) -> Result<Vec<String>> {
+		Ok(sqlx::query_scalar(
+			"SELECT resonite_name FROM users WHERE ($1::BOOLEAN IS NULL OR legacy = $1) AND deleted_at IS NULL",
+		)
+		.bind(filter.legacy)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Stores a new user with a validated Resonite ID and username
+	pub async fn create_user(&self, resonite_id: &str, name: &str) -> Result<User> {
+		sqlx::query_as(
+			"INSERT INTO users (resonite_id, resonite_name, updated_at) VALUES ($1, $2, CURRENT_TIMESTAMP) RETURNING *",
+		)
+		.bind(resonite_id)
+		.bind(name)
+		.fetch_one(&self.pool)
+		.await
+		.map_err(user_conflict)
+	}
+
+	/// Stores a new legacy (username-only) user with a validated username
+	pub async fn create_legacy_user(&self, name: &str) -> Result<User> {
+		Ok(sqlx::query_as(
+			"INSERT INTO users (resonite_name, legacy, updated_at) VALUES ($1, TRUE, CURRENT_TIMESTAMP) RETURNING *",
+		)
+		.bind(name)
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Updates an existing user's Resonite ID and (validated) username
+	pub async fn update_user(&self, id: i64, resonite_id: Option<&str>, name: &str) -> Result<bool> {
+		let mut tx = self.pool.begin().await?;
+		let updated = update_user_record(&mut tx, id, resonite_id, name).await?;
+		tx.commit().await?;
+		Ok(updated)
+	}
+
+	/// Sets (or with `None`, clears) the (validated) note on a user, returning the updated user
+	pub async fn set_user_note(&self, id: i64, note: Option<&str>) -> Result<Option<User>> {
+		Ok(
+			sqlx::query_as("UPDATE users SET note = $2 WHERE id = $1 AND deleted_at IS NULL RETURNING *")
+				.bind(id)
+				.bind(note)
+				.fetch_optional(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves users with a Resonite ID whose usernames haven't been refreshed since a date/time
+	pub async fn get_users_to_refresh(&self, before: OffsetDateTime, limit: i64) -> Result<Vec<User>> {
+		Ok(sqlx::query_as(
+			"SELECT users.* FROM users LEFT JOIN name_refreshes ON name_refreshes.user_id = users.id
+			WHERE users.resonite_id IS NOT NULL AND users.deleted_at IS NULL AND NOT users.anonymized
+				AND (name_refreshes.refreshed_at IS NULL OR name_refreshes.refreshed_at < $1)
+			ORDER BY name_refreshes.refreshed_at NULLS FIRST, users.id
+			LIMIT $2",
+		)
+		.bind(before)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Records that a user's username has just been refreshed from the Resonite API
+	pub async fn mark_name_refreshed(&self, user_id: i64) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO name_refreshes (user_id) VALUES ($1)
+			ON CONFLICT (user_id) DO UPDATE SET refreshed_at = CURRENT_TIMESTAMP",
+		)
+		.bind(user_id)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	/// Retrieves users without a Resonite ID after a user ID, in ID order
+	pub async fn get_users_without_ids(&self, after: i64, retry: bool, limit: i64) -> Result<Vec<User>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM users
+			WHERE id > $1 AND resonite_id IS NULL AND deleted_at IS NULL AND NOT anonymized
+				AND ($2 OR id NOT IN (SELECT user_id FROM id_backfills))
+			ORDER BY id LIMIT $3",
+		)
+		.bind(after)
+		.bind(retry)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Gives a user without a Resonite ID a (validated) one, returning whether it was set
+	pub async fn set_missing_resonite_id(&self, user_id: i64, resonite_id: &str) -> Result<bool> {
+		Ok(sqlx::query(
+			"UPDATE users SET resonite_id = $2, updated_at = CURRENT_TIMESTAMP WHERE id = $1 AND resonite_id IS NULL",
+		)
+		.bind(user_id)
+		.bind(resonite_id)
+		.execute(&self.pool)
+		.await
+		.map_err(user_conflict)?
+		.rows_affected()
+			> 0)
+	}
+
+	/// Records the outcome of looking up a user's Resonite ID by name
+	pub async fn record_id_backfill(&self, user_id: i64, outcome: &str) -> Result<()> {
+		sqlx::query(
+			"INSERT INTO id_backfills (user_id, outcome) VALUES ($1, $2)
+			ON CONFLICT (user_id) DO UPDATE SET outcome = excluded.outcome, attempted_at = CURRENT_TIMESTAMP",
+		)
+		.bind(user_id)
+		.bind(outcome)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	/// Retrieves the history of a user's Resonite username changes, newest first
+	pub async fn get_user_name_history(&self, user_id: i64) -> Result<Vec<NameChange>> {
+		Ok(
+			sqlx::query_as("SELECT * FROM user_name_history WHERE user_id = $1 ORDER BY id DESC")
+				.bind(user_id)
+				.fetch_all(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Counts the handshakes with each distinct world name, source, and event
+	pub async fn count_handshake_field_values(&self) -> Result<Vec<FieldValueCount>> {
+		Ok(sqlx::query_as(
+			r#"SELECT 'world_name' AS "column", world_name AS value, COUNT(*) AS handshakes
+			FROM handshakes WHERE world_name IS NOT NULL AND deleted_at IS NULL GROUP BY world_name
+			UNION ALL
+			SELECT 'source', source, COUNT(*)
+			FROM handshakes WHERE source IS NOT NULL AND deleted_at IS NULL GROUP BY source
+			UNION ALL
+			SELECT 'event', event, COUNT(*)
+			FROM handshakes WHERE event IS NOT NULL AND deleted_at IS NULL GROUP BY event"#,
+		)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Normalizes the usernames of all existing users (see [`super::Database::normalize_user_names`])
+	pub async fn normalize_user_names(&self) -> Result<NameNormalization> {
+		let mut tx = self.pool.begin().await?;
+		let users: Vec<User> = sqlx::query_as("SELECT * FROM users ORDER BY id")
+			.fetch_all(&mut *tx)
+			.await?;
+
+		let (report, renames) = plan_name_normalization(&users);
+		for (user, name) in renames {
+			record_name_change(&mut tx, user.id, &user.resonite_name, &name).await?;
+			sqlx::query("UPDATE users SET resonite_name = $2, updated_at = CURRENT_TIMESTAMP WHERE id = $1")
+				.bind(user.id)
+				.bind(name)
+				.execute(&mut *tx)
+				.await?;
+		}
+
+		tx.commit().await?;
+		Ok(report)
+	}
+
+	/// Merges one user into another in a single transaction (see [`super::Database::merge_users`])
+	pub async fn try_merge_users(&self, from: i64, to: i64) -> Result<Option<MergedUsers>> {
+		let mut tx = self.pool.begin().await?;
+		let from_user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE id = $1 AND deleted_at IS NULL")
+			.bind(from)
+			.fetch_optional(&mut *tx)
+			.await?;
+		let to_user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE id = $1 AND deleted_at IS NULL")
+			.bind(to)
+			.fetch_optional(&mut *tx)
+			.await?;
+		let (Some(from_user), Some(to_user)) = (from_user, to_user) else {
+			return Ok(None);
+		};
+
+		let handshakes = sqlx::query("UPDATE handshakes SET user_id = $2 WHERE user_id = $1")
+			.bind(from)
+			.bind(to)
+			.execute(&mut *tx)
+			.await?
+			.rows_affected();
+		sqlx::query("UPDATE user_name_history SET user_id = $2 WHERE user_id = $1")
+			.bind(from)
+			.bind(to)
+			.execute(&mut *tx)
+			.await?;
+		if from_user.resonite_name != to_user.resonite_name {
+			record_name_change(&mut tx, to, &from_user.resonite_name, &to_user.resonite_name).await?;
+		}
+		let note = merge_notes(to_user.note.as_deref(), from_user.note.as_deref());
+		sqlx::query(
+			"UPDATE users SET
+				created_at = LEAST($2, created_at),
+				last_seen_at = GREATEST($3, last_seen_at),
+				legacy = $4 AND legacy,
+				note = $5,
+				updated_at = CURRENT_TIMESTAMP
+			WHERE id = $1",
+		)
+		.bind(to)
+		.bind(from_user.created_at)
+		.bind(from_user.last_seen_at)
+		.bind(from_user.legacy)
+		.bind(note)
+		.execute(&mut *tx)
+		.await?;
+
+		// The Resonite ID can only be moved over once the other user is gone, since they're unique
+		sqlx::query("DELETE FROM users WHERE id = $1")
+			.bind(from)
+			.execute(&mut *tx)
+			.await?;
+		if to_user.resonite_id.is_none() {
+			sqlx::query("UPDATE users SET resonite_id = $2 WHERE id = $1")
+				.bind(to)
+				.bind(from_user.resonite_id)
+				.execute(&mut *tx)
+				.await?;
+		}
+
+		tx.commit().await?;
+		Ok(Some(MergedUsers { handshakes }))
+	}
+
+	/// Counts the number of user records
+	pub async fn count_users(&self) -> Result<i64> {
+		Ok(
+			sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE deleted_at IS NULL")
+				.fetch_one(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Counts the number of user records that match a filter
+	pub async fn count_users_matching(&self, filter: &UserFilter) -> Result<i64> {
+		Ok(sqlx::query_scalar(
+			"SELECT COUNT(*) FROM users WHERE ($1::BOOLEAN IS NULL OR legacy = $1) AND deleted_at IS NULL",
+		)
+		.bind(filter.legacy)
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a single handshake record by its ID
+	pub async fn get_handshake(&self, id: i64) -> Result<Option<Handshake>> {
+		Ok(
+			sqlx::query_as("SELECT * FROM handshakes WHERE id = $1 AND deleted_at IS NULL")
+				.bind(id)
+				.fetch_optional(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves a single handshake record by its ID, along with details of the user that performed it
+	pub async fn get_handshake_with_user(&self, id: i64) -> Result<Option<HandshakeWithUser>> {
+		Ok(sqlx::query_as(concat!(
+			"SELECT ",
+			handshake_with_user_columns!(),
+			" FROM handshakes INNER JOIN users ON users.id = handshakes.user_id
+			WHERE handshakes.id = $1 AND handshakes.deleted_at IS NULL"
+		))
+		.bind(id)
+		.fetch_optional(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves the most recent handshake records along with details of the users that performed them, newest first
+	pub async fn get_recent_handshakes(&self, limit: i64) -> Result<Vec<HandshakeWithUser>> {
+		Ok(sqlx::query_as(concat!(
+			"SELECT ",
+			handshake_with_user_columns!(),
+			" FROM handshakes INNER JOIN users ON users.id = handshakes.user_id
+			WHERE handshakes.deleted_at IS NULL
+			ORDER BY handshakes.id DESC LIMIT $1"
+		))
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves the most recent handshake record (optionally of a specific user) along with details of the user that
+	/// performed it
+	pub async fn get_latest_handshake(&self, user_id: Option<i64>) -> Result<Option<HandshakeWithUser>> {
+		Ok(sqlx::query_as(concat!(
+			"SELECT ",
+			handshake_with_user_columns!(),
+			" FROM handshakes INNER JOIN users ON users.id = handshakes.user_id
+			WHERE ($1::BIGINT IS NULL OR handshakes.user_id = $1) AND handshakes.deleted_at IS NULL
+			ORDER BY handshakes.created_at DESC, handshakes.id DESC LIMIT 1"
+		))
+		.bind(user_id)
+		.fetch_optional(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves the most recent handshake records of a user, newest first
+	pub async fn get_user_recent_handshakes(&self, user_id: i64, limit: i64) -> Result<Vec<Handshake>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM handshakes WHERE user_id = $1 AND deleted_at IS NULL ORDER BY created_at DESC, id DESC LIMIT $2",
+		)
+		.bind(user_id)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves statistics about a user's handshakes (see [`super::Database::get_user_stats`])
+	pub async fn get_user_stats(&self, user_id: i64, tz: &Tz) -> Result<UserStats> {
+		let (count, worlds, first_handshake_at, last_handshake_at): (
+			i64,
+			i64,
+			Option<OffsetDateTime>,
+			Option<OffsetDateTime>,
+		) = sqlx::query_as(
+			"SELECT COUNT(*), COUNT(DISTINCT world_name), MIN(created_at), MAX(created_at)
+			FROM handshakes WHERE user_id = $1 AND deleted_at IS NULL",
+		)
+		.bind(user_id)
+		.fetch_one(&self.pool)
+		.await?;
+
+		// Legacy handshakes may only have the time they were imported at, which would make up streaks that never happened
+		let times: Vec<OffsetDateTime> = sqlx::query_scalar(
+			"SELECT created_at FROM handshakes WHERE user_id = $1 AND NOT legacy AND deleted_at IS NULL
+			ORDER BY created_at",
+		)
+		.bind(user_id)
+		.fetch_all(&self.pool)
+		.await?;
+
+		Ok(UserStats {
+			user_id,
+			count,
+			worlds,
+			first_handshake_at,
+			last_handshake_at,
+			longest_streak: longest_streak(times.into_iter().map(|time| time.to_timezone(tz).date())),
+		})
+	}
+
+	/// Retrieves statistics about the handshakes that took place in a world
+	pub async fn get_world_stats(&self, world_name: &str) -> Result<Option<WorldStats>> {
+		Ok(sqlx::query_as(
+			"SELECT world_name, COUNT(*) AS count, COUNT(DISTINCT user_id) AS unique_users,
+				MIN(created_at) AS first_handshake_at, MAX(created_at) AS last_handshake_at
+			FROM handshakes WHERE world_name = $1 AND deleted_at IS NULL
+			GROUP BY world_name",
+		)
+		.bind(world_name)
+		.fetch_optional(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a page of handshake records that match a filter along with details of the users that performed them,
+	/// newest first, older than the given `(created_at, id)` key if one is given
+	pub async fn get_handshakes_before(
+		&self,
+		before: Option<(OffsetDateTime, i64)>,
+		filter: &HandshakeFilter,
+		limit: i64,
+	) -> Result<Vec<HandshakeWithUser>> {
+		let (before_created_at, before_id) = before.unzip();
+		Ok(sqlx::query_as(concat!(
+			"SELECT ",
+			handshake_with_user_columns!(),
+			" FROM handshakes INNER JOIN users ON users.id = handshakes.user_id
+			WHERE ($1::TIMESTAMPTZ IS NULL OR (handshakes.created_at, handshakes.id) < ($1, $2))
+				AND ($3::TEXT IS NULL OR handshakes.source = $3) AND ($4::BOOLEAN IS NULL OR handshakes.legacy = $4)
+				AND ($5::TEXT IS NULL OR handshakes.event = $5) AND handshakes.deleted_at IS NULL
+			ORDER BY handshakes.created_at DESC, handshakes.id DESC LIMIT $6"
+		))
+		.bind(before_created_at)
+		.bind(before_id)
+		.bind(filter.source.as_deref())
+		.bind(filter.legacy)
+		.bind(filter.event.as_deref())
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves all handshake records
+	pub async fn get_all_handshakes(&self) -> Result<Vec<Handshake>> {
+		Ok(sqlx::query_as("SELECT * FROM handshakes WHERE deleted_at IS NULL")
+			.fetch_all(&self.pool)
+			.await?)
+	}
+
+	/// Streams all handshake records in ID order
+	pub fn stream_all_handshakes(&self) -> BoxStream<'_, Result<Handshake>> {
+		sqlx::query_as("SELECT * FROM handshakes WHERE deleted_at IS NULL ORDER BY id")
+			.fetch(&self.pool)
+			.map_err(Into::into)
+			.boxed()
+	}
+
+	/// Streams the handshake records created within a time range along with details of the users that performed them,
+	/// in ID order
+	pub fn stream_handshakes_with_users_in<'a>(
+		&'a self,
+		range: &'a TimeRange,
+	) -> BoxStream<'a, Result<HandshakeWithUser>> {
+		sqlx::query_as(concat!(
+			"SELECT ",
+			handshake_with_user_columns!(),
+			" FROM handshakes INNER JOIN users ON users.id = handshakes.user_id
+			WHERE ($1::TIMESTAMPTZ IS NULL OR handshakes.created_at >= $1)
+				AND ($2::TIMESTAMPTZ IS NULL OR handshakes.created_at < $2) AND handshakes.deleted_at IS NULL
+			ORDER BY handshakes.id"
+		))
+		.bind(range.since)
+		.bind(range.until)
+		.fetch(&self.pool)
+		.map_err(Into::into)
+		.boxed()
+	}
+
+	/// Stores a new handshake along with its user and webhook deliveries (to each of the given URLs) in a single
+	/// transaction
+	pub async fn try_create_handshake(
+		&self,
+		info: &UserResoniteInfo,
+		shake: &HandshakeContext,
+		webhook_urls: &[String],
+	) -> Result<CreatedHandshake> {
+		let mut tx = self.pool.begin().await?;
+
+		// Locking users against other writers up front means concurrent handshakes wait for each other rather than both
+		// reading that the user is missing, like they do for the write lock in SQLite
+		sqlx::query("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE")
+			.execute(&mut *tx)
+			.await?;
+		let first_time = sqlx::query(
+			"INSERT INTO users (resonite_id, resonite_name, updated_at)
+			SELECT $1, $2, CURRENT_TIMESTAMP
+			WHERE NOT EXISTS (
+				SELECT 1 FROM users WHERE resonite_id = $1 OR (resonite_id IS NULL AND lower(resonite_name) = $3)
+			)
+			ON CONFLICT DO NOTHING",
+		)
+		.bind(&info.id)
+		.bind(&info.name)
+		.bind(info.name.to_ascii_lowercase())
+		.execute(&mut *tx)
+		.await?
+		.rows_affected()
+			> 0;
+
+		// Retrieve the corresponding user by its Resonite ID, falling back to a user without an ID that has its username.
+		// If there's neither, the user couldn't be created because another user (with a different ID) has the name.
+		let user: Option<User> = sqlx::query_as("SELECT * FROM users WHERE resonite_id = $1")
+			.bind(&info.id)
+			.fetch_optional(&mut *tx)
+			.await?;
+		let user = match user {
+			Some(user) => user,
+			None => sqlx::query_as(
+				"SELECT * FROM users WHERE resonite_id IS NULL AND lower(resonite_name) = $1 AND NOT anonymized
+				ORDER BY resonite_name = $2 DESC, id LIMIT 1",
+			)
+			.bind(info.name.to_ascii_lowercase())
+			.bind(&info.name)
+			.fetch_optional(&mut *tx)
+			.await?
+			.ok_or_else(|| ConflictError::new("name", "another user already has this username"))?,
+		};
+
+		// Deleted users still hold onto their ID and name, so they can't shake hands until they're restored
+		if user.deleted_at.is_some() {
+			let field = if user.resonite_id.as_ref() == Some(&info.id) {
+				"id"
+			} else {
+				"name"
+			};
+			return Err(ConflictError::new(field, format!("user {} has been deleted", user.id)).into());
+		}
+
+		// Returning an error for a banned user rolls back the user that may have just been created
+		check_ban(&mut tx, info).await?;
+
+		// Claim the user if its ID was unknown, or update its name if it has changed
+		let claimable = match &user.resonite_id {
+			None => true,
+			Some(id) => *id == info.id && user.resonite_name != info.name,
+		};
+		let mut user = if claimable {
+			update_user_record(&mut tx, user.id, Some(&info.id), &info.name).await?;
+			sqlx::query_as("SELECT * FROM users WHERE id = $1")
+				.bind(user.id)
+				.fetch_one(&mut *tx)
+				.await?
+		} else {
+			user
+		};
+
+		// Create the handshake record along with its webhook deliveries, so that they're never lost or sent for a
+		// handshake that didn't get stored
+		let handshake: Handshake = sqlx::query_as(
+			"INSERT INTO handshakes (user_id, world_name, source, event) VALUES ($1, $2, $3, $4) RETURNING *",
+		)
+		.bind(user.id)
+		.bind(shake.world.as_deref())
+		.bind(shake.source.as_deref())
+		.bind(shake.event.as_deref())
+		.fetch_one(&mut *tx)
+		.await?;
+
+		sqlx::query("UPDATE users SET last_seen_at = $2 WHERE id = $1")
+			.bind(user.id)
+			.bind(handshake.created_at)
+			.execute(&mut *tx)
+			.await?;
+		user.last_seen_at = Some(handshake.created_at);
+
+		// Count within the transaction so that the counts reflect exactly the handshakes up to and including this one.
+		// Other handshakes can't be stored at the same time while the users are locked.
+		let total_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM handshakes WHERE deleted_at IS NULL")
+			.fetch_one(&mut *tx)
+			.await?;
+		let user_count: i64 =
+			sqlx::query_scalar("SELECT COUNT(*) FROM handshakes WHERE user_id = $1 AND deleted_at IS NULL")
+				.bind(user.id)
+				.fetch_one(&mut *tx)
+				.await?;
+
+		if !webhook_urls.is_empty() {
+			let payload = webhook::Payload::handshake(handshake.clone(), user.clone(), first_time);
+			queue_webhook_deliveries(&mut tx, webhook_urls, &serde_json::to_string(&payload)?).await?;
+		}
+
+		tx.commit().await?;
+		Ok(CreatedHandshake {
+			handshake,
+			user,
+			first_time,
+			total_count,
+			user_count,
+		})
+	}
+
+	/// Stores a new legacy handshake and marks its user as seen at that time in a single transaction, returning its ID
+	pub async fn try_create_legacy_handshake(&self, user_id: i64) -> Result<i64> {
+		let mut tx = self.pool.begin().await?;
+		let (id, created_at): (i64, OffsetDateTime) =
+			sqlx::query_as("INSERT INTO handshakes (user_id, legacy) VALUES ($1, TRUE) RETURNING id, created_at")
+				.bind(user_id)
+				.fetch_one(&mut *tx)
+				.await?;
+		sqlx::query("UPDATE users SET last_seen_at = $2 WHERE id = $1")
+			.bind(user_id)
+			.bind(created_at)
+			.execute(&mut *tx)
+			.await?;
+		tx.commit().await?;
+		Ok(id)
+	}
+
+	/// Stores a handshake for an existing user that took place at a specific date/time (see
+	/// [`super::Database::create_handshake_at`])
+	#[cfg(any(test, feature = "test-util"))]
+	pub async fn create_handshake_at(
+		&self,
+		user_id: i64,
+		world_name: Option<&str>,
+		source: Option<&str>,
+		event: Option<&str>,
+		created_at: OffsetDateTime,
+	) -> Result<Handshake> {
+		let mut tx = self.pool.begin().await?;
+		let handshake = sqlx::query_as(
+			"INSERT INTO handshakes (user_id, world_name, source, event, created_at)
+			VALUES ($1, $2, $3, $4, $5) RETURNING *",
+		)
+		.bind(user_id)
+		.bind(world_name)
+		.bind(source)
+		.bind(event)
+		.bind(created_at)
+		.fetch_one(&mut *tx)
+		.await?;
+		sqlx::query(
+			"UPDATE users SET
+				created_at = LEAST(created_at, $2),
+				updated_at = LEAST(updated_at, $2),
+				last_seen_at = GREATEST(last_seen_at, $2)
+			WHERE id = $1",
+		)
+		.bind(user_id)
+		.bind(created_at)
+		.execute(&mut *tx)
+		.await?;
+		tx.commit().await?;
+		Ok(handshake)
+	}
+
+	/// Starts a transaction for importing records
+	pub async fn begin(&self) -> Result<ImportTransaction> {
+		Ok(ImportTransaction {
+			tx: self.pool.begin().await?,
+		})
+	}
+
+	/// Checks whether the database has no users or handshakes at all, including deleted ones
+	pub async fn is_empty(&self) -> Result<bool> {
+		Ok(
+			sqlx::query_scalar("SELECT NOT EXISTS(SELECT 1 FROM users) AND NOT EXISTS(SELECT 1 FROM handshakes)")
+				.fetch_one(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Counts the number of handshake records
+	pub async fn count_handshakes(&self) -> Result<i64> {
+		Ok(
+			sqlx::query_scalar("SELECT COUNT(*) FROM handshakes WHERE deleted_at IS NULL")
+				.fetch_one(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Counts the number of handshake records for a specific user that match a filter
+	pub async fn count_user_handshakes_matching(&self, id: i64, filter: &HandshakeFilter) -> Result<i64> {
+		Ok(sqlx::query_scalar(
+			"SELECT COUNT(*) FROM handshakes
+			WHERE user_id = $1 AND ($2::TEXT IS NULL OR source = $2) AND ($3::BOOLEAN IS NULL OR legacy = $3)
+				AND ($4::TEXT IS NULL OR event = $4) AND deleted_at IS NULL",
+		)
+		.bind(id)
+		.bind(filter.source.as_deref())
+		.bind(filter.legacy)
+		.bind(filter.event.as_deref())
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records created on a specific date, where the day starts at midnight in a timezone
+	pub async fn count_handshakes_on(&self, date: Date, tz: &Tz) -> Result<i64> {
+		let start = start_of_day(date, tz);
+		let end = date.next_day().map(|next| start_of_day(next, tz));
+		Ok(sqlx::query_scalar(
+			"SELECT COUNT(*) FROM handshakes
+			WHERE created_at >= $1 AND ($2::TIMESTAMPTZ IS NULL OR created_at < $2) AND deleted_at IS NULL",
+		)
+		.bind(start)
+		.bind(end)
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of users that shook hands for the first time ever in each quarter-hour (in UTC) within a time
+	/// range, along with the number of handshakes from users that had shaken hands before
+	pub async fn count_new_and_returning_per_quarter_hour(
+		&self,
+		range: &TimeRange,
+		event: Option<&str>,
+		include_legacy: bool,
+	) -> Result<Vec<QuarterHourBreakdown>> {
+		Ok(sqlx::query_as(
+			r#"SELECT to_timestamp(floor(extract(epoch FROM created_at) / 900) * 900) AS start,
+				COUNT(*) FILTER (WHERE first) AS new_users, COUNT(*) FILTER (WHERE NOT first) AS "returning"
+			FROM (
+				SELECT created_at, event,
+					ROW_NUMBER() OVER (PARTITION BY user_id ORDER BY created_at, id) = 1 AS first
+				FROM handshakes WHERE deleted_at IS NULL AND ($3 OR NOT legacy)
+			) AS handshakes
+			WHERE ($1::TIMESTAMPTZ IS NULL OR created_at >= $1) AND ($2::TIMESTAMPTZ IS NULL OR created_at < $2)
+				AND ($4::TEXT IS NULL OR event = $4)
+			GROUP BY 1"#,
+		)
+		.bind(range.since)
+		.bind(range.until)
+		.bind(include_legacy)
+		.bind(event)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records created in each quarter-hour (in UTC) within a time range, optionally in a
+	/// specific world and tagged with a specific event
+	pub async fn count_handshakes_per_quarter_hour(
+		&self,
+		range: &TimeRange,
+		world: Option<&str>,
+		event: Option<&str>,
+	) -> Result<Vec<(OffsetDateTime, i64)>> {
+		Ok(sqlx::query_as(
+			"SELECT to_timestamp(floor(extract(epoch FROM created_at) / 900) * 900), COUNT(*)
+			FROM handshakes
+			WHERE ($1::TIMESTAMPTZ IS NULL OR created_at >= $1) AND ($2::TIMESTAMPTZ IS NULL OR created_at < $2)
+				AND ($3::TEXT IS NULL OR world_name = $3) AND ($4::TEXT IS NULL OR event = $4) AND deleted_at IS NULL
+			GROUP BY 1",
+		)
+		.bind(range.since)
+		.bind(range.until)
+		.bind(world)
+		.bind(event)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records that match a filter
+	pub async fn count_handshakes_matching(&self, filter: &HandshakeFilter) -> Result<i64> {
+		Ok(sqlx::query_scalar(
+			"SELECT COUNT(*) FROM handshakes
+			WHERE ($1::TEXT IS NULL OR source = $1) AND ($2::BOOLEAN IS NULL OR legacy = $2)
+				AND ($3::TEXT IS NULL OR event = $3) AND deleted_at IS NULL",
+		)
+		.bind(filter.source.as_deref())
+		.bind(filter.legacy)
+		.bind(filter.event.as_deref())
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records created in each minute from the first of a set of date/times up to `now`,
+	/// along with how many of them were created since the second and third
+	pub async fn count_handshakes_per_minute(
+		&self,
+		since: [OffsetDateTime; 3],
+		now: OffsetDateTime,
+		world: Option<&str>,
+		event: Option<&str>,
+	) -> Result<Vec<MinuteCounts>> {
+		Ok(sqlx::query_as(
+			"SELECT (floor(extract(epoch FROM created_at) / 60) * 60)::BIGINT AS start, COUNT(*) AS count,
+				COUNT(*) FILTER (WHERE created_at >= $2) AS last_15, COUNT(*) FILTER (WHERE created_at >= $3) AS last_5
+			FROM handshakes
+			WHERE created_at >= $1 AND created_at <= $4
+				AND ($5::TEXT IS NULL OR world_name = $5) AND ($6::TEXT IS NULL OR event = $6) AND deleted_at IS NULL
+			GROUP BY 1",
+		)
+		.bind(since[0])
+		.bind(since[1])
+		.bind(since[2])
+		.bind(now)
+		.bind(world)
+		.bind(event)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records submitted from each source, most first
+	pub async fn count_handshakes_by_source(&self) -> Result<Vec<SourceCount>> {
+		Ok(sqlx::query_as(
+			"SELECT source, COUNT(*) AS count FROM handshakes WHERE deleted_at IS NULL
+			GROUP BY source ORDER BY COUNT(*) DESC, source NULLS FIRST",
+		)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records tagged with each event, most recently active first
+	pub async fn count_handshakes_by_event(&self) -> Result<Vec<EventSummary>> {
+		Ok(sqlx::query_as(
+			"SELECT event, COUNT(*) AS count, MIN(created_at) AS first_at, MAX(created_at) AS last_at
+			FROM handshakes WHERE deleted_at IS NULL
+			GROUP BY event ORDER BY event IS NULL, MAX(created_at) DESC, event",
+		)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Counts the number of handshake records for a specific user
+	pub async fn count_user_handshakes(&self, id: i64) -> Result<i64> {
+		Ok(
+			sqlx::query_scalar("SELECT COUNT(*) FROM handshakes WHERE user_id = $1 AND deleted_at IS NULL")
+				.bind(id)
+				.fetch_one(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves the users with the most handshakes, most first
+	pub async fn get_top_users(&self, limit: i64) -> Result<Vec<UserHandshakeCount>> {
+		Ok(sqlx::query_as(
+			"SELECT users.id AS user_id, users.resonite_name, COUNT(*) AS count
+			FROM handshakes INNER JOIN users ON users.id = handshakes.user_id
+			WHERE handshakes.deleted_at IS NULL
+			GROUP BY users.id
+			ORDER BY COUNT(*) DESC, MAX(handshakes.id) ASC
+			LIMIT $1",
+		)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves overall statistics (see [`super::Database::stats`])
+	pub async fn stats(&self, top_users: i64) -> Result<Stats> {
+		let (users, handshakes, worlds, newest_handshake_at, archived_handshakes): (
+			i64,
+			i64,
+			i64,
+			Option<OffsetDateTime>,
+			i64,
+		) = sqlx::query_as(
+			"SELECT
+				(SELECT COUNT(*) FROM users WHERE deleted_at IS NULL),
+				COUNT(*),
+				COUNT(DISTINCT world_name),
+				MAX(created_at),
+				(SELECT COALESCE(SUM(count), 0)::BIGINT FROM handshake_archive)
+			FROM handshakes WHERE deleted_at IS NULL",
+		)
+		.fetch_one(&self.pool)
+		.await?;
+
+		Ok(Stats {
+			users,
+			handshakes,
+			archived_handshakes,
+			worlds,
+			newest_handshake_at,
+			top_users: self.get_top_users(top_users).await?,
+		})
+	}
+
+	/// Retrieves a page of user records along with the number of handshakes each has performed
+	pub async fn get_users_with_counts(&self, limit: i64, offset: i64, order: UserOrder) -> Result<Vec<UserWithCount>> {
+		Ok(sqlx::query_as(
+			"SELECT users.*, COUNT(handshakes.id) AS count
+			FROM users LEFT JOIN handshakes ON handshakes.user_id = users.id AND handshakes.deleted_at IS NULL
+			WHERE users.deleted_at IS NULL
+			GROUP BY users.id
+			ORDER BY
+				CASE $3 WHEN 'count' THEN COUNT(handshakes.id) END DESC NULLS LAST,
+				CASE $3 WHEN 'name' THEN users.resonite_name END,
+				users.id
+			LIMIT $1 OFFSET $2",
+		)
+		.bind(limit)
+		.bind(offset)
+		.bind(order.as_str())
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a page of users that match a filter and have no Resonite ID, along with the number of handshakes each
+	/// has performed, ordered by name
+	pub async fn get_users_missing_resonite_id(
+		&self,
+		filter: &NameFilter,
+		limit: i64,
+		offset: i64,
+	) -> Result<Vec<UserWithCount>> {
+		Ok(sqlx::query_as(
+			r"SELECT users.*, COUNT(handshakes.id) AS count
+			FROM users LEFT JOIN handshakes ON handshakes.user_id = users.id AND handshakes.deleted_at IS NULL
+			WHERE users.resonite_id IS NULL AND NOT users.anonymized AND users.deleted_at IS NULL
+				AND ($1::TEXT IS NULL OR users.resonite_name ILIKE $1 ESCAPE '\')
+			GROUP BY users.id
+			ORDER BY users.resonite_name, users.id
+			LIMIT $2 OFFSET $3",
+		)
+		.bind(filter.pattern())
+		.bind(limit)
+		.bind(offset)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Counts the users that match a filter and have no Resonite ID (excluding anonymized users)
+	pub async fn count_users_missing_resonite_id(&self, filter: &NameFilter) -> Result<i64> {
+		Ok(sqlx::query_scalar(
+			r"SELECT COUNT(*) FROM users
+			WHERE resonite_id IS NULL AND NOT anonymized AND deleted_at IS NULL
+				AND ($1::TEXT IS NULL OR resonite_name ILIKE $1 ESCAPE '\')",
+		)
+		.bind(filter.pattern())
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves when users first shook hands, for those that first did before a date/time and on one of a set of days
+	/// of the year in UTC (each given as `MM-DD`)
+	pub async fn get_first_handshakes_before(
+		&self,
+		before: OffsetDateTime,
+		month_days: [Option<String>; 4],
+	) -> Result<Vec<FirstHandshake>> {
+		let [previous, same, next, leap_day] = month_days;
+		Ok(sqlx::query_as(
+			"SELECT users.id AS user_id, users.resonite_name, MIN(handshakes.created_at) AS first_at
+			FROM users JOIN handshakes ON handshakes.user_id = users.id AND handshakes.deleted_at IS NULL
+			WHERE users.deleted_at IS NULL AND NOT users.anonymized
+			GROUP BY users.id
+			HAVING MIN(handshakes.created_at) < $1
+				AND to_char(MIN(handshakes.created_at) AT TIME ZONE 'UTC', 'MM-DD') IN ($2, $3, $4, $5)",
+		)
+		.bind(before)
+		.bind(previous)
+		.bind(same)
+		.bind(next)
+		.bind(leap_day)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Removes a user's Resonite ID and name along with their name history (see [`super::Database::anonymize_user`])
+	pub async fn anonymize_user(&self, id: i64) -> Result<Option<User>> {
+		let mut tx = self.pool.begin().await?;
+		let user: Option<User> = sqlx::query_as(
+			"UPDATE users SET resonite_id = NULL, resonite_name = $2, anonymized = TRUE, note = NULL,
+				updated_at = CURRENT_TIMESTAMP
+			WHERE id = $1 AND deleted_at IS NULL
+			RETURNING *",
+		)
+		.bind(id)
+		.bind(format!("deleted-user-{id}"))
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(user_conflict)?;
+		let Some(user) = user else {
+			return Ok(None);
+		};
+
+		sqlx::query("DELETE FROM user_name_history WHERE user_id = $1")
+			.bind(id)
+			.execute(&mut *tx)
+			.await?;
+		tx.commit().await?;
+		Ok(Some(user))
+	}
+
+	/// Soft-deletes a user along with all of their handshakes in a single transaction
+	pub async fn try_delete_user(&self, id: i64) -> Result<bool> {
+		let mut tx = self.pool.begin().await?;
+		let deleted_at: Option<OffsetDateTime> = sqlx::query_scalar(
+			"UPDATE users SET deleted_at = CURRENT_TIMESTAMP WHERE id = $1 AND deleted_at IS NULL RETURNING deleted_at",
+		)
+		.bind(id)
+		.fetch_optional(&mut *tx)
+		.await?;
+
+		// Give the handshakes the same deletion time so that restoring the user only restores those deleted along with it
+		if let Some(deleted_at) = deleted_at {
+			sqlx::query("UPDATE handshakes SET deleted_at = $2 WHERE user_id = $1 AND deleted_at IS NULL")
+				.bind(id)
+				.bind(deleted_at)
+				.execute(&mut *tx)
+				.await?;
+		}
+
+		tx.commit().await?;
+		Ok(deleted_at.is_some())
+	}
+
+	/// Soft-deletes a handshake with a single statement
+	pub async fn try_delete_handshake(&self, id: i64) -> Result<bool> {
+		Ok(
+			sqlx::query("UPDATE handshakes SET deleted_at = CURRENT_TIMESTAMP WHERE id = $1 AND deleted_at IS NULL")
+				.bind(id)
+				.execute(&self.pool)
+				.await?
+				.rows_affected()
+				> 0,
+		)
+	}
+
+	/// Soft-deletes a user's most recent handshake if it took place since a date/time in a single transaction (see
+	/// [`super::Database::undo_latest_handshake`])
+	pub async fn try_undo_latest_handshake(
+		&self,
+		user_id: i64,
+		since: OffsetDateTime,
+		remove_user: bool,
+	) -> Result<UndoOutcome> {
+		let mut tx = self.pool.begin().await?;
+
+		// Locking the latest handshake first means concurrent undos of it wait for each other rather than both seeing it
+		// undeleted
+		let latest: Option<Handshake> = sqlx::query_as(
+			"SELECT * FROM handshakes WHERE user_id = $1 ORDER BY created_at DESC, id DESC LIMIT 1 FOR UPDATE",
+		)
+		.bind(user_id)
+		.fetch_optional(&mut *tx)
+		.await?;
+		let handshake = match latest {
+			None => return Ok(UndoOutcome::NoHandshakes),
+			Some(shake) if shake.deleted_at.is_some() => return Ok(UndoOutcome::AlreadyUndone(shake)),
+			Some(shake) if shake.created_at < since => return Ok(UndoOutcome::TooOld(shake)),
+			Some(shake) => {
+				sqlx::query_as::<_, Handshake>(
+					"UPDATE handshakes SET deleted_at = CURRENT_TIMESTAMP WHERE id = $1 RETURNING *",
+				)
+				.bind(shake.id)
+				.fetch_one(&mut *tx)
+				.await?
+			}
+		};
+
+		// A user whose only handshake was undone is deleted for good rather than soft-deleted, since a soft-deleted user
+		// couldn't shake hands again until they were restored
+		let remove_user = remove_user
+			&& sqlx::query_scalar("SELECT NOT EXISTS(SELECT 1 FROM handshakes WHERE user_id = $1 AND id != $2)")
+				.bind(user_id)
+				.bind(handshake.id)
+				.fetch_one(&mut *tx)
+				.await?;
+		if remove_user {
+			for statement in [
+				"DELETE FROM handshakes WHERE user_id = $1",
+				"DELETE FROM user_name_history WHERE user_id = $1",
+				"DELETE FROM users WHERE id = $1",
+			] {
+				sqlx::query(statement).bind(user_id).execute(&mut *tx).await?;
+			}
+		}
+
+		tx.commit().await?;
+		Ok(UndoOutcome::Undone {
+			handshake,
+			user_removed: remove_user,
+		})
+	}
+
+	/// Retrieves a page of soft-deleted users, most recently deleted first
+	pub async fn get_deleted_users(&self, limit: i64, offset: i64) -> Result<Vec<User>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM users WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC, id DESC LIMIT $1 OFFSET $2",
+		)
+		.bind(limit)
+		.bind(offset)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a page of soft-deleted handshakes, most recently deleted first
+	pub async fn get_deleted_handshakes(&self, limit: i64, offset: i64) -> Result<Vec<Handshake>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM handshakes WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC, id DESC LIMIT $1 OFFSET $2",
+		)
+		.bind(limit)
+		.bind(offset)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Restores a soft-deleted user along with the handshakes that were deleted with them
+	pub async fn restore_user(&self, id: i64) -> Result<bool> {
+		let mut tx = self.pool.begin().await?;
+		sqlx::query(
+			"UPDATE handshakes SET deleted_at = NULL
+			WHERE user_id = $1 AND deleted_at = (SELECT deleted_at FROM users WHERE id = $1)",
+		)
+		.bind(id)
+		.execute(&mut *tx)
+		.await?;
+		let restored = sqlx::query("UPDATE users SET deleted_at = NULL WHERE id = $1 AND deleted_at IS NOT NULL")
+			.bind(id)
+			.execute(&mut *tx)
+			.await?
+			.rows_affected()
+			> 0;
+
+		tx.commit().await?;
+		Ok(restored)
+	}
+
+	/// Restores a soft-deleted handshake of a user that isn't deleted
+	pub async fn restore_handshake(&self, id: i64) -> Result<bool> {
+		Ok(sqlx::query(
+			"UPDATE handshakes SET deleted_at = NULL
+			WHERE id = $1 AND deleted_at IS NOT NULL
+				AND user_id IN (SELECT id FROM users WHERE deleted_at IS NULL)",
+		)
+		.bind(id)
+		.execute(&self.pool)
+		.await?
+		.rows_affected()
+			> 0)
+	}
+
+	/// Permanently deletes records that were soft-deleted before a date/time in a single transaction
+	pub async fn try_purge_deleted(&self, before: OffsetDateTime) -> Result<PurgedCounts> {
+		let mut tx = self.pool.begin().await?;
+		let handshakes = sqlx::query(
+			"DELETE FROM handshakes
+			WHERE deleted_at < $1 OR user_id IN (SELECT id FROM users WHERE deleted_at < $1)",
+		)
+		.bind(before)
+		.execute(&mut *tx)
+		.await?
+		.rows_affected();
+		sqlx::query("DELETE FROM user_name_history WHERE user_id IN (SELECT id FROM users WHERE deleted_at < $1)")
+			.bind(before)
+			.execute(&mut *tx)
+			.await?;
+		let users = sqlx::query("DELETE FROM users WHERE deleted_at < $1")
+			.bind(before)
+			.execute(&mut *tx)
+			.await?
+			.rows_affected();
+
+		tx.commit().await?;
+		Ok(PurgedCounts { users, handshakes })
+	}
+
+	/// Deletes (and optionally archives, by their UTC dates) a batch of handshakes in a single transaction
+	pub async fn try_expire_handshakes(&self, before: OffsetDateTime, archive: bool, limit: i64) -> Result<u64> {
+		let mut tx = self.pool.begin().await?;
+		if archive {
+			sqlx::query(
+				"INSERT INTO handshake_archive (day, count)
+				SELECT (created_at AT TIME ZONE 'UTC')::DATE, COUNT(*) FROM (
+					SELECT created_at FROM handshakes WHERE created_at < $1 AND deleted_at IS NULL
+					ORDER BY created_at, id LIMIT $2
+				) AS expiring GROUP BY 1
+				ON CONFLICT (day) DO UPDATE SET count = handshake_archive.count + excluded.count",
+			)
+			.bind(before)
+			.bind(limit)
+			.execute(&mut *tx)
+			.await?;
+		}
+
+		let expired = sqlx::query(
+			"DELETE FROM handshakes WHERE id IN (
+				SELECT id FROM handshakes WHERE created_at < $1 ORDER BY created_at, id LIMIT $2
+			)",
+		)
+		.bind(before)
+		.bind(limit)
+		.execute(&mut *tx)
+		.await?
+		.rows_affected();
+
+		tx.commit().await?;
+		Ok(expired)
+	}
+
+	/// Bans a user from shaking hands by their Resonite ID, or by their (validated) username if no ID is given
+	pub async fn ban_user(&self, ban: &NewBan, name: Option<&str>) -> Result<Ban> {
+		sqlx::query_as("INSERT INTO banned_users (resonite_id, resonite_name, reason) VALUES ($1, $2, $3) RETURNING *")
+			.bind(ban.resonite_id.as_deref())
+			.bind(name)
+			.bind(ban.reason.as_deref())
+			.fetch_one(&self.pool)
+			.await
+			.map_err(|err| ban_conflict(err, ban))
+	}
+
+	/// Lifts the ban on a Resonite ID, returning whether there was one
+	pub async fn unban_user(&self, resonite_id: &str) -> Result<bool> {
+		Ok(sqlx::query("DELETE FROM banned_users WHERE resonite_id = $1")
+			.bind(resonite_id)
+			.execute(&self.pool)
+			.await?
+			.rows_affected()
+			> 0)
+	}
+
+	/// Lifts the ban on a username (in any case) that was banned without a Resonite ID, returning whether there was one
+	pub async fn unban_user_name(&self, resonite_name: &str) -> Result<bool> {
+		Ok(
+			sqlx::query("DELETE FROM banned_users WHERE resonite_id IS NULL AND lower(resonite_name) = $1")
+				.bind(resonite_name.to_ascii_lowercase())
+				.execute(&self.pool)
+				.await?
+				.rows_affected()
+				> 0,
+		)
+	}
+
+	/// Retrieves a page of bans, most recent first
+	pub async fn get_bans(&self, limit: i64, offset: i64) -> Result<Vec<Ban>> {
+		Ok(
+			sqlx::query_as("SELECT * FROM banned_users ORDER BY banned_at DESC, id DESC LIMIT $1 OFFSET $2")
+				.bind(limit)
+				.bind(offset)
+				.fetch_all(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Stores a new audit log entry
+	pub async fn create_audit_entry(&self, entry: &NewAuditEntry<'_>) -> Result<i64> {
+		Ok(sqlx::query_scalar(
+			"INSERT INTO audit_log (method, route, client_ip, scope, record_ids) VALUES ($1, $2, $3, $4, $5)
+			RETURNING id",
+		)
+		.bind(entry.method)
+		.bind(entry.route)
+		.bind(entry.client_ip.as_deref())
+		.bind(entry.scope)
+		.bind(&entry.record_ids)
+		.fetch_one(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a page of audit log entries, newest first
+	pub async fn get_audit_entries(&self, limit: i64, offset: i64) -> Result<Vec<AuditEntry>> {
+		Ok(
+			sqlx::query_as("SELECT * FROM audit_log ORDER BY id DESC LIMIT $1 OFFSET $2")
+				.bind(limit)
+				.bind(offset)
+				.fetch_all(&self.pool)
+				.await?,
+		)
+	}
+
+	/// Retrieves pending webhook deliveries that are due to be attempted, oldest first
+	pub async fn get_due_outbox_entries(&self, limit: i64) -> Result<Vec<OutboxEntry>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM webhook_outbox
+			WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+			ORDER BY id LIMIT $1",
+		)
+		.bind(limit)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Retrieves a page of webhook deliveries that haven't been delivered (pending or failed), newest first
+	pub async fn get_undelivered_outbox_entries(&self, limit: i64, offset: i64) -> Result<Vec<OutboxEntry>> {
+		Ok(sqlx::query_as(
+			"SELECT * FROM webhook_outbox WHERE status != 'delivered' ORDER BY id DESC LIMIT $1 OFFSET $2",
+		)
+		.bind(limit)
+		.bind(offset)
+		.fetch_all(&self.pool)
+		.await?)
+	}
+
+	/// Marks a webhook delivery as delivered
+	pub async fn mark_outbox_delivered(&self, id: i64) -> Result<()> {
+		sqlx::query(
+			"UPDATE webhook_outbox
+			SET status = 'delivered', attempts = attempts + 1, last_error = NULL, delivered_at = CURRENT_TIMESTAMP
+			WHERE id = $1",
+		)
+		.bind(id)
+		.execute(&self.pool)
+		.await?;
+		Ok(())
+	}
+
+	/// Records a failed attempt at a webhook delivery, retrying it after a delay if one is given
+	pub async fn mark_outbox_attempt_failed(&self, id: i64, error: &str, retry_in_secs: Option<i64>) -> Result<()> {
+		match retry_in_secs {
+			Some(secs) => {
+				sqlx::query(
+					"UPDATE webhook_outbox
+					SET attempts = attempts + 1, last_error = $2, next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $3)
+					WHERE id = $1",
+				)
+				.bind(id)
+				.bind(error)
+				.bind(secs)
+				.execute(&self.pool)
+				.await?
+			}
+			None => sqlx::query(
+				"UPDATE webhook_outbox SET status = 'failed', attempts = attempts + 1, last_error = $2 WHERE id = $1",
+			)
+			.bind(id)
+			.bind(error)
+			.execute(&self.pool)
+			.await?,
+		};
+		Ok(())
+	}
+
+	/// Resets an undelivered webhook delivery so that it's attempted again immediately with a fresh attempt count
+	pub async fn retry_outbox_entry(&self, id: i64) -> Result<bool> {
+		let result = sqlx::query(
+			"UPDATE webhook_outbox
+			SET status = 'pending', attempts = 0, next_attempt_at = CURRENT_TIMESTAMP
+			WHERE id = $1 AND status != 'delivered'",
+		)
+		.bind(id)
+		.execute(&self.pool)
+		.await?;
+
+		Ok(result.rows_affected() > 0)
+	}
+}
+
+#[cfg(any(test, feature = "test-util"))]
+/// Schema that a test database was opened in, which is dropped along with everything in it once it's dropped. The
+/// database's connections are named after the schema.
+#[derive(Debug)]
+struct TestSchema {
+	/// Options to connect to the database with
+	options: PgConnectOptions,
+
+	/// Name of the schema
+	name: String,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Drop for TestSchema {
+	fn drop(&mut self) {
+		// Dropping can happen within an async runtime (which can't be blocked on) or outside of one, so the schema is
+		// dropped on a thread of its own with a runtime of its own
+		let options = self.options.clone();
+		let name = std::mem::take(&mut self.name);
+		let dropped = std::thread::spawn(move || {
+			let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+			runtime.block_on(async {
+				// Connections of the pool that are still open could be holding locks on the schema, such as in
+				// transactions that were dropped without their rollbacks having been sent yet
+				let mut conn = PgConnection::connect_with(&options).await?;
+				sqlx::query(
+					"SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+					WHERE application_name = $1 AND pid <> pg_backend_pid()",
+				)
+				.bind(&name)
+				.execute(&mut conn)
+				.await?;
+				sqlx::query(&format!("DROP SCHEMA {name} CASCADE"))
+					.execute(&mut conn)
+					.await?;
+				conn.close().await?;
+				anyhow::Ok(())
+			})
+		})
+		.join();
+
+		match dropped {
+			Ok(Ok(())) => {}
+			Ok(Err(err)) => warn!("Unable to drop test schema {}: {err:#}", self.name),
+			Err(_) => warn!("Dropping test schema {} panicked", self.name),
+		}
+	}
+}
+
+/// Transaction for importing records, which is rolled back if it's dropped without being committed
+pub struct ImportTransaction {
+	/// Transaction the records are imported in
+	tx: sqlx::Transaction<'static, sqlx::Postgres>,
+}
+
+impl ImportTransaction {
+	/// Imports legacy handshakes, returning the outcome of each one in order (see
+	/// [`super::ImportTransaction::import`])
+	pub async fn import(
+		&mut self,
+		records: &[LegacyRecord],
+		add_handshake: bool,
+	) -> Result<Vec<Result<ImportOutcome>>> {
+		// Most batches import cleanly, so try the whole batch at once first and only fall back to a savepoint per record
+		// when one of them fails
+		let mut batch = self.tx.begin().await?;
+		let mut outcomes = Vec::with_capacity(records.len());
+		for record in records {
+			match import_legacy_record(&mut batch, record, add_handshake).await {
+				Ok(outcome) => outcomes.push(Ok(outcome)),
+				Err(_) => break,
+			}
+		}
+		if outcomes.len() == records.len() {
+			batch.commit().await?;
+			return Ok(outcomes);
+		}
+		batch.rollback().await?;
+
+		outcomes.clear();
+		for record in records {
+			let mut savepoint = self.tx.begin().await?;
+			let outcome = import_legacy_record(&mut savepoint, record, add_handshake).await;
+			if outcome.is_ok() {
+				savepoint.commit().await?;
+			} else {
+				savepoint.rollback().await?;
+			}
+			outcomes.push(outcome);
+		}
+		Ok(outcomes)
+	}
+
+	/// Restores a user from an export with all of their details except their ID, returning the ID they're stored
+	/// under instead (see [`super::ImportTransaction::restore_user`])
+	pub async fn restore_user(&mut self, user: &User, merge: bool) -> Result<(i64, ImportOutcome)> {
+		let mut savepoint = self.tx.begin().await?;
+		if merge {
+			if let Some(existing) =
+				find_legacy_user(&mut savepoint, user.resonite_id.as_deref(), &user.resonite_name).await?
+			{
+				savepoint.commit().await?;
+				return Ok((existing.id, ImportOutcome::Skipped));
+			}
+		}
+
+		let id = sqlx::query_scalar(
+			"INSERT INTO users (resonite_id, resonite_name, legacy, created_at, updated_at, last_seen_at, anonymized, note)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+			RETURNING id",
+		)
+		.bind(user.resonite_id.as_deref())
+		.bind(&user.resonite_name)
+		.bind(user.legacy)
+		.bind(user.created_at)
+		.bind(user.updated_at)
+		.bind(user.last_seen_at)
+		.bind(user.anonymized)
+		.bind(user.note.as_deref())
+		.fetch_one(&mut *savepoint)
+		.await?;
+		savepoint.commit().await?;
+		Ok((id, ImportOutcome::Created))
+	}
+
+	/// Restores a handshake from an export with all of its details except its ID, for the user stored under the given
+	/// ID (see [`super::ImportTransaction::restore_handshake`])
+	pub async fn restore_handshake(&mut self, shake: &Handshake, user_id: i64, merge: bool) -> Result<ImportOutcome> {
+		if merge {
+			let exists: bool =
+				sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM handshakes WHERE user_id = $1 AND created_at = $2)")
+					.bind(user_id)
+					.bind(shake.created_at)
+					.fetch_one(&mut *self.tx)
+					.await?;
+			if exists {
+				return Ok(ImportOutcome::Skipped);
+			}
+		}
+
+		let mut savepoint = self.tx.begin().await?;
+		sqlx::query(
+			"INSERT INTO handshakes (user_id, world_name, source, event, legacy, created_at)
+			VALUES ($1, $2, $3, $4, $5, $6)",
+		)
+		.bind(user_id)
+		.bind(shake.world_name.as_deref())
+		.bind(shake.source.as_deref())
+		.bind(shake.event.as_deref())
+		.bind(shake.legacy)
+		.bind(shake.created_at)
+		.execute(&mut *savepoint)
+		.await?;
+		sqlx::query("UPDATE users SET last_seen_at = GREATEST(last_seen_at, $2) WHERE id = $1")
+			.bind(user_id)
+			.bind(shake.created_at)
+			.execute(&mut *savepoint)
+			.await?;
+		savepoint.commit().await?;
+		Ok(ImportOutcome::AddedHandshake)
+	}
+
+	/// Writes everything imported so far to the database
+	pub async fn commit(self) -> Result<()> {
+		Ok(self.tx.commit().await?)
+	}
+}
+
+/// Checks whether an error is due to a lock that couldn't be acquired in time, or to a transaction that conflicted
+/// with another one and can be retried
+pub(super) fn is_busy(err: &PgDatabaseError) -> bool {
+	matches!(
+		err.code(),
+		SERIALIZATION_FAILURE | DEADLOCK_DETECTED | LOCK_NOT_AVAILABLE
+	)
+}
+
+/// Updates a user's Resonite ID and username, recording the rename in its history if it is one
+async fn update_user_record(
+	tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+	id: i64,
+	resonite_id: Option<&str>,
+	name: &str,
+) -> Result<bool> {
+	let old_name: Option<String> = sqlx::query_scalar("SELECT resonite_name FROM users WHERE id = $1")
+		.bind(id)
+		.fetch_optional(&mut **tx)
+		.await?;
+	if let Some(old_name) = old_name.filter(|old_name| old_name != name) {
+		record_name_change(tx, id, &old_name, name).await?;
+	}
+
+	let result = sqlx::query(
+		"UPDATE users SET resonite_id = $2, resonite_name = $3, updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+	)
+	.bind(id)
+	.bind(resonite_id)
+	.bind(name)
+	.execute(&mut **tx)
+	.await
+	.map_err(user_conflict)?;
+	Ok(result.rows_affected() > 0)
+}
+
+/// Fails with a [`BannedError`] if a user is banned by their Resonite ID, or by their username (in any case) for
+/// bans without an ID
+async fn check_ban(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, info: &UserResoniteInfo) -> Result<()> {
+	let banned_by_id: Option<bool> = sqlx::query_scalar(
+		"SELECT resonite_id IS NOT NULL FROM banned_users
+		WHERE resonite_id = $1 OR (resonite_id IS NULL AND lower(resonite_name) = $2)
+		ORDER BY resonite_id IS NULL LIMIT 1",
+	)
+	.bind(&info.id)
+	.bind(info.name.to_ascii_lowercase())
+	.fetch_optional(&mut **tx)
+	.await?;
+
+	match banned_by_id {
+		Some(true) => Err(BannedError { field: "id" }.into()),
+		Some(false) => Err(BannedError { field: "name" }.into()),
+		None => Ok(()),
+	}
+}
+
+/// Records a change of a user's Resonite username in its history
+async fn record_name_change(
+	tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+	user_id: i64,
+	old_name: &str,
+	new_name: &str,
+) -> Result<()> {
+	sqlx::query("INSERT INTO user_name_history (user_id, old_name, new_name) VALUES ($1, $2, $3)")
+		.bind(user_id)
+		.bind(old_name)
+		.bind(new_name)
+		.execute(&mut **tx)
+		.await?;
+	Ok(())
+}
+
+/// Queues a delivery of a webhook payload to each URL in the outbox
+async fn queue_webhook_deliveries(
+	tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+	urls: &[String],
+	payload: &str,
+) -> Result<()> {
+	for url in urls {
+		sqlx::query("INSERT INTO webhook_outbox (url, payload) VALUES ($1, $2)")
+			.bind(url)
+			.bind(payload)
+			.execute(&mut **tx)
+			.await?;
+	}
+	Ok(())
+}
+
+/// Imports a legacy handshake, creating the user that performed it if they don't exist yet (see
+/// [`super::import_legacy_record`])
+async fn import_legacy_record(
+	tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+	record: &LegacyRecord,
+	add_handshake: bool,
+) -> Result<ImportOutcome> {
+	let name = validate::name("name", &record.resonite_name)?;
+
+	let user = find_legacy_user(tx, record.resonite_id.as_deref(), &name).await?;
+	let (user_id, outcome) = match user {
+		None => {
+			let id = sqlx::query_scalar(
+				"INSERT INTO users (resonite_id, resonite_name, legacy, created_at, last_seen_at, updated_at)
+				VALUES ($1, $2, TRUE, COALESCE($3, CURRENT_TIMESTAMP), COALESCE($3, CURRENT_TIMESTAMP), CURRENT_TIMESTAMP)
+				RETURNING id",
+			)
+			.bind(record.resonite_id.as_deref())
+			.bind(&name)
+			.bind(record.created_at)
+			.fetch_one(&mut **tx)
+			.await?;
+			(id, ImportOutcome::Created)
+		}
+
+		Some(user) => {
+			let exists = match record.created_at {
+				Some(created_at) => {
+					sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM handshakes WHERE user_id = $1 AND created_at = $2)")
+						.bind(user.id)
+						.bind(created_at)
+						.fetch_one(&mut **tx)
+						.await?
+				}
+				None => !add_handshake
+					|| sqlx::query_scalar(
+						"SELECT EXISTS(SELECT 1 FROM handshakes WHERE user_id = $1 AND legacy AND deleted_at IS NULL)",
+					)
+					.bind(user.id)
+					.fetch_one(&mut **tx)
+					.await?,
+			};
+			if exists {
+				return Ok(ImportOutcome::Skipped);
+			}
+
+			// Users are treated as having existed since their earliest handshake
+			sqlx::query("UPDATE users SET created_at = LEAST(created_at, $2) WHERE id = $1")
+				.bind(user.id)
+				.bind(record.created_at)
+				.execute(&mut **tx)
+				.await?;
+			(user.id, ImportOutcome::AddedHandshake)
+		}
+	};
+
+	sqlx::query(
+		"INSERT INTO handshakes (user_id, world_name, legacy, created_at)
+		VALUES ($1, $2, TRUE, COALESCE($3, CURRENT_TIMESTAMP))",
+	)
+	.bind(user_id)
+	.bind(record.world_name.as_deref())
+	.bind(record.created_at)
+	.execute(&mut **tx)
+	.await?;
+
+	// New users already have the handshake's time as when they were last seen
+	if outcome == ImportOutcome::AddedHandshake {
+		sqlx::query(
+			"UPDATE users SET last_seen_at = (SELECT MAX(created_at) FROM handshakes WHERE user_id = $1 AND deleted_at IS NULL)
+			WHERE id = $1",
+		)
+		.bind(user_id)
+		.execute(&mut **tx)
+		.await?;
+	}
+
+	Ok(outcome)
+}
+
+/// Finds the existing user that a legacy record refers to, by Resonite ID (if given) and then by name, ignoring case
+/// (see [`super::find_legacy_user`])
+async fn find_legacy_user(
+	tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+	resonite_id: Option<&str>,
+	name: &str,
+) -> Result<Option<User>> {
+	if let Some(resonite_id) = resonite_id {
+		let user = sqlx::query_as("SELECT * FROM users WHERE resonite_id = $1 AND deleted_at IS NULL")
+			.bind(resonite_id)
+			.fetch_optional(&mut **tx)
+			.await?;
+		if user.is_some() {
+			return Ok(user);
+		}
+	}
+
+	let Some(mut user) = sqlx::query_as::<_, User>(
+		"SELECT * FROM users WHERE lower(resonite_name) = $1 AND deleted_at IS NULL AND NOT anonymized
+		ORDER BY resonite_name = $2 DESC, id LIMIT 1",
+	)
+	.bind(name.to_ascii_lowercase())
+	.bind(name)
+	.fetch_optional(&mut **tx)
+	.await?
+	else {
+		return Ok(None);
+	};
+
+	match (&user.resonite_id, resonite_id) {
+		(Some(existing), Some(_)) => {
+			return Err(ConflictError::new(
+				"resonite_id",
+				format!(
+					"user {} named \"{name}\" already has the Resonite ID {existing}",
+					user.id
+				),
+			)
+			.into());
+		}
+		(None, Some(id)) => {
+			sqlx::query("UPDATE users SET resonite_id = $2, updated_at = CURRENT_TIMESTAMP WHERE id = $1")
+				.bind(user.id)
+				.bind(id)
+				.execute(&mut **tx)
+				.await?;
+			user.resonite_id = Some(id.to_owned());
+		}
+		_ => {}
+	}
+	Ok(Some(user))
+}
@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use time::{format_description, OffsetDateTime, PrimitiveDateTime};
+
+use super::{Database, User, UserResoniteInfo};
+
+/// Gets the date/time a number of days before now, to the second
+#[must_use]
+pub fn days_ago(days: i64) -> OffsetDateTime {
+	let now = OffsetDateTime::now_utc();
+	now - time::Duration::nanoseconds(now.nanosecond().into()) - time::Duration::days(days)
+}
+
+/// Parses a UTC date/time in the form `2024-03-10 07:30:00`
+///
+/// # Panics
+/// If the date/time isn't in that form
+#[must_use]
+pub fn utc(datetime: &str) -> OffsetDateTime {
+	let format = format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
+	PrimitiveDateTime::parse(datetime, &format)
+		.unwrap_or_else(|err| panic!("invalid date/time {datetime:?}: {err}"))
+		.assume_utc()
+}
+
+/// Builder for seeding a database with users and their handshakes at known dates/times, so that tests of time ranges
+/// and leaderboards are reproducible. Each user is added with [`Fixture::user`] (or [`Fixture::legacy_user`]), then
+/// given batches of handshakes with [`Fixture::handshakes`], which the methods after it describe:
+///
+/// ```
+/// # use shaker::db::{testing::{days_ago, Fixture}, Database};
+/// # #[tokio::main]
+/// # async fn main() -> anyhow::Result<()> {
+/// let db = Database::open_in_memory().await?;
+/// let users = Fixture::new(&db)
+///     .user("U-Foo", "Foo")
+///     .handshakes(5)
+///     .at(days_ago(3))
+///     .in_world("Hub")
+///     .user("U-Bar", "Bar")
+///     .handshakes(2)
+///     .insert()
+///     .await?;
+/// assert_eq!(db.count_user_handshakes(users[0].id).await?, 5);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Handshakes in a batch are a minute apart, the last of them at the batch's date/time (which defaults to when the
+/// fixture was created). Everything is inserted in the order it was added.
+#[derive(Debug)]
+#[must_use]
+pub struct Fixture<'a> {
+	/// Database to insert into
+	db: &'a Database,
+
+	/// Date/time that batches of handshakes end at unless given another
+	now: OffsetDateTime,
+
+	/// Users to insert, along with their handshakes
+	users: Vec<FixtureUser>,
+}
+
+/// User to insert as part of a fixture
+#[derive(Debug)]
+struct FixtureUser {
+	/// Resonite ID of the user, if they aren't a legacy user
+	resonite_id: Option<String>,
+
+	/// Resonite username of the user
+	resonite_name: String,
+
+	/// Batches of handshakes to insert for the user
+	batches: Vec<Batch>,
+}
+
+/// Batch of handshakes to insert for a user
+#[derive(Debug)]
+struct Batch {
+	/// Number of handshakes in the batch
+	count: u32,
+
+	/// Date/time of the last handshake in the batch
+	at: OffsetDateTime,
+
+	/// World the handshakes took place in
+	world: Option<String>,
+
+	/// Source the handshakes were submitted from
+	source: Option<String>,
+
+	/// Event the handshakes took place at
+	event: Option<String>,
+}
+
+impl<'a> Fixture<'a> {
+	/// Starts a fixture for a database
+	pub fn new(db: &'a Database) -> Self {
+		Self {
+			db,
+			now: days_ago(0),
+			users: Vec::new(),
+		}
+	}
+
+	/// Adds a user, who the handshakes added after it belong to
+	pub fn user(self, resonite_id: &str, resonite_name: &str) -> Self {
+		self.push_user(Some(resonite_id), resonite_name)
+	}
+
+	/// Adds a legacy (username-only) user, who the handshakes added after it belong to
+	pub fn legacy_user(self, resonite_name: &str) -> Self {
+		self.push_user(None, resonite_name)
+	}
+
+	/// Adds a batch of handshakes for the most recently added user
+	///
+	/// # Panics
+	/// If no user has been added yet
+	pub fn handshakes(mut self, count: u32) -> Self {
+		let at = self.now;
+		self.users
+			.last_mut()
+			.expect("a user must be added before their handshakes")
+			.batches
+			.push(Batch {
+				count,
+				at,
+				world: None,
+				source: None,
+				event: None,
+			});
+		self
+	}
+
+	/// Sets the date/time of the last handshake in the most recently added batch
+	///
+	/// # Panics
+	/// If no handshakes have been added yet
+	pub fn at(mut self, at: OffsetDateTime) -> Self {
+		self.batch().at = at;
+		self
+	}
+
+	/// Sets the world that the most recently added batch of handshakes took place in
+	///
+	/// # Panics
+	/// If no handshakes have been added yet
+	pub fn in_world(mut self, world: &str) -> Self {
+		self.batch().world = Some(world.to_owned());
+		self
+	}
+
+	/// Sets the source that the most recently added batch of handshakes was submitted from
+	///
+	/// # Panics
+	/// If no handshakes have been added yet
+	pub fn from_source(mut self, source: &str) -> Self {
+		self.batch().source = Some(source.to_owned());
+		self
+	}
+
+	/// Sets the event that the most recently added batch of handshakes took place at
+	///
+	/// # Panics
+	/// If no handshakes have been added yet
+	pub fn at_event(mut self, event: &str) -> Self {
+		self.batch().event = Some(event.to_owned());
+		self
+	}
+
+	/// Inserts everything into the database, returning the users in the order they were added
+	pub async fn insert(self) -> Result<Vec<User>> {
+		let mut users = Vec::with_capacity(self.users.len());
+		for fixture in self.users {
+			let user = match &fixture.resonite_id {
+				Some(id) => {
+					let info = UserResoniteInfo::new(id.clone(), &fixture.resonite_name)?;
+					self.db.create_user(&info).await?
+				}
+				None => self.db.create_legacy_user(&fixture.resonite_name).await?,
+			};
+
+			for batch in &fixture.batches {
+				for before in (0..batch.count).rev() {
+					let at = batch.at - time::Duration::minutes(before.into());
+					self.db
+						.create_handshake_at(
+							user.id,
+							batch.world.as_deref(),
+							batch.source.as_deref(),
+							batch.event.as_deref(),
+							at,
+						)
+						.await?;
+				}
+			}
+
+			let user = self
+				.db
+				.get_user(user.id)
+				.await?
+				.with_context(|| format!("Unable to retrieve fixture user with ID {}", user.id))?;
+			users.push(user);
+		}
+		Ok(users)
+	}
+
+	/// Adds a user to the fixture
+	fn push_user(mut self, resonite_id: Option<&str>, resonite_name: &str) -> Self {
+		self.users.push(FixtureUser {
+			resonite_id: resonite_id.map(str::to_owned),
+			resonite_name: resonite_name.to_owned(),
+			batches: Vec::new(),
+		});
+		self
+	}
+
+	/// Gets the most recently added batch of handshakes
+	///
+	/// # Panics
+	/// If no batch has been added yet
+	fn batch(&mut self) -> &mut Batch {
+		self.users
+			.last_mut()
+			.and_then(|user| user.batches.last_mut())
+			.expect("handshakes must be added before they're described")
+	}
+}
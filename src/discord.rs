@@ -0,0 +1,362 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::{Client, Url};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, Date, OffsetDateTime};
+use time_tz::{OffsetDateTimeExt, Tz};
+use tokio::{sync::mpsc, time as tokio_time};
+use tracing::{debug, info, warn};
+
+use crate::db;
+
+/// Number of messages that may be queued before new ones are dropped
+const QUEUE_CAPACITY: usize = 64;
+
+/// Maximum number of attempts to deliver each message
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry, doubled for each subsequent one
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Maximum time to wait for Discord to respond
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Embed color for milestone announcements (gold)
+const MILESTONE_COLOR: u32 = 0x00F1_C40F;
+
+/// Embed color for first-time handshaker announcements (green)
+const FIRST_TIME_COLOR: u32 = 0x002E_CC71;
+
+/// Embed color for daily summaries (blurple)
+const SUMMARY_COLOR: u32 = 0x0058_65F2;
+
+/// Message to post to a Discord webhook
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Message {
+	/// Embeds to include in the message
+	pub embeds: Vec<Embed>,
+}
+
+impl Message {
+	/// Creates a message announcing that a handshake reached a milestone count
+	#[must_use]
+	pub fn milestone(number: i64, shake: &db::HandshakeWithUser) -> Self {
+		Self::single(Embed {
+			title: format!("🎉 Handshake number {number}!"),
+			description: format!(
+				"**{}** shook hands for handshake number {number}",
+				escape_markdown(&shake.resonite_name)
+			),
+			color: MILESTONE_COLOR,
+			timestamp: rfc3339(shake.created_at),
+			fields: world_field(shake),
+		})
+	}
+
+	/// Creates a message announcing a user's first handshake
+	#[must_use]
+	pub fn first_time(shake: &db::HandshakeWithUser) -> Self {
+		Self::single(Embed {
+			title: "👋 New handshaker!".to_owned(),
+			description: format!(
+				"**{}** shook hands for the first time",
+				escape_markdown(&shake.resonite_name)
+			),
+			color: FIRST_TIME_COLOR,
+			timestamp: rfc3339(shake.created_at),
+			fields: world_field(shake),
+		})
+	}
+
+	/// Creates a message summarizing the number of handshakes on a day
+	#[must_use]
+	pub fn daily_summary(date: Date, count: i64) -> Self {
+		let noun = if count == 1 { "handshake" } else { "handshakes" };
+		Self::single(Embed {
+			title: format!("📊 Daily summary for {date}"),
+			description: format!("{count} {noun} today"),
+			color: SUMMARY_COLOR,
+			timestamp: None,
+			fields: Vec::new(),
+		})
+	}
+
+	/// Creates a message containing a single embed
+	fn single(embed: Embed) -> Self {
+		Self { embeds: vec![embed] }
+	}
+}
+
+/// Rich embed within a Discord message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Embed {
+	/// Title of the embed
+	pub title: String,
+
+	/// Markdown body of the embed
+	pub description: String,
+
+	/// Color of the embed's side bar, as an RGB integer
+	pub color: u32,
+
+	/// RFC 3339 timestamp shown in the embed's footer
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub timestamp: Option<String>,
+
+	/// Name/value fields shown below the description
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub fields: Vec<Field>,
+}
+
+/// Name/value field within an embed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Field {
+	/// Name of the field
+	pub name: String,
+
+	/// Value of the field
+	pub value: String,
+
+	/// Whether the field may be shown alongside others on the same line
+	pub inline: bool,
+}
+
+/// Builds the field listing the world a handshake took place in, if it's known
+fn world_field(shake: &db::HandshakeWithUser) -> Vec<Field> {
+	shake
+		.world_name
+		.iter()
+		.map(|world| Field {
+			name: "World".to_owned(),
+			value: escape_markdown(world),
+			inline: true,
+		})
+		.collect()
+}
+
+/// Formats a date/time as RFC 3339, as Discord expects for embed timestamps
+fn rfc3339(datetime: OffsetDateTime) -> Option<String> {
+	datetime.format(&Rfc3339).ok()
+}
+
+/// Escapes characters that Discord would otherwise interpret as markdown
+fn escape_markdown(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for ch in text.chars() {
+		if matches!(
+			ch,
+			'\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']' | '(' | ')'
+		) {
+			escaped.push('\\');
+		}
+		escaped.push(ch);
+	}
+	escaped
+}
+
+/// Announcer that posts milestones, first-time handshakers, and daily summaries to a Discord webhook
+#[derive(Debug, Clone)]
+pub struct Discord {
+	/// Queue of messages waiting to be posted
+	queue: mpsc::Sender<Message>,
+
+	/// Number of handshakes between milestone announcements
+	milestone_interval: i64,
+
+	/// Whether to announce users shaking hands for the first time
+	announce_first_time: bool,
+}
+
+impl Discord {
+	/// Spawns the tasks that post messages and daily summaries to the Discord webhook URL, if one is given. Returns
+	/// `None` if there is no URL.
+	pub fn spawn(
+		db: db::Database,
+		url: Option<&Url>,
+		milestone_interval: i64,
+		announce_first_time: bool,
+		timezone: &'static Tz,
+	) -> anyhow::Result<Option<Self>> {
+		let Some(url) = url else {
+			return Ok(None);
+		};
+
+		let client = Client::builder()
+			.timeout(REQUEST_TIMEOUT)
+			.user_agent(concat!("Shaker/", env!("CARGO_PKG_VERSION")))
+			.build()?;
+
+		let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+		tokio::spawn(post_all(client, url.clone(), receiver));
+		tokio::spawn(post_daily_summaries(db, queue.clone(), timezone));
+
+		info!("Posting announcements to Discord every {milestone_interval} handshake(s)");
+		Ok(Some(Self {
+			queue,
+			milestone_interval,
+			announce_first_time,
+		}))
+	}
+
+	/// Queues any announcements warranted by a newly created handshake
+	pub async fn handshake_created(&self, db: &db::Database, created: &db::CreatedHandshake) -> anyhow::Result<()> {
+		let id = created.handshake.id;
+		let shake = db
+			.get_handshake_with_user(id)
+			.await?
+			.with_context(|| format!("Handshake {id} doesn't exist"))?;
+
+		if created.total_count % self.milestone_interval == 0 {
+			self.enqueue(Message::milestone(created.total_count, &shake));
+		}
+
+		if self.announce_first_time && created.first_time {
+			self.enqueue(Message::first_time(&shake));
+		}
+
+		Ok(())
+	}
+
+	/// Queues a message for posting, dropping it if the queue is full
+	fn enqueue(&self, message: Message) {
+		if self.queue.try_send(message).is_err() {
+			warn!("Discord queue is full; dropping message");
+		}
+	}
+}
+
+/// Posts queued messages to a Discord webhook URL until the queue is closed
+async fn post_all(client: Client, url: Url, mut queue: mpsc::Receiver<Message>) {
+	while let Some(message) = queue.recv().await {
+		post(&client, &url, &message).await;
+	}
+}
+
+/// Posts a message to a Discord webhook URL, retrying with exponential backoff. Failures are only logged.
+async fn post(client: &Client, url: &Url, message: &Message) {
+	let mut backoff = INITIAL_BACKOFF;
+
+	for attempt in 1..=MAX_ATTEMPTS {
+		let result = client
+			.post(url.clone())
+			.json(message)
+			.send()
+			.await
+			.and_then(reqwest::Response::error_for_status);
+		match result {
+			Ok(_) => {
+				debug!("Posted message to Discord");
+				return;
+			}
+			Err(err) if attempt < MAX_ATTEMPTS => {
+				debug!("Posting to Discord failed (attempt {attempt}/{MAX_ATTEMPTS}): {err}");
+				tokio_time::sleep(backoff).await;
+				backoff *= 2;
+			}
+			Err(err) => warn!("Dropping Discord message after {MAX_ATTEMPTS} attempts: {err}"),
+		}
+	}
+}
+
+/// Queues a summary of each day's handshakes once the day is over (in the given timezone)
+async fn post_daily_summaries(db: db::Database, queue: mpsc::Sender<Message>, timezone: &'static Tz) {
+	loop {
+		let now = OffsetDateTime::now_utc();
+		let today = now.to_timezone(timezone).date();
+		let Some(tomorrow) = today.next_day() else {
+			return;
+		};
+
+		let until_tomorrow = db::start_of_day(tomorrow, timezone) - now;
+		tokio_time::sleep(until_tomorrow.try_into().unwrap_or_default()).await;
+
+		match db.count_handshakes_on(today, timezone).await {
+			Ok(count) => {
+				if queue.send(Message::daily_summary(today, count)).await.is_err() {
+					return;
+				}
+			}
+			Err(err) => warn!("Unable to count handshakes for the daily Discord summary: {err}"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+	use time::Month;
+
+	use super::*;
+
+	fn handshake(world_name: Option<&str>) -> db::HandshakeWithUser {
+		db::HandshakeWithUser {
+			id: 5000,
+			user_id: 42,
+			resonite_id: Some("U-party_person".to_owned()),
+			resonite_name: "party_person".to_owned(),
+			world_name: world_name.map(ToOwned::to_owned),
+			source: None,
+			event: None,
+			created_at: OffsetDateTime::from_unix_timestamp(1_718_452_800).unwrap(),
+		}
+	}
+
+	#[test]
+	fn milestone_embed() {
+		let message = Message::milestone(5000, &handshake(Some("The Hub")));
+		assert_eq!(
+			serde_json::to_value(message).unwrap(),
+			json!({
+				"embeds": [{
+					"title": "🎉 Handshake number 5000!",
+					"description": "**party\\_person** shook hands for handshake number 5000",
+					"color": 0x00F1_C40F,
+					"timestamp": "2024-06-15T12:00:00Z",
+					"fields": [{ "name": "World", "value": "The Hub", "inline": true }],
+				}],
+			})
+		);
+	}
+
+	#[test]
+	fn first_time_embed_without_world() {
+		let message = Message::first_time(&handshake(None));
+		assert_eq!(
+			serde_json::to_value(message).unwrap(),
+			json!({
+				"embeds": [{
+					"title": "👋 New handshaker!",
+					"description": "**party\\_person** shook hands for the first time",
+					"color": 0x002E_CC71,
+					"timestamp": "2024-06-15T12:00:00Z",
+				}],
+			})
+		);
+	}
+
+	#[test]
+	fn daily_summary_embed() {
+		let date = Date::from_calendar_date(2024, Month::June, 15).unwrap();
+		assert_eq!(
+			serde_json::to_value(Message::daily_summary(date, 1)).unwrap(),
+			json!({
+				"embeds": [{
+					"title": "📊 Daily summary for 2024-06-15",
+					"description": "1 handshake today",
+					"color": 0x0058_65F2,
+				}],
+			})
+		);
+		assert_eq!(
+			Message::daily_summary(date, 0).embeds[0].description,
+			"0 handshakes today"
+		);
+	}
+
+	#[test]
+	fn markdown_is_escaped() {
+		assert_eq!(escape_markdown("*a_b*`c`"), "\\*a\\_b\\*\\`c\\`");
+		assert_eq!(escape_markdown("plain name"), "plain name");
+	}
+}
@@ -0,0 +1,472 @@
+use std::{
+	ffi::OsStr,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+	fs::File,
+	io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::db::{self, Handshake, HandshakeWithUser, TimeRange, User};
+
+/// Version of the export formats, incremented whenever they change in a way that readers need to know about
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Columns of CSV exports of users
+pub const USER_CSV_COLUMNS: [&str; 6] = [
+	"user_id",
+	"resonite_id",
+	"resonite_name",
+	"legacy",
+	"created_at",
+	"last_seen_at",
+];
+
+/// Column added to CSV exports of users when their notes are included
+pub const NOTE_CSV_COLUMN: &str = "note";
+
+/// Columns of CSV exports of handshakes
+pub const HANDSHAKE_CSV_COLUMNS: [&str; 5] = [
+	"handshake_id",
+	"resonite_id",
+	"resonite_name",
+	"world_name",
+	"created_at",
+];
+
+/// Format to export data to a file in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+	/// A single JSON document containing every user and handshake
+	Json,
+
+	/// A CSV file for users and another for handshakes
+	Csv,
+
+	/// Newline-delimited JSON, in the same format as `GET /export`: a header line, then a line for each user, then a
+	/// line for each handshake
+	Ndjson,
+}
+
+impl Format {
+	/// Determines the format from a path's extension, if it's a recognized one
+	#[must_use]
+	pub fn from_path(path: &Path) -> Option<Self> {
+		match path.extension().and_then(OsStr::to_str)?.to_ascii_lowercase().as_str() {
+			"json" => Some(Self::Json),
+			"csv" => Some(Self::Csv),
+			"ndjson" | "jsonl" => Some(Self::Ndjson),
+			_ => None,
+		}
+	}
+}
+
+/// Details of the data written by an export
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+	/// Files that were written
+	pub files: Vec<PathBuf>,
+
+	/// Number of records written
+	pub rows: u64,
+
+	/// Number of bytes written
+	pub bytes: u64,
+}
+
+/// Header fields at the start of a JSON export, describing its contents
+#[derive(Debug, Serialize)]
+struct JsonHeader {
+	/// Version of the export format
+	format_version: u32,
+
+	/// Version of the latest database migration applied when the data was exported
+	schema_version: Option<i64>,
+
+	/// Date/time the export was started
+	#[serde(with = "time::serde::rfc3339")]
+	exported_at: OffsetDateTime,
+}
+
+/// Single line of an NDJSON export, tagged with its type
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Line<'a> {
+	/// First line of the export, describing its contents
+	Header {
+		/// Version of the export format
+		format_version: u32,
+
+		/// Version of the latest database migration applied when the data was exported
+		schema_version: Option<i64>,
+
+		/// Date/time the export was started
+		#[serde(with = "time::serde::rfc3339")]
+		exported_at: OffsetDateTime,
+	},
+
+	/// User record
+	User(&'a User),
+
+	/// Handshake record
+	Handshake(&'a Handshake),
+}
+
+/// Exports every user and handshake to files, reading them from the database as they're written. JSON exports are
+/// written to the path as-is, while CSV exports are written alongside it, with `-users` and `-handshakes` added to the
+/// file name. Notes that admins have left on users are only included if asked for.
+pub async fn to_file(db: &db::Database, path: &Path, format: Format, include_notes: bool) -> Result<Summary> {
+	match format {
+		Format::Json => to_json_file(db, path, include_notes).await,
+		Format::Csv => to_csv_files(db, path, include_notes).await,
+		Format::Ndjson => to_ndjson_file(db, path, include_notes).await,
+	}
+}
+
+/// Writes every user and handshake to a single JSON document
+async fn to_json_file(db: &db::Database, path: &Path, include_notes: bool) -> Result<Summary> {
+	let mut out = Output::create(path).await?;
+	let header = JsonHeader {
+		format_version: FORMAT_VERSION,
+		schema_version: db.schema_version().await?,
+		exported_at: OffsetDateTime::now_utc(),
+	};
+
+	// Write the header's fields without closing the object, so the arrays of records can be added to it
+	let mut buf = serde_json::to_vec(&header)?;
+	buf.pop();
+	buf.extend_from_slice(b",\"users\":[");
+	out.write(&buf).await?;
+
+	let mut rows = 0;
+	let mut users = db.stream_all_users();
+	let mut first = true;
+	while let Some(user) = users.try_next().await? {
+		out.write_json_element(&exported_user(user, include_notes), first)
+			.await?;
+		first = false;
+		rows += 1;
+	}
+	drop(users);
+
+	out.write(b"],\"handshakes\":[").await?;
+	let mut handshakes = db.stream_all_handshakes();
+	let mut first = true;
+	while let Some(shake) = handshakes.try_next().await? {
+		out.write_json_element(&shake, first).await?;
+		first = false;
+		rows += 1;
+	}
+	drop(handshakes);
+
+	out.write(b"]}\n").await?;
+	let bytes = out.finish().await?;
+	Ok(Summary {
+		files: vec![path.to_owned()],
+		rows,
+		bytes,
+	})
+}
+
+/// Writes every user and handshake to CSV files alongside a path
+async fn to_csv_files(db: &db::Database, path: &Path, include_notes: bool) -> Result<Summary> {
+	let range = TimeRange::default();
+	let mut summary = Summary::default();
+	let mut buf = Vec::new();
+
+	let users_path = sibling_path(path, "users");
+	let mut out = Output::create(&users_path).await?;
+	write_user_csv_header(&mut buf, include_notes);
+	out.write(&buf).await?;
+	let mut users = db.stream_users_created_in(&range);
+	while let Some(user) = users.try_next().await? {
+		buf.clear();
+		write_user_csv(&mut buf, &user, include_notes)?;
+		out.write(&buf).await?;
+		summary.rows += 1;
+	}
+	drop(users);
+	summary.bytes += out.finish().await?;
+	summary.files.push(users_path);
+
+	let handshakes_path = sibling_path(path, "handshakes");
+	let mut out = Output::create(&handshakes_path).await?;
+	buf.clear();
+	write_csv_row(&mut buf, HANDSHAKE_CSV_COLUMNS);
+	out.write(&buf).await?;
+	let mut handshakes = db.stream_handshakes_with_users_in(&range);
+	while let Some(shake) = handshakes.try_next().await? {
+		buf.clear();
+		write_handshake_csv(&mut buf, &shake)?;
+		out.write(&buf).await?;
+		summary.rows += 1;
+	}
+	drop(handshakes);
+	summary.bytes += out.finish().await?;
+	summary.files.push(handshakes_path);
+
+	Ok(summary)
+}
+
+/// Writes every user and handshake to a single NDJSON file
+async fn to_ndjson_file(db: &db::Database, path: &Path, include_notes: bool) -> Result<Summary> {
+	let mut out = Output::create(path).await?;
+	let mut buf = Vec::new();
+	let header = Line::Header {
+		format_version: FORMAT_VERSION,
+		schema_version: db.schema_version().await?,
+		exported_at: OffsetDateTime::now_utc(),
+	};
+	write_ndjson_line(&mut buf, &header)?;
+	out.write(&buf).await?;
+
+	let mut rows = 0;
+	let mut users = db.stream_all_users();
+	while let Some(user) = users.try_next().await? {
+		buf.clear();
+		write_ndjson_line(&mut buf, &Line::User(&exported_user(user, include_notes)))?;
+		out.write(&buf).await?;
+		rows += 1;
+	}
+	drop(users);
+
+	let mut handshakes = db.stream_all_handshakes();
+	while let Some(shake) = handshakes.try_next().await? {
+		buf.clear();
+		write_ndjson_line(&mut buf, &Line::Handshake(&shake))?;
+		out.write(&buf).await?;
+		rows += 1;
+	}
+	drop(handshakes);
+
+	let bytes = out.finish().await?;
+	Ok(Summary {
+		files: vec![path.to_owned()],
+		rows,
+		bytes,
+	})
+}
+
+/// Prepares a user to be exported, leaving out their note unless notes are included
+#[must_use]
+pub fn exported_user(user: User, include_notes: bool) -> User {
+	if include_notes {
+		user
+	} else {
+		user.without_note()
+	}
+}
+
+/// Builds the path of a file alongside another, with a suffix added to its name (before the extension)
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+	let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+	let name = match path.extension() {
+		Some(extension) => format!("{stem}-{suffix}.{}", extension.to_string_lossy()),
+		None => format!("{stem}-{suffix}"),
+	};
+	path.with_file_name(name)
+}
+
+/// Buffered file being exported to, which keeps count of the bytes written to it
+struct Output {
+	/// Path of the file
+	path: PathBuf,
+
+	/// Writer for the file
+	writer: BufWriter<File>,
+
+	/// Number of bytes written so far
+	bytes: u64,
+}
+
+impl Output {
+	/// Creates (or truncates) a file to export to
+	async fn create(path: &Path) -> Result<Self> {
+		let file = File::create(path)
+			.await
+			.with_context(|| format!("Unable to create {}", path.display()))?;
+		Ok(Self {
+			path: path.to_owned(),
+			writer: BufWriter::new(file),
+			bytes: 0,
+		})
+	}
+
+	/// Writes data to the file
+	async fn write(&mut self, data: &[u8]) -> Result<()> {
+		self.writer
+			.write_all(data)
+			.await
+			.with_context(|| format!("Unable to write to {}", self.path.display()))?;
+		self.bytes += data.len() as u64;
+		Ok(())
+	}
+
+	/// Writes a value as an element of a JSON array, preceded by a comma unless it's the first one
+	async fn write_json_element(&mut self, value: &impl Serialize, first: bool) -> Result<()> {
+		let mut buf = if first { Vec::new() } else { vec![b','] };
+		serde_json::to_writer(&mut buf, value)?;
+		self.write(&buf).await
+	}
+
+	/// Flushes any buffered data to the file, returning the total number of bytes written
+	async fn finish(mut self) -> Result<u64> {
+		self.writer
+			.flush()
+			.await
+			.with_context(|| format!("Unable to write to {}", self.path.display()))?;
+		Ok(self.bytes)
+	}
+}
+
+/// Appends a line of an NDJSON export to a buffer
+pub fn write_ndjson_line(buf: &mut Vec<u8>, line: &Line<'_>) -> serde_json::Result<()> {
+	serde_json::to_writer(&mut *buf, line)?;
+	buf.push(b'\n');
+	Ok(())
+}
+
+/// Appends the header row of a CSV export of users to a buffer: [`USER_CSV_COLUMNS`], followed by [`NOTE_CSV_COLUMN`]
+/// if notes are included
+pub fn write_user_csv_header(buf: &mut Vec<u8>, include_notes: bool) {
+	write_csv_row(
+		buf,
+		USER_CSV_COLUMNS
+			.into_iter()
+			.chain(include_notes.then_some(NOTE_CSV_COLUMN)),
+	);
+}
+
+/// Appends a user to a CSV buffer as a row of [`USER_CSV_COLUMNS`], followed by their note if notes are included
+pub fn write_user_csv(buf: &mut Vec<u8>, user: &User, include_notes: bool) -> Result<(), time::error::Format> {
+	let last_seen_at = user.last_seen_at.map(|time| time.format(&Rfc3339)).transpose()?;
+	let note = include_notes.then(|| user.note.as_deref().unwrap_or_default());
+	write_csv_row(
+		buf,
+		[
+			user.id.to_string().as_str(),
+			user.resonite_id.as_deref().unwrap_or_default(),
+			&user.resonite_name,
+			if user.legacy { "true" } else { "false" },
+			&user.created_at.format(&Rfc3339)?,
+			last_seen_at.as_deref().unwrap_or_default(),
+		]
+		.into_iter()
+		.chain(note),
+	);
+	Ok(())
+}
+
+/// Appends a handshake to a CSV buffer as a row of [`HANDSHAKE_CSV_COLUMNS`]
+pub fn write_handshake_csv(buf: &mut Vec<u8>, shake: &HandshakeWithUser) -> Result<(), time::error::Format> {
+	write_csv_row(
+		buf,
+		[
+			shake.id.to_string().as_str(),
+			shake.resonite_id.as_deref().unwrap_or_default(),
+			&shake.resonite_name,
+			shake.world_name.as_deref().unwrap_or_default(),
+			&shake.created_at.format(&Rfc3339)?,
+		],
+	);
+	Ok(())
+}
+
+/// Appends a row of fields to a CSV buffer, quoting fields that contain commas, quotes, or line breaks (per RFC 4180)
+pub fn write_csv_row<'a>(buf: &mut Vec<u8>, fields: impl IntoIterator<Item = &'a str>) {
+	for (i, field) in fields.into_iter().enumerate() {
+		if i > 0 {
+			buf.push(b',');
+		}
+
+		if field.contains([',', '"', '\r', '\n']) {
+			buf.push(b'"');
+			buf.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+			buf.push(b'"');
+		} else {
+			buf.extend_from_slice(field.as_bytes());
+		}
+	}
+	buf.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn csv_fields_are_quoted_when_needed() {
+		let mut buf = Vec::new();
+		write_csv_row(&mut buf, ["1", "plain", "a,b", "say \"hi\"", "two\nlines", ""]);
+		assert_eq!(
+			String::from_utf8(buf).unwrap(),
+			"1,plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",\r\n"
+		);
+	}
+
+	#[test]
+	fn lines_are_tagged_with_their_type() {
+		let mut buf = Vec::new();
+		write_ndjson_line(
+			&mut buf,
+			&Line::Header {
+				format_version: FORMAT_VERSION,
+				schema_version: Some(20_240_831_120_000),
+				exported_at: OffsetDateTime::from_unix_timestamp(1_718_452_800).unwrap(),
+			},
+		)
+		.unwrap();
+
+		let shake = Handshake {
+			id: 7,
+			user_id: 3,
+			world_name: None,
+			source: Some("kiosk".to_owned()),
+			event: None,
+			created_at: OffsetDateTime::from_unix_timestamp(1_718_452_800).unwrap(),
+			legacy: false,
+			deleted_at: None,
+		};
+		write_ndjson_line(&mut buf, &Line::Handshake(&shake)).unwrap();
+
+		let text = String::from_utf8(buf).unwrap();
+		let lines: Vec<serde_json::Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+		assert_eq!(
+			lines[0],
+			json!({
+				"type": "header",
+				"format_version": 1,
+				"schema_version": 20_240_831_120_000_i64,
+				"exported_at": "2024-06-15T12:00:00Z",
+			})
+		);
+		assert_eq!(lines[1]["type"], "handshake");
+		assert_eq!(lines[1]["id"], 7);
+		assert_eq!(lines[1]["source"], "kiosk");
+		assert!(text.ends_with('\n'));
+	}
+
+	#[test]
+	fn formats_and_csv_paths_follow_the_given_path() {
+		assert_eq!(Format::from_path(Path::new("dump.JSON")), Some(Format::Json));
+		assert_eq!(Format::from_path(Path::new("dump.csv")), Some(Format::Csv));
+		assert_eq!(Format::from_path(Path::new("dump.ndjson")), Some(Format::Ndjson));
+		assert_eq!(Format::from_path(Path::new("dump")), None);
+		assert_eq!(
+			sibling_path(Path::new("out/dump.csv"), "users"),
+			Path::new("out/dump-users.csv")
+		);
+		assert_eq!(
+			sibling_path(Path::new("dump"), "handshakes"),
+			Path::new("dump-handshakes")
+		);
+	}
+}
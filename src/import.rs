@@ -0,0 +1,776 @@
+use std::{collections::HashMap, ffi::OsStr, path::Path};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use time::{
+	format_description::{self, well_known::Rfc3339},
+	OffsetDateTime, PrimitiveDateTime,
+};
+use tracing::error;
+
+use crate::{
+	db::{self, ImportOutcome, ImportTransaction, LegacyRecord},
+	export::FORMAT_VERSION,
+	validate::{self, FieldLimits, ValidationError},
+};
+
+/// Number of records to import in each transaction
+const BATCH_SIZE: usize = 500;
+
+/// Format of a file to import handshakes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+	/// Line-separated usernames, one for each handshake
+	Plain,
+
+	/// CSV with a header row naming its columns: `resonite_name`, and optionally `resonite_id`, `world_name`, and
+	/// `created_at` (in RFC 3339 or `YYYY-MM-DD HH:MM:SS` format, in UTC). Any other columns are ignored.
+	Csv,
+
+	/// Newline-delimited JSON from `GET /export` or the `export` command, restoring every user and handshake in it with
+	/// all of their details (other than their IDs, which are assigned anew)
+	Ndjson,
+}
+
+impl Format {
+	/// Determines the format from a path's extension, treating anything other than CSV or NDJSON as plain text
+	#[must_use]
+	pub fn from_path(path: &Path) -> Self {
+		let extension = path.extension().and_then(OsStr::to_str).map(str::to_ascii_lowercase);
+		match extension.as_deref() {
+			Some("csv") => Self::Csv,
+			Some("ndjson" | "jsonl") => Self::Ndjson,
+			_ => Self::Plain,
+		}
+	}
+}
+
+/// Options for importing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+	/// Whether to add a legacy handshake to existing users that don't have one yet, rather than skipping them
+	pub add_handshake: bool,
+
+	/// Whether to restore an NDJSON export into a database that already has data, using users that already exist
+	/// rather than creating them again and skipping handshakes they already have
+	pub merge: bool,
+
+	/// Whether to go through the whole import without writing anything
+	pub dry_run: bool,
+
+	/// Limits on the fields of imported users and handshakes
+	pub limits: FieldLimits,
+}
+
+/// Outcome of importing legacy handshakes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Summary {
+	/// Number of users created
+	pub users_created: u64,
+
+	/// Number of legacy handshakes created, for both new and existing users
+	pub handshakes_created: u64,
+
+	/// Number of records that were already in the database (or legacy handshakes for users that already existed),
+	/// which were left alone
+	pub skipped: u64,
+
+	/// Number of lines that were empty or only whitespace
+	pub blank: u64,
+
+	/// Records that couldn't be imported
+	pub failures: Vec<Failure>,
+
+	/// Names of the users created
+	pub created_names: Vec<String>,
+
+	/// Names of the existing users that were given handshakes
+	pub added_handshake_names: Vec<String>,
+
+	/// Names of the existing users that were skipped
+	pub skipped_names: Vec<String>,
+}
+
+/// Record that couldn't be imported
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+	/// Line number the record starts on (starting at 1)
+	pub line: usize,
+
+	/// Username in the record, if it got that far
+	pub name: Option<String>,
+
+	/// Why it couldn't be imported
+	pub error: String,
+}
+
+/// Record read from an import file, or why it couldn't be read
+type Row = (usize, Result<LegacyRecord, String>);
+
+/// Single line of an NDJSON export (see [`crate::export::Line`])
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExportLine {
+	/// First line of the export, describing its contents
+	Header {
+		/// Version of the export format
+		format_version: u32,
+	},
+
+	/// User record
+	User(db::User),
+
+	/// Handshake record
+	Handshake(db::Handshake),
+}
+
+/// Imports handshakes from the contents of a file, committing them in batches of [`BATCH_SIZE`] records. Importing
+/// the same contents again changes nothing: legacy handshakes for users that already exist are handled as described by
+/// [`ImportTransaction::import`], and NDJSON exports can only be imported into an empty database unless merging. A dry
+/// run imports everything in a single transaction that's never committed, so the database is left untouched even if
+/// it's interrupted partway through, while the summary still describes what would be done.
+pub async fn from_str(db: &db::Database, content: &str, format: Format, options: Options) -> Result<Summary> {
+	if format == Format::Ndjson && !options.merge && !db.is_empty().await? {
+		anyhow::bail!("The database already has users or handshakes; use --merge to restore the export into it anyway");
+	}
+
+	let mut summary = Summary::default();
+	let mut tx = db.begin_import().await?;
+	match format {
+		Format::Plain | Format::Csv => {
+			let rows: Vec<Row> = match format {
+				Format::Csv => csv_rows(content, &mut summary)?,
+				_ => plain_rows(content, &mut summary),
+			}
+			.into_iter()
+			.map(|(line, record)| {
+				let record =
+					record.and_then(|record| limit_record(record, options.limits).map_err(|err| err.to_string()));
+				(line, record)
+			})
+			.collect();
+			for chunk in rows.chunks(BATCH_SIZE) {
+				import_legacy_batch(&mut tx, chunk, options.add_handshake, &mut summary).await?;
+				tx = checkpoint(db, tx, options.dry_run).await?;
+			}
+		}
+
+		Format::Ndjson => {
+			let lines = ndjson_lines(content, &mut summary)?;
+			let mut user_ids = HashMap::new();
+			for chunk in lines.chunks(BATCH_SIZE) {
+				restore_batch(&mut tx, chunk, options, &mut user_ids, &mut summary).await?;
+				tx = checkpoint(db, tx, options.dry_run).await?;
+			}
+		}
+	}
+
+	// Dropping the transaction of a dry run rolls back everything in it
+	if !options.dry_run {
+		tx.commit().await?;
+	}
+	Ok(summary)
+}
+
+/// Commits a batch of imported records and starts a transaction for the next, unless it's a dry run
+async fn checkpoint(db: &db::Database, tx: ImportTransaction, dry_run: bool) -> Result<ImportTransaction> {
+	if dry_run {
+		return Ok(tx);
+	}
+	tx.commit().await?;
+	db.begin_import().await
+}
+
+/// Imports a batch of legacy handshakes, adding their outcomes to a summary
+async fn import_legacy_batch(
+	tx: &mut ImportTransaction,
+	rows: &[Row],
+	add_handshake: bool,
+	summary: &mut Summary,
+) -> Result<()> {
+	let mut batch = Vec::with_capacity(rows.len());
+	let mut lines = Vec::with_capacity(rows.len());
+	for (line, record) in rows {
+		match record {
+			Ok(record) => {
+				batch.push(record.clone());
+				lines.push(*line);
+			}
+			Err(err) => summary.fail(*line, None, err.clone()),
+		}
+	}
+
+	let outcomes = tx.import(&batch, add_handshake).await?;
+	for ((record, line), outcome) in batch.into_iter().zip(lines).zip(outcomes) {
+		let name = record.resonite_name;
+		match outcome {
+			Ok(ImportOutcome::Created) => {
+				summary.users_created += 1;
+				summary.handshakes_created += 1;
+				summary.created_names.push(name);
+			}
+			Ok(ImportOutcome::AddedHandshake) => {
+				summary.handshakes_created += 1;
+				summary.added_handshake_names.push(name);
+			}
+			Ok(ImportOutcome::Skipped) => {
+				summary.skipped += 1;
+				summary.skipped_names.push(name);
+			}
+			Err(err) => summary.fail(line, Some(&name), format!("{err:#}")),
+		}
+	}
+	Ok(())
+}
+
+/// Restores a batch of lines of an NDJSON export, adding their outcomes to a summary. The IDs that users in the export
+/// are restored under are kept track of, so that their handshakes can be restored for them.
+async fn restore_batch(
+	tx: &mut ImportTransaction,
+	lines: &[(usize, &str)],
+	options: Options,
+	user_ids: &mut HashMap<i64, i64>,
+	summary: &mut Summary,
+) -> Result<()> {
+	for &(line, text) in lines {
+		let parsed = serde_json::from_str(text).map_err(|err| err.to_string());
+		match parsed.and_then(|line| limit_line(line, options.limits).map_err(|err| err.to_string())) {
+			Ok(ExportLine::User(user)) => match tx.restore_user(&user, options.merge).await {
+				Ok((id, outcome)) => {
+					user_ids.insert(user.id, id);
+					if outcome == ImportOutcome::Skipped {
+						summary.skipped += 1;
+						summary.skipped_names.push(user.resonite_name);
+					} else {
+						summary.users_created += 1;
+						summary.created_names.push(user.resonite_name);
+					}
+				}
+				Err(err) => summary.fail(line, Some(&user.resonite_name), format!("{err:#}")),
+			},
+
+			Ok(ExportLine::Handshake(shake)) => {
+				let Some(&user_id) = user_ids.get(&shake.user_id) else {
+					summary.fail(
+						line,
+						None,
+						format!(
+							"handshake {} is for user {}, who wasn't restored",
+							shake.id, shake.user_id
+						),
+					);
+					continue;
+				};
+				match tx.restore_handshake(&shake, user_id, options.merge).await {
+					Ok(ImportOutcome::Skipped) => summary.skipped += 1,
+					Ok(_) => summary.handshakes_created += 1,
+					Err(err) => summary.fail(line, None, format!("{err:#}")),
+				}
+			}
+
+			Ok(ExportLine::Header { .. }) => summary.fail(line, None, "unexpected header".to_owned()),
+			Err(err) => summary.fail(line, None, err),
+		}
+	}
+	Ok(())
+}
+
+/// Applies the field limits to a legacy record
+fn limit_record(record: LegacyRecord, limits: FieldLimits) -> Result<LegacyRecord, ValidationError> {
+	if let Some(id) = &record.resonite_id {
+		limits.id("resonite_id", id)?;
+	}
+	Ok(LegacyRecord {
+		resonite_name: limits.name("resonite_name", record.resonite_name)?,
+		world_name: record
+			.world_name
+			.map(|world| limits.world("world_name", world))
+			.transpose()?,
+		..record
+	})
+}
+
+/// Applies the field limits to the user or handshake on a line of an NDJSON export
+fn limit_line(line: ExportLine, limits: FieldLimits) -> Result<ExportLine, ValidationError> {
+	Ok(match line {
+		ExportLine::User(user) => {
+			if let Some(id) = &user.resonite_id {
+				limits.id("resonite_id", id)?;
+			}
+			ExportLine::User(db::User {
+				resonite_name: limits.name("resonite_name", user.resonite_name)?,
+				..user
+			})
+		}
+		ExportLine::Handshake(shake) => ExportLine::Handshake(db::Handshake {
+			world_name: shake
+				.world_name
+				.map(|world| limits.world("world_name", world))
+				.transpose()?,
+			source: shake.source.map(|source| limits.source("source", source)).transpose()?,
+			event: shake.event.map(|event| limits.event("event", event)).transpose()?,
+			..shake
+		}),
+		line @ ExportLine::Header { .. } => line,
+	})
+}
+
+impl Summary {
+	/// Records a failure, logging it as well
+	fn fail(&mut self, line: usize, name: Option<&str>, error: String) {
+		if let Some(name) = name {
+			error!("Unable to import user {name} on line {line}: {error}");
+		} else {
+			error!("Unable to import line {line}: {error}");
+		}
+		self.failures.push(Failure {
+			line,
+			name: name.map(ToOwned::to_owned),
+			error,
+		});
+	}
+}
+
+/// Reads records from line-separated usernames
+fn plain_rows(content: &str, summary: &mut Summary) -> Vec<Row> {
+	let mut rows = Vec::new();
+	for (i, name) in content.lines().enumerate() {
+		if name.trim().is_empty() {
+			summary.blank += 1;
+			continue;
+		}
+
+		let record = LegacyRecord {
+			resonite_name: name.to_owned(),
+			..LegacyRecord::default()
+		};
+		rows.push((i + 1, Ok(record)));
+	}
+	rows
+}
+
+/// Reads the non-blank lines of an NDJSON export following its header, failing if it doesn't start with one of a
+/// supported version
+fn ndjson_lines<'a>(content: &'a str, summary: &mut Summary) -> Result<Vec<(usize, &'a str)>> {
+	let mut lines = content
+		.lines()
+		.enumerate()
+		.map(|(i, line)| (i + 1, line))
+		.filter(|(_, line)| {
+			let blank = line.trim().is_empty();
+			if blank {
+				summary.blank += 1;
+			}
+			!blank
+		});
+
+	let Some((_, header)) = lines.next() else {
+		return Ok(Vec::new());
+	};
+	match serde_json::from_str(header) {
+		Ok(ExportLine::Header { format_version }) if format_version <= FORMAT_VERSION => Ok(lines.collect()),
+		Ok(ExportLine::Header { format_version }) => anyhow::bail!(
+			"The export is in version {format_version} of the format, but only up to version {FORMAT_VERSION} is \
+			 supported"
+		),
+		_ => anyhow::bail!("The file doesn't start with the header of a Shaker export"),
+	}
+}
+
+/// Reads records from a CSV file with a header row, failing if it has no `resonite_name` column
+fn csv_rows(content: &str, summary: &mut Summary) -> Result<Vec<Row>> {
+	let content = content.strip_prefix('\u{FEFF}').unwrap_or(content);
+	let mut records = CsvRecords::new(content).filter(|(_, fields)| {
+		let blank = matches!(fields, Ok(fields) if fields.iter().all(|field| field.trim().is_empty()));
+		if blank {
+			summary.blank += 1;
+		}
+		!blank
+	});
+
+	let header = match records.next() {
+		Some((_, Ok(header))) => header,
+		Some((line, Err(err))) => anyhow::bail!("Unable to read the CSV header on line {line}: {err}"),
+		None => return Ok(Vec::new()),
+	};
+	let column = |name: &str| {
+		header
+			.iter()
+			.position(|column| column.trim().eq_ignore_ascii_case(name))
+	};
+	let Some(name_column) = column("resonite_name") else {
+		anyhow::bail!("CSV imports need a resonite_name column");
+	};
+	let id_column = column("resonite_id");
+	let world_column = column("world_name");
+	let time_column = column("created_at");
+
+	Ok(records
+		.map(|(line, fields)| {
+			let record = fields.and_then(|fields| {
+				if fields.len() != header.len() {
+					return Err(format!("expected {} field(s) but found {}", header.len(), fields.len()));
+				}
+
+				let optional = |column: Option<usize>| {
+					column
+						.map(|i| fields[i].trim())
+						.filter(|value| !value.is_empty())
+						.map(ToOwned::to_owned)
+				};
+				let resonite_id = optional(id_column);
+				if let Some(id) = &resonite_id {
+					validate::resonite_id("resonite_id", id).map_err(|err| err.to_string())?;
+				}
+				let created_at = optional(time_column)
+					.map(|time| parse_time(&time).ok_or_else(|| format!("invalid created_at: \"{time}\"")))
+					.transpose()?;
+
+				Ok(LegacyRecord {
+					resonite_id,
+					resonite_name: fields[name_column].clone(),
+					world_name: optional(world_column),
+					created_at,
+				})
+			});
+			(line, record)
+		})
+		.collect())
+}
+
+/// Parses a date/time in RFC 3339 format, or in `YYYY-MM-DD HH:MM:SS` format as UTC (as `SQLite` and many spreadsheets
+/// write them)
+fn parse_time(time: &str) -> Option<OffsetDateTime> {
+	OffsetDateTime::parse(time, &Rfc3339).ok().or_else(|| {
+		let format = format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").ok()?;
+		Some(PrimitiveDateTime::parse(time, &format).ok()?.assume_utc())
+	})
+}
+
+/// Iterator over the records of a CSV file (per RFC 4180, with either line ending), along with the line number that
+/// each starts on
+struct CsvRecords<'a> {
+	/// Remaining contents of the file
+	rest: std::iter::Peekable<std::str::Chars<'a>>,
+
+	/// Line number of the next record
+	line: usize,
+}
+
+impl<'a> CsvRecords<'a> {
+	/// Creates an iterator over the records in CSV contents
+	fn new(content: &'a str) -> Self {
+		Self {
+			rest: content.chars().peekable(),
+			line: 1,
+		}
+	}
+}
+
+impl Iterator for CsvRecords<'_> {
+	type Item = (usize, Result<Vec<String>, String>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.rest.peek()?;
+		let start = self.line;
+		let mut fields = vec![String::new()];
+		let mut quoted = false;
+
+		while let Some(ch) = self.rest.next() {
+			let field = fields.last_mut().expect("there's always a field");
+			match ch {
+				'"' if quoted => {
+					if self.rest.next_if_eq(&'"').is_some() {
+						field.push('"');
+					} else {
+						quoted = false;
+					}
+				}
+				'"' if field.is_empty() => quoted = true,
+				'\n' | '\r' if !quoted => {
+					if ch == '\r' {
+						self.rest.next_if_eq(&'\n');
+					}
+					self.line += 1;
+					return Some((start, Ok(fields)));
+				}
+				',' if !quoted => fields.push(String::new()),
+				_ => {
+					if ch == '\n' {
+						self.line += 1;
+					}
+					field.push(ch);
+				}
+			}
+		}
+
+		if quoted {
+			return Some((start, Err("quoted field is never closed".to_owned())));
+		}
+		Some((start, Ok(fields)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{export, validate::OverlongPolicy};
+
+	const ADD_HANDSHAKE: Options = Options {
+		add_handshake: true,
+		merge: false,
+		dry_run: false,
+		limits: FieldLimits::DEFAULT,
+	};
+
+	const DRY_RUN: Options = Options {
+		add_handshake: false,
+		merge: false,
+		dry_run: true,
+		limits: FieldLimits::DEFAULT,
+	};
+
+	async fn database() -> db::Database {
+		db::Database::open_in_memory().await.unwrap()
+	}
+
+	#[test]
+	fn csv_records_follow_quotes_across_lines() {
+		let content = "a,b\r\n\"x,1\",\"say \"\"hi\"\"\"\n\"two\nlines\",\n\"open";
+		let records: Vec<_> = CsvRecords::new(content).collect();
+		assert_eq!(
+			records,
+			[
+				(1, Ok(vec!["a".to_owned(), "b".to_owned()])),
+				(2, Ok(vec!["x,1".to_owned(), "say \"hi\"".to_owned()])),
+				(3, Ok(vec!["two\nlines".to_owned(), String::new()])),
+				(5, Err("quoted field is never closed".to_owned())),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn plain_imports_summarize_every_line() {
+		let db = database().await;
+		let content = "Alice\n\nBob\n  \nalice\n\u{200B}\n";
+		let summary = from_str(&db, content, Format::Plain, Options::default()).await.unwrap();
+		assert_eq!(
+			(
+				summary.users_created,
+				summary.handshakes_created,
+				summary.skipped,
+				summary.blank
+			),
+			(2, 2, 1, 2)
+		);
+		assert_eq!(summary.failures.len(), 1);
+		assert_eq!(summary.failures[0].line, 6);
+		assert_eq!(summary.created_names, ["Alice", "Bob"]);
+		assert_eq!(summary.skipped_names, ["alice"]);
+
+		// Importing again only skips, unless existing users without legacy handshakes should get one
+		let summary = from_str(&db, content, Format::Plain, Options::default()).await.unwrap();
+		assert_eq!((summary.users_created, summary.skipped), (0, 3));
+		assert!(db.delete_handshake(2).await.unwrap());
+		let summary = from_str(&db, content, Format::Plain, ADD_HANDSHAKE).await.unwrap();
+		assert_eq!((summary.handshakes_created, summary.skipped), (1, 2));
+	}
+
+	#[tokio::test]
+	async fn imports_apply_the_field_limits() {
+		let db = database().await;
+		let options = Options {
+			limits: FieldLimits {
+				name: 5,
+				policy: OverlongPolicy::Reject,
+				..FieldLimits::DEFAULT
+			},
+			..Options::default()
+		};
+		let content = "resonite_name,world_name\nAlice,Hub\nAlexander,Hub\nBob,\"Bad\tWorld\"\n";
+		let summary = from_str(&db, content, Format::Csv, options).await.unwrap();
+		assert_eq!(summary.created_names, ["Alice"]);
+		assert_eq!(
+			summary.failures.iter().map(|failure| failure.line).collect::<Vec<_>>(),
+			[3, 4]
+		);
+	}
+
+	#[tokio::test]
+	async fn large_imports_span_batches() {
+		let db = database().await;
+		let content: String = (0..10_000)
+			.map(|i| match i {
+				4321 => "\u{200B}\n".to_owned(),
+				_ if i % 20 == 19 => "\n".to_owned(),
+				_ if i % 20 == 18 => format!("user{}\n", i - 18),
+				_ => format!("User{i}\n"),
+			})
+			.collect();
+		let summary = from_str(&db, &content, Format::Plain, Options::default())
+			.await
+			.unwrap();
+		assert_eq!(
+			(
+				summary.users_created,
+				summary.handshakes_created,
+				summary.skipped,
+				summary.blank
+			),
+			(8999, 8999, 500, 500)
+		);
+		assert_eq!(summary.failures.len(), 1);
+		assert_eq!(summary.failures[0].line, 4322);
+		assert_eq!(db.count_users().await.unwrap(), 8999);
+		assert_eq!(db.count_handshakes().await.unwrap(), 8999);
+	}
+
+	#[tokio::test]
+	async fn dry_runs_change_nothing() {
+		let db = database().await;
+		let content = "Alice\nBob\nalice\n";
+		let dry = from_str(&db, content, Format::Plain, DRY_RUN).await.unwrap();
+		assert_eq!(db.count_users().await.unwrap(), 0);
+		assert_eq!(db.count_handshakes().await.unwrap(), 0);
+
+		let real = from_str(&db, content, Format::Plain, Options::default()).await.unwrap();
+		assert_eq!(dry, real);
+		assert_eq!(db.count_users().await.unwrap(), 2);
+	}
+
+	#[tokio::test]
+	async fn csv_imports_keep_ids_and_times() {
+		let db = database().await;
+		let content = "\u{FEFF}Resonite_ID,resonite_name,world_name,created_at,notes\n\
+			U-alice,Alice,Hub,2024-06-15T12:00:00Z,first\n\
+			U-alice,Alice,,2024-06-16 08:30:00,\n\
+			,Bob,,,\n\
+			\n\
+			U-carol,Carol,Hub,yesterday,\n\
+			nope,Dave,,,\n\
+			U-erin,Erin\n";
+		let summary = from_str(&db, content, Format::Csv, Options::default()).await.unwrap();
+		assert_eq!(
+			(
+				summary.users_created,
+				summary.handshakes_created,
+				summary.skipped,
+				summary.blank
+			),
+			(2, 3, 0, 1)
+		);
+		let lines: Vec<_> = summary.failures.iter().map(|failure| failure.line).collect();
+		assert_eq!(lines, [6, 7, 8]);
+
+		let alice = db.get_user_by_resonite_id("U-alice").await.unwrap().unwrap();
+		assert!(alice.legacy);
+		assert_eq!(alice.created_at, parse_time("2024-06-15T12:00:00Z").unwrap());
+		assert_eq!(alice.last_seen_at, parse_time("2024-06-16T08:30:00Z"));
+		let recent = db.get_user_recent_handshakes(alice.id, 5).await.unwrap();
+		assert_eq!(recent[1].world_name.as_deref(), Some("Hub"));
+		assert!(recent[0].world_name.is_none());
+
+		// Importing again changes nothing, and claiming a legacy user's name gives them the ID
+		let summary = from_str(&db, content, Format::Csv, Options::default()).await.unwrap();
+		assert_eq!((summary.handshakes_created, summary.skipped), (0, 3));
+		let summary = from_str(
+			&db,
+			"resonite_id,resonite_name\nU-bob,bob\n",
+			Format::Csv,
+			Options::default(),
+		)
+		.await
+		.unwrap();
+		assert_eq!(summary.skipped, 1);
+		assert_eq!(
+			db.get_user_by_resonite_id("U-bob")
+				.await
+				.unwrap()
+				.unwrap()
+				.resonite_name,
+			"Bob"
+		);
+		assert!(from_str(&db, "name\nAlice\n", Format::Csv, Options::default())
+			.await
+			.is_err());
+	}
+
+	#[tokio::test]
+	async fn exports_restore_to_equivalent_data() {
+		let dir = tempfile::tempdir().unwrap();
+
+		let source = database().await;
+		let csv = "resonite_id,resonite_name,world_name,created_at\nU-a,A,Hub,2024-06-15T12:00:00Z\n,Legacy,,\n";
+		from_str(&source, csv, Format::Csv, Options::default()).await.unwrap();
+		let deleted = source.create_legacy_user("Gone").await.unwrap();
+		assert!(source.delete_user(deleted.id).await.unwrap());
+		let shake = db::HandshakeContext {
+			id: "U-b".to_owned(),
+			name: "B".to_owned(),
+			world: Some("Cafe".to_owned()),
+			source: Some("kiosk".to_owned()),
+			event: Some("MMC".to_owned()),
+		};
+		source.create_handshake(shake.clone()).await.unwrap();
+		source.create_handshake(shake).await.unwrap();
+
+		let first = dir.path().join("first.ndjson");
+		export::to_file(&source, &first, export::Format::Ndjson, true)
+			.await
+			.unwrap();
+		let content = std::fs::read_to_string(&first).unwrap();
+
+		let target = database().await;
+		let summary = from_str(&target, &content, Format::Ndjson, Options::default())
+			.await
+			.unwrap();
+		assert_eq!((summary.users_created, summary.handshakes_created), (3, 4));
+		assert!(summary.failures.is_empty());
+
+		let second = dir.path().join("second.ndjson");
+		export::to_file(&target, &second, export::Format::Ndjson, true)
+			.await
+			.unwrap();
+		assert_eq!(
+			comparable(&content),
+			comparable(&std::fs::read_to_string(&second).unwrap())
+		);
+
+		// Restoring again needs merging, which finds everything already there
+		assert!(from_str(&target, &content, Format::Ndjson, Options::default())
+			.await
+			.is_err());
+		let merge = Options {
+			merge: true,
+			..Options::default()
+		};
+		let summary = from_str(&target, &content, Format::Ndjson, merge).await.unwrap();
+		assert_eq!(
+			(summary.users_created, summary.handshakes_created, summary.skipped),
+			(0, 0, 7)
+		);
+		assert_eq!(target.count_handshakes().await.unwrap(), 4);
+	}
+
+	/// Parses the records of an NDJSON export, replacing IDs with the names of the users they refer to
+	fn comparable(export: &str) -> Vec<serde_json::Value> {
+		let mut names = HashMap::new();
+		export
+			.lines()
+			.skip(1)
+			.map(|line| {
+				let mut value: serde_json::Value = serde_json::from_str(line).unwrap();
+				let record = value.as_object_mut().unwrap();
+				let id = record.remove("id").unwrap();
+				if record["type"] == "user" {
+					names.insert(id.to_string(), record["resonite_name"].clone());
+				} else {
+					let user = record["user_id"].to_string();
+					record.insert("user_id".to_owned(), names[&user].clone());
+				}
+				value
+			})
+			.collect()
+	}
+}
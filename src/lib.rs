@@ -0,0 +1,42 @@
+//! Shaker records handshakes between Resonite users and serves stats about them over an HTTP API. The `shaker` binary
+//! is a thin wrapper around [`run`], while [`db`] and [`api`] can be used on their own to work with the stored
+//! handshakes or serve the API from other programs and tests.
+
+#![warn(clippy::pedantic, missing_docs)]
+#![allow(clippy::missing_errors_doc)]
+
+/// HTTP API, including its routes, handlers, and the server that serves them
+pub mod api;
+/// API tokens and the roles they grant
+pub mod auth;
+/// Online backups of the database
+pub mod backup;
+/// Command-line interface, including its options and the commands it runs
+pub mod cli;
+/// Loading configuration from a TOML file
+pub mod config_file;
+/// Storage of users and handshakes in the database
+pub mod db;
+/// Posting handshake announcements to Discord
+pub mod discord;
+/// Exporting users and handshakes to files
+pub mod export;
+/// Importing users and handshakes from files
+pub mod import;
+/// Log output, including rotating log files
+pub mod logging;
+/// Looking up users in the Resonite API
+pub mod resonite;
+/// Integration with systemd socket activation and notifications
+pub mod systemd;
+/// Serving the API over TLS with reloadable certificates
+pub mod tls;
+/// Validation of user-supplied fields
+pub mod validate;
+/// Delivering webhook notifications of handshakes
+pub mod webhook;
+
+pub use cli::{
+	run, BackfillIdsArgs, Cli, Command, Config, DatabaseArgs, ExportArgs, FieldLimitArgs, ImportArgs, LegacyArgs,
+	LogFormat, MergeUsersArgs, OutputFormat, StatsArgs, UserArgs,
+};
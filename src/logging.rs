@@ -0,0 +1,193 @@
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	path::{Path, PathBuf},
+};
+
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_subscriber::fmt::{
+	writer::{BoxMakeWriter, MakeWriterExt},
+	MakeWriter,
+};
+
+use crate::Config;
+
+/// Number of bytes in a mebibyte, which log file sizes are given in
+const MEBIBYTE: u64 = 1024 * 1024;
+
+/// Creates the writer for logs as configured, which writes to stderr, a log file, or both. Colors are stripped from
+/// lines written to the file if `plain_file` is set. The returned guard must be kept until logging is done, since
+/// dropping it flushes any lines still waiting to be written to the file.
+pub fn writer(cfg: &Config, plain_file: bool) -> io::Result<(BoxMakeWriter, Option<WorkerGuard>)> {
+	let Some(path) = &cfg.log_file else {
+		return Ok((BoxMakeWriter::new(io::stderr), None));
+	};
+
+	// Lines are never dropped when the writer falls behind, since that's what the file is for
+	let file = RotatingFile::open(path, cfg.log_max_size * MEBIBYTE, cfg.log_keep)?;
+	let (file, guard) = NonBlockingBuilder::default().lossy(false).finish(file);
+	let writer = match (plain_file, cfg.log_file_only) {
+		(true, true) => BoxMakeWriter::new(PlainText(file)),
+		(true, false) => BoxMakeWriter::new(io::stderr.and(PlainText(file))),
+		(false, true) => BoxMakeWriter::new(file),
+		(false, false) => BoxMakeWriter::new(io::stderr.and(file)),
+	};
+	Ok((writer, Some(guard)))
+}
+
+/// Log file that's rotated once it would grow beyond a maximum size, renaming it with a `.1` suffix (and any older ones
+/// with the next number up) and starting a new one. Each write is kept whole in a single file, so as long as lines are
+/// written in one go, none are split or lost by rotating.
+#[derive(Debug)]
+pub struct RotatingFile {
+	/// Path to the current file
+	path: PathBuf,
+
+	/// Size in bytes beyond which the file is rotated
+	max_size: u64,
+
+	/// Number of rotated files to keep, deleting older ones
+	keep: u16,
+
+	/// Current file
+	file: File,
+
+	/// Size in bytes of the current file
+	size: u64,
+}
+
+impl RotatingFile {
+	/// Opens a log file for appending, creating it if it doesn't exist
+	pub fn open(path: &Path, max_size: u64, keep: u16) -> io::Result<Self> {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		let size = file.metadata()?.len();
+		Ok(Self {
+			path: path.to_owned(),
+			max_size,
+			keep,
+			file,
+			size,
+		})
+	}
+
+	/// Path that a rotated file is renamed to, where 1 is the newest
+	fn rotated_path(&self, number: u16) -> PathBuf {
+		let mut path = self.path.clone().into_os_string();
+		path.push(format!(".{number}"));
+		path.into()
+	}
+
+	/// Moves each rotated file one number up (deleting the oldest), moves the current file to the first number, and
+	/// starts a new current file
+	fn rotate(&mut self) -> io::Result<()> {
+		self.file.flush()?;
+		if self.keep > 0 {
+			for number in (1..self.keep).rev() {
+				match fs::rename(self.rotated_path(number), self.rotated_path(number + 1)) {
+					Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+					_ => {}
+				}
+			}
+			fs::rename(&self.path, self.rotated_path(1))?;
+		}
+
+		self.file = OpenOptions::new()
+			.create(true)
+			.write(true)
+			.truncate(true)
+			.open(&self.path)?;
+		self.size = 0;
+		Ok(())
+	}
+}
+
+impl Write for RotatingFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+			// Failing to rotate shouldn't lose the line, so it's written to the current file regardless
+			if let Err(err) = self.rotate() {
+				eprintln!("Unable to rotate log file {}: {err}", self.path.display());
+			}
+		}
+
+		let written = self.file.write(buf)?;
+		self.size += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+/// Writer that strips terminal colors (ANSI escape sequences) from everything written through it
+#[derive(Debug, Clone)]
+struct PlainText(NonBlocking);
+
+impl Write for PlainText {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let mut plain = Vec::with_capacity(buf.len());
+		let mut bytes = buf.iter().copied();
+		while let Some(byte) = bytes.next() {
+			if byte == 0x1b {
+				// Control sequences are ESC, [, any parameters, then a final byte in the range @ to ~
+				if bytes.next() == Some(b'[') {
+					bytes.find(|byte| (0x40..=0x7e).contains(byte));
+				}
+			} else {
+				plain.push(byte);
+			}
+		}
+
+		self.0.write_all(&plain)?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.0.flush()
+	}
+}
+
+impl<'a> MakeWriter<'a> for PlainText {
+	type Writer = Self;
+
+	fn make_writer(&'a self) -> Self::Writer {
+		self.clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn files_are_rotated_by_size() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("shaker.log");
+
+		let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+		for line in [
+			"one\n",
+			"two\n",
+			"three\n",
+			"four\n",
+			"a line longer than the maximum\n",
+		] {
+			file.write_all(line.as_bytes()).unwrap();
+		}
+		file.flush().unwrap();
+
+		// Lines are never split between files, even ones longer than the maximum size
+		let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+		assert_eq!(read("shaker.log"), "a line longer than the maximum\n");
+		assert_eq!(read("shaker.log.1"), "four\n");
+		assert_eq!(read("shaker.log.2"), "three\n");
+		assert!(!dir.path().join("shaker.log.3").exists());
+
+		// Reopening appends to the current file
+		drop(file);
+		let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+		file.write_all(b"five\n").unwrap();
+		assert_eq!(read("shaker.log"), "a line longer than the maximum\nfive\n");
+	}
+}
@@ -0,0 +1,588 @@
+use std::{
+	collections::HashMap,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use time::OffsetDateTime;
+use tokio::{
+	sync::{Mutex, Notify},
+	time as tokio_time,
+};
+use tracing::{debug, info, warn};
+
+use crate::{db, validate};
+
+/// Maximum time to wait for the Resonite API to respond
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of cached lookups at which expired ones are cleared out
+const MAX_CACHED: usize = 10_000;
+
+/// Number of users retrieved from the database at a time when refreshing usernames
+const REFRESH_BATCH_SIZE: i64 = 100;
+
+/// Number of users retrieved from the database at a time when backfilling IDs
+const BACKFILL_BATCH_SIZE: i64 = 100;
+
+/// Client for Resonite's public API that verifies user IDs, caching the results of lookups for a while
+#[derive(Debug, Clone)]
+pub struct Resonite {
+	/// HTTP client to make requests with
+	client: Client,
+
+	/// Base URL of the API
+	base_url: Url,
+
+	/// How long the result of a lookup is reused for
+	cache_ttl: Duration,
+
+	/// Whether submitted usernames are replaced with the ones from the API
+	canonical_names: bool,
+
+	/// Results of recent lookups by user ID
+	cache: Arc<Mutex<HashMap<String, CachedLookup>>>,
+}
+
+/// Result of looking up a user
+#[derive(Debug, Clone)]
+struct CachedLookup {
+	/// When the lookup was made
+	fetched_at: Instant,
+
+	/// User that was found, if any
+	user: Option<ResoniteUser>,
+}
+
+/// User returned by the Resonite API
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ResoniteUser {
+	/// Resonite user ID
+	pub id: String,
+
+	/// Current Resonite username
+	pub username: String,
+}
+
+impl Resonite {
+	/// Creates a client for the API at a base URL, reusing the results of lookups for the given duration
+	pub fn new(base_url: Url, cache_ttl: Duration, canonical_names: bool) -> anyhow::Result<Self> {
+		let client = Client::builder()
+			.timeout(REQUEST_TIMEOUT)
+			.user_agent(concat!("Shaker/", env!("CARGO_PKG_VERSION")))
+			.build()?;
+
+		Ok(Self {
+			client,
+			base_url,
+			cache_ttl,
+			canonical_names,
+			cache: Arc::default(),
+		})
+	}
+
+	/// Whether submitted usernames should be replaced with the ones from the API
+	#[must_use]
+	pub fn canonical_names(&self) -> bool {
+		self.canonical_names
+	}
+
+	/// Looks up a user by ID, returning none if the API doesn't know of one. Errors are returned for malformed IDs and
+	/// when the API couldn't be reached or gave an unexpected response, in which case nothing is cached.
+	pub async fn get_user(&self, id: &str) -> anyhow::Result<Option<ResoniteUser>> {
+		validate::resonite_id("id", id)?;
+		if let Some(lookup) = self.cache.lock().await.get(id) {
+			if lookup.fetched_at.elapsed() < self.cache_ttl {
+				return Ok(lookup.user.clone());
+			}
+		}
+
+		let user = self.fetch_user(id).await?;
+		let mut cache = self.cache.lock().await;
+		if cache.len() >= MAX_CACHED {
+			cache.retain(|_, lookup| lookup.fetched_at.elapsed() < self.cache_ttl);
+			if cache.len() >= MAX_CACHED {
+				cache.clear();
+			}
+		}
+		cache.insert(
+			id.to_owned(),
+			CachedLookup {
+				fetched_at: Instant::now(),
+				user: user.clone(),
+			},
+		);
+		Ok(user)
+	}
+
+	/// Requests a user from the API, bypassing the cache
+	pub async fn fetch_user(&self, id: &str) -> anyhow::Result<Option<ResoniteUser>> {
+		let mut url = self.base_url.clone();
+		url.path_segments_mut()
+			.map_err(|()| anyhow::anyhow!("Resonite API URL {} can't have a path", self.base_url))?
+			.pop_if_empty()
+			.extend(["users", id]);
+
+		let response = self
+			.client
+			.get(url)
+			.send()
+			.await
+			.context("Unable to reach the Resonite API")?;
+		if response.status() == StatusCode::NOT_FOUND {
+			debug!("Resonite API has no user {id}");
+			return Ok(None);
+		}
+
+		let user = response
+			.error_for_status()?
+			.json::<ResoniteUser>()
+			.await
+			.context("Unexpected response from the Resonite API")?;
+		debug!("Resonite API has user {id} named {}", user.username);
+		Ok(Some(user))
+	}
+
+	/// Searches the API for users by name, which matches partial names as well as exact ones
+	pub async fn search_users(&self, name: &str) -> anyhow::Result<Vec<ResoniteUser>> {
+		let mut url = self.base_url.clone();
+		url.path_segments_mut()
+			.map_err(|()| anyhow::anyhow!("Resonite API URL {} can't have a path", self.base_url))?
+			.pop_if_empty()
+			.push("users");
+		url.query_pairs_mut().append_pair("name", name);
+
+		let users = self
+			.client
+			.get(url)
+			.send()
+			.await
+			.context("Unable to reach the Resonite API")?
+			.error_for_status()?
+			.json::<Vec<ResoniteUser>>()
+			.await
+			.context("Unexpected response from the Resonite API")?;
+		debug!("Resonite API found {} user(s) searching for {name}", users.len());
+		Ok(users)
+	}
+}
+
+/// Background task that keeps the usernames of users with a Resonite ID up to date with the Resonite API, since they're
+/// otherwise only updated when the user shakes hands again
+#[derive(Debug, Clone)]
+pub struct NameRefresher {
+	/// Wakes the task to start a pass right away
+	wake: Arc<Notify>,
+}
+
+impl NameRefresher {
+	/// Spawns the task, which makes a pass over users whose names were last refreshed longer ago than the given age
+	/// every interval, making no more than the given number of requests per minute. Progress is stored in the database
+	/// as each user is refreshed, so passes pick up where they left off after a restart.
+	#[must_use]
+	pub fn spawn(
+		db: db::Database,
+		resonite: Resonite,
+		interval: Duration,
+		requests_per_minute: u32,
+		stale_after: time::Duration,
+	) -> Self {
+		let wake = Arc::new(Notify::new());
+		let task = RefreshTask {
+			db,
+			resonite,
+			stale_after,
+			limiter: tokio_time::interval(Duration::from_mins(1) / requests_per_minute),
+		};
+		tokio::spawn(task.run(Arc::clone(&wake), interval));
+
+		info!(
+			"Refreshing usernames from the Resonite API every {}s",
+			interval.as_secs()
+		);
+		Self { wake }
+	}
+
+	/// Wakes the task so that it starts a pass right away (or another as soon as the current one is done)
+	pub fn wake(&self) {
+		self.wake.notify_one();
+	}
+}
+
+/// State of the username refreshing task
+struct RefreshTask {
+	/// Database containing the users
+	db: db::Database,
+
+	/// Client to look users up with
+	resonite: Resonite,
+
+	/// Age after which a user's name is refreshed again
+	stale_after: time::Duration,
+
+	/// Ticker that paces requests to the API
+	limiter: tokio_time::Interval,
+}
+
+impl RefreshTask {
+	/// Makes a pass whenever woken or the interval has passed, forever
+	async fn run(mut self, wake: Arc<Notify>, interval: Duration) {
+		self.limiter
+			.set_missed_tick_behavior(tokio_time::MissedTickBehavior::Delay);
+		loop {
+			match self.pass().await {
+				Ok(0) => {}
+				Ok(renamed) => info!("Refreshed usernames from the Resonite API, renaming {renamed} user(s)"),
+				Err(err) => warn!("Unable to refresh usernames from the Resonite API: {err:#}"),
+			}
+
+			tokio::select! {
+				() = wake.notified() => {}
+				() = tokio_time::sleep(interval) => {}
+			}
+		}
+	}
+
+	/// Refreshes the names of all users that are due, returning how many were renamed. The pass stops at the first
+	/// request that fails, leaving the remaining users for the next one.
+	async fn pass(&mut self) -> anyhow::Result<u64> {
+		let before = OffsetDateTime::now_utc() - self.stale_after;
+		let mut renamed = 0;
+		loop {
+			let users = self.db.get_users_to_refresh(before, REFRESH_BATCH_SIZE).await?;
+			if users.is_empty() {
+				return Ok(renamed);
+			}
+
+			for user in users {
+				let Some(id) = &user.resonite_id else {
+					continue;
+				};
+
+				self.limiter.tick().await;
+				if let Some(current) = self.resonite.fetch_user(id).await? {
+					let name = validate::normalize_name(&current.username);
+					if name != user.resonite_name {
+						let old_name = user.resonite_name.clone();
+						match self
+							.db
+							.update_user(&db::User {
+								resonite_name: name.clone(),
+								..user.clone()
+							})
+							.await
+						{
+							Ok(_) => {
+								info!("Renamed user {} from {old_name} to {name} to match Resonite", user.id);
+								renamed += 1;
+							}
+							Err(err) => warn!("Unable to rename user {} to {name}: {err:#}", user.id),
+						}
+					}
+				}
+
+				self.db.mark_name_refreshed(user.id).await?;
+			}
+		}
+	}
+}
+
+/// Fills in the missing Resonite IDs of users (typically legacy ones) by searching the Resonite API for their names,
+/// only using an ID when exactly one user has the same name. The outcome for each user is stored in the database as it's
+/// looked up, so an interrupted backfill picks up where it left off.
+pub struct IdBackfill {
+	/// Database containing the users
+	db: db::Database,
+
+	/// Client to search for users with
+	resonite: Resonite,
+
+	/// Ticker that paces requests to the API
+	limiter: tokio_time::Interval,
+
+	/// Whether to only report what would be done, without changing anything
+	dry_run: bool,
+
+	/// Whether to look up users again whose names were already looked up without finding an ID
+	retry: bool,
+
+	/// Outcome for each user looked up so far
+	pub report: Vec<(db::User, BackfillOutcome)>,
+}
+
+/// Outcome of looking a user's Resonite ID up by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackfillOutcome {
+	/// Exactly one user has the name, so their ID was used (or would've been, for a dry run)
+	Filled(String),
+
+	/// Several users have the name, so none of their IDs were used
+	Ambiguous(usize),
+
+	/// No user has the name
+	NotFound,
+
+	/// Exactly one user has the name, but their ID already belongs to another user
+	Conflict {
+		/// Resonite ID that was found
+		resonite_id: String,
+
+		/// Database ID of the user that already has it
+		user_id: i64,
+	},
+}
+
+impl BackfillOutcome {
+	/// Name of the outcome, as stored in the database
+	#[must_use]
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Filled(_) => "filled",
+			Self::Ambiguous(_) => "ambiguous",
+			Self::NotFound => "not_found",
+			Self::Conflict { .. } => "conflict",
+		}
+	}
+}
+
+impl IdBackfill {
+	/// Sets up a backfill that makes no more than the given number of requests per minute
+	#[must_use]
+	pub fn new(db: db::Database, resonite: Resonite, requests_per_minute: u32, dry_run: bool, retry: bool) -> Self {
+		let mut limiter = tokio_time::interval(Duration::from_mins(1) / requests_per_minute);
+		limiter.set_missed_tick_behavior(tokio_time::MissedTickBehavior::Delay);
+		Self {
+			db,
+			resonite,
+			limiter,
+			dry_run,
+			retry,
+			report: Vec::new(),
+		}
+	}
+
+	/// Looks up every user without a Resonite ID, stopping at the first request that fails. IDs are never changed for
+	/// users that already have one, even if they were given one since the backfill started.
+	pub async fn run(&mut self) -> anyhow::Result<()> {
+		let mut after = 0;
+		loop {
+			let users = self
+				.db
+				.get_users_without_ids(after, self.retry, BACKFILL_BATCH_SIZE)
+				.await?;
+			let Some(last) = users.last() else {
+				return Ok(());
+			};
+			after = last.id;
+
+			for user in users {
+				self.limiter.tick().await;
+				let outcome = self.look_up(&user).await?;
+				match &outcome {
+					BackfillOutcome::Filled(id) => info!("Found Resonite ID {id} for user {}", user.id),
+					BackfillOutcome::Ambiguous(count) => debug!(
+						"{count} Resonite users are named {}, so user {} was left alone",
+						user.resonite_name, user.id
+					),
+					BackfillOutcome::NotFound => debug!("No Resonite user is named {}", user.resonite_name),
+					BackfillOutcome::Conflict { resonite_id, user_id } => warn!(
+						"Found Resonite ID {resonite_id} for user {}, but user {user_id} already has it",
+						user.id
+					),
+				}
+
+				if !self.dry_run {
+					self.db.record_id_backfill(user.id, outcome.as_str()).await?;
+				}
+				self.report.push((user, outcome));
+			}
+		}
+	}
+
+	/// Searches for a user's name and uses the ID of the only exact match, if there is one
+	async fn look_up(&self, user: &db::User) -> anyhow::Result<BackfillOutcome> {
+		let mut matches: Vec<_> = self
+			.resonite
+			.search_users(&user.resonite_name)
+			.await?
+			.into_iter()
+			.filter(|found| {
+				validate::normalize_name(&found.username).to_lowercase() == user.resonite_name.to_lowercase()
+			})
+			.collect();
+		matches.dedup_by(|a, b| a.id == b.id);
+
+		let found = match matches.len() {
+			0 => return Ok(BackfillOutcome::NotFound),
+			1 => matches.remove(0),
+			count => return Ok(BackfillOutcome::Ambiguous(count)),
+		};
+		if let Some(owner) = self.db.get_user_by_resonite_id(&found.id).await? {
+			return Ok(BackfillOutcome::Conflict {
+				resonite_id: found.id,
+				user_id: owner.id,
+			});
+		}
+
+		if !self.dry_run && !self.db.set_missing_resonite_id(user.id, &found.id).await? {
+			warn!(
+				"User {} was given a Resonite ID during the backfill, so it was left alone",
+				user.id
+			);
+		}
+		Ok(BackfillOutcome::Filled(found.id))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use axum::{
+		extract::{Path, Query},
+		http::StatusCode,
+		routing::get,
+		Json, Router,
+	};
+	use serde_json::json;
+
+	use super::*;
+
+	/// Serves a fake Resonite API that only knows of `U-known` (named "Known"), `U-knowing` (named "Knowing"), and two
+	/// users named "Twin", returning its URL and a count of the requests made to it
+	async fn fake_api() -> (Url, Arc<AtomicUsize>) {
+		let requests = Arc::new(AtomicUsize::new(0));
+		let lookups = Arc::clone(&requests);
+		let searches = Arc::clone(&requests);
+		let app = Router::new()
+			.route(
+				"/users/:id",
+				get(|Path(id): Path<String>| async move {
+					lookups.fetch_add(1, Ordering::SeqCst);
+					if id == "U-known" {
+						Ok(Json(json!({ "id": id, "username": "Known", "isVerified": true })))
+					} else {
+						Err(StatusCode::NOT_FOUND)
+					}
+				}),
+			)
+			.route(
+				"/users",
+				get(|Query(query): Query<HashMap<String, String>>| async move {
+					searches.fetch_add(1, Ordering::SeqCst);
+					let users = [
+						("U-known", "Known"),
+						("U-knowing", "Knowing"),
+						("U-twin1", "Twin"),
+						("U-twin2", "twin"),
+					];
+					let name = query.get("name").map(|name| name.to_lowercase()).unwrap_or_default();
+					Json(
+						users
+							.into_iter()
+							.filter(|(_, username)| username.to_lowercase().contains(&name))
+							.map(|(id, username)| json!({ "id": id, "username": username }))
+							.collect::<Vec<_>>(),
+					)
+				}),
+			);
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+		tokio::spawn(async move { axum::serve(listener, app).await });
+		(url, requests)
+	}
+
+	#[tokio::test]
+	async fn lookups_are_cached() {
+		let (url, requests) = fake_api().await;
+		let resonite = Resonite::new(url, Duration::from_hours(1), true).unwrap();
+		let user = resonite.get_user("U-known").await.unwrap().unwrap();
+		assert_eq!(user.username, "Known");
+		assert!(resonite.get_user("U-unknown").await.unwrap().is_none());
+		resonite.get_user("U-known").await.unwrap();
+		resonite.get_user("U-unknown").await.unwrap();
+		assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+		assert!(resonite.get_user("not an id").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn refreshes_rename_users_once() {
+		let (url, requests) = fake_api().await;
+		let db = db::Database::open_in_memory().await.unwrap();
+		for form in ["id=U-known&name=Old", "id=U-unknown&name=Other"] {
+			db.create_handshake(serde_urlencoded::from_str(form).unwrap())
+				.await
+				.unwrap();
+		}
+
+		let mut refresh = RefreshTask {
+			db: db.clone(),
+			resonite: Resonite::new(url, Duration::ZERO, false).unwrap(),
+			stale_after: time::Duration::days(7),
+			limiter: tokio_time::interval(Duration::from_millis(1)),
+		};
+		assert_eq!(refresh.pass().await.unwrap(), 1);
+		assert_eq!(db.get_user(1).await.unwrap().unwrap().resonite_name, "Known");
+		assert_eq!(db.get_user_name_history(1).await.unwrap()[0].old_name, "Old");
+		assert_eq!(db.get_user(2).await.unwrap().unwrap().resonite_name, "Other");
+
+		// Users that were just refreshed are left alone until they're due again
+		assert_eq!(refresh.pass().await.unwrap(), 0);
+		assert_eq!(requests.load(Ordering::SeqCst), 2);
+	}
+
+	#[tokio::test]
+	async fn backfills_only_use_unambiguous_matches() {
+		let (url, requests) = fake_api().await;
+		let db = db::Database::open_in_memory().await.unwrap();
+		for name in ["known", "Twin", "Nobody", "Knowing"] {
+			db.create_legacy_user(name).await.unwrap();
+		}
+		db.create_handshake(serde_urlencoded::from_str("id=U-knowing&name=Knowing2").unwrap())
+			.await
+			.unwrap();
+		let resonite = Resonite::new(url, Duration::ZERO, false).unwrap();
+		let backfill = |dry_run, retry| {
+			let mut backfill = IdBackfill::new(db.clone(), resonite.clone(), 60_000, dry_run, retry);
+			async move {
+				backfill.run().await.unwrap();
+				backfill
+					.report
+					.into_iter()
+					.map(|(user, outcome)| (user.id, outcome))
+					.collect::<Vec<_>>()
+			}
+		};
+
+		let expected = vec![
+			(1, BackfillOutcome::Filled("U-known".to_owned())),
+			(2, BackfillOutcome::Ambiguous(2)),
+			(3, BackfillOutcome::NotFound),
+			(
+				4,
+				BackfillOutcome::Conflict {
+					resonite_id: "U-knowing".to_owned(),
+					user_id: 5,
+				},
+			),
+		];
+		assert_eq!(backfill(true, false).await, expected);
+		assert_eq!(db.get_user(1).await.unwrap().unwrap().resonite_id, None);
+
+		assert_eq!(backfill(false, false).await, expected);
+		assert_eq!(
+			db.get_user(1).await.unwrap().unwrap().resonite_id.as_deref(),
+			Some("U-known")
+		);
+		assert_eq!(db.get_user(2).await.unwrap().unwrap().resonite_id, None);
+
+		// Users that were already looked up are skipped unless retrying
+		assert_eq!(backfill(false, false).await, vec![]);
+		assert_eq!(backfill(false, true).await, expected[1..]);
+		assert_eq!(requests.load(Ordering::SeqCst), 11);
+	}
+}
@@ -0,0 +1,247 @@
+use std::{env, ffi::OsStr, io, net::TcpListener, time::Duration};
+
+use anyhow::Context;
+use tokio::{sync::watch, time};
+use tracing::{debug, info, warn};
+
+/// Notification of the service's state sent to systemd's service manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notification<'a> {
+	/// Startup has finished and the service is ready for requests
+	Ready,
+
+	/// The service is shutting down
+	Stopping,
+
+	/// The service is still alive, for the watchdog
+	Watchdog,
+
+	/// Free-form description of the service's state
+	Status(&'a str),
+}
+
+impl Notification<'_> {
+	/// Formats the notification as a variable assignment for the notification protocol
+	fn assignment(&self) -> String {
+		match self {
+			Self::Ready => "READY=1".to_owned(),
+			Self::Stopping => "STOPPING=1".to_owned(),
+			Self::Watchdog => "WATCHDOG=1".to_owned(),
+			Self::Status(status) => format!("STATUS={}", status.replace('\n', " ")),
+		}
+	}
+}
+
+/// Notifies systemd of the service's state, if it's running as a `Type=notify` service (in which case the
+/// `NOTIFY_SOCKET` environment variable is set). Otherwise, nothing is done.
+pub fn notify(notifications: &[Notification]) {
+	let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+		return;
+	};
+
+	let message = message(notifications);
+	match send(&path, &message) {
+		Ok(()) => debug!("Notified systemd: {}", message.trim_end().replace('\n', ", ")),
+		Err(err) => warn!("Unable to notify systemd via {}: {err}", path.to_string_lossy()),
+	}
+}
+
+/// Spawns a task that pings systemd's watchdog until a shutdown is requested, if the watchdog is enabled for the
+/// service (with `WatchdogSec`)
+pub fn spawn_watchdog(mut shutdown: watch::Receiver<bool>) {
+	let Some(interval) = watchdog_interval(
+		env::var("WATCHDOG_USEC").ok().as_deref(),
+		env::var("WATCHDOG_PID").ok().as_deref(),
+		std::process::id(),
+	) else {
+		return;
+	};
+
+	info!("Pinging the systemd watchdog every {}ms", interval.as_millis());
+	tokio::spawn(async move {
+		let mut ticker = time::interval(interval);
+		loop {
+			tokio::select! {
+				_ = ticker.tick() => notify(&[Notification::Watchdog]),
+				_ = shutdown.wait_for(|requested| *requested) => return,
+			}
+		}
+	});
+}
+
+/// Takes the listening socket passed in by systemd's socket activation, if the service was started that way (in which
+/// case `LISTEN_FDS` and `LISTEN_PID` are set for this process). Only a single TCP socket is supported. This must only be
+/// called once, since the socket is owned by whatever takes it.
+pub fn take_listener() -> anyhow::Result<Option<TcpListener>> {
+	let Some(count) = listen_fds(
+		env::var("LISTEN_FDS").ok().as_deref(),
+		env::var("LISTEN_PID").ok().as_deref(),
+		std::process::id(),
+	)?
+	else {
+		return Ok(None);
+	};
+	if count != 1 {
+		anyhow::bail!("systemd passed {count} sockets, but only one can be listened on");
+	}
+
+	let listener = inherited_listener()?;
+	listener
+		.local_addr()
+		.context("Socket passed by systemd isn't a TCP socket")?;
+	listener.set_nonblocking(true)?;
+	Ok(Some(listener))
+}
+
+/// Takes ownership of the first socket passed by systemd
+#[cfg(unix)]
+#[allow(clippy::unnecessary_wraps)]
+fn inherited_listener() -> anyhow::Result<TcpListener> {
+	use std::os::fd::{FromRawFd, RawFd};
+
+	/// File descriptor of the first socket passed by systemd
+	const LISTEN_FDS_START: RawFd = 3;
+
+	// SAFETY: systemd passes the sockets starting at this descriptor for this process to own, and this is only called
+	// once (after checking that systemd did pass a socket)
+	Ok(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+/// Socket activation only exists on Unix platforms
+#[cfg(not(unix))]
+fn inherited_listener() -> anyhow::Result<TcpListener> {
+	anyhow::bail!("Sockets passed by systemd aren't supported on this platform")
+}
+
+/// Determines how many sockets systemd passed from the number it gave and the process they're meant for. None is
+/// returned if no sockets were passed or they're meant for another process.
+fn listen_fds(fds: Option<&str>, pid: Option<&str>, own_pid: u32) -> anyhow::Result<Option<u32>> {
+	let (Some(fds), Some(pid)) = (fds, pid) else {
+		return Ok(None);
+	};
+	if pid.parse() != Ok(own_pid) {
+		return Ok(None);
+	}
+
+	let count = fds
+		.parse()
+		.with_context(|| format!("LISTEN_FDS must be a number of sockets, not {fds:?}"))?;
+	Ok(Some(count).filter(|&count| count > 0))
+}
+
+/// Formats notifications as a message for the notification protocol, one assignment per line
+fn message(notifications: &[Notification]) -> String {
+	notifications
+		.iter()
+		.map(|notification| notification.assignment() + "\n")
+		.collect()
+}
+
+/// Determines how often to ping the watchdog from its timeout in microseconds and the process it's meant for (if
+/// given), pinging twice per timeout so that a late ping doesn't trip it. None is returned if the watchdog is disabled
+/// or meant for another process.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+	if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+		return None;
+	}
+
+	let usec: u64 = usec?.parse().ok().filter(|&usec| usec > 0)?;
+	Some(Duration::from_micros(usec) / 2)
+}
+
+/// Sends a message to the notification socket at a path, which is in the abstract namespace if it starts with `@`
+#[cfg(unix)]
+fn send(path: &OsStr, message: &str) -> io::Result<()> {
+	use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+	let socket = UnixDatagram::unbound()?;
+	let Some(name) = path.as_bytes().strip_prefix(b"@") else {
+		socket.send_to(message.as_bytes(), path)?;
+		return Ok(());
+	};
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	{
+		#[cfg(target_os = "android")]
+		use std::os::android::net::SocketAddrExt;
+		#[cfg(target_os = "linux")]
+		use std::os::linux::net::SocketAddrExt;
+
+		let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+		socket.send_to_addr(message.as_bytes(), &addr)?;
+		Ok(())
+	}
+
+	#[cfg(not(any(target_os = "linux", target_os = "android")))]
+	{
+		let _ = name;
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			"abstract sockets aren't supported on this platform",
+		))
+	}
+}
+
+/// Notification sockets only exist on Unix platforms
+#[cfg(not(unix))]
+fn send(_path: &OsStr, _message: &str) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"systemd notifications aren't supported on this platform",
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn messages_have_one_assignment_per_line() {
+		assert_eq!(message(&[Notification::Ready]), "READY=1\n");
+		assert_eq!(
+			message(&[Notification::Stopping, Notification::Status("Draining\nrequests")]),
+			"STOPPING=1\nSTATUS=Draining requests\n"
+		);
+		assert_eq!(message(&[Notification::Watchdog]), "WATCHDOG=1\n");
+	}
+
+	#[test]
+	fn watchdog_pings_twice_per_timeout_for_this_process() {
+		assert_eq!(
+			watchdog_interval(Some("10000000"), None, 42),
+			Some(Duration::from_secs(5))
+		);
+		assert_eq!(
+			watchdog_interval(Some("10000000"), Some("42"), 42),
+			Some(Duration::from_secs(5))
+		);
+		assert_eq!(watchdog_interval(Some("10000000"), Some("7"), 42), None);
+		assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+		assert_eq!(watchdog_interval(Some("soon"), None, 42), None);
+		assert_eq!(watchdog_interval(None, None, 42), None);
+	}
+
+	#[test]
+	fn passed_sockets_are_only_for_this_process() {
+		assert_eq!(listen_fds(Some("1"), Some("42"), 42).unwrap(), Some(1));
+		assert_eq!(listen_fds(Some("2"), Some("42"), 42).unwrap(), Some(2));
+		assert_eq!(listen_fds(Some("0"), Some("42"), 42).unwrap(), None);
+		assert_eq!(listen_fds(Some("1"), Some("7"), 42).unwrap(), None);
+		assert_eq!(listen_fds(Some("1"), None, 42).unwrap(), None);
+		assert_eq!(listen_fds(None, None, 42).unwrap(), None);
+		assert!(listen_fds(Some("one"), Some("42"), 42).is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn messages_are_sent_to_the_socket() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("notify.sock");
+		let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+		send(path.as_os_str(), &message(&[Notification::Ready])).unwrap();
+
+		let mut buf = [0; 64];
+		let len = socket.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..len], b"READY=1\n");
+	}
+}
@@ -0,0 +1,143 @@
+use std::{path::Path, sync::Arc, time::SystemTime};
+
+use anyhow::{bail, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+	client::{ServerCertVerified, ServerCertVerifier},
+	Certificate, ClientConfig, ClientConnection, Connection, PrivateKey, ServerConfig, ServerConnection, ServerName,
+};
+use tokio::fs;
+#[cfg(unix)]
+use tracing::{error, info};
+
+/// Loads a TLS server configuration from PEM-encoded certificate chain and private key files, ensuring both are
+/// readable and that the key actually belongs to the certificate
+#[tracing::instrument("Loading TLS certificate", level = "info")]
+pub async fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+	let cert_pem = fs::read(cert_path)
+		.await
+		.with_context(|| format!("Unable to read TLS certificate {}", cert_path.display()))?;
+	let key_pem = fs::read(key_path)
+		.await
+		.with_context(|| format!("Unable to read TLS private key {}", key_path.display()))?;
+
+	// Parse the certificate chain and private key
+	let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+		.map(|cert| cert.map(|cert| Certificate(cert.to_vec())))
+		.collect::<Result<Vec<_>, _>>()
+		.with_context(|| format!("Unable to parse TLS certificate {}", cert_path.display()))?;
+	if certs.is_empty() {
+		bail!("No certificates found in {}", cert_path.display());
+	}
+
+	let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+		.with_context(|| format!("Unable to parse TLS private key {}", key_path.display()))?
+		.with_context(|| format!("No private key found in {}", key_path.display()))?;
+	let key = PrivateKey(key.secret_der().to_vec());
+
+	// Build the server config
+	let mut config = ServerConfig::builder()
+		.with_safe_defaults()
+		.with_no_client_auth()
+		.with_single_cert(certs, key)
+		.context("Invalid TLS certificate or private key")?;
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+	let config = Arc::new(config);
+
+	// rustls doesn't check that the key belongs to the certificate, so do a handshake against ourselves to find out
+	verify_key_matches(Arc::clone(&config)).with_context(|| {
+		format!(
+			"TLS private key {} doesn't match certificate {}",
+			key_path.display(),
+			cert_path.display()
+		)
+	})?;
+
+	Ok(config)
+}
+
+/// Reloads the TLS certificate and key whenever a hangup signal is received, keeping the current ones if the new files
+/// can't be loaded
+#[cfg(unix)]
+pub async fn reload_on_hangup(config: RustlsConfig, cert_path: std::path::PathBuf, key_path: std::path::PathBuf) {
+	use tokio::signal::unix::{signal, SignalKind};
+
+	let mut hangup = match signal(SignalKind::hangup()) {
+		Ok(hangup) => hangup,
+		Err(err) => {
+			error!("Unable to install SIGHUP handler; TLS certificates won't be reloadable: {err}");
+			return;
+		}
+	};
+
+	while hangup.recv().await.is_some() {
+		match load(&cert_path, &key_path).await {
+			Ok(loaded) => {
+				config.reload_from_config(loaded);
+				info!("Reloaded TLS certificate");
+			}
+			Err(err) => error!("Unable to reload TLS certificate; keeping the current one: {err:#}"),
+		}
+	}
+}
+
+/// Performs an in-memory TLS handshake between a client and a server using the given config. The client accepts any
+/// certificate, but still verifies the server's handshake signature against it, so the handshake only succeeds if
+/// the private key matches the certificate.
+fn verify_key_matches(config: Arc<ServerConfig>) -> Result<()> {
+	let client_config = ClientConfig::builder()
+		.with_safe_defaults()
+		.with_custom_certificate_verifier(Arc::new(AnyCertificate))
+		.with_no_client_auth();
+	let mut client = Connection::from(ClientConnection::new(
+		Arc::new(client_config),
+		ServerName::try_from("localhost")?,
+	)?);
+	let mut server = Connection::from(ServerConnection::new(config)?);
+
+	// Shuttle records back and forth until the handshake completes, bounded in case it somehow stalls
+	for _ in 0..16 {
+		if !client.is_handshaking() && !server.is_handshaking() {
+			return Ok(());
+		}
+
+		transfer(&mut client, &mut server)?;
+		transfer(&mut server, &mut client)?;
+	}
+
+	bail!("TLS handshake didn't complete")
+}
+
+/// Moves all pending TLS records from one connection to another and processes them
+fn transfer(from: &mut Connection, to: &mut Connection) -> Result<()> {
+	let mut buf = Vec::new();
+	while from.wants_write() {
+		from.write_tls(&mut buf)?;
+	}
+
+	let mut records = buf.as_slice();
+	while !records.is_empty() {
+		to.read_tls(&mut records)?;
+		to.process_new_packets()?;
+	}
+
+	Ok(())
+}
+
+/// Certificate verifier that accepts any certificate. Only the certificate's identity is skipped; handshake signatures
+/// are still verified against it.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &Certificate,
+		_intermediates: &[Certificate],
+		_server_name: &ServerName,
+		_scts: &mut dyn Iterator<Item = &[u8]>,
+		_ocsp_response: &[u8],
+		_now: SystemTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
+}
@@ -0,0 +1,432 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+/// Prefix that all Resonite user IDs start with
+const RESONITE_ID_PREFIX: &str = "U-";
+
+/// Maximum length of a Resonite user ID, including its prefix
+pub const MAX_RESONITE_ID_LENGTH: usize = 64;
+
+/// Maximum number of characters in a note on a user
+pub const MAX_NOTE_LENGTH: usize = 2000;
+
+/// Error for a submitted value that isn't acceptable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+	/// Name of the field with the invalid value
+	pub field: &'static str,
+
+	/// Description of what's wrong with the value
+	pub message: String,
+}
+
+impl ValidationError {
+	/// Creates an error for a field
+	#[must_use]
+	pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+		Self {
+			field,
+			message: message.into(),
+		}
+	}
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid {}: {}", self.field, self.message)
+	}
+}
+
+impl std::error::Error for ValidationError {}
+
+/// How to handle submitted values that are longer than allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverlongPolicy {
+	/// Cut the value down to the maximum length
+	Truncate,
+
+	/// Reject the submission
+	Reject,
+}
+
+/// Maximum length of a submitted value, along with how to handle values that exceed it
+#[derive(Debug, Clone, Copy)]
+pub struct LengthLimit {
+	/// Maximum number of characters
+	pub max: usize,
+
+	/// How to handle values that are longer
+	pub policy: OverlongPolicy,
+}
+
+impl LengthLimit {
+	/// Applies the limit to a value, either truncating it or rejecting it if it's too long
+	pub fn apply(self, field: &'static str, mut value: String) -> Result<String, ValidationError> {
+		let Some((cutoff, _)) = value.char_indices().nth(self.max) else {
+			return Ok(value);
+		};
+
+		match self.policy {
+			OverlongPolicy::Truncate => {
+				value.truncate(cutoff);
+				Ok(value)
+			}
+			OverlongPolicy::Reject => Err(ValidationError::new(
+				field,
+				format!("must be at most {} characters long", self.max),
+			)),
+		}
+	}
+}
+
+/// Maximum lengths of the fields of submitted handshakes, along with how to handle values that exceed them
+#[derive(Debug, Clone, Copy)]
+pub struct FieldLimits {
+	/// Maximum number of characters in Resonite user IDs, which are rejected rather than truncated if they're longer
+	pub id: usize,
+
+	/// Maximum number of characters in usernames
+	pub name: usize,
+
+	/// Maximum number of characters in world names
+	pub world: usize,
+
+	/// Maximum number of characters in sources
+	pub source: usize,
+
+	/// Maximum number of characters in event identifiers
+	pub event: usize,
+
+	/// How to handle values that are longer
+	pub policy: OverlongPolicy,
+}
+
+impl FieldLimits {
+	/// Limits used unless configured otherwise
+	pub const DEFAULT: Self = Self {
+		id: MAX_RESONITE_ID_LENGTH,
+		name: 100,
+		world: 256,
+		source: 256,
+		event: 256,
+		policy: OverlongPolicy::Truncate,
+	};
+
+	/// Checks that a Resonite user ID isn't too long. A truncated ID would belong to someone else (if anyone), so long
+	/// IDs are always rejected.
+	pub fn id(self, field: &'static str, id: &str) -> Result<(), ValidationError> {
+		if id.chars().count() > self.id {
+			return Err(ValidationError::new(
+				field,
+				format!("must be at most {} characters long", self.id),
+			));
+		}
+		Ok(())
+	}
+
+	/// Checks the characters of a username and applies the length limit to it
+	pub fn name(self, field: &'static str, name: String) -> Result<String, ValidationError> {
+		self.text(field, self.name, name)
+	}
+
+	/// Checks the characters of a world name and applies the length limit to it
+	pub fn world(self, field: &'static str, world: String) -> Result<String, ValidationError> {
+		self.text(field, self.world, world)
+	}
+
+	/// Checks the characters of a source and applies the length limit to it
+	pub fn source(self, field: &'static str, source: String) -> Result<String, ValidationError> {
+		self.text(field, self.source, source)
+	}
+
+	/// Checks the characters of an event identifier and applies the length limit to it
+	pub fn event(self, field: &'static str, event: String) -> Result<String, ValidationError> {
+		self.text(field, self.event, event)
+	}
+
+	/// Limits that reject overlong values rather than truncating them, for finding stored values that exceed them
+	#[must_use]
+	pub fn rejecting(self) -> Self {
+		Self {
+			policy: OverlongPolicy::Reject,
+			..self
+		}
+	}
+
+	/// Checks the characters of a value and applies a length limit to it
+	fn text(self, field: &'static str, max: usize, value: String) -> Result<String, ValidationError> {
+		text(field, &value)?;
+		LengthLimit {
+			max,
+			policy: self.policy,
+		}
+		.apply(field, value)
+	}
+}
+
+impl Default for FieldLimits {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+/// Validates that a value only has printable characters. Control characters (including line breaks) are rejected, as
+/// is the replacement character that invalid text (such as an unpaired surrogate) is decoded as.
+pub fn text(field: &'static str, value: &str) -> Result<(), ValidationError> {
+	let Some(ch) = value
+		.chars()
+		.find(|&ch| ch.is_control() || ch == char::REPLACEMENT_CHARACTER)
+	else {
+		return Ok(());
+	};
+
+	let message = if ch == char::REPLACEMENT_CHARACTER {
+		"must be valid text (without unpaired surrogates or other invalid characters)".to_owned()
+	} else {
+		format!("must not contain control characters (such as {ch:?})")
+	};
+	Err(ValidationError::new(field, message))
+}
+
+/// Validates a note on a user, trimming leading/trailing whitespace and normalizing line breaks to `\n`. Notes may span
+/// several lines, but other control characters are rejected, as are notes longer than [`MAX_NOTE_LENGTH`]. A note with
+/// nothing left of it is no note at all.
+pub fn note(field: &'static str, note: &str) -> Result<Option<String>, ValidationError> {
+	let note = note.trim().replace("\r\n", "\n");
+	if note.is_empty() {
+		return Ok(None);
+	}
+
+	if note.chars().count() > MAX_NOTE_LENGTH {
+		return Err(ValidationError::new(
+			field,
+			format!("must be at most {MAX_NOTE_LENGTH} characters long"),
+		));
+	}
+	for line in note.split('\n') {
+		text(field, &line.replace('\t', " "))?;
+	}
+	Ok(Some(note))
+}
+
+/// Validates that a Resonite user ID is well-formed: the `U-` prefix followed by at least one ASCII letter, digit, `-`,
+/// `_`, or `.`, with no more than [`MAX_RESONITE_ID_LENGTH`] characters in total
+pub fn resonite_id(field: &'static str, id: &str) -> Result<(), ValidationError> {
+	let Some(rest) = id.strip_prefix(RESONITE_ID_PREFIX) else {
+		return Err(ValidationError::new(
+			field,
+			format!("Resonite user IDs must start with \"{RESONITE_ID_PREFIX}\""),
+		));
+	};
+
+	if rest.is_empty() {
+		return Err(ValidationError::new(
+			field,
+			format!("Resonite user IDs must have at least one character after \"{RESONITE_ID_PREFIX}\""),
+		));
+	}
+
+	if id.len() > MAX_RESONITE_ID_LENGTH {
+		return Err(ValidationError::new(
+			field,
+			format!("Resonite user IDs must be at most {MAX_RESONITE_ID_LENGTH} characters long"),
+		));
+	}
+
+	if let Some(ch) = rest
+		.chars()
+		.find(|ch| !(ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.')))
+	{
+		return Err(ValidationError::new(
+			field,
+			format!("Resonite user IDs must not contain {ch:?}"),
+		));
+	}
+
+	Ok(())
+}
+
+/// Normalizes a username (see [`normalize_name`]), ensuring that something is left of it
+pub fn name(field: &'static str, name: &str) -> Result<String, ValidationError> {
+	let normalized = normalize_name(name);
+	if normalized.is_empty() {
+		return Err(ValidationError::new(field, "names must not be empty"));
+	}
+	Ok(normalized)
+}
+
+/// Normalizes a username so that visually identical names are stored and looked up identically. The name is
+/// converted to Unicode NFC, leading/trailing whitespace and zero-width characters are trimmed, invisible zero-width
+/// spaces are removed, and internal runs of whitespace are collapsed into a single space.
+#[must_use]
+pub fn normalize_name(name: &str) -> String {
+	let mut normalized = String::with_capacity(name.len());
+	let mut pending_space = false;
+
+	for ch in name.nfc() {
+		if ch.is_whitespace() {
+			pending_space = true;
+		} else if is_zero_width_space(ch) || (normalized.is_empty() && is_zero_width(ch)) {
+			// Drop characters that are never meaningful, and any zero-width characters at the start
+		} else {
+			if pending_space && !normalized.is_empty() {
+				normalized.push(' ');
+			}
+			pending_space = false;
+			normalized.push(ch);
+		}
+	}
+
+	// Zero-width joiners/non-joiners can be meaningful inside a name, but not at the end of one
+	let trimmed = normalized.trim_end_matches(is_zero_width).len();
+	normalized.truncate(trimmed);
+	normalized
+}
+
+/// Checks whether a character is a zero-width space that never affects how text is displayed
+fn is_zero_width_space(ch: char) -> bool {
+	matches!(ch, '\u{200B}' | '\u{2060}' | '\u{FEFF}')
+}
+
+/// Checks whether a character is any zero-width character, including joiners that affect how adjacent characters are
+/// displayed
+fn is_zero_width(ch: char) -> bool {
+	is_zero_width_space(ch) || matches!(ch, '\u{200C}' | '\u{200D}')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn accepts_well_formed_ids() {
+		for id in [
+			"U-Gawdl3y",
+			"U-1NWSQbnHgxx",
+			"U-some_name",
+			"U-some-name",
+			"U-a.b",
+			"U-x",
+		] {
+			assert_eq!(resonite_id("id", id), Ok(()), "{id}");
+		}
+	}
+
+	#[test]
+	fn rejects_missing_or_wrong_prefix() {
+		for id in ["", "Gawdl3y", "u-Gawdl3y", "U_Gawdl3y", "-U-Gawdl3y", " U-Gawdl3y"] {
+			assert!(resonite_id("id", id).is_err(), "{id:?}");
+		}
+	}
+
+	#[test]
+	fn rejects_empty_body() {
+		assert!(resonite_id("id", "U-").is_err());
+	}
+
+	#[test]
+	fn rejects_whitespace_and_other_characters() {
+		for id in [
+			"U-Gawd l3y",
+			"U-Gawdl3y ",
+			"U-Gawdl3y\n",
+			"U-Gawd\tl3y",
+			"U-Gäwdl3y",
+			"U-Gawdl3y!",
+		] {
+			assert!(resonite_id("id", id).is_err(), "{id:?}");
+		}
+	}
+
+	#[test]
+	fn enforces_length_bounds() {
+		let longest = format!("U-{}", "a".repeat(MAX_RESONITE_ID_LENGTH - 2));
+		assert_eq!(resonite_id("id", &longest), Ok(()));
+
+		let too_long = format!("U-{}", "a".repeat(MAX_RESONITE_ID_LENGTH - 1));
+		assert!(resonite_id("id", &too_long).is_err());
+	}
+
+	#[test]
+	fn reports_the_field() {
+		let err = resonite_id("resonite_id", "nope").unwrap_err();
+		assert_eq!(err.field, "resonite_id");
+	}
+
+	#[test]
+	fn rejects_control_and_invalid_characters() {
+		assert_eq!(text("name", "Foo Bar \u{1F468}"), Ok(()));
+		for value in ["Foo\nBar", "Foo\tBar", "\u{7}", "Foo\u{85}", "Foo\u{FFFD}"] {
+			assert_eq!(text("name", value).unwrap_err().field, "name", "{value:?}");
+		}
+	}
+
+	#[test]
+	fn limits_each_field() {
+		let limits = FieldLimits {
+			id: 6,
+			name: 3,
+			world: 4,
+			source: 5,
+			event: 2,
+			policy: OverlongPolicy::Truncate,
+		};
+		assert_eq!(limits.id("id", "U-abcd"), Ok(()));
+		assert!(limits.id("id", "U-abcde").is_err());
+		assert_eq!(limits.name("name", "Abcd".to_owned()).unwrap(), "Abc");
+		assert_eq!(limits.world("world", "Hubbub".to_owned()).unwrap(), "Hubb");
+		assert_eq!(limits.source("source", "Source".to_owned()).unwrap(), "Sourc");
+		assert_eq!(limits.event("event", "MMC".to_owned()).unwrap(), "MM");
+		assert!(limits.name("name", "A\nb".to_owned()).is_err());
+
+		let err = limits.rejecting().name("name", "Abcd".to_owned()).unwrap_err();
+		assert_eq!(err.message, "must be at most 3 characters long");
+	}
+
+	#[test]
+	fn validates_notes() {
+		assert_eq!(
+			note("note", "  Prefers no photos\r\n\tAsk first \n")
+				.unwrap()
+				.as_deref(),
+			Some("Prefers no photos\n\tAsk first")
+		);
+		assert_eq!(note("note", " \n ").unwrap(), None);
+		assert!(note("note", "Bell\u{7}").is_err());
+		assert!(note("note", &"a".repeat(MAX_NOTE_LENGTH)).is_ok());
+		let err = note("note", &"a".repeat(MAX_NOTE_LENGTH + 1)).unwrap_err();
+		assert_eq!(err.field, "note");
+	}
+
+	#[test]
+	fn normalizes_whitespace() {
+		assert_eq!(normalize_name("Foo"), "Foo");
+		assert_eq!(normalize_name("Foo "), "Foo");
+		assert_eq!(normalize_name("\t Foo\u{00A0}"), "Foo");
+		assert_eq!(normalize_name("Foo   Bar"), "Foo Bar");
+		assert_eq!(normalize_name("Foo\u{3000}\nBar"), "Foo Bar");
+		assert_eq!(normalize_name("   "), "");
+	}
+
+	#[test]
+	fn removes_zero_width_characters() {
+		assert_eq!(normalize_name("Foo\u{200B}"), "Foo");
+		assert_eq!(normalize_name("\u{FEFF}Foo"), "Foo");
+		assert_eq!(normalize_name("F\u{200B}oo"), "Foo");
+		assert_eq!(normalize_name("\u{200D}Foo\u{200C}"), "Foo");
+		assert_eq!(normalize_name("Foo \u{200B}"), "Foo");
+	}
+
+	#[test]
+	fn keeps_meaningful_joiners() {
+		let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+		assert_eq!(normalize_name(family), family);
+	}
+
+	#[test]
+	fn composes_to_nfc() {
+		assert_eq!(normalize_name("Jose\u{0301}"), "Jos\u{00E9}");
+	}
+}
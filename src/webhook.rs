@@ -0,0 +1,253 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::future;
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client, Url};
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use sha2::Sha256;
+use time::OffsetDateTime;
+use tokio::{sync::Notify, time as tokio_time};
+use tracing::{debug, info, warn};
+
+use crate::db;
+
+/// Maximum number of outbox entries to attempt at once
+const BATCH_SIZE: u16 = 32;
+
+/// How often to check the outbox for retries that have become due
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Seconds to wait before the first retry, doubled for each subsequent one
+const INITIAL_BACKOFF_SECS: i64 = 5;
+
+/// Maximum number of seconds to wait between retries
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+/// Maximum time to wait for a webhook endpoint to respond
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header containing the hex-encoded HMAC-SHA256 signature of a payload
+pub const SIGNATURE_HEADER: &str = "x-shaker-signature";
+
+/// Header containing the Unix timestamp (in seconds) that a payload was signed at
+pub const TIMESTAMP_HEADER: &str = "x-shaker-timestamp";
+
+/// Payload sent to webhooks when a handshake is created
+#[derive(Debug, Clone, Serialize)]
+pub struct Payload {
+	/// Type of event that occurred
+	pub event: &'static str,
+
+	/// Handshake that was created
+	pub handshake: db::Handshake,
+
+	/// User that shook hands (without any note an admin has left on them)
+	pub user: db::User,
+
+	/// Whether this was the user's first handshake
+	pub first_time: bool,
+}
+
+impl Payload {
+	/// Creates a payload for a newly created handshake
+	#[must_use]
+	pub fn handshake(handshake: db::Handshake, user: db::User, first_time: bool) -> Self {
+		Self {
+			event: "handshake",
+			handshake,
+			user: user.without_note(),
+			first_time,
+		}
+	}
+}
+
+/// Computes the hex-encoded HMAC-SHA256 signature of a webhook body.
+///
+/// The signed content is the ASCII decimal Unix timestamp (in seconds, as sent in the `X-Shaker-Timestamp` header),
+/// followed by a single `.` byte, followed by the exact bytes of the request body:
+///
+/// ```text
+/// <timestamp>.<body>
+/// ```
+///
+/// Including the timestamp lets receivers reject replayed deliveries by checking that it's recent. The signature is
+/// sent as lowercase hex in the `X-Shaker-Signature` header.
+///
+/// # Panics
+/// Never in practice, since HMAC accepts keys of any length.
+#[must_use]
+pub fn sign(secret: &Secret<String>, timestamp: i64, body: &[u8]) -> String {
+	let mut mac =
+		Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes()).expect("HMAC accepts keys of any length");
+	mac.update(timestamp.to_string().as_bytes());
+	mac.update(b".");
+	mac.update(body);
+	hex::encode(mac.finalize().into_bytes())
+}
+
+/// Outgoing webhook notifier. Deliveries are queued in the database's outbox alongside the handshakes they're for, so
+/// they survive restarts; this drains the outbox in the background, retrying failures with exponential backoff.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+	/// Wakes the delivery task when new deliveries have been queued
+	wake: Arc<Notify>,
+}
+
+impl Webhooks {
+	/// Spawns the outbox delivery task if there are any webhook URLs, signing payloads with the secret if one is given
+	/// and giving up on each delivery after the given number of attempts. Returns `None` if there are no URLs.
+	pub fn spawn(
+		db: db::Database,
+		urls: &[Url],
+		secret: Option<&Secret<String>>,
+		max_attempts: u32,
+	) -> anyhow::Result<Option<Self>> {
+		if urls.is_empty() {
+			return Ok(None);
+		}
+
+		let client = Client::builder()
+			.timeout(REQUEST_TIMEOUT)
+			.user_agent(concat!("Shaker/", env!("CARGO_PKG_VERSION")))
+			.build()?;
+
+		let wake = Arc::new(Notify::new());
+		let outbox = Outbox {
+			db,
+			client,
+			secret: secret.cloned(),
+			max_attempts,
+		};
+		tokio::spawn(outbox.run(Arc::clone(&wake)));
+
+		info!("Delivering webhooks to {} URL(s)", urls.len());
+		Ok(Some(Self { wake }))
+	}
+
+	/// Wakes the delivery task so that newly-queued deliveries are sent right away
+	pub fn wake(&self) {
+		self.wake.notify_one();
+	}
+}
+
+/// Background task that delivers webhooks queued in the outbox
+struct Outbox {
+	/// Database containing the outbox
+	db: db::Database,
+
+	/// HTTP client to deliver with
+	client: Client,
+
+	/// Secret to sign payloads with
+	secret: Option<Secret<String>>,
+
+	/// Maximum number of attempts to deliver each payload
+	max_attempts: u32,
+}
+
+impl Outbox {
+	/// Delivers due entries whenever woken or polled, forever
+	async fn run(self, wake: Arc<Notify>) {
+		loop {
+			match self.deliver_due().await {
+				// A full batch means there are probably more due entries waiting
+				Ok(count) if count >= usize::from(BATCH_SIZE) => continue,
+				Ok(_) => {}
+				Err(err) => warn!("Unable to process webhook outbox: {err:#}"),
+			}
+
+			tokio::select! {
+				() = wake.notified() => {}
+				() = tokio_time::sleep(POLL_INTERVAL) => {}
+			}
+		}
+	}
+
+	/// Attempts delivery of a batch of due entries concurrently, returning how many there were
+	async fn deliver_due(&self) -> anyhow::Result<usize> {
+		let entries = self.db.get_due_outbox_entries(i64::from(BATCH_SIZE)).await?;
+		future::join_all(entries.iter().map(|entry| self.deliver(entry))).await;
+		Ok(entries.len())
+	}
+
+	/// Attempts delivery of an entry and records the outcome
+	async fn deliver(&self, entry: &db::OutboxEntry) {
+		let result = match self.send(entry).await {
+			Ok(()) => {
+				debug!("Delivered webhook {} to {}", entry.id, entry.url);
+				self.db.mark_outbox_delivered(entry.id).await
+			}
+			Err(err) => {
+				let attempt = u32::try_from(entry.attempts).unwrap_or(u32::MAX).saturating_add(1);
+				let retry_in = (attempt < self.max_attempts).then(|| backoff(attempt));
+				if let Some(delay) = retry_in {
+					debug!(
+						"Webhook {} delivery to {} failed (attempt {attempt}/{}); retrying in {delay}s: {err:#}",
+						entry.id, entry.url, self.max_attempts
+					);
+				} else {
+					warn!(
+						"Giving up on webhook {} delivery to {} after {attempt} attempt(s): {err:#}",
+						entry.id, entry.url
+					);
+				}
+				self.db
+					.mark_outbox_attempt_failed(entry.id, &format!("{err:#}"), retry_in)
+					.await
+			}
+		};
+
+		if let Err(err) = result {
+			warn!("Unable to record outcome of webhook {} delivery: {err:#}", entry.id);
+		}
+	}
+
+	/// Sends an entry's payload to its URL. Each attempt is signed with a fresh timestamp so receivers enforcing a
+	/// freshness window accept retries.
+	async fn send(&self, entry: &db::OutboxEntry) -> anyhow::Result<()> {
+		let mut request = self
+			.client
+			.post(&entry.url)
+			.header(CONTENT_TYPE, "application/json")
+			.body(entry.payload.clone());
+
+		if let Some(secret) = &self.secret {
+			let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+			request = request
+				.header(TIMESTAMP_HEADER, timestamp)
+				.header(SIGNATURE_HEADER, sign(secret, timestamp, entry.payload.as_bytes()));
+		}
+
+		request.send().await?.error_for_status()?;
+		Ok(())
+	}
+}
+
+/// Gets the number of seconds to wait before retrying after the given attempt, doubling each time up to a cap
+fn backoff(attempt: u32) -> i64 {
+	INITIAL_BACKOFF_SECS
+		.saturating_mul(1 << attempt.saturating_sub(1).min(20))
+		.min(MAX_BACKOFF_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn signs_the_timestamp_and_body() {
+		let secret = Secret::new("It's a secret to everybody".to_owned());
+		let body = br#"{"event":"handshake"}"#;
+
+		// Computed independently, as HMAC-SHA256 over `1700000000.{"event":"handshake"}`
+		let signature = sign(&secret, 1_700_000_000, body);
+		assert_eq!(
+			signature,
+			"e17c6f099842528f8710dea0ed6c39ff5e847dee52f75529ec030f380f88e097"
+		);
+
+		assert_ne!(sign(&secret, 1_700_000_001, body), signature);
+		assert_ne!(sign(&secret, 1_700_000_000, br#"{"event":"handshakes"}"#), signature);
+	}
+}
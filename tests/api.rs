@@ -0,0 +1,258 @@
+//! In-process tests of the HTTP API, sending requests straight to the router without binding a socket
+
+use std::collections::HashMap;
+
+use axum::{
+	body::Body,
+	http::{header, Method, Request, StatusCode},
+	response::Response,
+	Router,
+};
+use shaker::{
+	api::{self, AppState},
+	auth::{ScopedToken, TokenRegistry},
+	db::Database,
+};
+use tower::ServiceExt;
+
+/// Token accepted with the admin scope
+const ADMIN_TOKEN: &str = "admin:hunter2";
+
+/// Token accepted with the read scope
+const READ_TOKEN: &str = "read:letmelook";
+
+/// Builds the API's router over a fresh database on each backend being tested (see [`Database::open_tests`]), accepting
+/// the given tokens (or requiring none if there aren't any)
+async fn apps(tokens: &[&str]) -> Vec<Router> {
+	let tokens: Vec<ScopedToken> = tokens.iter().map(|token| token.parse().unwrap()).collect();
+	Database::open_tests()
+		.await
+		.unwrap()
+		.into_iter()
+		.map(|db| api::router(AppState::new(TokenRegistry::new(&tokens), db)))
+		.collect()
+}
+
+/// Sends a request to the router, authenticating with the token if one is given
+async fn send(app: &Router, method: Method, uri: &str, token: Option<&str>, form: Option<&str>) -> Response {
+	let mut request = Request::builder().method(method).uri(uri);
+	if let Some(token) = token {
+		let secret = token.split_once(':').map_or(token, |(_, secret)| secret);
+		request = request.header(header::AUTHORIZATION, format!("Bearer {secret}"));
+	}
+	let body = match form {
+		Some(form) => {
+			request = request.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+			Body::from(form.to_owned())
+		}
+		None => Body::empty(),
+	};
+	app.clone().oneshot(request.body(body).unwrap()).await.unwrap()
+}
+
+/// Reads the whole body of a response as text
+async fn text(response: Response) -> String {
+	let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+	String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Reads the machine-readable code from an error response
+async fn error_code(response: Response) -> String {
+	let body: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+	body["code"].as_str().unwrap().to_owned()
+}
+
+/// Submits a handshake with a write-capable token, returning the fields of the response
+async fn shake(app: &Router, form: &str) -> HashMap<String, String> {
+	let response = send(app, Method::POST, "/handshakes", Some(ADMIN_TOKEN), Some(form)).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	serde_urlencoded::from_str(&text(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn requests_are_authenticated_by_token() {
+	for app in apps(&[ADMIN_TOKEN, READ_TOKEN]).await {
+		let response = send(&app, Method::GET, "/handshakes/count", None, None).await;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+		assert_eq!(error_code(response).await, "bad_request");
+
+		let response = send(&app, Method::GET, "/handshakes/count", Some("admin:wrong"), None).await;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+		assert_eq!(error_code(response).await, "unauthorized");
+
+		let response = send(&app, Method::GET, "/handshakes/count", Some(READ_TOKEN), None).await;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		// The token may also be given in the query string
+		let response = send(&app, Method::GET, "/handshakes/count?token=letmelook", None, None).await;
+		assert_eq!(response.status(), StatusCode::OK);
+
+		// Tokens only grant their own scope
+		let form = Some("id=U-reader&name=Reader");
+		let response = send(&app, Method::POST, "/handshakes", Some(READ_TOKEN), form).await;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		assert_eq!(error_code(response).await, "forbidden");
+	}
+}
+
+#[tokio::test]
+async fn requests_are_unauthenticated_without_tokens() {
+	for app in apps(&[]).await {
+		let response = send(&app, Method::GET, "/handshakes/count", None, None).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		let response = send(&app, Method::POST, "/handshakes", None, Some("id=U-anyone&name=Anyone")).await;
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+}
+
+#[tokio::test]
+async fn handshakes_are_created_and_counted() {
+	for app in apps(&[ADMIN_TOKEN]).await {
+		let created = shake(&app, "id=U-alice&name=Alice&source=lobby").await;
+		assert_eq!(created["first_time"], "true");
+		assert_eq!(created["total_count"], "1");
+		assert_eq!(created["user_count"], "1");
+		assert_eq!(created["resonite_name"], "Alice");
+
+		let created = shake(&app, "id=U-alice&name=Alice").await;
+		assert_eq!(created["first_time"], "false");
+		assert_eq!(created["total_count"], "2");
+		assert_eq!(created["user_count"], "2");
+
+		let created = shake(&app, "id=U-bob&name=Bob").await;
+		assert_eq!(created["first_time"], "true");
+		assert_eq!(created["total_count"], "3");
+
+		// Invalid handshakes are refused without being counted
+		let form = Some("id=alice&name=Alice");
+		let response = send(&app, Method::POST, "/handshakes", Some(ADMIN_TOKEN), form).await;
+		assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+		assert_eq!(error_code(response).await, "invalid");
+
+		let count = |uri: &'static str| {
+			let app = app.clone();
+			async move {
+				let response = send(&app, Method::GET, uri, Some(ADMIN_TOKEN), None).await;
+				assert_eq!(response.status(), StatusCode::OK, "{uri}");
+				text(response).await
+			}
+		};
+		assert_eq!(count("/handshakes/count").await, "3");
+		assert_eq!(count("/users/count").await, "2");
+		assert_eq!(count("/handshakes/count?source=lobby").await, "1");
+		assert_eq!(count("/handshakes/count/user?id=U-alice&name=Alice").await, "2");
+		assert_eq!(count("/handshakes/count/user?id=U-bob&name=Bob").await, "1");
+
+		let response = send(
+			&app,
+			Method::GET,
+			"/handshakes/count/user?id=U-nobody&name=Nobody",
+			Some(ADMIN_TOKEN),
+			None,
+		)
+		.await;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		assert_eq!(error_code(response).await, "not_found");
+	}
+}
+
+#[tokio::test]
+async fn handshakes_are_filtered_and_aggregated_by_event() {
+	for app in apps(&[ADMIN_TOKEN]).await {
+		shake(&app, "id=U-alice&name=Alice&event=MMC").await;
+		shake(&app, "id=U-bob&name=Bob&event=MMC").await;
+		let created = shake(&app, "id=U-bob&name=Bob").await;
+		assert!(!created.contains_key("event"));
+
+		let get = |uri: &'static str| {
+			let app = app.clone();
+			async move {
+				let response = send(&app, Method::GET, uri, Some(ADMIN_TOKEN), None).await;
+				assert_eq!(response.status(), StatusCode::OK, "{uri}");
+				serde_json::from_str::<serde_json::Value>(&text(response).await).unwrap()
+			}
+		};
+		assert_eq!(get("/handshakes/count?event=MMC").await, 2);
+		assert_eq!(get("/handshakes/count?event=Nothing").await, 0);
+		assert_eq!(
+			get("/handshakes?event=MMC").await["handshakes"]
+				.as_array()
+				.unwrap()
+				.len(),
+			2
+		);
+		assert_eq!(get("/handshakes/daily?event=MMC").await["days"][0]["count"], 2);
+
+		let events = get("/events").await;
+		assert_eq!(events[0]["event"], "MMC");
+		assert_eq!(events[0]["count"], 2);
+		assert!(events[1]["event"].is_null(), "untagged handshakes come last");
+		assert_eq!(events[1]["count"], 1);
+	}
+}
+
+#[tokio::test]
+async fn user_notes_are_only_shown_to_admins() {
+	for app in apps(&[ADMIN_TOKEN, READ_TOKEN]).await {
+		let created = shake(&app, "id=U-alice&name=Alice").await;
+		let user_id = &created["user_id"];
+
+		let response = send(
+			&app,
+			Method::PATCH,
+			&format!("/users/{user_id}"),
+			Some(READ_TOKEN),
+			Some("note=Nope"),
+		)
+		.await;
+		assert_eq!(response.status(), StatusCode::FORBIDDEN);
+		let response = send(
+			&app,
+			Method::PATCH,
+			&format!("/users/{user_id}"),
+			Some(ADMIN_TOKEN),
+			Some("note=Organizes+meetups"),
+		)
+		.await;
+		assert_eq!(response.status(), StatusCode::OK);
+		let user: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+		assert_eq!(user["note"], "Organizes meetups");
+
+		let users = |token| {
+			let app = app.clone();
+			async move {
+				let response = send(&app, Method::GET, "/users?include=counts", Some(token), None).await;
+				assert_eq!(response.status(), StatusCode::OK);
+				serde_json::from_str::<serde_json::Value>(&text(response).await).unwrap()
+			}
+		};
+		assert_eq!(users(ADMIN_TOKEN).await[0]["note"], "Organizes meetups");
+		assert!(users(READ_TOKEN).await[0].get("note").is_none());
+
+		let csv = |uri| {
+			let app = app.clone();
+			async move { text(send(&app, Method::GET, uri, Some(ADMIN_TOKEN), None).await).await }
+		};
+		assert!(!csv("/export/users.csv").await.contains("meetups"));
+		assert!(csv("/export/users.csv?include_notes=true")
+			.await
+			.contains(",Organizes meetups\r\n"));
+
+		let response = send(
+			&app,
+			Method::PUT,
+			&format!("/users/{user_id}/note"),
+			Some(ADMIN_TOKEN),
+			None,
+		)
+		.await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert!(
+			users(ADMIN_TOKEN).await[0].get("note").is_none(),
+			"an empty body clears the note"
+		);
+
+		let response = send(&app, Method::PUT, "/users/999/note", Some(ADMIN_TOKEN), None).await;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	}
+}
@@ -0,0 +1,562 @@
+//! Tests that exercise every method of the database from outside the crate, against in-memory and temporary databases
+//! (and the `PostgreSQL` database at `TEST_DATABASE_URL` too, if it's set)
+
+use std::time::Duration;
+
+use futures_util::TryStreamExt;
+use shaker::{
+	db::{
+		Database, EventFilter, HandshakeContext, HandshakeFilter, ImportOutcome, LegacyRecord, NameFilter,
+		NewAuditEntry, NewBan, TimeRange, UndoOutcome, UserFilter, UserOrder, UserResoniteInfo,
+	},
+	validate::FieldLimits,
+};
+use time::OffsetDateTime;
+use time_tz::{timezones, OffsetDateTimeExt};
+
+fn info(id: &str, name: &str) -> UserResoniteInfo {
+	UserResoniteInfo::new(id.to_owned(), name).unwrap()
+}
+
+fn context(id: &str, name: &str, world: Option<&str>, source: Option<&str>) -> HandshakeContext {
+	HandshakeContext {
+		id: id.to_owned(),
+		name: name.to_owned(),
+		world: world.map(str::to_owned),
+		source: source.map(str::to_owned),
+		event: None,
+	}
+}
+
+/// Range of time covering everything stored during a test
+fn around_now() -> TimeRange {
+	let now = OffsetDateTime::now_utc();
+	TimeRange {
+		since: Some(now - time::Duration::days(1)),
+		until: Some(now + time::Duration::days(1)),
+	}
+}
+
+#[tokio::test]
+async fn databases_can_be_opened_and_maintained() {
+	let db = Database::open_in_memory().await.unwrap();
+	assert!(!db.is_read_only());
+	assert_eq!(db.pool_status().size, 1);
+	assert!(db.pending_migrations().await.unwrap().is_empty());
+	db.ensure_migrated().await.unwrap();
+	assert!(db.schema_version().await.unwrap().is_some());
+	assert_eq!(db.journal_mode().await.unwrap(), "memory");
+	assert!(db.is_empty().await.unwrap());
+
+	let revision = db.data_revision().await.unwrap();
+	db.create_user(&info("U-a", "A")).await.unwrap();
+	assert_ne!(db.data_revision().await.unwrap(), revision);
+	assert!(db.integrity_check().await.unwrap().ok);
+	db.close().await;
+
+	// Temporary databases are files on disk, so they can be backed up and reopened
+	let db = Database::open_temp().await.unwrap();
+	assert_eq!(db.journal_mode().await.unwrap(), "wal");
+	db.create_handshake(context("U-a", "A", None, None)).await.unwrap();
+	let dir = tempfile::tempdir().unwrap();
+	let backup = dir.path().join("backup.db");
+	db.backup_to(&backup).await.unwrap();
+	assert!(db.backup_to(&backup).await.is_err());
+
+	let copy = Database::open_url(&format!("sqlite://{}", backup.display()))
+		.await
+		.unwrap();
+	assert_eq!(copy.count_handshakes().await.unwrap(), 1);
+	copy.close().await;
+
+	let settings = shaker::db::ConnectionSettings {
+		read_only: true,
+		..Default::default()
+	};
+	let copy = Database::open(&backup, settings, shaker::db::PoolSettings::default())
+		.await
+		.unwrap();
+	assert!(copy.is_read_only());
+	copy.ensure_migrated().await.unwrap();
+	copy.close().await;
+
+	let options = format!("sqlite://{}", backup.display()).parse().unwrap();
+	let copy = Database::open_with(options, shaker::db::PoolSettings::default())
+		.await
+		.unwrap();
+	copy.migrate().await.unwrap();
+	assert_eq!(copy.count_users().await.unwrap(), 1);
+	copy.close().await;
+}
+
+#[tokio::test]
+async fn users_can_be_created_and_looked_up() {
+	for db in Database::open_tests().await.unwrap() {
+		let alice = db.create_user(&info("U-alice", "Alice")).await.unwrap();
+		let legacy = db.create_legacy_user("Bob").await.unwrap();
+		assert!(legacy.legacy);
+
+		assert_eq!(db.get_user(alice.id).await.unwrap().unwrap().resonite_name, "Alice");
+		assert_eq!(
+			db.get_user_by_resonite_id("U-alice").await.unwrap().unwrap().id,
+			alice.id
+		);
+		assert_eq!(
+			db.get_user_by_resonite_name("alice").await.unwrap().unwrap().id,
+			alice.id
+		);
+		assert_eq!(db.get_users_by_resonite_name("BOB").await.unwrap().len(), 1);
+		assert_eq!(
+			db.get_user_by_resonite_info(&info("U-other", "Bob"))
+				.await
+				.unwrap()
+				.unwrap()
+				.id,
+			legacy.id
+		);
+
+		// Only users with handshakes have shaken hands, and legacy users are found by name
+		assert!(!db.has_shaken_hands("U-bob", Some("Bob")).await.unwrap());
+		db.create_legacy_handshake(legacy.id).await.unwrap();
+		assert!(db.has_shaken_hands("U-bob", Some("Bob")).await.unwrap());
+		assert!(!db.has_shaken_hands("U-carol", Some("Carol")).await.unwrap());
+
+		// Renaming a user keeps their old name around for lookups
+		let mut renamed = alice.clone();
+		renamed.resonite_name = "Alicia".to_owned();
+		assert!(db.update_user(&renamed).await.unwrap());
+		let history = db.get_user_name_history(alice.id).await.unwrap();
+		assert_eq!(
+			(history[0].old_name.as_str(), history[0].new_name.as_str()),
+			("Alice", "Alicia")
+		);
+		assert_eq!(
+			db.get_user_by_past_resonite_name("Alice").await.unwrap().unwrap().id,
+			alice.id
+		);
+		assert!(db
+			.get_user_by_resonite_info(&info("U-new", "Alice"))
+			.await
+			.unwrap()
+			.is_none());
+		assert_eq!(
+			db.get_user_by_resonite_info_with_history(&info("U-new", "Alice"))
+				.await
+				.unwrap()
+				.unwrap()
+				.id,
+			alice.id
+		);
+
+		assert_eq!(db.get_all_users().await.unwrap().len(), 2);
+		assert_eq!(db.stream_all_users().try_collect::<Vec<_>>().await.unwrap().len(), 2);
+		let range = around_now();
+		assert_eq!(
+			db.stream_users_created_in(&range)
+				.try_collect::<Vec<_>>()
+				.await
+				.unwrap()
+				.len(),
+			2
+		);
+		assert_eq!(db.search_users_prefix("ali", 10).await.unwrap()[0].id, alice.id);
+		assert_eq!(db.search_users_fts("lici", 10).await.unwrap()[0].id, alice.id);
+
+		let legacy_only = UserFilter { legacy: Some(true) };
+		assert_eq!(db.get_user_resonite_names(&legacy_only).await.unwrap(), ["Bob"]);
+		assert_eq!(db.count_users().await.unwrap(), 2);
+		assert_eq!(db.count_users_matching(&legacy_only).await.unwrap(), 1);
+
+		let by_name = db.get_users_with_counts(10, 0, UserOrder::Name).await.unwrap();
+		assert_eq!(by_name[0].user.resonite_name, "Alicia");
+		assert_eq!(by_name[0].count, 0);
+	}
+}
+
+#[tokio::test]
+async fn usernames_and_ids_can_be_maintained() {
+	for db in Database::open_tests().await.unwrap() {
+		let alice = db.create_user(&info("U-alice", "Alice")).await.unwrap();
+		let legacy = db.create_legacy_user("Bob").await.unwrap();
+
+		// Names due a refresh are the ones never refreshed, or last refreshed before the cutoff
+		let cutoff = OffsetDateTime::now_utc() - time::Duration::minutes(1);
+		assert_eq!(db.get_users_to_refresh(cutoff, 10).await.unwrap().len(), 1);
+		db.mark_name_refreshed(alice.id).await.unwrap();
+		assert!(db.get_users_to_refresh(cutoff, 10).await.unwrap().is_empty());
+
+		// Users without IDs are only looked up once unless retrying
+		assert_eq!(db.get_users_without_ids(0, false, 10).await.unwrap()[0].id, legacy.id);
+		db.record_id_backfill(legacy.id, "not_found").await.unwrap();
+		assert!(db.get_users_without_ids(0, false, 10).await.unwrap().is_empty());
+		assert_eq!(db.get_users_without_ids(0, true, 10).await.unwrap().len(), 1);
+		assert!(db.set_missing_resonite_id(legacy.id, "U-bob").await.unwrap());
+		assert!(!db.set_missing_resonite_id(legacy.id, "U-robert").await.unwrap());
+
+		// Names are normalized as they're stored, so there's nothing left to normalize, but names differing only in case
+		// are still found as duplicates until they're merged
+		let shouty = db.create_legacy_user("ALICE").await.unwrap();
+		assert_eq!(db.normalize_user_names().await.unwrap().updated, 0);
+		let duplicates = db.find_duplicate_names().await.unwrap();
+		assert_eq!(duplicates.len(), 1);
+		assert_eq!(duplicates[0].user_ids, [alice.id, shouty.id]);
+		db.merge_users(shouty.id, alice.id).await.unwrap().unwrap();
+		assert!(db.find_duplicate_names().await.unwrap().is_empty());
+
+		let limits = FieldLimits {
+			name: 3,
+			..FieldLimits::DEFAULT
+		};
+		let violations = db.find_limit_violations(limits).await.unwrap();
+		assert_eq!(violations.len(), 1);
+		assert_eq!(violations[0].user_id, Some(alice.id));
+	}
+}
+
+#[tokio::test]
+async fn unmatched_users_are_listed_by_name() {
+	for db in Database::open_tests().await.unwrap() {
+		db.create_user(&info("U-alice", "Alice")).await.unwrap();
+		let robert = db.create_legacy_user("Robert").await.unwrap();
+		let bob = db.create_legacy_user("Bob").await.unwrap();
+		let carol = db.create_legacy_user("Carol").await.unwrap();
+		db.create_legacy_handshake(bob.id).await.unwrap();
+		db.create_legacy_handshake(bob.id).await.unwrap();
+		db.anonymize_user(carol.id).await.unwrap().unwrap();
+
+		let everyone = NameFilter::default();
+		let unmatched = db.get_users_missing_resonite_id(&everyone, 10, 0).await.unwrap();
+		let listed: Vec<_> = unmatched.iter().map(|user| (user.user.id, user.count)).collect();
+		assert_eq!(listed, [(bob.id, 2), (robert.id, 0)]);
+		assert_eq!(db.count_users_missing_resonite_id(&everyone).await.unwrap(), 2);
+		assert_eq!(
+			db.get_users_missing_resonite_id(&everyone, 10, 1).await.unwrap()[0]
+				.user
+				.id,
+			robert.id
+		);
+
+		let ro = NameFilter {
+			q: Some("ro".to_owned()),
+		};
+		assert_eq!(
+			db.get_users_missing_resonite_id(&ro, 10, 0).await.unwrap()[0].user.id,
+			robert.id
+		);
+		assert_eq!(db.count_users_missing_resonite_id(&ro).await.unwrap(), 1);
+
+		db.set_missing_resonite_id(bob.id, "U-bob").await.unwrap();
+		assert_eq!(db.count_users_missing_resonite_id(&everyone).await.unwrap(), 1);
+	}
+}
+
+#[tokio::test]
+async fn handshakes_can_be_created_and_retrieved() {
+	for db in Database::open_tests().await.unwrap() {
+		let first = db
+			.create_handshake(context("U-alice", "Alice", Some("Hub"), Some("tablet")))
+			.await
+			.unwrap();
+		assert!(first.first_time);
+		db.create_handshake(context("U-alice", "Alice", Some("Hub"), None))
+			.await
+			.unwrap();
+		let bob = db.create_legacy_user("Bob").await.unwrap();
+		let legacy = db.create_legacy_handshake(bob.id).await.unwrap();
+		assert!(legacy.legacy);
+
+		let alice = first.user.id;
+		let id = first.handshake.id;
+		assert_eq!(db.get_handshake(id).await.unwrap().unwrap().user_id, alice);
+		assert_eq!(
+			db.get_handshake_with_user(id).await.unwrap().unwrap().resonite_name,
+			"Alice"
+		);
+		assert_eq!(db.get_recent_handshakes(10).await.unwrap().len(), 3);
+		assert_eq!(
+			db.get_latest_handshake(Some(bob.id)).await.unwrap().unwrap().id,
+			legacy.id
+		);
+		assert_eq!(db.get_user_recent_handshakes(alice, 1).await.unwrap().len(), 1);
+		assert_eq!(db.get_all_handshakes().await.unwrap().len(), 3);
+		assert_eq!(
+			db.stream_all_handshakes().try_collect::<Vec<_>>().await.unwrap().len(),
+			3
+		);
+		let range = around_now();
+		assert_eq!(
+			db.stream_handshakes_with_users_in(&range)
+				.try_collect::<Vec<_>>()
+				.await
+				.unwrap()
+				.len(),
+			3
+		);
+
+		let page = db
+			.get_handshakes_before(None, &HandshakeFilter::default(), 2)
+			.await
+			.unwrap();
+		assert_eq!(page.len(), 2);
+		let last = page.last().unwrap();
+		let rest = db
+			.get_handshakes_before(Some((last.created_at, last.id)), &HandshakeFilter::default(), 2)
+			.await
+			.unwrap();
+		assert_eq!(rest.len(), 1);
+
+		let utc = timezones::db::UTC;
+		let stats = db.get_user_stats(alice, utc).await.unwrap();
+		assert_eq!((stats.count, stats.worlds, stats.longest_streak), (2, 1, 1));
+		let world = db.get_world_stats("Hub").await.unwrap().unwrap();
+		assert_eq!((world.count, world.unique_users), (2, 1));
+		assert!(db.get_world_stats("Elsewhere").await.unwrap().is_none());
+	}
+}
+
+#[tokio::test]
+async fn handshakes_are_tagged_with_events() {
+	for db in Database::open_tests().await.unwrap() {
+		for (id, name, event) in [
+			("U-alice", "Alice", Some("MMC")),
+			("U-bob", "Bob", Some("MMC")),
+			("U-alice", "Alice", Some("Meetup")),
+			("U-carol", "Carol", None),
+		] {
+			let shake = HandshakeContext {
+				event: event.map(str::to_owned),
+				..context(id, name, None, None)
+			};
+			db.create_handshake(shake).await.unwrap();
+		}
+		let alice = db.get_user_by_resonite_id("U-alice").await.unwrap().unwrap().id;
+
+		let mmc = HandshakeFilter {
+			event: Some("MMC".to_owned()),
+			..HandshakeFilter::default()
+		};
+		assert_eq!(db.count_handshakes_matching(&mmc).await.unwrap(), 2);
+		assert_eq!(db.count_user_handshakes_matching(alice, &mmc).await.unwrap(), 1);
+		let page = db.get_handshakes_before(None, &mmc, 10).await.unwrap();
+		assert_eq!(page.len(), 2);
+		assert!(page.iter().all(|shake| shake.event.as_deref() == Some("MMC")));
+
+		let utc = timezones::db::UTC;
+		let meetup = EventFilter {
+			event: Some("Meetup".to_owned()),
+		};
+		let days = db.count_handshakes_per_day(&around_now(), &meetup, utc).await.unwrap();
+		assert_eq!(days.iter().map(|day| day.count).sum::<i64>(), 1);
+
+		// Untagged handshakes are counted together at the end (the events are all equally recent, to the second)
+		let events = db.count_handshakes_by_event().await.unwrap();
+		let mut summary: Vec<_> = events
+			.iter()
+			.map(|event| (event.event.as_deref(), event.count))
+			.collect();
+		assert_eq!(summary.pop(), Some((None, 1)));
+		summary.sort_unstable();
+		assert_eq!(summary, [(Some("MMC"), 2), (Some("Meetup"), 1)]);
+		assert!(events.iter().all(|event| event.first_at <= event.last_at));
+	}
+}
+
+#[tokio::test]
+async fn handshakes_are_counted() {
+	for db in Database::open_tests().await.unwrap() {
+		for (id, name, source) in [
+			("U-alice", "Alice", Some("tablet")),
+			("U-alice", "Alice", None),
+			("U-bob", "Bob", Some("tablet")),
+		] {
+			db.create_handshake(context(id, name, Some("Hub"), source))
+				.await
+				.unwrap();
+		}
+		let alice = db.get_user_by_resonite_id("U-alice").await.unwrap().unwrap().id;
+
+		let tablet = HandshakeFilter {
+			source: Some("tablet".to_owned()),
+			..HandshakeFilter::default()
+		};
+		assert_eq!(db.count_handshakes().await.unwrap(), 3);
+		assert_eq!(db.count_handshakes_matching(&tablet).await.unwrap(), 2);
+		assert_eq!(db.count_user_handshakes(alice).await.unwrap(), 2);
+		assert_eq!(db.count_user_handshakes_matching(alice, &tablet).await.unwrap(), 1);
+
+		let sources = db.count_handshakes_by_source().await.unwrap();
+		assert_eq!((sources[0].source.as_deref(), sources[0].count), (Some("tablet"), 2));
+
+		let utc = timezones::db::UTC;
+		let today = OffsetDateTime::now_utc().to_timezone(utc).date();
+		assert_eq!(db.count_handshakes_on(today, utc).await.unwrap(), 3);
+		let range = around_now();
+		let days = db
+			.count_handshakes_per_day(&range, &EventFilter::default(), utc)
+			.await
+			.unwrap();
+		assert_eq!(days.iter().map(|day| day.count).sum::<i64>(), 3);
+		let heatmap = db.get_activity_heatmap(&range, Some("Hub"), utc).await.unwrap();
+		assert_eq!(heatmap.iter().map(|cell| cell.count).sum::<i64>(), 3);
+
+		let top = db.get_top_users(1).await.unwrap();
+		assert_eq!((top[0].user_id, top[0].count), (alice, 2));
+		let stats = db.stats(5).await.unwrap();
+		assert_eq!((stats.users, stats.handshakes, stats.worlds), (2, 3, 1));
+		let by_count = db.get_users_with_counts(10, 0, UserOrder::Count).await.unwrap();
+		assert_eq!((by_count[0].user.id, by_count[0].count), (alice, 2));
+
+		// Everyone has shaken hands since a minute ago, but not since a minute from now
+		let filter = UserFilter::default();
+		let minute = time::Duration::minutes(1);
+		let inactive = |since| db.get_inactive_users(since, &filter, 10, 0);
+		assert!(inactive(OffsetDateTime::now_utc() - minute).await.unwrap().is_empty());
+		assert_eq!(inactive(OffsetDateTime::now_utc() + minute).await.unwrap().len(), 2);
+	}
+}
+
+#[tokio::test]
+async fn records_can_be_deleted_and_restored() {
+	for db in Database::open_tests().await.unwrap() {
+		let alice = db
+			.create_handshake(context("U-alice", "Alice", None, None))
+			.await
+			.unwrap();
+		let bob = db.create_handshake(context("U-bob", "Bob", None, None)).await.unwrap();
+
+		assert!(db.delete_handshake(bob.handshake.id).await.unwrap());
+		assert_eq!(db.get_deleted_handshakes(10, 0).await.unwrap().len(), 1);
+		assert!(db.restore_handshake(bob.handshake.id).await.unwrap());
+
+		assert!(db.delete_user(bob.user.id).await.unwrap());
+		assert_eq!(db.get_deleted_users(10, 0).await.unwrap()[0].id, bob.user.id);
+		assert!(db.restore_user(bob.user.id).await.unwrap());
+		assert_eq!(db.count_handshakes().await.unwrap(), 2);
+
+		let outcome = db
+			.undo_latest_handshake(bob.user.id, Duration::from_secs(300), false)
+			.await
+			.unwrap();
+		assert!(matches!(
+			outcome,
+			UndoOutcome::Undone {
+				user_removed: false,
+				..
+			}
+		));
+		let purged = db
+			.purge_deleted(OffsetDateTime::now_utc() + time::Duration::minutes(1))
+			.await
+			.unwrap();
+		assert_eq!((purged.users, purged.handshakes), (0, 1));
+
+		let anonymized = db.anonymize_user(alice.user.id).await.unwrap().unwrap();
+		assert!(anonymized.resonite_id.is_none());
+		assert_eq!(db.count_handshakes().await.unwrap(), 1);
+
+		let expired = db
+			.expire_handshakes(OffsetDateTime::now_utc() + time::Duration::minutes(1), true, 10)
+			.await
+			.unwrap();
+		assert_eq!(expired, 1);
+		assert_eq!(db.count_handshakes().await.unwrap(), 0);
+		assert_eq!(db.stats(0).await.unwrap().archived_handshakes, 1);
+	}
+}
+
+#[tokio::test]
+async fn bans_and_audit_entries_are_stored() {
+	for db in Database::open_tests().await.unwrap() {
+		let by_id = NewBan {
+			resonite_id: Some("U-alice".to_owned()),
+			reason: Some("spam".to_owned()),
+			..NewBan::default()
+		};
+		let by_name = NewBan {
+			resonite_name: Some("Bob".to_owned()),
+			..NewBan::default()
+		};
+		db.ban_user(&by_id).await.unwrap();
+		db.ban_user(&by_name).await.unwrap();
+		assert_eq!(db.get_bans(10, 0).await.unwrap().len(), 2);
+		assert!(db
+			.create_handshake(context("U-alice", "Alice", None, None))
+			.await
+			.is_err());
+		assert!(db.unban_user("U-alice").await.unwrap());
+		assert!(!db.unban_user("U-alice").await.unwrap());
+		assert!(db.unban_user_name("bob").await.unwrap());
+		assert!(db.get_bans(10, 0).await.unwrap().is_empty());
+
+		let entry = NewAuditEntry {
+			method: "DELETE",
+			route: "/users/:id",
+			client_ip: Some("192.0.2.1".to_owned()),
+			scope: "admin",
+			record_ids: "1".to_owned(),
+		};
+		let id = db.create_audit_entry(&entry).await.unwrap();
+		let entries = db.get_audit_entries(10, 0).await.unwrap();
+		assert_eq!((entries[0].id, entries[0].route.as_str()), (id, "/users/:id"));
+	}
+}
+
+#[tokio::test]
+async fn webhook_deliveries_are_queued_in_the_outbox() {
+	for db in Database::open_tests().await.unwrap() {
+		let db = db.with_webhook_urls(&["https://example.com/hook"]);
+		db.create_handshake(context("U-alice", "Alice", None, None))
+			.await
+			.unwrap();
+
+		let due = db.get_due_outbox_entries(10).await.unwrap();
+		assert_eq!(due.len(), 1);
+		let id = due[0].id;
+
+		db.mark_outbox_attempt_failed(id, "timed out", Some(3600))
+			.await
+			.unwrap();
+		assert!(db.get_due_outbox_entries(10).await.unwrap().is_empty());
+		db.mark_outbox_attempt_failed(id, "timed out", None).await.unwrap();
+		let undelivered = db.get_undelivered_outbox_entries(10, 0).await.unwrap();
+		assert_eq!((undelivered[0].status.as_str(), undelivered[0].attempts), ("failed", 2));
+
+		assert!(db.retry_outbox_entry(id).await.unwrap());
+		assert_eq!(db.get_due_outbox_entries(10).await.unwrap()[0].attempts, 0);
+		db.mark_outbox_delivered(id).await.unwrap();
+		assert!(db.get_undelivered_outbox_entries(10, 0).await.unwrap().is_empty());
+	}
+}
+
+#[tokio::test]
+async fn imports_are_only_written_once_committed() {
+	for db in Database::open_tests().await.unwrap() {
+		let records = [
+			LegacyRecord {
+				resonite_id: None,
+				resonite_name: "Alice".to_owned(),
+				world_name: None,
+				created_at: None,
+			},
+			LegacyRecord {
+				resonite_id: Some("U-bob".to_owned()),
+				resonite_name: "Bob".to_owned(),
+				world_name: Some("Hub".to_owned()),
+				created_at: None,
+			},
+		];
+
+		let mut import = db.begin_import().await.unwrap();
+		let outcomes = import.import(&records, true).await.unwrap();
+		assert!(outcomes
+			.iter()
+			.all(|outcome| matches!(outcome, Ok(ImportOutcome::Created))));
+		drop(import);
+		assert!(db.is_empty().await.unwrap());
+
+		let mut import = db.begin_import().await.unwrap();
+		import.import(&records, true).await.unwrap();
+		import.commit().await.unwrap();
+		assert_eq!(db.count_handshakes().await.unwrap(), 2);
+	}
+}